@@ -1,21 +1,97 @@
-use futures::future::try_join_all;
-use std::{collections::BTreeMap, fs};
+use futures::future::{join_all, try_join_all};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, atomic::Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use anyhow::{Error, anyhow};
+use anyhow::{Error, anyhow, bail};
 use rand::{Rng, distr::Alphanumeric};
 use urlencoding::encode_binary;
 
-use crate::{app::ui_models::TorrentItem, torrent::Torrent};
+use crate::{
+    app::{
+        check_order::{CheckOrder, PendingCheck},
+        config::{Config, ConfigWatch},
+        removal::{RemovalPolicy, SeedState},
+        seed_limit::SeedLimit,
+        snapshot::{SessionSnapshot, TorrentSnapshot},
+        start_queue::{QueueOrder, StartQueue, Waiting},
+        swarm_preview::SharedPreview,
+        ui_models::{ConnectionItem, DiskItem, SessionStatus, TorrentItem},
+    },
+    torrent::{
+        Torrent,
+        allocation::Allocation,
+        ban_list::BanList,
+        bind::BindConfig,
+        builder::TorrentBuilder,
+        limits::{Limits, SharedLimits},
+        listener::{self, Acceptors},
+        privacy, proxy,
+        tasks::{self, Subsystem},
+        timeout::Timeouts,
+        tracker::{self, external_ip::ExternalIp, tls},
+    },
+};
 
+pub mod check_order;
+pub mod config;
+pub mod removal;
+pub mod seed_limit;
+pub mod snapshot;
+pub mod start_queue;
+pub mod swarm_preview;
 pub mod ui_models;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CurrentScreen {
     Main,
+    Connections,
+    DiskStats,
 }
 
+/// Directory torrent data is stored under.
+pub const DOWNLOAD_DIR: &str = "downloads";
+
+/// How long a complete torrent seeds without peers before its memory is
+/// reclaimed. Stopped torrents are reclaimed straight away.
+const DORMANT_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// How long exiting waits for trackers to hear that we stopped. Private
+/// trackers count the final totals of the `stopped` announce towards the
+/// ratio, but a tracker that is down mustn't keep us from exiting.
+const STOPPED_ANNOUNCE_WINDOW: Duration = Duration::from_secs(5);
+
 pub struct App {
     torrents: BTreeMap<String, Torrent>,
     pub peer_id: String,
+    peer_id_bytes: [u8; 20],
+    download_dir: PathBuf,
+    check_order: CheckOrder,
+    external_ip: ExternalIp,
+    /// Peers banned for sending bad data, shared by every torrent.
+    bans: BanList,
+    /// Connection and upload slot caps, shared by every torrent.
+    limits: SharedLimits,
+    removal_policy: Option<RemovalPolicy>,
+    seed_limit: Option<SeedLimit>,
+    /// Torrents started past `BTRS_MAX_ACTIVE`, see [`start_queue`].
+    start_queue: StartQueue,
+    /// Config file reloaded when it changes, see [`config`].
+    config_watch: Option<ConfigWatch>,
+    /// Swarm of the torrent in the add dialog, see [`swarm_preview`].
+    swarm_preview: SharedPreview,
+    /// Takers of incoming connections, one per loaded torrent.
+    acceptors: Acceptors,
+}
+
+impl Default for App {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl App {
@@ -35,9 +111,26 @@ impl App {
 
         let peer_id = encode_binary(&peer_id_bytes).into_owned();
 
+        let config = Config::load().unwrap_or_else(|e| {
+            eprintln!("ERROR: {e:#}, using the environment only");
+            Config::env()
+        });
+
         let mut app = Self {
             torrents: BTreeMap::new(),
             peer_id,
+            peer_id_bytes,
+            download_dir: PathBuf::from(DOWNLOAD_DIR),
+            check_order: CheckOrder::from_config(&config),
+            external_ip: ExternalIp::from_env(),
+            bans: BanList::from_env(),
+            limits: SharedLimits::new(Limits::from_lookup(|name| config.var(name))),
+            removal_policy: RemovalPolicy::from_config(&config),
+            seed_limit: SeedLimit::from_config(&config),
+            start_queue: StartQueue::from_config(&config),
+            config_watch: ConfigWatch::from_env(),
+            swarm_preview: SharedPreview::default(),
+            acceptors: Acceptors::default(),
         };
 
         app.add_torrent("test_files/A_Little_Princess_WB39_WOC_2001-07_archive.torrent")
@@ -49,20 +142,560 @@ impl App {
     pub fn add_torrent(&mut self, file_path: &str) -> Result<(), Error> {
         let bytes: Vec<u8> = fs::read(file_path).expect("{file_path} not found.");
 
-        let torrent = Torrent::load(&bytes, &self.peer_id)?;
+        self.add_torrent_bytes(&bytes)?;
+
+        Ok(())
+    }
 
-        self.torrents.insert(torrent.info_hash().into(), torrent);
+    /// Loads a torrent from .torrent file bytes, returning its info hash.
+    pub fn add_torrent_bytes(&mut self, bytes: &[u8]) -> Result<String, Error> {
+        let mut torrent = Torrent::load(bytes, &self.peer_id)?;
+        torrent.set_external_ip(self.external_ip.clone());
+        torrent.set_ban_list(self.bans.clone());
+        torrent.set_limits(self.limits.clone());
+        let info_hash = torrent.info_hash_hex();
+
+        self.insert_torrent(torrent);
+
+        Ok(info_hash)
+    }
+
+    /// Adds a loaded torrent to the session, so it also takes incoming
+    /// connections once started.
+    fn insert_torrent(&mut self, torrent: Torrent) {
+        let acceptor = torrent.acceptor(&self.download_dir, self.peer_id_bytes);
+        self.acceptors
+            .lock()
+            .unwrap()
+            .insert(*torrent.info_hash(), Arc::new(acceptor));
+        self.torrents.insert(torrent.info_hash_hex(), torrent);
+    }
+
+    /// Accepts connections from peers in the background, on the port
+    /// announced to trackers.
+    pub async fn listen(&self) -> Result<(), Error> {
+        // Peers would reach us around the proxy.
+        proxy::check_direct("incoming peer connections")?;
+        let socket = listener::bind(BindConfig::from_env().address).await?;
+        tasks::spawn(
+            Subsystem::Peer,
+            listener::serve(socket, Arc::clone(&self.acceptors)),
+        );
 
         Ok(())
     }
 
-    pub fn tick(&mut self) {}
+    /// Writes every loaded torrent and its transfer totals to a snapshot file.
+    pub async fn export_session(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let mut torrents = vec![];
 
-    pub async fn download_torrent(&mut self, selected: &str) -> Result<(), Error> {
+        for torrent in self.torrents.values() {
+            let (uploaded, downloaded) = torrent.transfer_totals();
+
+            torrents.push(TorrentSnapshot {
+                metainfo: torrent.metainfo_bytes().to_vec().into(),
+                uploaded,
+                downloaded,
+                last_active: torrent.last_active(),
+                added: Some(torrent.added()),
+                completed: torrent.completed().await,
+                key: Some(torrent.tracker_key().await),
+                trackers: Some(torrent.trackers().await),
+                allocation: torrent.allocation().map(|a| a.to_string()),
+            });
+        }
+
+        SessionSnapshot::new(torrents).write(path)
+    }
+
+    /// Adds every torrent from a snapshot file. Torrents that are already
+    /// loaded keep their current state, the rest are queued to have their
+    /// data checked. Entries that don't load are skipped.
+    pub async fn import_session(&mut self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let snapshot = SessionSnapshot::read(path)?;
+        let mut imported = vec![];
+
+        for (number, entry) in snapshot.torrents.into_iter().enumerate() {
+            let mut torrent = match Torrent::load(&entry.metainfo, &self.peer_id) {
+                Ok(torrent) => torrent,
+                Err(e) => {
+                    eprintln!("[Snapshot] Skipping torrent {}: {e:#}", number + 1);
+                    continue;
+                }
+            };
+
+            if self.torrents.contains_key(&torrent.info_hash_hex()) {
+                continue;
+            }
+
+            torrent.restore_transfer_totals(entry.uploaded, entry.downloaded);
+            torrent.set_last_active(entry.last_active);
+            if let Some(added) = entry.added {
+                torrent.restore_dates(added, entry.completed).await;
+            }
+            if let Some(key) = entry.key {
+                torrent.restore_tracker_key(key).await;
+            }
+            if let Some(trackers) = entry.trackers {
+                torrent.restore_trackers(trackers).await;
+            }
+            match entry.allocation.as_deref().map(str::parse) {
+                Some(Ok(allocation)) => torrent.set_allocation(Some(allocation)),
+                Some(Err(e)) => eprintln!("[Snapshot] Ignoring allocation: {e:#}"),
+                None => {}
+            }
+            torrent.set_external_ip(self.external_ip.clone());
+            torrent.set_ban_list(self.bans.clone());
+            torrent.set_limits(self.limits.clone());
+            imported.push(torrent.info_hash_hex());
+            self.insert_torrent(torrent);
+        }
+
+        self.check_torrents(imported).await;
+
+        Ok(())
+    }
+
+    /// Checks the data of the given torrents one after another in the
+    /// configured [`CheckOrder`]. Torrents not in the queue, or already
+    /// checked, can be started while the rest are still checking.
+    async fn check_torrents(&self, info_hashes: Vec<String>) {
+        let mut pending = vec![];
+        for info_hash in info_hashes {
+            let torrent = &self.torrents[&info_hash];
+            torrent.queue_check().await;
+            pending.push(PendingCheck {
+                size: torrent.total_length(),
+                last_active: torrent.last_active(),
+                info_hash,
+            });
+        }
+        self.check_order.sort(&mut pending);
+
+        let checks: Vec<_> = pending
+            .iter()
+            .map(|check| self.torrents[&check.info_hash].check_task(self.download_dir.clone()))
+            .collect();
+
+        tasks::spawn(Subsystem::Check, async move {
+            for check in checks {
+                check.await;
+            }
+        });
+    }
+
+    /// Creates a torrent of `path`, saves it as `<name>.torrent` in the
+    /// working directory and adds it to the session. An empty `tracker`
+    /// creates a trackerless torrent.
+    pub fn create_torrent(&mut self, path: &str, tracker: &str) -> Result<String, Error> {
+        let mut builder = TorrentBuilder::new(path);
+        if !tracker.is_empty() {
+            builder = builder.tracker(tracker);
+        }
+        let bytes = builder.build()?;
+        let info_hash = self.add_torrent_bytes(&bytes)?;
+
+        let name = self.torrents[&info_hash].name().to_owned();
+        fs::write(format!("{name}.torrent"), &bytes)?;
+
+        Ok(info_hash)
+    }
+
+    pub fn magnet_uri(&self, selected: &str) -> Result<String, Error> {
+        Ok(self
+            .torrents
+            .get(selected)
+            .ok_or(anyhow!("Element not found"))?
+            .magnet_uri())
+    }
+
+    /// Every open peer connection across all torrents.
+    pub async fn connection_items(&self) -> Vec<ConnectionItem> {
+        let mut items = vec![];
+
+        for torrent in self.torrents.values() {
+            for connection in torrent.connections().await {
+                items.push(ConnectionItem::from_connection(torrent, connection));
+            }
+        }
+
+        items
+    }
+
+    pub fn session_status(&self) -> SessionStatus {
+        let mut status = SessionStatus::new(self.external_ip.get());
+        status.swarm_preview = self.swarm_preview.lock().unwrap().clone();
+
+        status
+    }
+
+    /// Scrapes the trackers of the .torrent file or magnet link `source`
+    /// for the add dialog.
+    pub fn preview_swarm(&self, source: &str) -> Result<(), Error> {
+        swarm_preview::start(&self.swarm_preview, source, &self.peer_id)
+    }
+
+    /// Adds the .torrent file at `path` from the add dialog and checks
+    /// its data.
+    pub async fn open_torrent(&mut self, path: &str) -> Result<(), Error> {
+        *self.swarm_preview.lock().unwrap() = None;
+        if path.starts_with("magnet:") {
+            bail!("Magnet links can't be added yet, fetching their metadata isn't supported");
+        }
+
+        let bytes = fs::read(path)?;
+        let info_hash = self.add_torrent_bytes(&bytes)?;
+        self.check_torrents(vec![info_hash]).await;
+
+        Ok(())
+    }
+
+    pub fn disk_items(&self) -> Vec<DiskItem> {
+        self.torrents.values().map(DiskItem::from_torrent).collect()
+    }
+
+    pub async fn kill_connection(&self, selected: &str, address: &str) -> Result<(), Error> {
         self.torrents
-            .get_mut(selected)
+            .get(selected)
+            .ok_or(anyhow!("Element not found"))?
+            .kill_connection(address)
+            .await;
+
+        Ok(())
+    }
+
+    pub async fn force_announce(&self, selected: &str) -> Result<(), Error> {
+        self.torrents
+            .get(selected)
             .ok_or(anyhow!("Element not found"))?
-            .start_tracker();
+            .force_announce()
+            .await;
+
+        Ok(())
+    }
+
+    /// Adds a tracker to the selected torrent while it runs.
+    pub async fn add_tracker(&self, selected: &str, url: &str) -> Result<(), Error> {
+        self.torrents
+            .get(selected)
+            .ok_or(anyhow!("Element not found"))?
+            .add_tracker(url)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn remove_tracker(&self, selected: &str, url: &str) -> Result<(), Error> {
+        self.torrents
+            .get(selected)
+            .ok_or(anyhow!("Element not found"))?
+            .remove_tracker(url)
+            .await;
+
+        Ok(())
+    }
+
+    /// Stops every torrent, waiting [`STOPPED_ANNOUNCE_WINDOW`] at most for
+    /// trackers to hear that we stopped.
+    pub async fn shutdown(&mut self) {
+        let stops: Vec<_> = self.torrents.values_mut().map(Torrent::stop).collect();
+
+        let _ = tokio::time::timeout(STOPPED_ANNOUNCE_WINDOW, join_all(stops)).await;
+    }
+
+    /// Periodic housekeeping, run about once a second.
+    pub async fn tick(&mut self) -> Result<(), Error> {
+        if self.config_watch.as_mut().is_some_and(ConfigWatch::changed) {
+            self.reload_config();
+        }
+
+        for torrent in self.torrents.values() {
+            if torrent.poll_completion().await {
+                println!("[Seeding] {} is complete", privacy::name(torrent.name()));
+                if let Err(e) = torrent.journal(&self.download_dir).clear() {
+                    eprintln!("[Journal] {e:#}");
+                }
+            }
+        }
+        self.apply_seed_limit().await?;
+        self.apply_removal_policy().await?;
+        self.start_waiting().await;
+
+        for torrent in self.torrents.values_mut() {
+            if torrent.is_dormant(DORMANT_AFTER).await {
+                torrent.reclaim_memory().await;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stops seeding every running torrent that reached the
+    /// [`SeedLimit`].
+    async fn apply_seed_limit(&mut self) -> Result<(), Error> {
+        let Some(limit) = self.seed_limit.clone() else {
+            return Ok(());
+        };
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        for torrent in self.torrents.values_mut() {
+            if torrent.is_running() && limit.is_reached(Self::seed_state(torrent, now).await) {
+                println!(
+                    "[Seeding] {} reached its seed limit, stopping",
+                    privacy::name(torrent.name())
+                );
+                torrent.stop();
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn seed_state(torrent: &Torrent, now: u64) -> SeedState {
+        SeedState {
+            ratio: torrent.ratio(),
+            complete_for: torrent
+                .completed()
+                .await
+                .map(|completed| Duration::from_secs(now.saturating_sub(completed))),
+        }
+    }
+
+    /// Removes every torrent the [`RemovalPolicy`] says is done seeding.
+    async fn apply_removal_policy(&mut self) -> Result<(), Error> {
+        let Some(policy) = self.removal_policy.clone() else {
+            return Ok(());
+        };
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut done = vec![];
+        for (info_hash, torrent) in &self.torrents {
+            if policy.should_remove(Self::seed_state(torrent, now).await) {
+                done.push(info_hash.clone());
+            }
+        }
+
+        for info_hash in done {
+            // The torrent is gone either way, only its data may be left.
+            if let Err(e) = self.remove_torrent(&info_hash, policy.delete_data).await {
+                eprintln!("ERROR: Failed to remove {info_hash}: {e:#}");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Applies the settings of the config file that take effect without
+    /// restarting torrents. A config that can't be read leaves the
+    /// current settings alone.
+    pub fn reload_config(&mut self) {
+        let Some(watch) = &self.config_watch else {
+            return;
+        };
+        // A deleted file leaves the environment.
+        let config = if watch.path().exists() {
+            match Config::read(watch.path()) {
+                Ok(config) => config,
+                Err(e) => {
+                    eprintln!("ERROR: Not reloading config: {e:#}");
+                    return;
+                }
+            }
+        } else {
+            Config::env()
+        };
+
+        self.seed_limit = SeedLimit::from_config(&config);
+        self.removal_policy = RemovalPolicy::from_config(&config);
+        self.start_queue.reconfigure(&config);
+        self.limits
+            .set(Limits::from_lookup(|name| config.var(name)));
+        println!(
+            "[Config] Reloaded, seed limit {:?}, removal policy {:?}, max active {:?}, {:?}",
+            self.seed_limit,
+            self.removal_policy,
+            self.start_queue.max_active,
+            self.limits.get()
+        );
+    }
+
+    /// Stops a torrent and removes it from the session, optionally
+    /// deleting its downloaded data.
+    pub async fn remove_torrent(&mut self, selected: &str, delete_data: bool) -> Result<(), Error> {
+        let mut torrent = self
+            .torrents
+            .remove(selected)
+            .ok_or(anyhow!("Element not found"))?;
+        torrent.stop();
+        self.acceptors.lock().unwrap().remove(torrent.info_hash());
+        self.start_queue.remove(selected);
+
+        if delete_data {
+            // A crafted name like ".." must not delete outside the download
+            // directory.
+            let mut components = Path::new(torrent.name()).components();
+            if !matches!(
+                (components.next(), components.next()),
+                (Some(std::path::Component::Normal(_)), None)
+            ) {
+                bail!(
+                    "Refusing to delete data of torrent named {:?}",
+                    torrent.name()
+                );
+            }
+
+            let path = torrent.data_path(&self.download_dir);
+            let result = if path.is_dir() {
+                fs::remove_dir_all(&path)
+            } else {
+                fs::remove_file(&path)
+            };
+            match result {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(Error::from(e).context(format!("Cannot delete {}", path.display())));
+                }
+                _ => {}
+            }
+            torrent.journal(&self.download_dir).clear()?;
+        }
+
+        Ok(())
+    }
+
+    /// Starts the torrent, or queues it if too many are running. Stops it
+    /// instead if it is running or queued already.
+    pub async fn download_torrent(&mut self, selected: &str) -> Result<(), Error> {
+        let active = self.active_count();
+        let torrent = self
+            .torrents
+            .get_mut(selected)
+            .ok_or(anyhow!("Element not found"))?;
+
+        if torrent.is_running() {
+            torrent.stop();
+            return Ok(());
+        }
+        if self.start_queue.remove(selected) {
+            torrent.unqueue_start();
+            return Ok(());
+        }
+
+        if torrent.check_status().await.is_pending() {
+            eprintln!(
+                "[Check] {} is still being checked",
+                privacy::name(torrent.name())
+            );
+            return Ok(());
+        }
+        if !self.start_queue.has_room(active) {
+            self.queue_torrent(selected).await;
+            return Ok(());
+        }
+
+        self.start_torrent(selected).await
+    }
+
+    /// Torrents running, i.e. taking a slot of the start queue.
+    fn active_count(&self) -> usize {
+        self.torrents.values().filter(|t| t.is_running()).count()
+    }
+
+    /// Adds a torrent to the start queue, scraping its trackers for the
+    /// smart order.
+    async fn queue_torrent(&mut self, info_hash: &str) {
+        let torrent = &self.torrents[info_hash];
+        let waiting = Waiting {
+            info_hash: String::from(info_hash),
+            complete: torrent.left_handle().load(Ordering::Relaxed) == 0,
+            swarm: Arc::default(),
+        };
+        println!(
+            "[Queue] {} is waiting for a slot",
+            privacy::name(torrent.name())
+        );
+        torrent.queue_start();
+
+        if self.start_queue.order == QueueOrder::Smart {
+            let urls: Vec<String> = torrent.trackers().await.into_iter().flatten().collect();
+            let target = *torrent.info_hash();
+            let swarm = Arc::clone(&waiting.swarm);
+            tasks::spawn(Subsystem::Tracker, async move {
+                let client = tls::tracker_client();
+                let timeout = Timeouts::default().tracker;
+                // The first tracker that answers will do.
+                for url in urls {
+                    if let Ok(stats) = tracker::scrape(&client, &url, &target, timeout).await {
+                        *swarm.lock().unwrap() = Some(stats);
+                        break;
+                    }
+                }
+            });
+        }
+        self.start_queue.push(waiting);
+    }
+
+    /// Starts queued torrents while there is room for them. A torrent that
+    /// fails to start is marked as failed and the rest go on.
+    async fn start_waiting(&mut self) {
+        while self.start_queue.has_room(self.active_count()) {
+            let Some(waiting) = self.start_queue.pop_next() else {
+                break;
+            };
+            if !self.torrents.contains_key(&waiting.info_hash) {
+                continue;
+            }
+
+            if let Err(e) = self.start_torrent(&waiting.info_hash).await {
+                eprintln!("ERROR: Failed to start {}: {e:#}", waiting.info_hash);
+                self.torrents[&waiting.info_hash].fail(format!("start failed: {e}"));
+            }
+        }
+    }
+
+    /// Allocates the torrent's files if it is downloading and starts
+    /// announcing.
+    async fn start_torrent(&mut self, selected: &str) -> Result<(), Error> {
+        let torrent = self
+            .torrents
+            .get_mut(selected)
+            .ok_or(anyhow!("Element not found"))?;
+
+        if torrent.left_handle().load(Ordering::Relaxed) > 0 {
+            match torrent.allocate_files(self.download_dir.clone()).await {
+                Ok((allocation, Some(filesystem))) => println!(
+                    "[Allocation] {}: {allocation} on {filesystem}",
+                    privacy::name(torrent.name())
+                ),
+                Ok(_) => {}
+                // Starting would only fail later, when pieces are written.
+                Err(e) => {
+                    eprintln!("ERROR: Not starting, allocation failed: {e:#}");
+                    torrent.unqueue_start();
+                    return Ok(());
+                }
+            }
+        }
+        // A queued torrent only leaves the queue by pausing.
+        torrent.unqueue_start();
+        torrent.start_tracker();
+
+        Ok(())
+    }
+
+    /// Moves the torrent's allocation strategy on to the next override,
+    /// back to the filesystem's after the last one.
+    pub fn cycle_allocation(&mut self, selected: &str) -> Result<(), Error> {
+        let torrent = self
+            .torrents
+            .get_mut(selected)
+            .ok_or(anyhow!("Element not found"))?;
+
+        torrent.set_allocation(match torrent.allocation() {
+            None => Some(Allocation::Full),
+            Some(Allocation::Full) => Some(Allocation::Sparse),
+            Some(Allocation::Sparse) => Some(Allocation::WriteThrough),
+            Some(Allocation::WriteThrough) => None,
+        });
 
         Ok(())
     }
@@ -70,10 +703,7 @@ impl App {
     pub async fn torrent_items(&self) -> Result<Vec<TorrentItem>, anyhow::Error> {
         // By default sorted based on key, which is info hash
 
-        let futures = self
-            .torrents
-            .iter()
-            .map(|(_k, v)| TorrentItem::try_from_torrent(v));
+        let futures = self.torrents.values().map(TorrentItem::try_from_torrent);
 
         try_join_all(futures).await
     }
@@ -0,0 +1,82 @@
+//! Order torrents are checked in on startup.
+//!
+//! Torrents are checked one at a time so those at the front of the queue
+//! become startable as soon as possible. Set the `BTRS_CHECK_ORDER`
+//! environment variable to `smallest` (the default) or `recent` to choose
+//! which come first.
+
+use crate::app::config::Config;
+
+pub const CHECK_ORDER_ENV_VAR: &str = "BTRS_CHECK_ORDER";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CheckOrder {
+    /// Smallest torrents first, as they finish checking fastest.
+    #[default]
+    SmallestFirst,
+    /// Most recently started torrents first, never started ones last.
+    RecentlyActive,
+}
+
+/// A torrent waiting to be checked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingCheck {
+    pub info_hash: String,
+    pub size: u64,
+    pub last_active: Option<u64>,
+}
+
+impl CheckOrder {
+    pub fn from_config(config: &Config) -> Self {
+        match config.var(CHECK_ORDER_ENV_VAR).as_deref() {
+            Some("recent") => CheckOrder::RecentlyActive,
+            Some("smallest") | None => CheckOrder::SmallestFirst,
+            Some(other) => {
+                eprintln!("[Check] Unknown {CHECK_ORDER_ENV_VAR} '{other}', using smallest");
+                CheckOrder::SmallestFirst
+            }
+        }
+    }
+
+    /// Sorts pending checks into the order they should run in. Ties keep
+    /// their existing order.
+    pub fn sort(self, checks: &mut [PendingCheck]) {
+        match self {
+            CheckOrder::SmallestFirst => checks.sort_by_key(|check| check.size),
+            CheckOrder::RecentlyActive => {
+                checks.sort_by_key(|check| std::cmp::Reverse(check.last_active))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(info_hash: &str, size: u64, last_active: Option<u64>) -> PendingCheck {
+        PendingCheck {
+            info_hash: String::from(info_hash),
+            size,
+            last_active,
+        }
+    }
+
+    #[test]
+    fn test_sort_orders() {
+        let mut checks = vec![
+            check("large", 300, Some(10)),
+            check("never", 100, None),
+            check("recent", 200, Some(20)),
+        ];
+        let hashes = |checks: &[PendingCheck]| -> Vec<String> {
+            checks.iter().map(|c| c.info_hash.clone()).collect()
+        };
+
+        CheckOrder::SmallestFirst.sort(&mut checks);
+        assert_eq!(hashes(&checks), vec!["never", "recent", "large"]);
+
+        CheckOrder::RecentlyActive.sort(&mut checks);
+        assert_eq!(hashes(&checks), vec!["recent", "large", "never"]);
+    }
+}
@@ -0,0 +1,154 @@
+//! Session settings read from an optional config file as well as the
+//! environment.
+//!
+//! Point `BTRS_CONFIG` at a file of `NAME=value` lines, using the names of
+//! the environment variables, e.g. `BTRS_SEED_RATIO=2`. Blank lines and
+//! lines starting with `#` are skipped, and values in the file win over
+//! the environment. The file covers the check order, the start queue,
+//! the seeding and removal limits and the connection limits, the rest is
+//! only read from the environment.
+//!
+//! The file is watched while btrs runs, and all but the check order are
+//! applied as soon as it changes, without restarting any torrent.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{Context, Error, bail};
+
+pub const CONFIG_ENV_VAR: &str = "BTRS_CONFIG";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Config {
+    values: HashMap<String, String>,
+}
+
+impl Config {
+    /// Settings from the environment alone.
+    pub fn env() -> Self {
+        Self::default()
+    }
+
+    /// Reads the file named by [`CONFIG_ENV_VAR`], or only the
+    /// environment if it isn't set.
+    pub fn load() -> Result<Self, Error> {
+        match config_path() {
+            Some(path) => Self::read(&path),
+            None => Ok(Self::env()),
+        }
+    }
+
+    pub fn read(path: &Path) -> Result<Self, Error> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Cannot read config {}", path.display()))?;
+
+        Self::parse(&text).with_context(|| format!("Invalid config {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let mut values = HashMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((name, value)) = line.split_once('=') else {
+                bail!("Line {} isn't NAME=value", number + 1);
+            };
+            values.insert(String::from(name.trim()), String::from(value.trim()));
+        }
+
+        Ok(Self { values })
+    }
+
+    /// Value of the setting `name`, from the file or else the environment.
+    pub fn var(&self, name: &str) -> Option<String> {
+        self.values
+            .get(name)
+            .cloned()
+            .or_else(|| std::env::var(name).ok())
+    }
+
+    /// Whether the setting `name` is given at all, for flags.
+    pub fn is_set(&self, name: &str) -> bool {
+        self.values.contains_key(name) || std::env::var_os(name).is_some()
+    }
+}
+
+fn config_path() -> Option<PathBuf> {
+    std::env::var_os(CONFIG_ENV_VAR).map(PathBuf::from)
+}
+
+/// Notices changes to the config file by its modification time.
+#[derive(Debug)]
+pub struct ConfigWatch {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl ConfigWatch {
+    /// Watches the file named by [`CONFIG_ENV_VAR`], `None` if it isn't
+    /// set.
+    pub fn from_env() -> Option<Self> {
+        config_path().map(Self::new)
+    }
+
+    pub fn new(path: PathBuf) -> Self {
+        let modified = modified(&path);
+
+        Self { path, modified }
+    }
+
+    /// Whether the file was modified, created or deleted since the last
+    /// call.
+    pub fn changed(&mut self) -> bool {
+        let modified = modified(&self.path);
+
+        modified != std::mem::replace(&mut self.modified, modified)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config =
+            Config::parse("# Seeding\nBTRS_TEST_SEED_RATIO = 2.5\n\nBTRS_TEST_REMOVE_DATA=\n")
+                .unwrap();
+
+        assert_eq!(config.var("BTRS_TEST_SEED_RATIO").as_deref(), Some("2.5"));
+        assert!(config.is_set("BTRS_TEST_REMOVE_DATA"));
+        assert_eq!(config.var("BTRS_TEST_UNSET"), None);
+        assert!(Config::parse("BTRS_TEST_SEED_RATIO").is_err());
+    }
+
+    #[test]
+    fn test_watch_notices_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("btrs.conf");
+        let mut watch = ConfigWatch::new(path.clone());
+        assert!(!watch.changed());
+
+        fs::write(&path, "BTRS_SEED_RATIO=1").unwrap();
+        assert!(watch.changed());
+        assert!(!watch.changed());
+
+        fs::remove_file(&path).unwrap();
+        assert!(watch.changed());
+    }
+}
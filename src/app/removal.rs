@@ -0,0 +1,132 @@
+//! Automatic removal of completed torrents that have seeded enough.
+//!
+//! Disabled unless at least one limit is set:
+//! - `BTRS_REMOVE_RATIO`: remove once the upload ratio reaches this value.
+//! - `BTRS_REMOVE_SEED_DAYS`: remove once complete for this many days.
+//!
+//! A torrent is removed as soon as either limit is met. Setting
+//! `BTRS_REMOVE_DATA` also deletes its downloaded data.
+
+use std::time::Duration;
+
+use crate::app::config::Config;
+
+pub const RATIO_ENV_VAR: &str = "BTRS_REMOVE_RATIO";
+pub const SEED_DAYS_ENV_VAR: &str = "BTRS_REMOVE_SEED_DAYS";
+pub const DELETE_DATA_ENV_VAR: &str = "BTRS_REMOVE_DATA";
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemovalPolicy {
+    pub ratio: Option<f64>,
+    pub seed_time: Option<Duration>,
+    pub delete_data: bool,
+}
+
+/// What the policy needs to know about a torrent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SeedState {
+    pub ratio: f64,
+    /// How long the torrent has been complete, `None` while incomplete.
+    pub complete_for: Option<Duration>,
+}
+
+impl RemovalPolicy {
+    /// Reads the policy from `config`, `None` when no limit is set.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let policy = Self {
+            ratio: parse_limit(config, RATIO_ENV_VAR, "Removal", Some),
+            seed_time: parse_limit(config, SEED_DAYS_ENV_VAR, "Removal", days),
+            delete_data: config.is_set(DELETE_DATA_ENV_VAR),
+        };
+
+        (policy.ratio.is_some() || policy.seed_time.is_some()).then_some(policy)
+    }
+
+    pub fn should_remove(&self, state: SeedState) -> bool {
+        state.reached(self.ratio, self.seed_time)
+    }
+}
+
+impl SeedState {
+    /// Whether the torrent is complete and meets either limit.
+    pub fn reached(&self, ratio: Option<f64>, seed_time: Option<Duration>) -> bool {
+        let Some(complete_for) = self.complete_for else {
+            return false;
+        };
+
+        ratio.is_some_and(|ratio| self.ratio >= ratio)
+            || seed_time.is_some_and(|seed_time| complete_for >= seed_time)
+    }
+}
+
+/// Reads the limit `var` from `config` as a number passed through
+/// `convert`, logging under `tag` and ignoring values that don't parse or
+/// that `convert` rejects.
+pub fn parse_limit<T>(
+    config: &Config,
+    var: &str,
+    tag: &str,
+    convert: impl FnOnce(f64) -> Option<T>,
+) -> Option<T> {
+    config.var(var).and_then(|value| {
+        let limit = value.parse::<f64>().ok().and_then(convert);
+        if limit.is_none() {
+            eprintln!("[{tag}] Ignoring invalid {var}");
+        }
+        limit
+    })
+}
+
+/// `days` as a duration, `None` when too long to represent, including
+/// infinity.
+pub fn days(days: f64) -> Option<Duration> {
+    Duration::try_from_secs_f64(DAY.as_secs_f64() * days.max(0.0)).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_remove() {
+        let policy = RemovalPolicy {
+            ratio: Some(2.0),
+            seed_time: Some(DAY * 7),
+            delete_data: false,
+        };
+        let state = |ratio, days: Option<u32>| SeedState {
+            ratio,
+            complete_for: days.map(|days| DAY * days),
+        };
+
+        assert!(!policy.should_remove(state(5.0, None)));
+        assert!(!policy.should_remove(state(1.0, Some(1))));
+        assert!(policy.should_remove(state(2.0, Some(0))));
+        assert!(policy.should_remove(state(0.0, Some(7))));
+
+        let ratio_only = RemovalPolicy {
+            seed_time: None,
+            ..policy
+        };
+        assert!(!ratio_only.should_remove(state(0.0, Some(365))));
+    }
+
+    #[test]
+    fn test_from_config_ignores_unrepresentable_days() {
+        let config = Config::parse(&format!("{SEED_DAYS_ENV_VAR}=inf\n{RATIO_ENV_VAR}=3")).unwrap();
+        let policy = RemovalPolicy::from_config(&config).unwrap();
+        assert_eq!(policy.seed_time, None);
+        assert_eq!(policy.ratio, Some(3.0));
+
+        let config = Config::parse(&format!("{SEED_DAYS_ENV_VAR}=1e20")).unwrap();
+        assert_eq!(RemovalPolicy::from_config(&config), None);
+
+        let config = Config::parse(&format!("{SEED_DAYS_ENV_VAR}=1.5")).unwrap();
+        assert_eq!(
+            RemovalPolicy::from_config(&config).unwrap().seed_time,
+            Some(DAY * 3 / 2)
+        );
+    }
+}
@@ -0,0 +1,84 @@
+//! Stopping completed torrents once they have seeded enough.
+//!
+//! Disabled unless at least one limit is set:
+//! - `BTRS_SEED_RATIO`: stop seeding once the upload ratio reaches this
+//!   value.
+//! - `BTRS_SEED_DAYS`: stop seeding once complete for this many days.
+//!
+//! Unlike the [`removal`](super::removal) limits, the torrent stays in the
+//! session and can be started again by hand.
+
+use std::time::Duration;
+
+use crate::app::{
+    config::Config,
+    removal::{SeedState, days, parse_limit},
+};
+
+pub const RATIO_ENV_VAR: &str = "BTRS_SEED_RATIO";
+pub const SEED_DAYS_ENV_VAR: &str = "BTRS_SEED_DAYS";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeedLimit {
+    pub ratio: Option<f64>,
+    pub seed_time: Option<Duration>,
+}
+
+impl SeedLimit {
+    /// Reads the limits from `config`, `None` when neither is set.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let limit = Self {
+            ratio: parse_limit(config, RATIO_ENV_VAR, "Seeding", Some),
+            seed_time: parse_limit(config, SEED_DAYS_ENV_VAR, "Seeding", days),
+        };
+
+        (limit.ratio.is_some() || limit.seed_time.is_some()).then_some(limit)
+    }
+
+    pub fn is_reached(&self, state: SeedState) -> bool {
+        state.reached(self.ratio, self.seed_time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    #[test]
+    fn test_is_reached() {
+        let limit = SeedLimit {
+            ratio: Some(1.5),
+            seed_time: None,
+        };
+        let state = |ratio, days: Option<u32>| SeedState {
+            ratio,
+            complete_for: days.map(|days| DAY * days),
+        };
+
+        // Still downloading, whatever was uploaded meanwhile.
+        assert!(!limit.is_reached(state(3.0, None)));
+        assert!(!limit.is_reached(state(1.0, Some(30))));
+        assert!(limit.is_reached(state(1.5, Some(0))));
+
+        let time_only = SeedLimit {
+            ratio: None,
+            seed_time: Some(DAY * 2),
+        };
+        assert!(!time_only.is_reached(state(9.0, Some(1))));
+        assert!(time_only.is_reached(state(0.0, Some(2))));
+    }
+
+    #[test]
+    fn test_from_config_ignores_unrepresentable_days() {
+        let config = Config::parse(&format!("{SEED_DAYS_ENV_VAR}=inf")).unwrap();
+        assert_eq!(SeedLimit::from_config(&config), None);
+
+        let config = Config::parse(&format!("{SEED_DAYS_ENV_VAR}=2")).unwrap();
+        assert_eq!(
+            SeedLimit::from_config(&config).unwrap().seed_time,
+            Some(DAY * 2)
+        );
+    }
+}
@@ -0,0 +1,129 @@
+//! Export and import of the whole client session.
+//!
+//! A snapshot is a single bencoded file holding every loaded torrent's
+//! original .torrent bytes and its transfer state, but no payload data,
+//! so a session can be moved between machines.
+
+use std::{fs, path::Path};
+
+use anyhow::{Context, Error, bail};
+use serde_bytes::ByteBuf;
+use serde_derive::{Deserialize, Serialize};
+
+/// Default file a session is exported to and imported from.
+pub const SNAPSHOT_FILE: &str = "btrs_session.snapshot";
+
+const SNAPSHOT_VERSION: u64 = 1;
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub struct SessionSnapshot {
+    pub version: u64,
+    pub torrents: Vec<TorrentSnapshot>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub struct TorrentSnapshot {
+    /// The .torrent file exactly as it was loaded.
+    pub metainfo: ByteBuf,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    /// Unix time the torrent was last started, used to order start-up
+    /// checks.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_active: Option<u64>,
+    /// Unix time the torrent was added, missing from older snapshots.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub added: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completed: Option<u64>,
+    /// Tracker announce key, a new one is generated when missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// Tracker tiers including runtime edits, the .torrent file's own
+    /// trackers when missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trackers: Option<Vec<Vec<String>>>,
+    /// Allocation strategy the torrent overrides, picked from the
+    /// filesystem when missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allocation: Option<String>,
+}
+
+impl SessionSnapshot {
+    pub fn new(torrents: Vec<TorrentSnapshot>) -> Self {
+        Self {
+            version: SNAPSHOT_VERSION,
+            torrents,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        serde_bencode::to_bytes(self).context("Failed to encode session snapshot")
+    }
+
+    /// Decodes a snapshot, rejecting versions newer than this build understands.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let snapshot: SessionSnapshot =
+            serde_bencode::from_bytes(bytes).context("Session snapshot is not valid bencode")?;
+
+        if snapshot.version > SNAPSHOT_VERSION {
+            bail!(
+                "Session snapshot version {} is newer than supported version {SNAPSHOT_VERSION}",
+                snapshot.version
+            );
+        }
+
+        Ok(snapshot)
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+        fs::write(path, self.to_bytes()?)
+            .with_context(|| format!("Failed to write session snapshot {}", path.display()))
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let bytes = fs::read(path)
+            .with_context(|| format!("Failed to read session snapshot {}", path.display()))?;
+
+        Self::from_bytes(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let snapshot = SessionSnapshot::new(vec![TorrentSnapshot {
+            metainfo: ByteBuf::from(b"d4:infod4:name4:testee".to_vec()),
+            uploaded: 10,
+            downloaded: 20,
+            last_active: Some(30),
+            added: Some(40),
+            completed: None,
+            key: Some(String::from("0badcafe")),
+            trackers: Some(vec![
+                vec![String::from("http://tracker.test")],
+                vec![String::from("udp://backup.test:6969")],
+            ]),
+            allocation: Some(String::from("sparse")),
+        }]);
+
+        let bytes = snapshot.to_bytes().unwrap();
+
+        assert_eq!(SessionSnapshot::from_bytes(&bytes).unwrap(), snapshot);
+    }
+
+    #[test]
+    fn test_snapshot_rejects_newer_version() {
+        let mut snapshot = SessionSnapshot::new(vec![]);
+        snapshot.version = SNAPSHOT_VERSION + 1;
+
+        let bytes = snapshot.to_bytes().unwrap();
+
+        assert!(SessionSnapshot::from_bytes(&bytes).is_err());
+    }
+}
@@ -0,0 +1,194 @@
+//! Torrents waiting for a free slot before they start.
+//!
+//! Unlimited unless `BTRS_MAX_ACTIVE` caps how many torrents run at once.
+//! Starting a torrent past the cap queues it instead, and queued torrents
+//! start as running ones stop. `BTRS_QUEUE_ORDER` picks which goes next:
+//! - `fifo` (the default): the one queued first.
+//! - `smart`: going by a scrape of each torrent's trackers, downloads
+//!   with the most seeders per leecher, which should finish fastest, and
+//!   complete torrents with the most leechers per seeder, which need our
+//!   upload most. Torrents without scrape results go last.
+
+use std::sync::{Arc, Mutex};
+
+use crate::{app::config::Config, torrent::tracker::ScrapeStats};
+
+pub const MAX_ACTIVE_ENV_VAR: &str = "BTRS_MAX_ACTIVE";
+pub const QUEUE_ORDER_ENV_VAR: &str = "BTRS_QUEUE_ORDER";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QueueOrder {
+    #[default]
+    Fifo,
+    Smart,
+}
+
+/// A torrent waiting to start.
+#[derive(Debug, Clone)]
+pub struct Waiting {
+    pub info_hash: String,
+    /// Whether it would seed rather than download.
+    pub complete: bool,
+    /// Filled in once one of its trackers answers a scrape.
+    pub swarm: Arc<Mutex<Option<ScrapeStats>>>,
+}
+
+impl Waiting {
+    /// How much starting the torrent is worth in [`QueueOrder::Smart`],
+    /// `None` without scrape results.
+    fn priority(&self) -> Option<f64> {
+        let swarm = (*self.swarm.lock().unwrap())?;
+        let (wanted, offered) = if self.complete {
+            (swarm.leechers, swarm.seeders)
+        } else {
+            (swarm.seeders, swarm.leechers)
+        };
+
+        Some(wanted as f64 / (offered + 1) as f64)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct StartQueue {
+    pub max_active: Option<usize>,
+    pub order: QueueOrder,
+    waiting: Vec<Waiting>,
+}
+
+impl StartQueue {
+    pub fn from_config(config: &Config) -> Self {
+        let max_active = config.var(MAX_ACTIVE_ENV_VAR).and_then(|value| {
+            value
+                .parse()
+                .inspect_err(|_| eprintln!("[Queue] Ignoring invalid {MAX_ACTIVE_ENV_VAR}"))
+                .ok()
+        });
+        let order = match config.var(QUEUE_ORDER_ENV_VAR).as_deref() {
+            Some("smart") => QueueOrder::Smart,
+            Some("fifo") | None => QueueOrder::Fifo,
+            Some(other) => {
+                eprintln!("[Queue] Unknown {QUEUE_ORDER_ENV_VAR} '{other}', using fifo");
+                QueueOrder::Fifo
+            }
+        };
+
+        Self {
+            max_active,
+            order,
+            waiting: vec![],
+        }
+    }
+
+    /// Takes the limits of `config`, keeping the torrents waiting.
+    pub fn reconfigure(&mut self, config: &Config) {
+        let waiting = std::mem::take(&mut self.waiting);
+        *self = Self {
+            waiting,
+            ..Self::from_config(config)
+        };
+    }
+
+    /// Whether another torrent may start while `active` are running.
+    pub fn has_room(&self, active: usize) -> bool {
+        self.max_active.is_none_or(|max| active < max)
+    }
+
+    pub fn contains(&self, info_hash: &str) -> bool {
+        self.waiting.iter().any(|w| w.info_hash == info_hash)
+    }
+
+    pub fn push(&mut self, waiting: Waiting) {
+        if !self.contains(&waiting.info_hash) {
+            self.waiting.push(waiting);
+        }
+    }
+
+    /// Takes the torrent out of the queue, returning whether it was queued.
+    pub fn remove(&mut self, info_hash: &str) -> bool {
+        let before = self.waiting.len();
+        self.waiting.retain(|w| w.info_hash != info_hash);
+
+        self.waiting.len() < before
+    }
+
+    /// Takes the torrent to start next out of the queue.
+    pub fn pop_next(&mut self) -> Option<Waiting> {
+        let position = match self.order {
+            QueueOrder::Fifo => (!self.waiting.is_empty()).then_some(0),
+            // The earliest queued of the best, unscraped ones last.
+            QueueOrder::Smart => self
+                .waiting
+                .iter()
+                .enumerate()
+                .max_by(|(a_index, a), (b_index, b)| {
+                    a.priority()
+                        .partial_cmp(&b.priority())
+                        .unwrap_or(std::cmp::Ordering::Equal)
+                        .then(b_index.cmp(a_index))
+                })
+                .map(|(position, _)| position),
+        }?;
+
+        Some(self.waiting.remove(position))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn waiting(info_hash: &str, complete: bool, swarm: Option<(u64, u64)>) -> Waiting {
+        Waiting {
+            info_hash: String::from(info_hash),
+            complete,
+            swarm: Arc::new(Mutex::new(swarm.map(|(seeders, leechers)| ScrapeStats {
+                seeders,
+                leechers,
+                completed: 0,
+            }))),
+        }
+    }
+
+    fn drain(queue: &mut StartQueue) -> Vec<String> {
+        std::iter::from_fn(|| queue.pop_next())
+            .map(|w| w.info_hash)
+            .collect()
+    }
+
+    #[test]
+    fn test_orders() {
+        let fill = |queue: &mut StartQueue| {
+            queue.push(waiting("unscraped", false, None));
+            queue.push(waiting("dead", false, Some((0, 40))));
+            queue.push(waiting("healthy", false, Some((50, 10))));
+            queue.push(waiting("needed seed", true, Some((1, 30))));
+            queue.push(waiting("healthy", false, None));
+        };
+
+        let mut queue = StartQueue::default();
+        fill(&mut queue);
+        assert_eq!(
+            drain(&mut queue),
+            vec!["unscraped", "dead", "healthy", "needed seed"]
+        );
+
+        queue.order = QueueOrder::Smart;
+        fill(&mut queue);
+        assert!(queue.remove("dead"));
+        assert!(!queue.remove("dead"));
+        assert_eq!(
+            drain(&mut queue),
+            vec!["needed seed", "healthy", "unscraped"]
+        );
+    }
+
+    #[test]
+    fn test_room() {
+        let mut queue = StartQueue::default();
+        assert!(queue.has_room(1000));
+
+        queue.max_active = Some(2);
+        assert!(queue.has_room(1));
+        assert!(!queue.has_room(2));
+    }
+}
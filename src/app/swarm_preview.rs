@@ -0,0 +1,103 @@
+//! Swarm preview of a torrent about to be added.
+//!
+//! Every tracker of the .torrent file or magnet link is scraped, without
+//! announcing, so the add dialog can show whether anyone is seeding
+//! before disk space is committed to it.
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Error};
+use futures::future::join_all;
+
+use crate::torrent::{
+    Torrent, magnet,
+    metainfo::MetaInfo,
+    tasks::{self, Subsystem},
+    timeout::Timeouts,
+    tracker::{self, ScrapeStats, tls},
+};
+
+/// Preview shared between the scrape tasks and the UI.
+pub type SharedPreview = Arc<Mutex<Option<SwarmPreview>>>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SwarmPreview {
+    /// Path or magnet link typed into the add dialog.
+    pub source: String,
+    pub name: String,
+    pub trackers: Vec<TrackerScrape>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackerScrape {
+    pub url: String,
+    /// `None` until the tracker answers or the scrape fails.
+    pub result: Option<Result<ScrapeStats, String>>,
+}
+
+/// Name, info hash and trackers of the .torrent file at `source`, or of
+/// the magnet link `source`.
+fn read_source(source: &str, peer_id: &str) -> Result<(String, [u8; 20], Vec<String>), Error> {
+    if source.starts_with("magnet:") {
+        let magnet = magnet::parse_magnet(source)?;
+        let name = magnet
+            .name
+            .unwrap_or_else(|| magnet::magnet_uri(&magnet.info_hash));
+
+        return Ok((name, magnet.info_hash, magnet.trackers));
+    }
+
+    let bytes = std::fs::read(source).with_context(|| format!("Cannot read {source}"))?;
+    let torrent = Torrent::load(&bytes, peer_id)?;
+    let trackers = MetaInfo::from_bytes(&bytes)?
+        .tracker_tiers()
+        .into_iter()
+        .flatten()
+        .collect();
+
+    Ok((torrent.name().to_owned(), *torrent.info_hash(), trackers))
+}
+
+/// Replaces `preview` with one for `source` and scrapes its trackers in
+/// the background, filling in each result as it arrives.
+pub fn start(preview: &SharedPreview, source: &str, peer_id: &str) -> Result<(), Error> {
+    let (name, info_hash, urls) = read_source(source, peer_id)?;
+
+    *preview.lock().unwrap() = Some(SwarmPreview {
+        source: source.to_owned(),
+        name,
+        trackers: urls
+            .iter()
+            .map(|url| TrackerScrape {
+                url: url.clone(),
+                result: None,
+            })
+            .collect(),
+    });
+
+    let preview = Arc::clone(preview);
+    let source = source.to_owned();
+    tasks::spawn(Subsystem::Tracker, async move {
+        let client = tls::tracker_client();
+        let timeout = Timeouts::default().tracker;
+
+        join_all(urls.into_iter().enumerate().map(|(i, url)| {
+            let (client, preview, source) = (&client, &preview, &source);
+            async move {
+                let result = tracker::scrape(client, &url, &info_hash, timeout)
+                    .await
+                    .map_err(|e| format!("{e:#}"));
+
+                // The dialog may have moved on to another torrent.
+                if let Some(preview) = preview.lock().unwrap().as_mut()
+                    && preview.source == *source
+                {
+                    preview.trackers[i].result = Some(result);
+                }
+            }
+        }))
+        .await;
+    });
+
+    Ok(())
+}
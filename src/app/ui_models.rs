@@ -1,26 +1,184 @@
-use crate::torrent::{Peer, Torrent, files::FileEntry};
+use std::net::IpAddr;
+
+use crate::app::swarm_preview::SwarmPreview;
+use crate::torrent::{
+    Connection, Peer, Torrent,
+    allocation::Allocation,
+    files::FileEntry,
+    geoip::{self, GeoStats},
+    io_stats::IoSnapshot,
+    peer_session::{
+        message_stats::{self, MessageCounts},
+        upload::UploadQueueSnapshot,
+    },
+    peer_store::{PeerSource, SourceStats},
+    state::TorrentState,
+    tasks,
+    tracker::TrackerStats,
+    verify::CheckStatus,
+};
 
 #[derive(Clone)]
 pub struct TorrentItem {
     pub name: String,
     pub progress: f64,
-    pub status: String,
-    pub download_speed: String,
+    pub state: TorrentState,
+    /// Outcome of the last announce, including the error if it failed.
+    pub tracker_status: String,
+    /// Bytes per second, see [`Torrent::transfer_rates`].
+    pub download_rate: u64,
+    pub upload_rate: u64,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// Bytes still to download.
+    pub left: u64,
     pub info_hash: String,
     pub peer_list: Vec<Peer>,
     pub files: FileEntry,
+    /// Unix time the torrent was added.
+    pub added: u64,
+    /// Unix time the torrent was completed.
+    pub completed: Option<u64>,
+    /// Messages exchanged with each connected peer, by address, and the
+    /// blocks it is waiting for us to send.
+    pub peer_messages: Vec<(String, MessageCounts, UploadQueueSnapshot)>,
+    pub trackers: Vec<TrackerStats>,
+    /// Allocation strategy override, `None` to follow the filesystem.
+    pub allocation: Option<Allocation>,
+    /// Peers found and connected to through each discovery mechanism.
+    pub peer_sources: Vec<(PeerSource, SourceStats)>,
+    /// Connected peers by country and network, `None` without a GeoIP
+    /// database.
+    pub peer_locations: Option<GeoStats>,
 }
 
 impl TorrentItem {
     pub async fn try_from_torrent(t: &Torrent) -> Result<Self, anyhow::Error> {
+        let check_status = t.check_status().await;
+        let progress = match &check_status {
+            CheckStatus::Checked { have } if !have.is_empty() => {
+                have.iter().filter(|&&valid| valid).count() as f64 / have.len() as f64
+            }
+            _ => 0.0,
+        };
+
+        let tracker_status = t.tracker_status().await;
+        let trackers = t.tracker_stats().await;
+        let (upload_rate, download_rate) = t.transfer_rates();
+        let (uploaded, downloaded) = t.transfer_totals();
+        let peer_messages: Vec<_> = t
+            .connections()
+            .await
+            .into_iter()
+            .map(|c| (c.address, c.messages, c.upload_queue))
+            .collect();
+        let peer_locations = geoip::database()
+            .map(|db| db.distribution(peer_messages.iter().map(|(address, ..)| address.as_str())));
+
         Ok(TorrentItem {
             name: String::from(t.name()),
-            progress: 0.0,
-            status: String::from("Stopped"),
-            download_speed: String::from("0.0kb/s"),
-            info_hash: String::from(t.info_hash()),
+            progress,
+            tracker_status: match trackers.iter().find_map(|t| t.warning.as_deref()) {
+                Some(warning) => format!("{tracker_status}, warning: {warning}"),
+                None => tracker_status.to_string(),
+            },
+            state: t.state(),
+            download_rate,
+            upload_rate,
+            downloaded,
+            uploaded,
+            left: t.left(),
+            info_hash: t.info_hash_hex(),
             peer_list: t.peer_list().await.to_vec(),
             files: t.get_file_tree()?,
+            added: t.added(),
+            completed: t.completed().await,
+            peer_messages,
+            trackers,
+            allocation: t.allocation(),
+            peer_sources: t.source_stats().await,
+            peer_locations,
         })
     }
 }
+
+/// A peer connection in the global connections view.
+#[derive(Clone)]
+pub struct ConnectionItem {
+    pub torrent_name: String,
+    pub info_hash: String,
+    pub address: String,
+    pub client: String,
+    pub state: String,
+    pub downloaded: u64,
+    pub download_rate: u64,
+    pub upload_rate: u64,
+}
+
+impl ConnectionItem {
+    pub fn from_connection(t: &Torrent, connection: Connection) -> Self {
+        let state = &connection.state;
+        let flags = [
+            (state.is_choked, "choked"),
+            (state.is_interested, "interested"),
+            (!state.is_choking, "unchoking"),
+            (state.is_peer_interested, "peer interested"),
+            (state.is_snubbed, "snubbed"),
+        ];
+
+        ConnectionItem {
+            torrent_name: String::from(t.name()),
+            info_hash: t.info_hash_hex(),
+            address: connection.address,
+            client: state.client.clone().unwrap_or_default(),
+            state: flags
+                .iter()
+                .filter(|(set, _)| *set)
+                .map(|(_, name)| *name)
+                .collect::<Vec<_>>()
+                .join(", "),
+            downloaded: connection.stats.downloaded,
+            download_rate: connection.stats.download_rate,
+            upload_rate: connection.stats.upload_rate,
+        }
+    }
+}
+
+/// Disk activity of one torrent in the disk stats view.
+#[derive(Clone)]
+pub struct DiskItem {
+    pub torrent_name: String,
+    pub stats: IoSnapshot,
+}
+
+impl DiskItem {
+    pub fn from_torrent(t: &Torrent) -> Self {
+        DiskItem {
+            torrent_name: String::from(t.name()),
+            stats: t.io_stats(),
+        }
+    }
+}
+
+/// Session wide details shown in the status bar.
+#[derive(Clone, Default)]
+pub struct SessionStatus {
+    pub external_ip: Option<IpAddr>,
+    /// Messages exchanged with all peers since start up.
+    pub messages: MessageCounts,
+    /// Running tasks by subsystem, see [`tasks`].
+    pub tasks: Vec<(&'static str, usize)>,
+    /// Swarm of the torrent in the add dialog.
+    pub swarm_preview: Option<SwarmPreview>,
+}
+
+impl SessionStatus {
+    pub fn new(external_ip: Option<IpAddr>) -> Self {
+        Self {
+            external_ip,
+            messages: message_stats::global_counts(),
+            tasks: tasks::running_counts(),
+            swarm_preview: None,
+        }
+    }
+}
@@ -0,0 +1,64 @@
+//! btrs (BitTorrent Rust Shell), a terminal-based BitTorrent client.
+//!
+//! The binary in `main.rs` drives the [`tui`] using the state held
+//! in [`app`]; all protocol logic lives in [`torrent`].
+
+#[cfg(feature = "tui")]
+use ratatui::crossterm::event::Event;
+
+pub mod app;
+pub mod torrent;
+#[cfg(feature = "tui")]
+pub mod tui;
+
+#[cfg(feature = "tui")]
+#[derive(Debug)]
+pub enum AppEvent {
+    Terminal(Event),
+    Custom(AppEventType),
+}
+
+#[derive(Debug)]
+pub enum AppEventType {
+    Download(String),
+    ExportSession,
+    ImportSession,
+    /// Create a torrent from a local path and add it to the session.
+    CreateTorrent {
+        path: String,
+        tracker: String,
+    },
+    /// Scrape the trackers of a .torrent file or magnet link before
+    /// adding it.
+    PreviewSwarm(String),
+    /// Add the .torrent file at this path.
+    AddTorrent(String),
+    /// Close the connection to `address` in the torrent with `info_hash`.
+    KillConnection {
+        info_hash: String,
+        address: String,
+    },
+    /// Switch the torrent with this info hash to the next allocation
+    /// strategy override.
+    CycleAllocation(String),
+    /// Copy the magnet link of the torrent with this info hash.
+    CopyMagnet(String),
+    /// Announce the torrent with this info hash without waiting for the
+    /// tracker's interval.
+    ForceAnnounce(String),
+    /// Add `url` to the trackers of the torrent with `info_hash`.
+    AddTracker {
+        info_hash: String,
+        url: String,
+    },
+    RemoveTracker {
+        info_hash: String,
+        url: String,
+    },
+    /// Sent about once a second for periodic work.
+    Tick,
+    /// Hand the terminal back to the shell and stop until resumed, on
+    /// Ctrl-Z or SIGTSTP.
+    Suspend,
+    Exit,
+}
@@ -1,41 +1,38 @@
-pub mod app;
-pub mod torrent;
-pub mod tui;
-
-use anyhow::Error;
-use app::App;
+use anyhow::{Context, Error, bail};
+use btrs::{
+    AppEvent, AppEventType,
+    app::{App, snapshot::SNAPSHOT_FILE},
+    torrent::{builder::TorrentBuilder, privacy},
+    tui::{Tui, copy_to_clipboard},
+};
 
 use ratatui::{
     Terminal,
-    crossterm::event::{self, Event, KeyEventKind},
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
     prelude::Backend,
 };
 use tokio::sync::mpsc;
 use tokio::time::Duration;
 
-use crate::tui::Tui;
-
-#[derive(Debug)]
-pub enum AppEvent {
-    Terminal(Event),
-    Custom(AppEventType),
-}
-
-#[derive(Debug)]
-pub enum AppEventType {
-    Download(String),
-    Exit,
-}
-
 #[tokio::main]
 async fn main() -> Result<(), Error> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().is_some_and(|arg| arg == "create") {
+        return create_torrent(&args[1..]);
+    }
+
+    privacy::init_from_env();
     let mut app = App::new();
+    if let Err(e) = app.listen().await {
+        eprintln!("ERROR: Not accepting incoming connections: {e:#}");
+    }
 
     let mut terminal = ratatui::init();
 
     run_app(&mut terminal, &mut app).await.unwrap();
 
     ratatui::restore();
+    app.shutdown().await;
 
     Ok(())
 }
@@ -53,34 +50,212 @@ async fn run_app<B: Backend>(
             // Block in a separate thread to poll for terminal events
             if let Ok(Ok(true)) =
                 tokio::task::spawn_blocking(|| event::poll(Duration::from_millis(100))).await
+                && let Ok(Ok(evt)) = tokio::task::spawn_blocking(event::read).await
+                && tx1.send(AppEvent::Terminal(evt)).await.is_err()
             {
-                if let Ok(evt) = tokio::task::spawn_blocking(event::read).await {
-                    if let Ok(evt) = evt {
-                        if tx1.send(AppEvent::Terminal(evt)).await.is_err() {
-                            break;
-                        }
-                    }
-                }
+                break;
+            }
+        }
+    });
+
+    let tx2 = tx.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            if tx2
+                .send(AppEvent::Custom(AppEventType::Tick))
+                .await
+                .is_err()
+            {
+                break;
             }
         }
     });
 
+    // SIGTSTP from outside the terminal, Ctrl-Z itself arrives as a key
+    // in raw mode.
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let tx3 = tx.clone();
+        let mut sigtstp = signal(SignalKind::from_raw(libc::SIGTSTP))?;
+        tokio::spawn(async move {
+            while sigtstp.recv().await.is_some() {
+                if tx3
+                    .send(AppEvent::Custom(AppEventType::Suspend))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+    }
+
     let mut tui = Tui::new(tx.clone());
 
     while let Some(event) = rx.recv().await {
         match event {
             AppEvent::Terminal(event) => match event {
+                Event::Key(key_event)
+                    if key_event.kind == KeyEventKind::Press
+                        && key_event.code == KeyCode::Char('z')
+                        && key_event.modifiers.contains(KeyModifiers::CONTROL) =>
+                {
+                    suspend(terminal)?
+                }
                 Event::Key(key_event) if key_event.kind == KeyEventKind::Press => {
                     tui.handle_key(key_event).await?
                 }
+                // Lay out for the new size straight away.
+                Event::Resize(..) => terminal.autoresize()?,
                 _ => {}
             },
             AppEvent::Custom(AppEventType::Download(key)) => app.download_torrent(&key).await?,
+            AppEvent::Custom(AppEventType::ExportSession) => {
+                if let Err(e) = app.export_session(SNAPSHOT_FILE).await {
+                    eprintln!("ERROR: Failed to export session: {e:#}");
+                }
+            }
+            AppEvent::Custom(AppEventType::ImportSession) => {
+                if let Err(e) = app.import_session(SNAPSHOT_FILE).await {
+                    eprintln!("ERROR: Failed to import session: {e:#}");
+                }
+            }
+            AppEvent::Custom(AppEventType::CreateTorrent { path, tracker }) => {
+                if let Err(e) = app.create_torrent(&path, &tracker) {
+                    eprintln!("ERROR: Failed to create torrent from {path}: {e:#}");
+                }
+            }
+            AppEvent::Custom(AppEventType::PreviewSwarm(source)) => {
+                if let Err(e) = app.preview_swarm(&source) {
+                    eprintln!("ERROR: Failed to preview {source}: {e:#}");
+                }
+            }
+            AppEvent::Custom(AppEventType::AddTorrent(source)) => {
+                if let Err(e) = app.open_torrent(&source).await {
+                    eprintln!("ERROR: Failed to add {source}: {e:#}");
+                }
+            }
+            AppEvent::Custom(AppEventType::KillConnection { info_hash, address }) => {
+                if let Err(e) = app.kill_connection(&info_hash, &address).await {
+                    eprintln!("ERROR: Failed to close connection to {address}: {e:#}");
+                }
+            }
+            AppEvent::Custom(AppEventType::CycleAllocation(key)) => {
+                if let Err(e) = app.cycle_allocation(&key) {
+                    eprintln!("ERROR: Failed to change allocation of {key}: {e:#}");
+                }
+            }
+            AppEvent::Custom(AppEventType::CopyMagnet(key)) => {
+                if let Err(e) = app.magnet_uri(&key).and_then(|uri| copy_to_clipboard(&uri)) {
+                    eprintln!("ERROR: Failed to copy magnet link: {e:#}");
+                }
+            }
+            AppEvent::Custom(AppEventType::ForceAnnounce(key)) => {
+                if let Err(e) = app.force_announce(&key).await {
+                    eprintln!("ERROR: Failed to reannounce {key}: {e:#}");
+                }
+            }
+            AppEvent::Custom(AppEventType::AddTracker { info_hash, url }) => {
+                if let Err(e) = app.add_tracker(&info_hash, &url).await {
+                    eprintln!(
+                        "ERROR: Failed to add tracker {}: {e:#}",
+                        privacy::tracker_url(&url)
+                    );
+                }
+            }
+            AppEvent::Custom(AppEventType::RemoveTracker { info_hash, url }) => {
+                if let Err(e) = app.remove_tracker(&info_hash, &url).await {
+                    eprintln!(
+                        "ERROR: Failed to remove tracker {}: {e:#}",
+                        privacy::tracker_url(&url)
+                    );
+                }
+            }
+            AppEvent::Custom(AppEventType::Tick) => app.tick().await?,
+            AppEvent::Custom(AppEventType::Suspend) => suspend(terminal)?,
             AppEvent::Custom(AppEventType::Exit) => break,
         }
         let torrent_items = app.torrent_items().await?;
-        terminal.draw(|f| tui.draw(f, &torrent_items))?;
+        let connections = app.connection_items().await;
+        let disk_items = app.disk_items();
+        let status = app.session_status();
+        terminal.draw(|f| tui.draw(f, &torrent_items, &connections, &disk_items, &status))?;
+    }
+
+    Ok(())
+}
+
+/// Restores the shell's terminal and stops the process, taking the
+/// terminal back over once resumed with `fg`.
+#[cfg(unix)]
+fn suspend<B: Backend>(terminal: &mut Terminal<B>) -> Result<(), Error> {
+    use ratatui::crossterm::{
+        execute,
+        terminal::{EnterAlternateScreen, enable_raw_mode},
+    };
+
+    ratatui::restore();
+    // SIGSTOP can't be caught, unlike the SIGTSTP we handle ourselves.
+    // SAFETY: raise only sends a signal to this process.
+    unsafe {
+        libc::raise(libc::SIGSTOP);
     }
 
+    enable_raw_mode()?;
+    execute!(std::io::stdout(), EnterAlternateScreen)?;
+    terminal.clear()?;
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn suspend<B: Backend>(_terminal: &mut Terminal<B>) -> Result<(), Error> {
+    Ok(())
+}
+
+const CREATE_USAGE: &str = "usage: btrs create <path> [-t <tracker>]... [-p <piece length>] [-c <comment>] [--private] [-o <output>]";
+
+/// `btrs create`: writes a .torrent file for a local file or directory.
+fn create_torrent(args: &[String]) -> Result<(), Error> {
+    let mut args = args.iter();
+    let path = args.next().context(CREATE_USAGE)?;
+
+    let mut builder = TorrentBuilder::new(path);
+    let mut output = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().context(CREATE_USAGE);
+
+        match arg.as_str() {
+            "-t" | "--tracker" => builder = builder.tracker(value()?),
+            "-p" | "--piece-length" => {
+                builder = builder.piece_length(value()?.parse().context("Invalid piece length")?)
+            }
+            "-c" | "--comment" => builder = builder.comment(value()?),
+            "--private" => builder = builder.private(true),
+            "-o" | "--output" => output = Some(value()?.clone()),
+            other => bail!("Unknown option {other}\n{CREATE_USAGE}"),
+        }
+    }
+
+    let bytes = builder.build()?;
+    let output = match output {
+        Some(output) => output,
+        None => format!(
+            "{}.torrent",
+            std::path::Path::new(path)
+                .file_name()
+                .context("Path has no file name")?
+                .to_string_lossy()
+        ),
+    };
+
+    std::fs::write(&output, bytes).with_context(|| format!("Cannot write {output}"))?;
+    println!("Created {output}");
+
     Ok(())
 }
@@ -2,38 +2,195 @@
 //! torrent client, including loading METAINFO and
 //! making requests to trackers.
 
-use std::{net::Ipv4Addr, sync::Arc};
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use anyhow::{Context, Error};
+use anyhow::{Context, Error, bail};
 use serde_bencode::value::Value;
 use sha1::{Digest, Sha1};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Notify, broadcast::error::RecvError};
+use tokio::task::{AbortHandle, JoinHandle};
 use tokio::time::{Duration, Instant};
-use urlencoding::encode_binary;
 
 use metainfo::MetaInfo;
 
 use crate::torrent::{
+    allocation::{Allocation, Filesystem},
+    ban_list::BanList,
+    block_reader::BlockReader,
+    choker::{CHOKE_INTERVAL, ChokeCandidate, Choker},
+    io_stats::{IoSnapshot, IoStats},
+    limits::SharedLimits,
+    listener::Acceptor,
     metainfo::info::InfoEnum,
-    tracker::{PeersEnum, TrackerSession},
+    peer_session::{
+        PeerSession, PeerState, SessionHandle, message_stats::MessageCounts,
+        peer_stats::PeerStatsSnapshot, upload::UploadQueueSnapshot,
+    },
+    peer_store::{PeerSource, PeerStore, SourceStats},
+    piece_journal::PieceJournal,
+    piece_manager::{PieceManager, PieceRequest, WorkQueue},
+    state::TorrentState,
+    super_seed::SuperSeed,
+    tasks::Subsystem,
+    tracker::{PeersEnum, TrackerSession, TrackerStats, TrackerStatus, external_ip::ExternalIp},
+    transfer_stats::TransferStats,
+    verify::CheckStatus,
 };
 
+pub mod allocation;
+pub mod ban_list;
+pub mod bind;
+pub mod block_reader;
+pub mod builder;
+pub mod choker;
+pub mod client_id;
+pub mod file_watch;
 pub mod files;
+#[cfg(test)]
+pub(crate) mod fixtures;
+pub mod geoip;
+pub mod io_stats;
+pub mod limits;
+pub mod listener;
+pub mod magnet;
 pub mod metainfo;
 pub mod peer_session;
-mod piece_manager;
+pub mod peer_store;
+pub mod piece_journal;
+pub mod piece_manager;
+pub mod privacy;
+pub mod proxy;
+pub mod state;
+pub mod super_seed;
+pub mod supervisor;
+pub mod tasks;
+pub mod timeout;
 pub mod tracker;
+pub mod transfer_stats;
+pub mod verify;
+
 pub struct Torrent {
     metainfo: MetaInfo,
-    info_hash: String,
+    metainfo_bytes: Vec<u8>,
+    /// Extracted from `metainfo_bytes` when first needed, dropped again
+    /// while the torrent is dormant.
+    info_bytes: OnceLock<Arc<Vec<u8>>>,
+    info_hash: [u8; 20],
     tracker_session: Arc<Mutex<TrackerSession>>, // TODO: PieceStorage
-                                                 // TODO: Vec<PeerSession>
+    tracker_task: Option<AbortHandle>,
+    /// Hands out upload slots while the torrent runs.
+    choker_task: Option<AbortHandle>,
+    /// The `stopped` announce sent by [`Torrent::stop`], cut short when the
+    /// torrent starts or stops again so it doesn't hold the tracker.
+    stopped_announce: Option<AbortHandle>,
+    /// Connected peer sessions by address. Entries lapse once the session
+    /// ends.
+    sessions: Arc<Mutex<HashMap<String, SessionHandle>>>,
+    check_status: Arc<Mutex<CheckStatus>>,
+    io_stats: Arc<IoStats>,
+    external_ip: ExternalIp,
+    /// Peers banned for sending bad data, shared by every torrent.
+    bans: BanList,
+    /// Connection and upload slot caps, shared by every torrent.
+    limits: SharedLimits,
+    /// Unix time the torrent was last started.
+    last_active: Option<u64>,
+    /// Unix time the torrent was added to the client.
+    added: u64,
+    /// Unix time the torrent's data was first found complete.
+    completed: Arc<Mutex<Option<u64>>>,
+    /// Bytes still to download, everything until the data is checked.
+    left: Arc<AtomicU64>,
+    /// Piece data exchanged with peers, over every session of the client.
+    transfer: Arc<TransferStats>,
+    /// When a running, complete torrent was first seen without peers.
+    idle_since: Option<Instant>,
+    /// What the torrent is doing, moved through by the engine only.
+    state: Arc<std::sync::Mutex<TorrentState>>,
+    /// Wakes the tracker loop for a forced announce.
+    reannounce: Arc<Notify>,
+    /// Wakes the choke loop for a round, when a peer's interest changes.
+    choke_round: Arc<Notify>,
+    /// Reveals pieces to peers one at a time once complete, `None` unless
+    /// super-seeding is enabled, see [`super_seed`].
+    super_seed: Option<SuperSeed>,
+    /// Peers from every tracker response and other sources.
+    peer_store: Arc<Mutex<PeerStore>>,
+    /// Allocation strategy chosen for this torrent, the filesystem's
+    /// when `None`.
+    allocation: Option<Allocation>,
+    /// The torrent's data as served to peers and library consumers, see
+    /// [`Torrent::block_reader`].
+    blocks: OnceLock<Arc<BlockReader>>,
+    /// Pieces still to download, shared by the sessions working on them.
+    work: Arc<Mutex<WorkQueue>>,
+}
+
+/// Snapshot of one open peer connection.
+#[derive(Clone, Debug)]
+pub struct Connection {
+    pub address: String,
+    pub state: PeerState,
+    pub messages: MessageCounts,
+    pub stats: PeerStatsSnapshot,
+    pub upload_queue: UploadQueueSnapshot,
 }
 
 #[derive(Clone)]
 pub struct Peer {
     pub ip: String,
     pub port: u64,
+    /// Client decoded from the peer ID, when the tracker sent one.
+    pub client: Option<String>,
+    /// Comments the peer shared over ut_comment while connected.
+    pub comments: Vec<String>,
+    /// Transfer stats while connected.
+    pub stats: Option<PeerStatsSnapshot>,
+    /// Blocks the peer is waiting for us to send while connected.
+    pub upload_queue: Option<UploadQueueSnapshot>,
+    /// Peer ID the tracker sent, which ties together the IPv4 and IPv6
+    /// addresses of a dual-homed peer.
+    pub peer_id: Option<Vec<u8>>,
+}
+
+impl Peer {
+    /// `ip:port` to connect to, with IPv6 addresses in brackets.
+    pub fn address(&self) -> String {
+        match self.ip.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => format!("[{ip}]:{}", self.port),
+            _ => format!("{}:{}", self.ip, self.port),
+        }
+    }
+
+    /// Peers of a BEP 7 compact `peers6` string, 16 address and 2 port
+    /// bytes each.
+    pub fn from_compact6(bytes: &[u8]) -> Vec<Peer> {
+        bytes
+            .chunks_exact(18)
+            .map(|chunk| {
+                let octets: [u8; 16] = chunk[..16].try_into().unwrap();
+                Peer {
+                    ip: Ipv6Addr::from(octets).to_string(),
+                    port: u16::from_be_bytes([chunk[16], chunk[17]]) as u64,
+                    client: None,
+                    comments: vec![],
+                    stats: None,
+                    upload_queue: None,
+                    peer_id: None,
+                }
+            })
+            .collect()
+    }
 }
 
 impl From<PeersEnum> for Vec<Peer> {
@@ -46,6 +203,14 @@ impl From<PeersEnum> for Vec<Peer> {
                     peers.push(Peer {
                         ip: peer_raw.ip.clone(),
                         port: peer_raw.port,
+                        client: peer_raw
+                            .peer_id
+                            .as_deref()
+                            .and_then(|peer_id| client_id::client_name(peer_id)),
+                        comments: vec![],
+                        stats: None,
+                        upload_queue: None,
+                        peer_id: peer_raw.peer_id.as_ref().map(|id| id.to_vec()),
                     });
                 }
             }
@@ -53,7 +218,15 @@ impl From<PeersEnum> for Vec<Peer> {
                 for chunk in items.chunks_exact(6) {
                     let ip = Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]).to_string();
                     let port: u64 = u16::from_be_bytes([chunk[4], chunk[5]]) as u64;
-                    peers.push(Peer { ip, port })
+                    peers.push(Peer {
+                        ip,
+                        port,
+                        client: None,
+                        comments: vec![],
+                        stats: None,
+                        upload_queue: None,
+                        peer_id: None,
+                    })
                 }
             }
         }
@@ -62,30 +235,67 @@ impl From<PeersEnum> for Vec<Peer> {
     }
 }
 
+/// How often the tracker loop checks whether it ran out of peers.
+const PEER_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
 impl Torrent {
     /// Adds a torrent to the client from bytes loaded from a .torrent file.
     pub fn load(bytes: &[u8], peer_id: &str) -> Result<Self, Error> {
-        let metainfo = MetaInfo::from_bytes(&bytes)?;
-        let info_hash = Self::calculate_info_hash(&bytes)?;
+        let metainfo = MetaInfo::from_bytes(bytes)?;
+        let warnings = metainfo.check().context("Broken torrent")?;
+        let info_bytes = Self::extract_info_bytes(bytes)?;
+        let info_hash = Self::calculate_info_hash(&info_bytes);
 
-        let tracker_session = TrackerSession::new(&metainfo, &info_hash, peer_id);
+        let tracker_session = TrackerSession::new(&metainfo, info_hash, peer_id);
+        let left = Arc::new(AtomicU64::new(metainfo.info().total_length()));
+        let super_seed =
+            super_seed::enabled().then(|| SuperSeed::new(PieceRequest::all(metainfo.info()).len()));
 
-        Ok(Self {
+        let torrent = Self {
             metainfo,
-            info_hash: String::from(info_hash),
+            metainfo_bytes: bytes.to_vec(),
+            info_bytes: OnceLock::from(Arc::new(info_bytes)),
+            info_hash,
             tracker_session: Arc::new(Mutex::new(tracker_session)),
-        })
+            tracker_task: None,
+            choker_task: None,
+            stopped_announce: None,
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            check_status: Arc::new(Mutex::new(CheckStatus::default())),
+            io_stats: Arc::new(IoStats::default()),
+            external_ip: ExternalIp::default(),
+            bans: BanList::default(),
+            limits: SharedLimits::default(),
+            last_active: None,
+            added: unix_time(),
+            completed: Arc::new(Mutex::new(None)),
+            left,
+            transfer: Arc::default(),
+            idle_since: None,
+            state: Arc::new(std::sync::Mutex::new(TorrentState::Paused)),
+            reannounce: Arc::default(),
+            choke_round: Arc::default(),
+            super_seed,
+            peer_store: Arc::default(),
+            allocation: None,
+            blocks: OnceLock::new(),
+            work: Arc::default(),
+        };
+        for warning in warnings {
+            eprintln!("WARNING: {}: {warning}", privacy::name(torrent.name()));
+        }
+
+        Ok(torrent)
     }
 
-    /// Calculates an `info_hash` from the info dictionary bytes found in
-    /// the .torrent file.
+    /// Extracts the bencoded info dictionary from the .torrent file bytes.
     ///
     /// Returns an [`Error`](`anyhow::Error`) if:
     ///     - bytes are not valid bencode,
     ///     - info key is missing from bencode,
     ///     - an error happens converting back to bytes
-    fn calculate_info_hash(bytes: &[u8]) -> Result<String, Error> {
-        let value: Value = serde_bencode::from_bytes(&bytes)
+    fn extract_info_bytes(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        let value: Value = serde_bencode::from_bytes(bytes)
             .context("Failed to decode .torrent file as bencode")?;
 
         let info_value = match value {
@@ -95,47 +305,319 @@ impl Torrent {
             _ => anyhow::bail!("Top-level bencode structure is not a dictionary"),
         };
 
-        let info_bytes = serde_bencode::to_bytes(info_value)
-            .context("Failed to re-encode 'info' value to bencode")?;
+        serde_bencode::to_bytes(info_value).context("Failed to re-encode 'info' value to bencode")
+    }
 
+    /// Calculates an `info_hash` from the info dictionary bytes found in
+    /// the .torrent file.
+    fn calculate_info_hash(info_bytes: &[u8]) -> [u8; 20] {
         let mut hasher = Sha1::new();
-        hasher.update(&info_bytes);
-        let hash = hasher.finalize();
+        hasher.update(info_bytes);
 
-        Ok(encode_binary(&hash).into_owned())
+        hasher.finalize().into()
     }
 
     pub fn start_tracker(&mut self) {
+        if self.is_running() {
+            return;
+        }
+        self.last_active = Some(unix_time());
+        // Whether a stop is cut short, leaving the tracker session half
+        // way through it.
+        let cut_stop_short = self
+            .stopped_announce
+            .take()
+            .is_some_and(|stopped_announce| {
+                let cut_short = !stopped_announce.is_finished();
+                stopped_announce.abort();
+                cut_short
+            });
+
         let tracker = Arc::clone(&self.tracker_session);
+        let external_ip = self.external_ip.clone();
+        let left = Arc::clone(&self.left);
+        let transfer = Arc::clone(&self.transfer);
+        let sessions = Arc::clone(&self.sessions);
+        let state = Arc::clone(&self.state);
+        set_state(&state, Self::active_state(&left));
+        let reannounce = Arc::clone(&self.reannounce);
+        let peer_store = Arc::clone(&self.peer_store);
+        let max_peers = self.limits.get().max_connections_per_torrent;
 
-        tokio::spawn(async move {
+        let task = tasks::spawn(Subsystem::Tracker, async move {
             {
                 let mut session = tracker.lock().await;
+                session.max_peers = max_peers;
+                if cut_stop_short {
+                    session.cancel_stopped();
+                }
                 if session.started {
                     return;
                 }
 
                 session.started = true;
             }
-            loop {
-                let wait_time = {
-                    let mut session = tracker.lock().await;
+
+            let make = || {
+                Self::tracker_loop(
+                    Arc::clone(&tracker),
+                    external_ip.clone(),
+                    Arc::clone(&left),
+                    Arc::clone(&transfer),
+                    Arc::clone(&sessions),
+                    Arc::clone(&reannounce),
+                    Arc::clone(&peer_store),
+                )
+            };
+            supervisor::supervise("tracker", make, |exit| {
+                if exit.is_error() {
+                    set_state(&state, TorrentState::Error(format!("tracker {exit}")));
+                }
+            })
+            .await;
+        });
+        self.tracker_task = Some(task.abort_handle());
+
+        let sessions = Arc::clone(&self.sessions);
+        let round = Arc::clone(&self.choke_round);
+        let limits = self.limits.clone();
+        let choker = tasks::spawn(Subsystem::Peer, async move {
+            supervisor::run("choker", Self::choke_loop(sessions, round, limits)).await
+        });
+        if let Some(previous) = self.choker_task.replace(choker.abort_handle()) {
+            previous.abort();
+        }
+    }
+
+    /// Retunes the upload slots and hands them out every
+    /// [`CHOKE_INTERVAL`], and hands them out again whenever `round` is
+    /// raised in between. There are never more slots than `limits` allow.
+    async fn choke_loop(
+        sessions: Arc<Mutex<HashMap<String, SessionHandle>>>,
+        round: Arc<Notify>,
+        limits: SharedLimits,
+    ) -> Result<(), Error> {
+        let mut choker = Choker::default();
+        let mut retune = tokio::time::interval(CHOKE_INTERVAL);
+        loop {
+            let retuning = tokio::select! {
+                _ = retune.tick() => true,
+                _ = round.notified() => false,
+            };
+
+            let open: Vec<SessionHandle> = sessions.lock().await.values().cloned().collect();
+            let mut handles = vec![];
+            let mut peers = vec![];
+            for session in open {
+                let Some(state) = session.state() else {
+                    continue;
+                };
+                let (interested, choked) = {
+                    let state = state.lock().await;
+                    (state.is_peer_interested, state.is_choking)
+                };
+                let stats = session.peer_stats();
+                peers.push(ChokeCandidate {
+                    interested,
+                    choked,
+                    upload_rate: stats.upload_rate,
+                    download_rate: stats.download_rate,
+                });
+                handles.push(session);
+            }
+
+            // Reloading the config may have lowered the cap.
+            choker.set_max_slots(limits.get().max_upload_slots);
+            if retuning {
+                choker.retune(&peers);
+            }
+            for (session, unchoked) in handles.iter().zip(choker.unchoked(&peers)) {
+                session.set_choking(!unchoked);
+            }
+        }
+    }
+
+    /// Announces whenever the tracker asks, or earlier when out of peers.
+    async fn tracker_loop(
+        tracker: Arc<Mutex<TrackerSession>>,
+        external_ip: ExternalIp,
+        left: Arc<AtomicU64>,
+        transfer: Arc<TransferStats>,
+        sessions: Arc<Mutex<HashMap<String, SessionHandle>>>,
+        reannounce: Arc<Notify>,
+        peer_store: Arc<Mutex<PeerStore>>,
+    ) -> Result<(), Error> {
+        loop {
+            let active_peers = sessions
+                .lock()
+                .await
+                .values()
+                .filter(|session| session.is_alive())
+                .count();
+
+            let wait_time = {
+                let mut session = tracker.lock().await;
+                session.active_peers = active_peers;
+                session.announce_early();
+
+                // Trackerless torrents wait for a tracker to be added.
+                if session.url.is_some()
+                    && Instant::from_std(session.next_announce) <= Instant::now()
+                {
                     session.started = true;
-                    if let Err(e) = session.update().await {
-                        eprintln!("[Tracker] Update failed: {:?}", e);
+                    Self::report_totals(&mut session, &left, &transfer);
+                    // Failures are shown from the tracker status and
+                    // rescheduled by the session itself.
+                    if session.update(&external_ip).await.is_ok() {
+                        let mut store = peer_store.lock().await;
+                        store.add(session.take_peers(), PeerSource::Tracker);
                     }
+                }
+                // Also for peers from other sources while the tracker is
+                // quiet or unreachable.
+                peer_store.lock().await.forget_stale();
 
-                    // Wait 5 seconds if wait time is in the past
-                    if Instant::from_std(session.next_announce) < Instant::now() {
-                        Instant::now() + Duration::from_secs(5)
-                    } else {
-                        Instant::from_std(session.next_announce)
-                    }
-                };
+                // Wake up regularly to notice running out of peers.
+                Instant::from_std(session.next_announce).min(Instant::now() + PEER_CHECK_INTERVAL)
+            };
 
-                tokio::time::sleep_until(wait_time).await;
+            tokio::select! {
+                _ = tokio::time::sleep_until(wait_time) => {}
+                _ = reannounce.notified() => {}
             }
+        }
+    }
+
+    /// Copies the current transfer totals into `session` for its next
+    /// announce.
+    fn report_totals(session: &mut TrackerSession, left: &AtomicU64, transfer: &TransferStats) {
+        session.left = left.load(Ordering::Relaxed);
+        (session.uploaded, session.downloaded) = transfer.totals();
+    }
+
+    /// Announces to the tracker now, or as soon as its `min interval`
+    /// allows. Does nothing while the torrent is stopped.
+    pub async fn force_announce(&self) {
+        if !self.is_running() {
+            return;
+        }
+
+        self.tracker_session.lock().await.force_announce();
+        self.reannounce.notify_one();
+    }
+
+    /// Stops announcing and closes every peer connection. The `stopped`
+    /// announce runs in the background, await the returned handle to wait
+    /// for it, e.g. before exiting.
+    pub fn stop(&mut self) -> JoinHandle<()> {
+        if let Some(choker) = self.choker_task.take() {
+            choker.abort();
+        }
+        let was_running = match self.tracker_task.take() {
+            Some(task) => {
+                task.abort();
+                true
+            }
+            None => false,
+        };
+        let tracker = Arc::clone(&self.tracker_session);
+        let sessions = Arc::clone(&self.sessions);
+        let external_ip = self.external_ip.clone();
+        let left = Arc::clone(&self.left);
+        let transfer = Arc::clone(&self.transfer);
+        set_state(&self.state, TorrentState::Paused);
+        if let Some(stopped_announce) = self.stopped_announce.take() {
+            stopped_announce.abort();
+        }
+
+        let task = tasks::spawn(Subsystem::Stop, async move {
+            for (_, session) in sessions.lock().await.drain() {
+                session.kill();
+            }
+
+            // The peers are gone, so these are the final totals.
+            let mut session = tracker.lock().await;
+            Self::report_totals(&mut session, &left, &transfer);
+            if was_running
+                && session.started
+                && session.url.is_some()
+                && let Err(e) = session.announce_stopped(&external_ip).await
+            {
+                eprintln!("[Tracker] Stopped announce failed: {e:#}");
+            }
+            session.started = false;
         });
+        self.stopped_announce = Some(task.abort_handle());
+
+        task
+    }
+
+    pub fn state(&self) -> TorrentState {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// Downloading, or seeding once nothing is left.
+    fn active_state(left: &AtomicU64) -> TorrentState {
+        if left.load(Ordering::Relaxed) == 0 {
+            TorrentState::Seeding
+        } else {
+            TorrentState::Downloading
+        }
+    }
+
+    /// Whether the tracker is being announced to.
+    pub fn is_running(&self) -> bool {
+        self.tracker_task
+            .as_ref()
+            .is_some_and(|task| !task.is_finished())
+    }
+
+    /// Records that the download just finished, so the tracker is sent
+    /// `completed`.
+    pub async fn mark_completed(&self) {
+        self.completed.lock().await.get_or_insert_with(unix_time);
+        self.tracker_session.lock().await.mark_completed();
+        if self.is_running() {
+            set_state(&self.state, TorrentState::Seeding);
+        }
+    }
+
+    /// Moves a running torrent whose last piece was just verified on to
+    /// seeding, announcing `completed` straight away. Returns whether it
+    /// did.
+    pub async fn poll_completion(&self) -> bool {
+        if self.state() != TorrentState::Downloading || self.left.load(Ordering::Relaxed) > 0 {
+            return false;
+        }
+
+        self.mark_completed().await;
+        self.force_announce().await;
+        true
+    }
+
+    /// Where the torrent's file or top level directory is stored under
+    /// `root`.
+    pub fn data_path(&self, root: &Path) -> PathBuf {
+        root.join(self.name())
+    }
+
+    /// Journal of the torrent's unfinished pieces, kept next to its data
+    /// under `root`.
+    pub fn journal(&self, root: &Path) -> PieceJournal {
+        PieceJournal::new(root.join(format!(".{}.journal", self.info_hash_hex())))
+    }
+
+    /// Uploaded bytes over the torrent's size, or over the downloaded bytes
+    /// if more than its size was downloaded.
+    pub fn ratio(&self) -> f64 {
+        let (uploaded, downloaded) = self.transfer_totals();
+        let base = downloaded.max(self.total_length());
+
+        if base == 0 {
+            0.0
+        } else {
+            uploaded as f64 / base as f64
+        }
     }
 
     pub fn name(&self) -> &str {
@@ -145,16 +627,572 @@ impl Torrent {
         }
     }
 
-    pub fn info_hash(&self) -> &str {
+    /// Magnet link for the torrent with its name and every tracker, e.g.
+    /// `magnet:?xt=urn:btih:<hex>&dn=<name>&tr=<url>`.
+    pub fn magnet_uri(&self) -> String {
+        let mut uri = format!(
+            "magnet:?xt=urn:btih:{}&dn={}",
+            self.info_hash_hex(),
+            urlencoding::encode(self.name())
+        );
+
+        for url in self.metainfo.get_tracker_urls() {
+            uri.push_str("&tr=");
+            uri.push_str(&urlencoding::encode(url));
+        }
+
+        uri
+    }
+
+    /// Size of the torrent's data across all files.
+    pub fn total_length(&self) -> u64 {
+        self.metainfo.info.total_length()
+    }
+
+    /// Unix time the torrent was last started, carried over between
+    /// sessions.
+    pub fn last_active(&self) -> Option<u64> {
+        self.last_active
+    }
+
+    /// Shares the session wide external address with this torrent's
+    /// tracker.
+    pub fn set_external_ip(&mut self, external_ip: ExternalIp) {
+        self.external_ip = external_ip;
+    }
+
+    /// Shares the client's banned peers with this torrent, so it neither
+    /// dials nor accepts them.
+    pub fn set_ban_list(&mut self, bans: BanList) {
+        self.bans = bans;
+    }
+
+    /// Shares the client's connection and upload slot caps with this
+    /// torrent.
+    pub fn set_limits(&mut self, limits: SharedLimits) {
+        self.limits = limits;
+    }
+
+    /// Super-seeds peers that connect once the torrent is complete, as set
+    /// by [`super_seed::SUPER_SEED_ENV_VAR`] otherwise.
+    pub fn set_super_seed(&mut self, enabled: bool) {
+        self.super_seed =
+            enabled.then(|| SuperSeed::new(PieceRequest::all(self.metainfo.info()).len()));
+    }
+
+    /// Has `manager` ban peers of this torrent's sessions that keep
+    /// sending bad data.
+    pub fn ban_corrupt_peers(&self, manager: &mut PieceManager) {
+        manager.set_ban_list(Arc::clone(&self.sessions), self.bans.clone());
+    }
+
+    pub fn set_last_active(&mut self, last_active: Option<u64>) {
+        self.last_active = last_active;
+    }
+
+    pub fn allocation(&self) -> Option<Allocation> {
+        self.allocation
+    }
+
+    /// Overrides the allocation strategy picked from the filesystem, or
+    /// goes back to it with `None`.
+    pub fn set_allocation(&mut self, allocation: Option<Allocation>) {
+        self.allocation = allocation;
+    }
+
+    /// Creates the torrent's files under `root` with the chosen
+    /// allocation strategy, returning it along with the filesystem it was
+    /// picked for.
+    pub async fn allocate_files(
+        &self,
+        root: PathBuf,
+    ) -> Result<(Allocation, Option<Filesystem>), Error> {
+        let data_path = self.data_path(&root);
+        let (detected, filesystem) = allocation::strategy_for(&data_path);
+        let strategy = self.allocation.unwrap_or(detected);
+        let files = self.metainfo.info().layout(&root);
+
+        tasks::spawn_blocking(Subsystem::Disk, move || {
+            files
+                .iter()
+                .try_for_each(|(path, length)| allocation::allocate(path, *length, strategy))
+        })
+        .await??;
+
+        Ok((strategy, filesystem))
+    }
+
+    pub fn added(&self) -> u64 {
+        self.added
+    }
+
+    pub async fn completed(&self) -> Option<u64> {
+        *self.completed.lock().await
+    }
+
+    /// Restores when the torrent was added and completed in a previous
+    /// session.
+    pub async fn restore_dates(&mut self, added: u64, completed: Option<u64>) {
+        self.added = added;
+        *self.completed.lock().await = completed;
+    }
+
+    pub async fn check_status(&self) -> CheckStatus {
+        self.check_status.lock().await.clone()
+    }
+
+    /// Marks the torrent as waiting for its turn to be checked.
+    pub async fn queue_check(&self) {
+        *self.check_status.lock().await = CheckStatus::Queued;
+        set_state(&self.state, TorrentState::Queued);
+    }
+
+    /// Marks the torrent as waiting for a slot to start in, see
+    /// `StartQueue`.
+    pub fn queue_start(&self) {
+        set_state(&self.state, TorrentState::Queued);
+    }
+
+    /// Takes the torrent out of the start queue again, if it is queued.
+    pub fn unqueue_start(&self) {
+        if self.state() == TorrentState::Queued {
+            set_state(&self.state, TorrentState::Paused);
+        }
+    }
+
+    /// Marks the torrent as failed, with `reason` shown until it is
+    /// started again.
+    pub fn fail(&self, reason: String) {
+        set_state(&self.state, TorrentState::Error(reason));
+    }
+
+    /// Check of the torrent's data under `root`, to be awaited once it is
+    /// this torrent's turn. The hashing runs on a blocking thread.
+    pub fn check_task(&self, root: PathBuf) -> impl Future<Output = ()> + Send + 'static {
+        let status = Arc::clone(&self.check_status);
+        let io_stats = Arc::clone(&self.io_stats);
+        let completed = Arc::clone(&self.completed);
+        let left = Arc::clone(&self.left);
+        let metainfo_bytes = self.metainfo_bytes.clone();
+        let state = Arc::clone(&self.state);
+
+        async move {
+            *status.lock().await = CheckStatus::Checking;
+            set_state(&state, TorrentState::Checking);
+
+            let result = tasks::spawn_blocking(Subsystem::Disk, move || {
+                MetaInfo::from_bytes(&metainfo_bytes).map(|metainfo| {
+                    let have = verify::verify_pieces(&root, metainfo.info(), &io_stats);
+                    (verify::left(metainfo.info(), &have), have)
+                })
+            })
+            .await;
+
+            *status.lock().await = match result {
+                Ok(Ok((remaining, have))) => {
+                    left.store(remaining, Ordering::Relaxed);
+                    if have.iter().all(|&valid| valid) {
+                        completed.lock().await.get_or_insert_with(unix_time);
+                    }
+                    // Torrents can't be started while checking.
+                    set_state(&state, TorrentState::Paused);
+                    CheckStatus::Checked { have }
+                }
+                Ok(Err(e)) => {
+                    eprintln!("[Check] Failed to check torrent: {e:#}");
+                    set_state(&state, TorrentState::Error(format!("check failed: {e}")));
+                    CheckStatus::Unchecked
+                }
+                Err(e) => {
+                    eprintln!("[Check] Check task failed: {e}");
+                    set_state(&state, TorrentState::Error(format!("check failed: {e}")));
+                    CheckStatus::Unchecked
+                }
+            };
+        }
+    }
+
+    /// Bytes still to download.
+    pub fn left(&self) -> u64 {
+        self.left.load(Ordering::Relaxed)
+    }
+
+    /// Bytes still to download, shared with whatever verifies pieces so
+    /// announces report real progress.
+    pub fn left_handle(&self) -> Arc<AtomicU64> {
+        Arc::clone(&self.left)
+    }
+
+    /// Disk activity for this torrent's data.
+    pub fn io_stats(&self) -> IoSnapshot {
+        self.io_stats.snapshot()
+    }
+
+    pub fn info_hash(&self) -> &[u8; 20] {
         &self.info_hash
     }
 
+    /// The info hash as lowercase hex, used to identify the torrent.
+    pub fn info_hash_hex(&self) -> String {
+        self.info_hash
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    /// The .torrent file this torrent was loaded from.
+    pub fn metainfo_bytes(&self) -> &[u8] {
+        &self.metainfo_bytes
+    }
+
+    /// The bencoded info dictionary, served to peers over ut_metadata.
+    pub fn info_bytes(&self) -> Arc<Vec<u8>> {
+        let info_bytes = self.info_bytes.get_or_init(|| {
+            let info_bytes = Self::extract_info_bytes(&self.metainfo_bytes)
+                .expect("info dictionary was extracted when the torrent was loaded");
+            Arc::new(info_bytes)
+        });
+
+        Arc::clone(info_bytes)
+    }
+
+    /// Whether the torrent is stopped, or has been seeding without any
+    /// peers for at least `after`, so its memory can be reclaimed.
+    pub async fn is_dormant(&mut self, after: Duration) -> bool {
+        if !self.is_running() {
+            return true;
+        }
+
+        let idle = self.completed().await.is_some()
+            && !self
+                .sessions
+                .lock()
+                .await
+                .values()
+                .any(|session| session.is_alive());
+        if !idle {
+            self.idle_since = None;
+            return false;
+        }
+
+        self.idle_since.get_or_insert_with(Instant::now).elapsed() >= after
+    }
+
+    /// Drops state that is rebuilt when next needed: the info dictionary,
+    /// the known peers and closed sessions.
+    pub async fn reclaim_memory(&mut self) {
+        self.info_bytes.take();
+        self.peer_store.lock().await.clear();
+
+        let mut sessions = self.sessions.lock().await;
+        prune_sessions(&mut sessions);
+        sessions.shrink_to_fit();
+    }
+
+    /// Total `(uploaded, downloaded)` bytes reported to the tracker.
+    pub fn transfer_totals(&self) -> (u64, u64) {
+        self.transfer.totals()
+    }
+
+    /// Current `(upload, download)` bytes per second.
+    pub fn transfer_rates(&self) -> (u64, u64) {
+        self.transfer.rates()
+    }
+
+    /// Restores transfer totals carried over from a previous session.
+    pub fn restore_transfer_totals(&self, uploaded: u64, downloaded: u64) {
+        self.transfer.restore(uploaded, downloaded);
+    }
+
+    /// Transfer totals, shared with the torrent's peer sessions, see
+    /// [`PeerSession::set_transfer_stats`].
+    pub fn transfer_stats_handle(&self) -> Arc<TransferStats> {
+        Arc::clone(&self.transfer)
+    }
+
+    /// The announce key, kept across sessions so the tracker keeps
+    /// recognising us.
+    pub async fn tracker_key(&self) -> String {
+        self.tracker_session.lock().await.key.clone()
+    }
+
+    pub async fn restore_tracker_key(&self, key: String) {
+        self.tracker_session.lock().await.key = key;
+    }
+
+    /// State of each of the torrent's trackers.
+    pub async fn tracker_stats(&self) -> Vec<TrackerStats> {
+        self.tracker_session.lock().await.stats()
+    }
+
+    /// The torrent's trackers in tiers, as edited at runtime.
+    pub async fn trackers(&self) -> Vec<Vec<String>> {
+        self.tracker_session.lock().await.tiers.clone()
+    }
+
+    /// Replaces the trackers from the .torrent file with tiers saved from
+    /// a previous session.
+    pub async fn restore_trackers(&self, tiers: Vec<Vec<String>>) {
+        self.tracker_session.lock().await.set_tiers(tiers);
+    }
+
+    /// Adds a tracker in a tier of its own, announcing to it straight away
+    /// if the torrent had none. Returns `false` if it was already there.
+    ///
+    /// Returns an [`Error`](`anyhow::Error`) if `url` isn't an HTTP(S) or
+    /// UDP tracker.
+    pub async fn add_tracker(&self, url: &str) -> Result<bool, Error> {
+        let url = url.trim();
+        if !["http://", "https://", "udp://"]
+            .iter()
+            .any(|scheme| url.starts_with(scheme))
+        {
+            anyhow::bail!("Tracker {url} is not an http, https or udp URL");
+        }
+
+        let added = self.tracker_session.lock().await.add_tracker(url);
+        if added {
+            self.reannounce.notify_one();
+        }
+
+        Ok(added)
+    }
+
+    /// Removes a tracker, moving on to the next one if it was being
+    /// announced to. Returns `false` if it wasn't one of the torrent's.
+    pub async fn remove_tracker(&self, url: &str) -> bool {
+        let removed = self.tracker_session.lock().await.remove_tracker(url);
+        if removed {
+            self.reannounce.notify_one();
+        }
+
+        removed
+    }
+
+    pub async fn tracker_status(&self) -> TrackerStatus {
+        self.tracker_session.lock().await.status.clone()
+    }
+
+    /// Marks the torrent as a partial seed, see [`TrackerSession::partial_seed`].
+    pub async fn set_partial_seed(&self, partial_seed: bool) {
+        self.tracker_session.lock().await.partial_seed = partial_seed;
+    }
+
+    /// Registers a peer session so details learned over the connection
+    /// show up in [`Torrent::peer_list`].
+    pub async fn attach_session(&self, session: &PeerSession) {
+        self.record_connection(session.url(), true).await;
+        self.sessions
+            .lock()
+            .await
+            .insert(String::from(session.url()), session.handle());
+    }
+
+    /// Takes connections peers open for this torrent, serving its data
+    /// stored under `root`.
+    pub fn acceptor(&self, root: &Path, peer_id: [u8; 20]) -> Acceptor {
+        Acceptor {
+            info_hash: self.info_hash,
+            peer_id,
+            state: Arc::clone(&self.state),
+            check_status: Arc::clone(&self.check_status),
+            sessions: Arc::clone(&self.sessions),
+            transfer: Arc::clone(&self.transfer),
+            peer_store: Arc::clone(&self.peer_store),
+            blocks: self.block_reader(root),
+            bans: self.bans.clone(),
+            limits: self.limits.clone(),
+            choke_round: Arc::clone(&self.choke_round),
+            super_seed: self.super_seed.clone(),
+        }
+    }
+
+    /// Reader of the torrent's data stored under `root`, shared by every
+    /// user so they all see pieces as they are verified. The first `root`
+    /// asked for is kept.
+    pub fn block_reader(&self, root: &Path) -> Arc<BlockReader> {
+        let blocks = self
+            .blocks
+            .get_or_init(|| Arc::new(BlockReader::new(root, self.metainfo.info())));
+
+        Arc::clone(blocks)
+    }
+
+    /// Pieces still to download, for sessions to take work from.
+    pub fn work_queue(&self) -> Arc<Mutex<WorkQueue>> {
+        Arc::clone(&self.work)
+    }
+
+    /// Reads `length` bytes from `offset` into the file at index `file`,
+    /// in the order of [`InfoEnum::layout`], of the data stored under
+    /// `root`.
+    ///
+    /// Pieces of the range that aren't verified yet are moved to the
+    /// front of the download queue and waited for, so content can be read
+    /// while the rest is still downloading, e.g. to stream media.
+    pub async fn read_range(
+        &self,
+        root: &Path,
+        file: usize,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, Error> {
+        let layout = self.metainfo.info().layout(root);
+        let Some((path, file_length)) = layout.get(file) else {
+            bail!("Torrent has no file {file}, only {}", layout.len());
+        };
+        if offset.saturating_add(length) > *file_length {
+            bail!(
+                "Range {offset}+{length} runs past the end of {} ({file_length} bytes)",
+                path.display()
+            );
+        }
+        let start = layout[..file].iter().map(|(_, length)| length).sum::<u64>() + offset;
+
+        let blocks = self.block_reader(root);
+        // Subscribe before looking, so no piece is verified unnoticed.
+        let mut verified = blocks.subscribe();
+        if let CheckStatus::Checked { have } = &*self.check_status.lock().await {
+            for (index, _) in have.iter().enumerate().filter(|(_, have)| **have) {
+                blocks.mark_verified(index as u32);
+            }
+        }
+
+        let missing = |blocks: &BlockReader| -> Vec<u32> {
+            blocks
+                .pieces_in(start, length)
+                .filter(|index| !blocks.has_piece(*index))
+                .collect()
+        };
+        self.work.lock().await.prioritize(&missing(&blocks));
+        while !missing(&blocks).is_empty() {
+            match verified.recv().await {
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => bail!("Torrent's data went away"),
+            }
+        }
+
+        tasks::spawn_blocking(Subsystem::Disk, move || blocks.read_at(start, length)).await?
+    }
+
+    /// Counts how connecting to the peer at `address` went, towards the
+    /// sources that named it. Attached sessions are counted already.
+    pub async fn record_connection(&self, address: &str, connected: bool) {
+        self.peer_store
+            .lock()
+            .await
+            .record_connection(address, connected);
+    }
+
+    /// Known peers and connection outcomes of every peer source.
+    pub async fn source_stats(&self) -> Vec<(PeerSource, SourceStats)> {
+        self.peer_store.lock().await.source_stats()
+    }
+
+    /// Every open peer connection, ordered by address.
+    pub async fn connections(&self) -> Vec<Connection> {
+        let mut sessions = self.sessions.lock().await;
+        prune_sessions(&mut sessions);
+
+        let mut connections = vec![];
+        for (address, session) in sessions.iter() {
+            if let Some(state) = session.state() {
+                connections.push(Connection {
+                    address: address.clone(),
+                    state: state.lock().await.clone(),
+                    messages: session.message_counts(),
+                    stats: session.peer_stats(),
+                    upload_queue: session.upload_queue(),
+                });
+            }
+        }
+        connections.sort_by(|a, b| a.address.cmp(&b.address));
+
+        connections
+    }
+
+    /// Closes the connection to the peer at `address`, returning whether
+    /// one was open.
+    pub async fn kill_connection(&self, address: &str) -> bool {
+        match self.sessions.lock().await.remove(address) {
+            Some(session) => {
+                session.kill();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Adds peers learned outside the tracker, e.g. from PEX or by hand.
+    /// Returns how many weren't known yet.
+    pub async fn add_peers(&self, peers: Vec<Peer>, source: PeerSource) -> usize {
+        self.peer_store.lock().await.add(peers, source)
+    }
+
+    /// The address of the peer at `address` in the other address family,
+    /// if it is known by both, to race against it when dialing.
+    pub async fn alternate_address(&self, address: &str) -> Option<String> {
+        let alternate = self.peer_store.lock().await.alternate(address)?;
+
+        (!self.bans.is_banned(&alternate)).then_some(alternate)
+    }
+
+    /// Up to `limit` known peers without a live connection and not
+    /// banned, most recently seen first, for opening new connections.
+    /// Fewer once the torrent nears its connection cap.
+    pub async fn peer_candidates(&self, limit: usize) -> Vec<String> {
+        let sessions = self.sessions.lock().await;
+        let open = sessions.values().filter(|s| s.is_alive()).count();
+        let room = self
+            .limits
+            .get()
+            .max_connections_per_torrent
+            .saturating_sub(open);
+        let limit = limit.min(room);
+
+        self.peer_store.lock().await.candidates(
+            |address| {
+                self.bans.is_banned(address)
+                    || sessions.get(address).is_some_and(SessionHandle::is_alive)
+            },
+            limit,
+        )
+    }
+
+    /// Every known peer, with details from its connection if there is one.
     pub async fn peer_list(&self) -> Vec<Peer> {
-        let tracker = Arc::clone(&self.tracker_session);
+        let mut peers = self.peer_store.lock().await.peers();
 
-        let session = tracker.lock().await;
+        let connections = self.connections().await;
 
-        session.peer_list.clone()
+        for peer in peers.iter_mut() {
+            let address = peer.address();
+            let Some(Connection {
+                state,
+                stats,
+                upload_queue,
+                ..
+            }) = connections.iter().find(|c| c.address == address)
+            else {
+                continue;
+            };
+
+            if state.client.is_some() {
+                peer.client = state.client.clone();
+            }
+            peer.comments = state
+                .comments
+                .iter()
+                .map(|comment| match comment.owner.as_str() {
+                    "" => comment.text.clone(),
+                    owner => format!("{owner}: {}", comment.text),
+                })
+                .collect();
+            peer.stats = Some(*stats);
+            peer.upload_queue = Some(*upload_queue);
+        }
+
+        peers
     }
 
     pub fn get_file_tree(&self) -> Result<files::FileEntry, anyhow::Error> {
@@ -167,9 +1205,193 @@ impl Torrent {
                 }
             }
             InfoEnum::SingleFile(info_single_file) => {
-                root.insert_path(&[info_single_file.name.clone()])?;
+                root.insert_path(std::slice::from_ref(&info_single_file.name))?;
             }
         }
         Ok(root)
     }
 }
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Moves `state` to `next`, logging a move that isn't allowed instead of
+/// making it.
+fn set_state(state: &std::sync::Mutex<TorrentState>, next: TorrentState) {
+    if let Err(e) = state.lock().unwrap().transition(next) {
+        eprintln!("[State] {e:#}");
+    }
+}
+
+/// Drops ended sessions, logging why each ended.
+fn prune_sessions(sessions: &mut HashMap<String, SessionHandle>) {
+    sessions.retain(|address, session| {
+        if session.is_alive() {
+            return true;
+        }
+
+        if let Some(reason) = session.end_reason() {
+            println!(
+                "[Peer] {} disconnected, {reason}",
+                privacy::address(address)
+            );
+        }
+        false
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::fixtures;
+
+    #[tokio::test]
+    async fn test_stopped_torrent_reclaims_and_rehydrates() {
+        let fixture = fixtures::single_file(5);
+        let mut torrent = fixture.load();
+        let info_bytes = torrent.info_bytes();
+
+        assert!(torrent.is_dormant(Duration::from_secs(3600)).await);
+        torrent.reclaim_memory().await;
+        assert!(torrent.info_bytes.get().is_none());
+        assert_eq!(torrent.info_bytes(), info_bytes);
+    }
+
+    #[tokio::test]
+    async fn test_read_range_waits_for_its_pieces() {
+        let fixture = fixtures::single_file(3 * fixtures::PIECE_LENGTH);
+        let (dir, data) = (fixture.root(), fixture.data());
+        let torrent = fixture.load();
+        let work = torrent.work_queue();
+        for request in PieceRequest::all(torrent.metainfo.info()) {
+            work.lock().await.push(request);
+        }
+
+        let read = torrent.read_range(dir, 0, 16394, 16384);
+        tokio::pin!(read);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), &mut read)
+                .await
+                .is_err()
+        );
+        // The pieces read from are fetched first.
+        assert_eq!(
+            work.lock().await.next_for(1, |_| true).unwrap().piece_index,
+            1
+        );
+
+        let blocks = torrent.block_reader(dir);
+        blocks.mark_verified(1);
+        blocks.mark_verified(2);
+        assert_eq!(read.await.unwrap(), &data[16394..32778]);
+
+        assert!(torrent.read_range(dir, 0, 49000, 1000).await.is_err());
+        assert!(torrent.read_range(dir, 1, 0, 1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stopped_announce_reports_final_totals() {
+        let fixture = fixtures::single_file(5);
+        let mut torrent = fixture.load();
+        torrent.restore_transfer_totals(100, 50);
+
+        // What peer sessions record while the torrent runs.
+        let transfer = torrent.transfer_stats_handle();
+        transfer.record_upload(20);
+        transfer.record_download(5);
+        torrent.left.store(0, Ordering::Relaxed);
+        torrent.stop().await.unwrap();
+
+        let request = torrent.tracker_session.lock().await.create_request();
+        assert_eq!(
+            (request.uploaded, request.downloaded, request.left),
+            (120, 55, 0)
+        );
+        assert_eq!(torrent.transfer_totals(), (120, 55));
+    }
+
+    #[tokio::test]
+    async fn test_restart_cuts_hanging_stopped_announce_short() {
+        use tokio::{io::AsyncReadExt, net::TcpListener, sync::mpsc};
+
+        // A tracker that reads announces and never answers them.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let (announces, mut announced) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut connections = vec![];
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = vec![0; 4096];
+                let length = socket.read(&mut request).await.unwrap();
+                let request = String::from_utf8_lossy(&request[..length]).into_owned();
+                let event = ["started", "stopped"]
+                    .into_iter()
+                    .find(|event| request.contains(&format!("event={event}")));
+                announces.send(event).unwrap();
+                connections.push(socket);
+            }
+        });
+
+        let mut torrent = fixtures::single_file(5).with_tracker(&url).load();
+
+        async fn next(
+            announced: &mut mpsc::UnboundedReceiver<Option<&'static str>>,
+        ) -> Option<&'static str> {
+            tokio::time::timeout(Duration::from_secs(5), announced.recv())
+                .await
+                .unwrap()
+                .unwrap()
+        }
+        torrent.start_tracker();
+        assert_eq!(next(&mut announced).await, Some("started"));
+        drop(torrent.stop());
+        assert_eq!(next(&mut announced).await, Some("stopped"));
+
+        // Starting again doesn't wait for the stopped announce to time out.
+        torrent.start_tracker();
+        assert_eq!(next(&mut announced).await, Some("started"));
+        drop(torrent.stop());
+    }
+
+    #[tokio::test]
+    async fn test_verified_download_moves_on_to_seeding() {
+        let fixture = fixtures::single_file(5);
+        let torrent = fixture.load();
+        set_state(&torrent.state, TorrentState::Downloading);
+        assert!(!torrent.poll_completion().await);
+
+        torrent.left.store(0, Ordering::Relaxed);
+        assert!(torrent.poll_completion().await);
+        assert!(torrent.completed().await.is_some());
+        assert_eq!(
+            torrent.tracker_session.lock().await.create_request().event,
+            Some(tracker::TrackerEvent::Completed)
+        );
+    }
+
+    #[test]
+    fn test_magnet_uri() {
+        let torrent = fixtures::single_file(5)
+            .with_tracker("http://one.test/announce")
+            .with_tracker("udp://two.test:80")
+            .load();
+
+        let hash: String = Sha1::digest(torrent.info_bytes().as_slice())
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+
+        assert_eq!(
+            torrent.magnet_uri(),
+            format!(
+                "magnet:?xt=urn:btih:{hash}&dn=data.bin\
+                 &tr=http%3A%2F%2Fone.test%2Fannounce&tr=udp%3A%2F%2Ftwo.test%3A80"
+            )
+        );
+    }
+}
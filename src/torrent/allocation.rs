@@ -0,0 +1,243 @@
+//! Reserving disk space for a torrent's files before pieces arrive.
+//!
+//! Preallocating every file up front avoids fragmentation and running out
+//! of space halfway through on ext4 or XFS. On copy-on-write filesystems
+//! like btrfs the reserved blocks are never written in place, so it only
+//! costs time, and on network mounts or FUSE filesystems such as ntfs-3g
+//! it can mean sending the whole torrent as zeros first. The strategy is
+//! picked from the filesystem the data is stored on unless the torrent
+//! overrides it.
+
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{Context, Error, bail};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Allocation {
+    /// Every file reserved at its full size, with `fallocate` where
+    /// available.
+    Full,
+    /// Every file created at its full size without reserving blocks.
+    Sparse,
+    /// Nothing created up front, files grow as pieces are written.
+    WriteThrough,
+}
+
+impl fmt::Display for Allocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Allocation::Full => write!(f, "full"),
+            Allocation::Sparse => write!(f, "sparse"),
+            Allocation::WriteThrough => write!(f, "write-through"),
+        }
+    }
+}
+
+impl FromStr for Allocation {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(Allocation::Full),
+            "sparse" => Ok(Allocation::Sparse),
+            "write-through" => Ok(Allocation::WriteThrough),
+            _ => bail!("Unknown allocation strategy {s}, expected full, sparse or write-through"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filesystem {
+    Btrfs,
+    Ext4,
+    Xfs,
+    /// ntfs3 or ntfs-3g, which shows up as a FUSE mount.
+    Ntfs,
+    /// NFS or SMB.
+    Network,
+    Other,
+}
+
+impl fmt::Display for Filesystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Filesystem::Btrfs => write!(f, "btrfs"),
+            Filesystem::Ext4 => write!(f, "ext4"),
+            Filesystem::Xfs => write!(f, "XFS"),
+            Filesystem::Ntfs => write!(f, "NTFS"),
+            Filesystem::Network => write!(f, "network mount"),
+            Filesystem::Other => write!(f, "other filesystem"),
+        }
+    }
+}
+
+impl Filesystem {
+    /// Strategy that suits the filesystem.
+    pub fn allocation(self) -> Allocation {
+        match self {
+            Filesystem::Ext4 | Filesystem::Xfs => Allocation::Full,
+            Filesystem::Btrfs | Filesystem::Other => Allocation::Sparse,
+            Filesystem::Ntfs | Filesystem::Network => Allocation::WriteThrough,
+        }
+    }
+
+    /// Filesystem of a `statfs` magic number.
+    fn from_magic(magic: u64) -> Self {
+        match magic {
+            0x9123_683e => Filesystem::Btrfs,
+            0xef53 => Filesystem::Ext4,
+            0x5846_5342 => Filesystem::Xfs,
+            // ntfs3, and FUSE mounts, which are usually ntfs-3g.
+            0x7366_746e | 0x6573_5546 => Filesystem::Ntfs,
+            // NFS, SMB, CIFS and SMB2.
+            0x6969 | 0x517b | 0xff53_4d42 | 0xfe53_4d42 => Filesystem::Network,
+            _ => Filesystem::Other,
+        }
+    }
+}
+
+/// Filesystem `path` is or would be stored on, `None` where it can't be
+/// told.
+pub fn detect(path: &Path) -> Option<Filesystem> {
+    // The data usually doesn't exist yet, ask about its closest ancestor.
+    let existing = path.ancestors().find(|p| p.exists())?;
+
+    statfs_magic(existing).map(Filesystem::from_magic)
+}
+
+#[cfg(target_os = "linux")]
+fn statfs_magic(path: &Path) -> Option<u64> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat = std::mem::MaybeUninit::<libc::statfs>::uninit();
+    // SAFETY: `path` is NUL terminated and `stat` is only read once
+    // `statfs` filled it in.
+    let stat = unsafe {
+        if libc::statfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return None;
+        }
+        stat.assume_init()
+    };
+
+    Some(stat.f_type as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn statfs_magic(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Strategy for data stored at `path`, sparse files where the filesystem
+/// is unknown.
+pub fn strategy_for(path: &Path) -> (Allocation, Option<Filesystem>) {
+    let filesystem = detect(path);
+
+    (
+        filesystem.map_or(Allocation::Sparse, Filesystem::allocation),
+        filesystem,
+    )
+}
+
+/// Creates the file at `path` with room for `length` bytes. Existing
+/// files are never shrunk or overwritten, they may hold data already.
+pub fn allocate(path: &Path, length: u64, allocation: Allocation) -> Result<(), Error> {
+    if allocation == Allocation::WriteThrough {
+        return Ok(());
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Cannot create {}", parent.display()))?;
+    }
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("Cannot open {}", path.display()))?;
+    if file.metadata()?.len() >= length {
+        return Ok(());
+    }
+
+    match allocation {
+        Allocation::Full => reserve(&file, length),
+        _ => file.set_len(length),
+    }
+    .with_context(|| format!("Cannot allocate {length} bytes for {}", path.display()))
+}
+
+#[cfg(target_os = "linux")]
+fn reserve(file: &File, length: u64) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: the descriptor stays open for the duration of the call.
+    match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, length as libc::off_t) } {
+        0 => Ok(()),
+        // Not every filesystem supports it, a sparse file will do.
+        libc::EOPNOTSUPP | libc::EINVAL => file.set_len(length),
+        errno => Err(std::io::Error::from_raw_os_error(errno)),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reserve(file: &File, length: u64) -> std::io::Result<()> {
+    file.set_len(length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strategy_per_filesystem() {
+        assert_eq!(Filesystem::from_magic(0x9123_683e), Filesystem::Btrfs);
+        assert_eq!(Filesystem::from_magic(0xef53), Filesystem::Ext4);
+        assert_eq!(Filesystem::from_magic(0x6969), Filesystem::Network);
+        assert_eq!(Filesystem::from_magic(0x1234), Filesystem::Other);
+
+        assert_eq!(Filesystem::Ext4.allocation(), Allocation::Full);
+        assert_eq!(Filesystem::Btrfs.allocation(), Allocation::Sparse);
+        assert_eq!(Filesystem::Network.allocation(), Allocation::WriteThrough);
+
+        for allocation in [
+            Allocation::Full,
+            Allocation::Sparse,
+            Allocation::WriteThrough,
+        ] {
+            assert_eq!(
+                allocation.to_string().parse::<Allocation>().unwrap(),
+                allocation
+            );
+        }
+        assert!("zeros".parse::<Allocation>().is_err());
+    }
+
+    #[test]
+    fn test_allocate_keeps_existing_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let full = dir.path().join("album/a.bin");
+        let sparse = dir.path().join("album/b.bin");
+        let lazy = dir.path().join("album/c.bin");
+
+        allocate(&full, 1000, Allocation::Full).unwrap();
+        allocate(&sparse, 1000, Allocation::Sparse).unwrap();
+        allocate(&lazy, 1000, Allocation::WriteThrough).unwrap();
+        assert_eq!(fs::metadata(&full).unwrap().len(), 1000);
+        assert_eq!(fs::metadata(&sparse).unwrap().len(), 1000);
+        assert!(!lazy.exists());
+
+        fs::write(&full, b"data").unwrap();
+        allocate(&full, 2, Allocation::Full).unwrap();
+        assert_eq!(fs::read(&full).unwrap(), b"data");
+
+        // Missing paths are detected from their closest existing ancestor.
+        #[cfg(target_os = "linux")]
+        assert!(detect(&dir.path().join("missing/file")).is_some());
+    }
+}
@@ -0,0 +1,138 @@
+//! Peers banned for sending data that fails hash checks.
+//!
+//! Bans are by IP address, so a banned peer can't come back from another
+//! port, and hold for every torrent of the client. They last until btrs
+//! exits, unless `BTRS_BAN_FILE` names a file to keep them in: one address
+//! per line, read at start up and added to with every ban.
+
+use std::{
+    collections::BTreeSet,
+    fs::{self, OpenOptions},
+    io::Write,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Error};
+
+pub const BAN_FILE_ENV_VAR: &str = "BTRS_BAN_FILE";
+
+/// Shared handle to the banned addresses.
+#[derive(Debug, Clone, Default)]
+pub struct BanList {
+    banned: Arc<Mutex<BTreeSet<IpAddr>>>,
+    /// Where bans are kept between runs.
+    path: Option<PathBuf>,
+}
+
+/// The IP of `address`, either `ip:port` or a bare IP.
+fn ip_of(address: &str) -> Option<IpAddr> {
+    address
+        .parse::<SocketAddr>()
+        .map(|address| address.ip())
+        .or_else(|_| address.parse())
+        .ok()
+}
+
+impl BanList {
+    /// Bans kept in the file named by [`BAN_FILE_ENV_VAR`], or a list that
+    /// isn't kept if it isn't set or can't be read.
+    pub fn from_env() -> Self {
+        match std::env::var_os(BAN_FILE_ENV_VAR) {
+            Some(path) => Self::load(PathBuf::from(path)).unwrap_or_else(|e| {
+                eprintln!("WARNING: {e:#}, bans won't be kept");
+                Self::default()
+            }),
+            None => Self::default(),
+        }
+    }
+
+    /// Reads the bans in `path`, which doesn't have to exist yet, and
+    /// keeps new ones there.
+    pub fn load(path: PathBuf) -> Result<Self, Error> {
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Cannot read {}", path.display()));
+            }
+        };
+
+        let mut banned = BTreeSet::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            match line.parse() {
+                Ok(ip) => {
+                    banned.insert(ip);
+                }
+                Err(_) => eprintln!(
+                    "WARNING: {} line {}: {line:?} isn't an IP address",
+                    path.display(),
+                    number + 1
+                ),
+            }
+        }
+
+        Ok(Self {
+            banned: Arc::new(Mutex::new(banned)),
+            path: Some(path),
+        })
+    }
+
+    /// Bans the IP of `address`. Returns whether it wasn't banned before.
+    pub fn ban(&self, address: &str) -> bool {
+        let Some(ip) = ip_of(address) else {
+            return false;
+        };
+        if !self.banned.lock().unwrap().insert(ip) {
+            return false;
+        }
+
+        if let Some(path) = &self.path
+            && let Err(e) = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{ip}"))
+        {
+            eprintln!("WARNING: Cannot keep ban in {}: {e}", path.display());
+        }
+
+        true
+    }
+
+    pub fn is_banned(&self, address: &str) -> bool {
+        ip_of(address).is_some_and(|ip| self.banned.lock().unwrap().contains(&ip))
+    }
+
+    pub fn banned(&self) -> Vec<IpAddr> {
+        self.banned.lock().unwrap().iter().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bans_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bans");
+        let bans = BanList::load(path.clone()).unwrap();
+
+        assert!(bans.ban("10.0.0.1:6881"));
+        assert!(!bans.ban("10.0.0.1:51413"));
+        assert!(bans.ban("[2001:db8::1]:6881"));
+        assert!(!bans.ban("not an address"));
+        assert!(bans.is_banned("10.0.0.1:7000"));
+        assert!(!bans.is_banned("10.0.0.2:6881"));
+
+        let reloaded = BanList::load(path).unwrap();
+        assert_eq!(reloaded.banned(), bans.banned());
+        assert!(reloaded.is_banned("2001:db8::1"));
+    }
+}
@@ -0,0 +1,201 @@
+//! Binding outgoing connections to a local address or network interface.
+//!
+//! Tracker announces and peer connections normally leave through the
+//! default route. With a VPN that isn't the default route, or as a guard
+//! against the VPN dropping, they can be pinned to it instead:
+//!
+//! - `BTRS_BIND_ADDRESS`: local IP to connect from, e.g. the VPN's
+//!   address. Targets of the other address family can't be reached.
+//! - `BTRS_BIND_INTERFACE`: interface to send through, e.g. `tun0`. Only
+//!   supported on Linux; connections fail rather than use another route
+//!   while the interface is down.
+//!
+//! A peer reachable at both an IPv6 and an IPv4 address is dialed at both,
+//! the second a moment after the first as in RFC 8305 (happy eyeballs),
+//! so a network with broken IPv6 only costs that moment.
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use reqwest::ClientBuilder;
+use tokio::net::{TcpSocket, TcpStream, lookup_host};
+
+pub const BIND_ADDRESS_ENV_VAR: &str = "BTRS_BIND_ADDRESS";
+pub const BIND_INTERFACE_ENV_VAR: &str = "BTRS_BIND_INTERFACE";
+
+/// Head start of the first address of a dual-homed peer, RFC 8305's
+/// connection attempt delay.
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BindConfig {
+    pub address: Option<IpAddr>,
+    pub interface: Option<String>,
+}
+
+impl BindConfig {
+    /// Reads [`BIND_ADDRESS_ENV_VAR`] and [`BIND_INTERFACE_ENV_VAR`],
+    /// ignoring an address that doesn't parse.
+    pub fn from_env() -> Self {
+        let address = std::env::var(BIND_ADDRESS_ENV_VAR).ok().and_then(|value| {
+            value
+                .parse()
+                .inspect_err(|_| eprintln!("[Bind] Ignoring invalid {BIND_ADDRESS_ENV_VAR}"))
+                .ok()
+        });
+        let interface = std::env::var(BIND_INTERFACE_ENV_VAR)
+            .ok()
+            .filter(|interface| !interface.is_empty());
+
+        if interface.is_some() && !cfg!(target_os = "linux") {
+            eprintln!("[Bind] {BIND_INTERFACE_ENV_VAR} is only supported on Linux, ignoring it");
+        }
+
+        Self { address, interface }
+    }
+
+    /// Binds the tracker HTTP client.
+    pub fn apply(&self, builder: ClientBuilder) -> ClientBuilder {
+        #[cfg(target_os = "linux")]
+        let builder = match &self.interface {
+            Some(interface) => builder.interface(interface),
+            None => builder,
+        };
+
+        builder.local_address(self.address)
+    }
+
+    /// Connects to a peer at `address` (`host:port`), trying each address
+    /// it resolves to.
+    pub async fn connect(&self, address: &str) -> io::Result<TcpStream> {
+        if *self == Self::default() {
+            return TcpStream::connect(address).await;
+        }
+
+        let mut last_error = None;
+        for target in lookup_host(address).await? {
+            match self.connect_to(target).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{address} resolved to no addresses"),
+            )
+        }))
+    }
+
+    /// Connects to `primary`, and to `alternate` as well unless `primary`
+    /// connected within [`CONNECTION_ATTEMPT_DELAY`]. Whichever connects
+    /// first is kept, along with its address, and the other dropped.
+    pub async fn connect_either(
+        &self,
+        primary: &str,
+        alternate: Option<&str>,
+    ) -> io::Result<(TcpStream, String)> {
+        let first = self.connect(primary);
+        let Some(alternate) = alternate else {
+            return Ok((first.await?, String::from(primary)));
+        };
+        let mut first = std::pin::pin!(first);
+
+        // A failure gives the alternate its turn straight away.
+        tokio::select! {
+            result = &mut first => match result {
+                Ok(stream) => return Ok((stream, String::from(primary))),
+                Err(_) => return Ok((self.connect(alternate).await?, String::from(alternate))),
+            },
+            _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY) => {}
+        }
+
+        let mut second = std::pin::pin!(self.connect(alternate));
+        tokio::select! {
+            result = &mut first => match result {
+                Ok(stream) => Ok((stream, String::from(primary))),
+                Err(_) => Ok((second.await?, String::from(alternate))),
+            },
+            result = &mut second => match result {
+                Ok(stream) => Ok((stream, String::from(alternate))),
+                Err(_) => Ok((first.await?, String::from(primary))),
+            },
+        }
+    }
+
+    async fn connect_to(&self, target: SocketAddr) -> io::Result<TcpStream> {
+        let socket = match target {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+
+        #[cfg(target_os = "linux")]
+        if let Some(interface) = &self.interface {
+            socket.bind_device(Some(interface.as_bytes()))?;
+        }
+        if let Some(ip) = self.address {
+            socket.bind(SocketAddr::new(ip, 0))?;
+        }
+
+        socket.connect(target).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_connect_from_bound_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+        let bind = BindConfig {
+            address: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            interface: None,
+        };
+
+        let stream = bind.connect(&target).await.unwrap();
+        assert_eq!(stream.local_addr().unwrap().ip(), Ipv4Addr::LOCALHOST);
+
+        // An IPv4 source can't reach an IPv6 target.
+        assert!(bind.connect("[::1]:1").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connect_either_takes_what_answers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+        // Closed again, so connecting is refused.
+        let refused = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let bind = BindConfig::default();
+
+        let (_, address) = bind.connect_either(&refused, Some(&target)).await.unwrap();
+        assert_eq!(address, target);
+        let (_, address) = bind.connect_either(&target, Some(&refused)).await.unwrap();
+        assert_eq!(address, target);
+        assert!(bind.connect_either(&refused, None).await.is_err());
+    }
+
+    #[test]
+    fn test_apply_builds_client() {
+        let bind = BindConfig {
+            address: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            interface: None,
+        };
+
+        assert!(bind.apply(reqwest::Client::builder()).build().is_ok());
+    }
+}
@@ -0,0 +1,287 @@
+//! Reading blocks of a torrent's data from disk to serve peer requests.
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+use anyhow::{Context, Error, bail};
+use tokio::sync::broadcast;
+
+use crate::torrent::metainfo::info::InfoEnum;
+
+/// Largest request we serve. Clients normally ask for 16 KiB, but some
+/// use other sizes and the final block of the final piece is usually
+/// shorter, so anything up to this is accepted.
+pub const MAX_REQUEST_LENGTH: u32 = 128 * 1024;
+
+/// Newly verified pieces a session may fall behind on before it has to
+/// announce every piece again.
+const HAVE_BACKLOG: usize = 256;
+
+#[derive(Debug)]
+struct StoredFile {
+    path: PathBuf,
+    /// Offset of the file within the torrent's concatenated data.
+    offset: u64,
+    length: u64,
+}
+
+#[derive(Debug)]
+pub struct BlockReader {
+    files: Vec<StoredFile>,
+    piece_length: u64,
+    total_length: u64,
+    /// Pieces verified on disk. Only these are served, so peers never get
+    /// data that is still downloading or failed its hash check.
+    verified: RwLock<Vec<bool>>,
+    /// Pieces verified since the sessions sent their bitfield.
+    haves: broadcast::Sender<u32>,
+}
+
+impl BlockReader {
+    /// Reads the data of a torrent stored under `root`.
+    pub fn new(root: &Path, info: &InfoEnum) -> Self {
+        let mut offset = 0;
+        let files = info
+            .layout(root)
+            .into_iter()
+            .map(|(path, length)| {
+                let file = StoredFile {
+                    path,
+                    offset,
+                    length,
+                };
+                offset += length;
+                file
+            })
+            .collect();
+
+        Self {
+            files,
+            piece_length: info.piece_length(),
+            total_length: offset,
+            verified: RwLock::default(),
+            haves: broadcast::channel(HAVE_BACKLOG).0,
+        }
+    }
+
+    /// Replaces the verified pieces with the result of a check.
+    pub fn set_verified(&self, have: Vec<bool>) {
+        *self.verified.write().unwrap() = have;
+    }
+
+    /// Records that piece `index` was downloaded and passed its check,
+    /// telling [`BlockReader::subscribe`]rs the first time.
+    pub fn mark_verified(&self, index: u32) {
+        let mut verified = self.verified.write().unwrap();
+        let position = index as usize;
+        if verified.len() <= position {
+            verified.resize(position + 1, false);
+        }
+        if !std::mem::replace(&mut verified[position], true) {
+            // Nobody listening just means no session is connected.
+            let _ = self.haves.send(index);
+        }
+    }
+
+    /// Pieces verified from now on, for Have messages. Subscribe before
+    /// reading [`BlockReader::bitfield`] so no piece falls in between.
+    pub fn subscribe(&self) -> broadcast::Receiver<u32> {
+        self.haves.subscribe()
+    }
+
+    pub fn has_piece(&self, index: u32) -> bool {
+        self.verified
+            .read()
+            .unwrap()
+            .get(index as usize)
+            .is_some_and(|have| *have)
+    }
+
+    pub fn piece_count(&self) -> u32 {
+        self.total_length.div_ceil(self.piece_length.max(1)) as u32
+    }
+
+    /// Whether every piece is verified, so we are seeding.
+    pub fn is_complete(&self) -> bool {
+        (0..self.piece_count()).all(|index| self.has_piece(index))
+    }
+
+    /// Verified pieces as the payload of a Bitfield message, `None` while
+    /// there are none to announce.
+    pub fn bitfield(&self) -> Option<Vec<u8>> {
+        let count = self.piece_count();
+        let mut bitfield = vec![0u8; count.div_ceil(8) as usize];
+        let mut any = false;
+
+        for index in (0..count).filter(|index| self.has_piece(*index)) {
+            bitfield[index as usize / 8] |= 0x80 >> (index % 8);
+            any = true;
+        }
+
+        any.then_some(bitfield)
+    }
+
+    /// Length of piece `index`, the final piece holds whatever is left.
+    pub fn piece_size(&self, index: u32) -> Option<u64> {
+        let start = index as u64 * self.piece_length;
+        (start < self.total_length).then(|| self.piece_length.min(self.total_length - start))
+    }
+
+    /// Checks that a request lies within its piece and isn't too large.
+    pub fn validate(&self, index: u32, begin: u32, length: u32) -> Result<(), Error> {
+        let Some(piece_size) = self.piece_size(index) else {
+            bail!("Requested piece {index} does not exist");
+        };
+        if length == 0 || length > MAX_REQUEST_LENGTH {
+            bail!("Requested block length {length} is outside 1..={MAX_REQUEST_LENGTH}");
+        }
+        if begin as u64 + length as u64 > piece_size {
+            bail!(
+                "Requested block {begin}+{length} runs past the end of piece {index} ({piece_size} bytes)"
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Reads a validated block, which may span several files.
+    pub fn read(&self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>, Error> {
+        self.validate(index, begin, length)?;
+
+        self.read_at(
+            index as u64 * self.piece_length + begin as u64,
+            length as u64,
+        )
+    }
+
+    /// Pieces holding `length` bytes from `start` of the torrent's data.
+    pub fn pieces_in(&self, start: u64, length: u64) -> std::ops::Range<u32> {
+        let piece_length = self.piece_length.max(1);
+        if length == 0 {
+            return 0..0;
+        }
+
+        (start / piece_length) as u32..(start + length).div_ceil(piece_length) as u32
+    }
+
+    /// Reads `length` bytes from `start` of the torrent's data, whether
+    /// verified or not, across as many files and pieces as they span.
+    pub fn read_at(&self, start: u64, length: u64) -> Result<Vec<u8>, Error> {
+        let end = start + length;
+        if end > self.total_length {
+            bail!(
+                "Range {start}+{length} runs past the end of the data ({} bytes)",
+                self.total_length
+            );
+        }
+        let mut block = Vec::with_capacity(length as usize);
+
+        for file in &self.files {
+            let file_end = file.offset + file.length;
+            if file_end <= start || file.offset >= end {
+                continue;
+            }
+
+            let from = start.max(file.offset) - file.offset;
+            let to = end.min(file_end) - file.offset;
+
+            let mut handle = File::open(&file.path)
+                .with_context(|| format!("Cannot open {}", file.path.display()))?;
+            handle.seek(SeekFrom::Start(from))?;
+            let read = handle.take(to - from).read_to_end(&mut block)?;
+            if (read as u64) < to - from {
+                bail!("{} is shorter than expected", file.path.display());
+            }
+        }
+
+        Ok(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use serde_bytes::ByteBuf;
+
+    use super::*;
+    use crate::torrent::metainfo::info::{FilesDict, InfoMultiFile};
+
+    /// Two files of 6 and 5 bytes in pieces of 4, so the final piece is 3
+    /// bytes long.
+    fn reader(dir: &Path) -> BlockReader {
+        fs::create_dir(dir.join("album")).unwrap();
+        fs::write(dir.join("album/a.txt"), b"abcdef").unwrap();
+        fs::write(dir.join("album/b.txt"), b"ghijk").unwrap();
+
+        let file = |name: &str, length| FilesDict {
+            length,
+            md5: None,
+            path: vec![String::from(name)],
+        };
+        let info = InfoEnum::MultiFile(InfoMultiFile {
+            name: String::from("album"),
+            piece_length: 4,
+            pieces: ByteBuf::from(vec![0; 20 * 3]),
+            files: vec![file("a.txt", 6), file("b.txt", 5)],
+        });
+
+        BlockReader::new(dir, &info)
+    }
+
+    #[test]
+    fn test_reads_odd_sizes_across_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let reader = reader(dir.path());
+
+        assert_eq!(reader.read(1, 1, 3).unwrap(), b"fgh");
+        assert_eq!(reader.read(0, 0, 4).unwrap(), b"abcd");
+        assert_eq!(reader.read(2, 0, 3).unwrap(), b"ijk");
+        assert_eq!(reader.read(2, 2, 1).unwrap(), b"k");
+
+        assert_eq!(reader.read_at(3, 6).unwrap(), b"defghi");
+        assert_eq!(reader.pieces_in(3, 6), 0..3);
+        assert_eq!(reader.pieces_in(4, 4), 1..2);
+        assert!(reader.read_at(8, 4).is_err());
+    }
+
+    #[test]
+    fn test_rejects_out_of_bounds_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let reader = reader(dir.path());
+
+        assert_eq!(reader.piece_size(2), Some(3));
+        assert_eq!(reader.piece_size(3), None);
+        assert!(reader.validate(2, 0, 4).is_err());
+        assert!(reader.validate(2, 3, 1).is_err());
+        assert!(reader.validate(0, 0, 0).is_err());
+        assert!(reader.validate(3, 0, 1).is_err());
+    }
+
+    #[test]
+    fn test_tracks_verified_pieces() {
+        let dir = tempfile::tempdir().unwrap();
+        let reader = reader(dir.path());
+        assert!(!reader.has_piece(0));
+
+        reader.set_verified(vec![true, false, false]);
+        let mut haves = reader.subscribe();
+        reader.mark_verified(2);
+        reader.mark_verified(2);
+        assert_eq!(haves.try_recv(), Ok(2));
+        assert!(haves.try_recv().is_err());
+        assert!(reader.has_piece(0));
+        assert!(!reader.has_piece(1));
+        assert!(reader.has_piece(2));
+        assert!(!reader.has_piece(3));
+        assert!(!reader.is_complete());
+        assert_eq!(reader.bitfield(), Some(vec![0b1010_0000]));
+
+        reader.mark_verified(1);
+        assert!(reader.is_complete());
+    }
+}
@@ -0,0 +1,355 @@
+//! Creation of .torrent files from a file or directory on disk.
+
+use std::{
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Error, bail};
+use serde_bytes::ByteBuf;
+use serde_derive::Serialize;
+use sha1::{Digest, Sha1};
+
+/// Bounds for the automatically chosen piece length.
+const MIN_PIECE_LENGTH: u64 = 16 * 1024;
+const MAX_PIECE_LENGTH: u64 = 16 * 1024 * 1024;
+
+/// Piece count the automatic piece length aims to stay under.
+const TARGET_PIECES: u64 = 1500;
+
+#[derive(Serialize)]
+struct FileDict {
+    length: u64,
+    path: Vec<String>,
+}
+
+/// Info dictionary as written by the builder. Kept separate from the
+/// parsing types so optional keys are left out rather than encoded empty.
+#[derive(Serialize)]
+struct BuiltInfo {
+    name: String,
+    #[serde(rename = "piece length")]
+    piece_length: u64,
+    pieces: ByteBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    length: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    files: Option<Vec<FileDict>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    private: Option<u8>,
+}
+
+#[derive(Serialize)]
+struct BuiltMetaInfo {
+    info: BuiltInfo,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    announce: Option<String>,
+    #[serde(rename = "announce-list", skip_serializing_if = "Option::is_none")]
+    announce_list: Option<Vec<Vec<String>>>,
+    #[serde(rename = "creation date")]
+    creation_date: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
+    #[serde(rename = "created by")]
+    created_by: String,
+}
+
+pub struct TorrentBuilder {
+    path: PathBuf,
+    piece_length: Option<u64>,
+    trackers: Vec<String>,
+    private: bool,
+    comment: Option<String>,
+}
+
+impl TorrentBuilder {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            piece_length: None,
+            trackers: vec![],
+            private: false,
+            comment: None,
+        }
+    }
+
+    /// Sets the piece length, which must be a power of two of at least
+    /// 16 KiB. Chosen from the total size when not set.
+    pub fn piece_length(mut self, piece_length: u64) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    /// Adds a tracker, each in its own tier. The first is also written as
+    /// the `announce` key for clients without announce-list support.
+    /// Without any the torrent is trackerless.
+    pub fn tracker(mut self, url: &str) -> Self {
+        self.trackers.push(String::from(url));
+        self
+    }
+
+    /// Private torrents (BEP 27) only get peers from their trackers.
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    pub fn comment(mut self, comment: &str) -> Self {
+        self.comment = Some(String::from(comment));
+        self
+    }
+
+    /// Hashes the files and returns the bencoded .torrent file.
+    pub fn build(&self) -> Result<Vec<u8>, Error> {
+        let name = self
+            .path
+            .file_name()
+            .context("Torrent path has no file name")?
+            .to_string_lossy()
+            .into_owned();
+
+        let files = Self::collect_files(&self.path)?;
+        let total_length: u64 = files.iter().map(|(_, length)| length).sum();
+        if total_length == 0 {
+            bail!("Cannot create a torrent of empty files");
+        }
+
+        let piece_length = match self.piece_length {
+            Some(length) if length < MIN_PIECE_LENGTH || !length.is_power_of_two() => {
+                bail!("Piece length must be a power of two of at least {MIN_PIECE_LENGTH}")
+            }
+            Some(length) => length,
+            None => auto_piece_length(total_length),
+        };
+
+        let pieces = Self::hash_pieces(&self.path, &files, piece_length)?;
+
+        let (length, files) = if self.path.is_dir() {
+            let files = files
+                .into_iter()
+                .map(|(path, length)| FileDict {
+                    length,
+                    path: path
+                        .components()
+                        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+                        .collect(),
+                })
+                .collect();
+            (None, Some(files))
+        } else {
+            (Some(total_length), None)
+        };
+
+        let metainfo = BuiltMetaInfo {
+            info: BuiltInfo {
+                name,
+                piece_length,
+                pieces: ByteBuf::from(pieces),
+                length,
+                files,
+                private: self.private.then_some(1),
+            },
+            announce: self.trackers.first().cloned(),
+            announce_list: (self.trackers.len() > 1)
+                .then(|| self.trackers.iter().map(|url| vec![url.clone()]).collect()),
+            creation_date: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            comment: self.comment.clone(),
+            created_by: format!("btrs {}", env!("CARGO_PKG_VERSION")),
+        };
+
+        serde_bencode::to_bytes(&metainfo).context("Failed to encode torrent")
+    }
+
+    /// Lists the files under `root` with their lengths, relative to `root`
+    /// and in a stable order. A single file is listed with an empty path.
+    fn collect_files(root: &Path) -> Result<Vec<(PathBuf, u64)>, Error> {
+        let metadata =
+            fs::metadata(root).with_context(|| format!("Cannot read {}", root.display()))?;
+        if metadata.is_file() {
+            return Ok(vec![(PathBuf::new(), metadata.len())]);
+        }
+
+        let mut files = vec![];
+        let mut dirs = vec![PathBuf::new()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(root.join(&dir))? {
+                let entry = entry?;
+                let relative = dir.join(entry.file_name());
+                let file_type = entry.file_type()?;
+
+                if file_type.is_dir() {
+                    dirs.push(relative);
+                } else if file_type.is_file() {
+                    files.push((relative, entry.metadata()?.len()));
+                }
+            }
+        }
+
+        if files.is_empty() {
+            bail!("{} contains no files", root.display());
+        }
+        files.sort();
+
+        Ok(files)
+    }
+
+    fn hash_pieces(
+        root: &Path,
+        files: &[(PathBuf, u64)],
+        piece_length: u64,
+    ) -> Result<Vec<u8>, Error> {
+        let mut pieces = vec![];
+        let mut piece = Vec::with_capacity(piece_length as usize);
+
+        for (path, _) in files {
+            let path = if path.as_os_str().is_empty() {
+                root.to_path_buf()
+            } else {
+                root.join(path)
+            };
+            let mut file =
+                File::open(&path).with_context(|| format!("Cannot open {}", path.display()))?;
+
+            loop {
+                let wanted = piece_length as usize - piece.len();
+                let read = (&mut file).take(wanted as u64).read_to_end(&mut piece)?;
+
+                if piece.len() == piece_length as usize {
+                    pieces.extend_from_slice(&Sha1::digest(&piece));
+                    piece.clear();
+                }
+                if read < wanted {
+                    break;
+                }
+            }
+        }
+
+        if !piece.is_empty() {
+            pieces.extend_from_slice(&Sha1::digest(&piece));
+        }
+
+        Ok(pieces)
+    }
+}
+
+/// Smallest power of two piece length that keeps the piece count near
+/// [`TARGET_PIECES`].
+fn auto_piece_length(total_length: u64) -> u64 {
+    let mut piece_length = MIN_PIECE_LENGTH;
+    while piece_length < MAX_PIECE_LENGTH && total_length / piece_length > TARGET_PIECES {
+        piece_length *= 2;
+    }
+
+    piece_length
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::{
+        Torrent,
+        metainfo::{MetaInfo, info::InfoEnum},
+    };
+
+    #[test]
+    fn test_single_file_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        let data: Vec<u8> = (0..40_000u32).map(|i| i as u8).collect();
+        fs::write(&path, &data).unwrap();
+
+        let bytes = TorrentBuilder::new(&path)
+            .tracker("http://tracker.test/announce")
+            .private(true)
+            .build()
+            .unwrap();
+
+        let metainfo = MetaInfo::from_bytes(&bytes).unwrap();
+        let InfoEnum::SingleFile(info) = metainfo.info() else {
+            panic!("expected a single file torrent");
+        };
+
+        assert_eq!(info.name, "data.bin");
+        assert_eq!(info.length, 40_000);
+        assert_eq!(info.piece_length, MIN_PIECE_LENGTH);
+        assert_eq!(info.pieces.len(), 3 * 20);
+        assert_eq!(
+            &info.pieces[..20],
+            Sha1::digest(&data[..MIN_PIECE_LENGTH as usize]).as_slice()
+        );
+        assert!(Torrent::load(&bytes, "-RS0001-abcdefghijkl").is_ok());
+    }
+
+    #[test]
+    fn test_directory_pieces_span_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("album");
+        fs::create_dir_all(root.join("disc2")).unwrap();
+        fs::write(root.join("a.txt"), vec![1u8; 20_000]).unwrap();
+        fs::write(root.join("disc2/b.txt"), vec![2u8; 20_000]).unwrap();
+
+        let bytes = TorrentBuilder::new(&root)
+            .tracker("http://one.test/announce")
+            .tracker("http://two.test/announce")
+            .build()
+            .unwrap();
+
+        let metainfo = MetaInfo::from_bytes(&bytes).unwrap();
+        let InfoEnum::MultiFile(info) = metainfo.info() else {
+            panic!("expected a multi file torrent");
+        };
+
+        let paths: Vec<_> = info.files.iter().map(|f| f.path.join("/")).collect();
+        assert_eq!(paths, vec!["a.txt", "disc2/b.txt"]);
+
+        let mut second_piece = vec![1u8; 20_000 - MIN_PIECE_LENGTH as usize];
+        second_piece.extend(vec![2u8; 2 * MIN_PIECE_LENGTH as usize - 20_000]);
+        assert_eq!(&info.pieces[20..40], Sha1::digest(&second_piece).as_slice());
+    }
+
+    #[test]
+    fn test_rejects_bad_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        fs::write(&path, b"data").unwrap();
+
+        assert!(
+            TorrentBuilder::new(dir.path().join("missing"))
+                .build()
+                .is_err()
+        );
+        assert!(
+            TorrentBuilder::new(&path)
+                .tracker("http://tracker.test/announce")
+                .piece_length(30_000)
+                .build()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_trackerless() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        fs::write(&path, b"data").unwrap();
+
+        let bytes = TorrentBuilder::new(&path).build().unwrap();
+
+        assert!(!String::from_utf8_lossy(&bytes).contains("announce"));
+        assert!(
+            MetaInfo::from_bytes(&bytes)
+                .unwrap()
+                .get_tracker_urls()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_auto_piece_length() {
+        assert_eq!(auto_piece_length(1024), MIN_PIECE_LENGTH);
+        assert_eq!(auto_piece_length(4 * 1024 * 1024 * 1024), 4 * 1024 * 1024);
+        assert_eq!(auto_piece_length(u64::MAX), MAX_PIECE_LENGTH);
+    }
+}
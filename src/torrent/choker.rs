@@ -0,0 +1,222 @@
+//! Upload slot management.
+//!
+//! Decides how many peers may be unchoked at once. Rather than a fixed
+//! count, [`SlotTuner`] opens slots while each one still gets a useful
+//! share of the uplink and closes them again once the per-slot rate
+//! drops below a threshold. The most slots it opens is capped by
+//! `BTRS_MAX_UPLOAD_SLOTS`, see [`limits`](crate::torrent::limits).
+//!
+//! Every [`CHOKE_INTERVAL`] a torrent's [`Choker`] retunes the slots from
+//! the last round's rates and hands them to the interested peers sending
+//! us the most, or taking the most from us once we only seed. The rest
+//! are choked.
+
+use std::{cmp::Reverse, time::Duration};
+
+/// How often the slots are retuned and handed out again.
+pub const CHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Upload rate below which a slot is not worth keeping open, in bytes/s.
+const DEFAULT_SLOT_THRESHOLD: u64 = 3 * 1024;
+
+/// Slots opened at most unless configured otherwise.
+pub const DEFAULT_MAX_SLOTS: usize = 50;
+
+#[derive(Debug, Clone)]
+pub struct SlotTuner {
+    slots: usize,
+    min_slots: usize,
+    max_slots: usize,
+    slot_threshold: u64,
+}
+
+impl Default for SlotTuner {
+    fn default() -> Self {
+        Self::new(2, DEFAULT_MAX_SLOTS, DEFAULT_SLOT_THRESHOLD)
+    }
+}
+
+impl SlotTuner {
+    /// Creates a tuner starting at `min_slots` unchoked peers.
+    pub fn new(min_slots: usize, max_slots: usize, slot_threshold: u64) -> Self {
+        debug_assert!(
+            min_slots <= max_slots,
+            "min_slots must not exceed max_slots"
+        );
+
+        Self {
+            slots: min_slots,
+            min_slots,
+            max_slots,
+            slot_threshold,
+        }
+    }
+
+    /// Current number of upload slots.
+    pub fn slots(&self) -> usize {
+        self.slots
+    }
+
+    /// Caps the slots at `max_slots`, closing any past it right away.
+    pub fn set_max_slots(&mut self, max_slots: usize) {
+        self.max_slots = max_slots;
+        self.min_slots = self.min_slots.min(max_slots);
+        self.slots = self.slots.min(max_slots);
+    }
+
+    /// Adjusts the slot count from the upload rates (bytes/s) measured on
+    /// each currently unchoked peer over the last choking round.
+    ///
+    /// A slot is added when every open slot is saturated above the
+    /// threshold, and removed when the average per-slot rate falls below
+    /// it. Slots are only added once all current ones are in use, so a
+    /// quiet swarm doesn't ratchet the count up.
+    pub fn update(&mut self, slot_rates: &[u64]) -> usize {
+        if slot_rates.is_empty() {
+            return self.slots;
+        }
+
+        let total: u64 = slot_rates.iter().sum();
+        let average = total / slot_rates.len() as u64;
+        let slowest = slot_rates.iter().copied().min().unwrap_or(0);
+
+        if average < self.slot_threshold {
+            self.slots = self.slots.saturating_sub(1).max(self.min_slots);
+        } else if slowest >= self.slot_threshold && slot_rates.len() >= self.slots {
+            self.slots = (self.slots + 1).min(self.max_slots);
+        }
+
+        self.slots
+    }
+}
+
+/// What a choking round looks at of a connected peer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChokeCandidate {
+    /// The peer wants data from us.
+    pub interested: bool,
+    /// We choke the peer.
+    pub choked: bool,
+    /// Bytes/s we send the peer.
+    pub upload_rate: u64,
+    /// Bytes/s the peer sends us.
+    pub download_rate: u64,
+}
+
+/// Chooses which peers of a torrent are unchoked.
+#[derive(Debug, Clone, Default)]
+pub struct Choker {
+    tuner: SlotTuner,
+}
+
+impl Choker {
+    /// See [`SlotTuner::set_max_slots`].
+    pub fn set_max_slots(&mut self, max_slots: usize) {
+        self.tuner.set_max_slots(max_slots);
+    }
+
+    /// Updates the slot count from the upload rates of the peers unchoked
+    /// over the last round.
+    pub fn retune(&mut self, peers: &[ChokeCandidate]) -> usize {
+        let rates: Vec<u64> = peers
+            .iter()
+            .filter(|peer| peer.interested && !peer.choked)
+            .map(|peer| peer.upload_rate)
+            .collect();
+
+        self.tuner.update(&rates)
+    }
+
+    /// Whether each of `peers` gets a slot, the fastest interested peers
+    /// first.
+    pub fn unchoked(&self, peers: &[ChokeCandidate]) -> Vec<bool> {
+        let mut order: Vec<usize> = (0..peers.len())
+            .filter(|&index| peers[index].interested)
+            .collect();
+        order.sort_by_key(|&index| Reverse((peers[index].download_rate, peers[index].upload_rate)));
+
+        let mut unchoked = vec![false; peers.len()];
+        for index in order.into_iter().take(self.tuner.slots()) {
+            unchoked[index] = true;
+        }
+
+        unchoked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slots_grow_while_saturated() {
+        let mut tuner = SlotTuner::new(2, 4, 1000);
+
+        assert_eq!(tuner.update(&[5000, 5000]), 3);
+        assert_eq!(tuner.update(&[5000, 5000, 5000]), 4);
+        // Capped at max_slots.
+        assert_eq!(tuner.update(&[5000, 5000, 5000, 5000]), 4);
+    }
+
+    #[test]
+    fn test_slots_shrink_when_per_slot_rate_drops() {
+        let mut tuner = SlotTuner::new(2, 10, 1000);
+        tuner.update(&[5000, 5000]);
+        tuner.update(&[5000, 5000, 5000]);
+
+        assert_eq!(tuner.update(&[500, 500, 500, 500]), 3);
+        assert_eq!(tuner.update(&[500, 500, 500]), 2);
+        // Never below min_slots.
+        assert_eq!(tuner.update(&[500, 500]), 2);
+    }
+
+    #[test]
+    fn test_slots_hold_when_not_all_in_use() {
+        let mut tuner = SlotTuner::new(3, 10, 1000);
+
+        assert_eq!(tuner.update(&[5000]), 3);
+        assert_eq!(tuner.update(&[]), 3);
+    }
+
+    #[test]
+    fn test_lowered_max_closes_slots() {
+        let mut tuner = SlotTuner::new(2, 10, 1000);
+        tuner.update(&[5000, 5000]);
+        tuner.update(&[5000, 5000, 5000]);
+
+        tuner.set_max_slots(1);
+        assert_eq!(tuner.slots(), 1);
+        assert_eq!(tuner.update(&[5000]), 1);
+    }
+
+    #[test]
+    fn test_fastest_interested_peers_unchoked() {
+        let peer = |interested, download_rate, upload_rate| ChokeCandidate {
+            interested,
+            choked: true,
+            upload_rate,
+            download_rate,
+        };
+        let peers = [
+            peer(true, 0, 500),
+            peer(false, 9000, 0),
+            peer(true, 2000, 0),
+            peer(true, 0, 100),
+        ];
+
+        // Two slots to start with, for the peers sending us the most,
+        // then those we send the most.
+        let mut choker = Choker::default();
+        assert_eq!(choker.unchoked(&peers), [true, false, true, false]);
+
+        choker.set_max_slots(1);
+        assert_eq!(choker.unchoked(&peers), [false, false, true, false]);
+    }
+
+    #[test]
+    fn test_slots_hold_with_one_slow_slot() {
+        let mut tuner = SlotTuner::new(2, 10, 1000);
+
+        assert_eq!(tuner.update(&[5000, 200]), 2);
+    }
+}
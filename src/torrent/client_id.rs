@@ -0,0 +1,193 @@
+//! Identification of BitTorrent clients from their peer IDs.
+//!
+//! Most clients encode their name and version at the start of the peer
+//! ID, either Azureus style (`-qB4650-...`), Shadow style
+//! (`S58B-----...`) or Mainline style (`M4-3-6--...`).
+
+/// Azureus style two letter client codes.
+const AZUREUS_CLIENTS: &[(&str, &str)] = &[
+    ("AZ", "Vuze"),
+    ("BC", "BitComet"),
+    ("BI", "BiglyBT"),
+    ("BT", "BitTorrent"),
+    ("DE", "Deluge"),
+    ("FW", "FrostWire"),
+    ("KT", "KTorrent"),
+    ("LT", "libtorrent"),
+    ("lt", "rTorrent"),
+    ("qB", "qBittorrent"),
+    ("RS", "btrs"),
+    ("TR", "Transmission"),
+    ("TX", "Tixati"),
+    ("UT", "µTorrent"),
+    ("UM", "µTorrent Mac"),
+    ("WW", "WebTorrent"),
+];
+
+/// Shadow style single letter client codes.
+const SHADOW_CLIENTS: &[(u8, &str)] = &[
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaculture"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT Bit Torrent"),
+];
+
+/// Decodes a human readable client name and version from a peer ID,
+/// e.g. `"qBittorrent 4.6.5"`. Returns `None` if the ID follows no
+/// known convention.
+pub fn client_name(peer_id: &[u8]) -> Option<String> {
+    azureus_style(peer_id)
+        .or_else(|| mainline_style(peer_id))
+        .or_else(|| shadow_style(peer_id))
+}
+
+/// `-XXvvvv-`: two character client code and four version characters.
+fn azureus_style(peer_id: &[u8]) -> Option<String> {
+    if peer_id.len() < 8 || peer_id[0] != b'-' || peer_id[7] != b'-' {
+        return None;
+    }
+
+    let code = std::str::from_utf8(&peer_id[1..3]).ok()?;
+    if !code.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+
+    let parts = peer_id[3..7]
+        .iter()
+        .map(|c| version_digit(*c))
+        .collect::<Option<Vec<u32>>>()?;
+
+    let name = AZUREUS_CLIENTS
+        .iter()
+        .find(|(c, _)| *c == code)
+        .map_or(code, |(_, name)| name);
+
+    Some(format!("{name} {}", format_version(&parts)))
+}
+
+/// `Mx-y-z--`: Mainline, with dash separated version numbers.
+fn mainline_style(peer_id: &[u8]) -> Option<String> {
+    if peer_id.first() != Some(&b'M') {
+        return None;
+    }
+
+    let header = std::str::from_utf8(peer_id.get(1..8)?).ok()?;
+    let parts: Vec<&str> = header.trim_end_matches('-').split('-').collect();
+    if parts.len() != 3 || parts.iter().any(|p| p.parse::<u32>().is_err()) {
+        return None;
+    }
+
+    Some(format!("Mainline {}", parts.join(".")))
+}
+
+/// `Xvvv--`: one letter client code and up to four version characters
+/// terminated by a dash.
+fn shadow_style(peer_id: &[u8]) -> Option<String> {
+    let (_, name) = SHADOW_CLIENTS
+        .iter()
+        .find(|(code, _)| peer_id.first() == Some(code))?;
+
+    let dash = peer_id.get(1..6)?.iter().position(|c| *c == b'-')?;
+    if dash == 0 {
+        return None;
+    }
+
+    let version: Vec<u32> = peer_id[1..=dash]
+        .iter()
+        .map(|c| shadow_digit(*c))
+        .collect::<Option<_>>()?;
+
+    let version: Vec<String> = version.iter().map(u32::to_string).collect();
+
+    Some(format!("{name} {}", version.join(".")))
+}
+
+/// Azureus version characters are digits, or letters for values >= 10.
+fn version_digit(c: u8) -> Option<u32> {
+    match c {
+        b'0'..=b'9' => Some((c - b'0') as u32),
+        b'A'..=b'Z' => Some((c - b'A') as u32 + 10),
+        b'a'..=b'z' => Some((c - b'a') as u32 + 10),
+        _ => None,
+    }
+}
+
+fn shadow_digit(c: u8) -> Option<u32> {
+    match c {
+        b'0'..=b'9' => Some((c - b'0') as u32),
+        b'A'..=b'Z' => Some((c - b'A') as u32 + 10),
+        b'a'..=b'z' => Some((c - b'a') as u32 + 36),
+        b'.' => Some(62),
+        _ => None,
+    }
+}
+
+/// Joins version parts with dots, dropping trailing zeros beyond the
+/// minor version, so `4600` reads as `4.6`.
+fn format_version(parts: &[u32]) -> String {
+    let mut end = parts.len();
+    while end > 2 && parts[end - 1] == 0 {
+        end -= 1;
+    }
+
+    parts[..end]
+        .iter()
+        .map(u32::to_string)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_azureus_style() {
+        assert_eq!(
+            client_name(b"-qB4600-abcdefghijkl").as_deref(),
+            Some("qBittorrent 4.6")
+        );
+        assert_eq!(
+            client_name(b"-TR4050-abcdefghijkl").as_deref(),
+            Some("Transmission 4.0.5")
+        );
+        assert_eq!(
+            client_name(b"-RS0001-abcdefghijkl").as_deref(),
+            Some("btrs 0.0.0.1")
+        );
+        // Unknown codes still report the version.
+        assert_eq!(
+            client_name(b"-ZZ1200-abcdefghijkl").as_deref(),
+            Some("ZZ 1.2")
+        );
+    }
+
+    #[test]
+    fn test_shadow_style() {
+        assert_eq!(
+            client_name(b"S58B-----abcdefghijk").as_deref(),
+            Some("Shadow 5.8.11")
+        );
+        assert_eq!(
+            client_name(b"T03I--00abcdefghijk").as_deref(),
+            Some("BitTornado 0.3.18")
+        );
+    }
+
+    #[test]
+    fn test_mainline_style() {
+        assert_eq!(
+            client_name(b"M4-3-6--abcdefghijkl").as_deref(),
+            Some("Mainline 4.3.6")
+        );
+    }
+
+    #[test]
+    fn test_unknown_peer_id() {
+        assert_eq!(client_name(&[0u8; 20]), None);
+        assert_eq!(client_name(b"-q"), None);
+    }
+}
@@ -0,0 +1,229 @@
+//! Detection of seeded files that were modified or deleted outside of
+//! btrs.
+//!
+//! Each file's size and modification time are recorded once its data is
+//! verified. A later [`FileWatcher::check`] compares them against the
+//! filesystem and marks the pieces overlapping any changed file invalid,
+//! so they stop being advertised or served until re-checked.
+
+use std::{
+    collections::BTreeSet,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::torrent::metainfo::info::InfoEnum;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileStamp {
+    length: u64,
+    modified: Option<SystemTime>,
+}
+
+impl FileStamp {
+    fn read(path: &Path) -> Option<Self> {
+        let metadata = std::fs::metadata(path).ok()?;
+
+        Some(Self {
+            length: metadata.len(),
+            modified: metadata.modified().ok(),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct WatchedFile {
+    path: PathBuf,
+    /// Offset of the file within the torrent's concatenated data.
+    offset: u64,
+    length: u64,
+    stamp: Option<FileStamp>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Modified,
+    Deleted,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileChange {
+    pub path: PathBuf,
+    pub kind: ChangeKind,
+    /// Pieces overlapping the file, now marked invalid.
+    pub pieces: RangeInclusive<u32>,
+}
+
+#[derive(Debug)]
+pub struct FileWatcher {
+    files: Vec<WatchedFile>,
+    piece_length: u64,
+    invalid: BTreeSet<u32>,
+}
+
+impl FileWatcher {
+    /// Watches the files of a torrent whose data lives under `root`,
+    /// taking their current state as known good.
+    pub fn new(root: &Path, info: &InfoEnum) -> Self {
+        let piece_length = info.piece_length();
+
+        let mut offset = 0;
+        let files = info
+            .layout(root)
+            .into_iter()
+            .map(|(path, length)| {
+                let file = WatchedFile {
+                    stamp: FileStamp::read(&path),
+                    path,
+                    offset,
+                    length,
+                };
+                offset += length;
+                file
+            })
+            .collect();
+
+        Self {
+            files,
+            piece_length,
+            invalid: BTreeSet::new(),
+        }
+    }
+
+    /// Compares every file against its recorded state, returning the ones
+    /// that changed since the last check.
+    pub fn check(&mut self) -> Vec<FileChange> {
+        let mut changes = vec![];
+
+        for file in self.files.iter_mut() {
+            let current = FileStamp::read(&file.path);
+            if current == file.stamp {
+                continue;
+            }
+
+            let kind = match current {
+                Some(_) => ChangeKind::Modified,
+                None => ChangeKind::Deleted,
+            };
+            file.stamp = current;
+
+            // Empty files don't overlap any piece.
+            if file.length == 0 {
+                continue;
+            }
+
+            let first = (file.offset / self.piece_length) as u32;
+            let last = ((file.offset + file.length - 1) / self.piece_length) as u32;
+            self.invalid.extend(first..=last);
+
+            changes.push(FileChange {
+                path: file.path.clone(),
+                kind,
+                pieces: first..=last,
+            });
+        }
+
+        changes
+    }
+
+    pub fn is_piece_valid(&self, piece_index: u32) -> bool {
+        !self.invalid.contains(&piece_index)
+    }
+
+    /// Pieces invalidated by changed files that still need re-checking.
+    pub fn invalid_pieces(&self) -> impl Iterator<Item = u32> + '_ {
+        self.invalid.iter().copied()
+    }
+
+    /// Marks a piece valid again after it was re-checked.
+    pub fn revalidate(&mut self, piece_index: u32) {
+        self.invalid.remove(&piece_index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use serde_bytes::ByteBuf;
+
+    use super::*;
+    use crate::torrent::metainfo::info::{FilesDict, InfoMultiFile};
+
+    fn multi_file_info() -> InfoEnum {
+        InfoEnum::MultiFile(InfoMultiFile {
+            name: String::from("album"),
+            piece_length: 4,
+            pieces: ByteBuf::from(vec![0; 20 * 3]),
+            files: vec![
+                FilesDict {
+                    length: 6,
+                    md5: None,
+                    path: vec![String::from("a.txt")],
+                },
+                FilesDict {
+                    length: 5,
+                    md5: None,
+                    path: vec![String::from("b.txt")],
+                },
+            ],
+        })
+    }
+
+    #[test]
+    fn test_unchanged_files_report_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("album")).unwrap();
+        fs::write(dir.path().join("album/a.txt"), b"aaaaaa").unwrap();
+        fs::write(dir.path().join("album/b.txt"), b"bbbbb").unwrap();
+
+        let mut watcher = FileWatcher::new(dir.path(), &multi_file_info());
+
+        assert!(watcher.check().is_empty());
+        assert!(watcher.is_piece_valid(0));
+    }
+
+    #[test]
+    fn test_deleted_file_invalidates_overlapping_pieces() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("album")).unwrap();
+        fs::write(dir.path().join("album/a.txt"), b"aaaaaa").unwrap();
+        fs::write(dir.path().join("album/b.txt"), b"bbbbb").unwrap();
+
+        let mut watcher = FileWatcher::new(dir.path(), &multi_file_info());
+        fs::remove_file(dir.path().join("album/b.txt")).unwrap();
+
+        let changes = watcher.check();
+
+        // b.txt covers bytes 6..11, so pieces 1 and 2.
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].kind, ChangeKind::Deleted);
+        assert_eq!(changes[0].pieces, 1..=2);
+        assert!(watcher.is_piece_valid(0));
+        assert!(!watcher.is_piece_valid(1));
+
+        // Reported once, until it changes again.
+        assert!(watcher.check().is_empty());
+
+        watcher.revalidate(1);
+        assert_eq!(watcher.invalid_pieces().collect::<Vec<_>>(), vec![2]);
+    }
+
+    #[test]
+    fn test_resized_file_is_modified() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("album")).unwrap();
+        fs::write(dir.path().join("album/a.txt"), b"aaaaaa").unwrap();
+        fs::write(dir.path().join("album/b.txt"), b"bbbbb").unwrap();
+
+        let mut watcher = FileWatcher::new(dir.path(), &multi_file_info());
+        fs::write(dir.path().join("album/a.txt"), b"truncated").unwrap();
+
+        let changes = watcher.check();
+
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].kind, ChangeKind::Modified);
+        assert_eq!(changes[0].pieces, 0..=1);
+    }
+}
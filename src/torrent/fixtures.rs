@@ -0,0 +1,495 @@
+//! Generated .torrent files and their data, for tests.
+//!
+//! Every fixture is built from scratch in a temporary directory, so tests
+//! don't depend on torrents checked into the repository and can pick the
+//! exact layout they exercise: one file, several files, files aligned to
+//! pieces with BEP 47 pad files, BEP 52 v2 only or hybrid, or metainfo
+//! broken in one of the ways [`health`](super::metainfo::health) rejects.
+//!
+//! File data is deterministic and differs per file, so data read from the
+//! wrong place doesn't pass for the right one.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde_bencode::value::Value;
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use tempfile::TempDir;
+
+use crate::torrent::Torrent;
+
+/// Peer ID fixtures are loaded with.
+pub const PEER_ID: &str = "-RS0001-abcdefghijkl";
+
+/// Piece length of every fixture.
+pub const PIECE_LENGTH: usize = 16 * 1024;
+
+/// Size of the blocks v2 hashes files in.
+const V2_BLOCK_SIZE: usize = 16 * 1024;
+
+/// Name of single file fixtures.
+pub const FILE_NAME: &str = "data.bin";
+
+/// Name of the directory of multi-file fixtures.
+pub const DIR_NAME: &str = "folder";
+
+/// A .torrent file and its data on disk.
+pub struct Fixture {
+    /// Holds the data, deleted with the fixture.
+    dir: TempDir,
+    /// The bencoded .torrent file.
+    pub bytes: Vec<u8>,
+    /// Every file of the torrent in order, pad files included, by path
+    /// within the torrent, empty for a single file.
+    pub files: Vec<(Vec<String>, Vec<u8>)>,
+}
+
+impl Fixture {
+    /// The download directory the torrent's data is stored under.
+    pub fn root(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Where the file at `path` within the torrent is stored.
+    pub fn path(&self, path: &[&str]) -> PathBuf {
+        match path {
+            [] => self.root().join(FILE_NAME),
+            path => path
+                .iter()
+                .fold(self.root().join(DIR_NAME), |dir, part| dir.join(part)),
+        }
+    }
+
+    /// The torrent's content, every file one after the other.
+    pub fn data(&self) -> Vec<u8> {
+        self.files
+            .iter()
+            .flat_map(|(_, data)| data.clone())
+            .collect()
+    }
+
+    /// Adds the tracker at `url` to the torrent, the first one added also
+    /// as its `announce`.
+    pub fn with_tracker(mut self, url: &str) -> Self {
+        let Ok(Value::Dict(mut metainfo)) = serde_bencode::from_bytes(&self.bytes) else {
+            unreachable!("fixtures are dictionaries");
+        };
+        metainfo
+            .entry(key("announce"))
+            .or_insert_with(|| bytes(url));
+        let Value::List(tiers) = metainfo
+            .entry(key("announce-list"))
+            .or_insert_with(|| Value::List(vec![]))
+        else {
+            unreachable!("announce-list is a list");
+        };
+        tiers.push(Value::List(vec![bytes(url)]));
+
+        self.bytes =
+            serde_bencode::to_bytes(&Value::Dict(metainfo)).expect("Fixture should encode");
+        self
+    }
+
+    pub fn load(&self) -> Torrent {
+        Torrent::load(&self.bytes, PEER_ID).expect("Fixture should load")
+    }
+}
+
+/// `length` bytes of content for the file numbered `seed`.
+pub fn payload(length: usize, seed: u8) -> Vec<u8> {
+    (0..length)
+        .map(|i| ((i % 251) as u8).wrapping_add(seed.wrapping_mul(37)))
+        .collect()
+}
+
+/// One file of `length` bytes.
+pub fn single_file(length: usize) -> Fixture {
+    let files = vec![(vec![], payload(length, 0))];
+    let mut info = v1_info(FILE_NAME, &files);
+    info.insert(key("length"), Value::Int(length as i64));
+
+    write(files, metainfo(info, None))
+}
+
+/// Files at `/` separated paths with their lengths, in a directory.
+pub fn multi_file(files: &[(&str, usize)]) -> Fixture {
+    let files = contents(files);
+    let mut info = v1_info(DIR_NAME, &files);
+    info.insert(key("files"), file_list(&files));
+
+    write(files, metainfo(info, None))
+}
+
+/// Like [`multi_file`], with a BEP 47 pad file after each file that
+/// doesn't end on a piece boundary, so every file starts a piece. Pad
+/// files are written to disk as zeros, as btrs stores them like any other
+/// file.
+pub fn padded(files: &[(&str, usize)]) -> Fixture {
+    let files = pad(contents(files));
+    let mut info = v1_info(DIR_NAME, &files);
+    info.insert(key("files"), file_list(&files));
+
+    write(files, metainfo(info, None))
+}
+
+/// A BEP 52 v2 only torrent, with a file tree and piece layers but no v1
+/// `pieces`.
+pub fn v2(files: &[(&str, usize)]) -> Fixture {
+    let files = contents(files);
+    let (file_tree, piece_layers) = v2_tree(&files);
+    let info = fields([
+        ("name", bytes(DIR_NAME)),
+        ("piece length", Value::Int(PIECE_LENGTH as i64)),
+        ("meta version", Value::Int(2)),
+        ("file tree", file_tree),
+    ]);
+
+    write(files, metainfo(info, Some(piece_layers)))
+}
+
+/// A BEP 52 hybrid torrent: the padded v1 layout of [`padded`] along
+/// with the v2 file tree of the same files.
+pub fn hybrid(files: &[(&str, usize)]) -> Fixture {
+    let files = contents(files);
+    let (file_tree, piece_layers) = v2_tree(&files);
+    let files = pad(files);
+    let mut info = v1_info(DIR_NAME, &files);
+    info.insert(key("files"), file_list(&files));
+    info.insert(key("meta version"), Value::Int(2));
+    info.insert(key("file tree"), file_tree);
+
+    write(files, metainfo(info, Some(piece_layers)))
+}
+
+/// Ways metainfo can be broken beyond use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Broken {
+    ZeroPieceLength,
+    /// Piece hashes cut off mid-hash.
+    TruncatedHashes,
+    /// One piece hash short of the data.
+    MissingHash,
+    EmptyFileList,
+    /// A path climbing out of the download directory.
+    PathTraversal,
+    DuplicatePath,
+    /// A file that is also the directory of another.
+    FileInsideFile,
+    /// No info dictionary at all.
+    NoInfo,
+}
+
+impl Broken {
+    pub const ALL: [Broken; 8] = [
+        Broken::ZeroPieceLength,
+        Broken::TruncatedHashes,
+        Broken::MissingHash,
+        Broken::EmptyFileList,
+        Broken::PathTraversal,
+        Broken::DuplicatePath,
+        Broken::FileInsideFile,
+        Broken::NoInfo,
+    ];
+}
+
+/// A .torrent file broken as `broken` says, otherwise a valid two file
+/// torrent. There is no data to go with it.
+pub fn broken(broken: Broken) -> Vec<u8> {
+    let files = contents(&[("a.txt", 20_000), ("b/c.txt", 30_000)]);
+    let mut info = v1_info(DIR_NAME, &files);
+    let mut paths: Vec<Vec<String>> = files.iter().map(|(path, _)| path.clone()).collect();
+
+    match broken {
+        Broken::ZeroPieceLength => {
+            info.insert(key("piece length"), Value::Int(0));
+        }
+        Broken::TruncatedHashes | Broken::MissingHash => {
+            let Some(Value::Bytes(pieces)) = info.get_mut(&key("pieces")) else {
+                unreachable!("v1 info has pieces");
+            };
+            let cut = if broken == Broken::MissingHash { 20 } else { 7 };
+            pieces.truncate(pieces.len() - cut);
+        }
+        Broken::EmptyFileList => paths.clear(),
+        Broken::PathTraversal => paths[1] = vec![String::from(".."), String::from("c.txt")],
+        Broken::DuplicatePath => paths[1] = paths[0].clone(),
+        Broken::FileInsideFile => paths[1] = vec![String::from("a.txt"), String::from("c.txt")],
+        Broken::NoInfo => {
+            return serde_bencode::to_bytes(&dict([("created by", bytes("btrs tests"))]))
+                .expect("Fixture should encode");
+        }
+    }
+
+    let files: Vec<_> = paths
+        .into_iter()
+        .zip(files)
+        .map(|(path, (_, data))| (path, data))
+        .collect();
+    info.insert(key("files"), file_list(&files));
+
+    serde_bencode::to_bytes(&metainfo(info, None)).expect("Fixture should encode")
+}
+
+fn key(name: &str) -> Vec<u8> {
+    name.as_bytes().to_vec()
+}
+
+fn bytes(value: &str) -> Value {
+    Value::Bytes(key(value))
+}
+
+fn fields<const N: usize>(entries: [(&str, Value); N]) -> HashMap<Vec<u8>, Value> {
+    entries
+        .into_iter()
+        .map(|(name, value)| (key(name), value))
+        .collect()
+}
+
+fn dict<const N: usize>(entries: [(&str, Value); N]) -> Value {
+    Value::Dict(fields(entries))
+}
+
+/// Files of `/` separated paths and lengths with their content.
+fn contents(files: &[(&str, usize)]) -> Vec<(Vec<String>, Vec<u8>)> {
+    files
+        .iter()
+        .enumerate()
+        .map(|(seed, (path, length))| {
+            (
+                path.split('/').map(String::from).collect(),
+                payload(*length, seed as u8),
+            )
+        })
+        .collect()
+}
+
+/// Adds a zero filled pad file after every file but the last that doesn't
+/// end on a piece boundary.
+fn pad(files: Vec<(Vec<String>, Vec<u8>)>) -> Vec<(Vec<String>, Vec<u8>)> {
+    let count = files.len();
+    let mut padded = vec![];
+    for (number, (path, data)) in files.into_iter().enumerate() {
+        let gap = (PIECE_LENGTH - data.len() % PIECE_LENGTH) % PIECE_LENGTH;
+        padded.push((path, data));
+        if gap > 0 && number + 1 < count {
+            padded.push((vec![String::from(".pad"), gap.to_string()], vec![0; gap]));
+        }
+    }
+
+    padded
+}
+
+/// The v1 keys shared by every info dictionary, the piece hashes covering
+/// `files` one after the other.
+fn v1_info(name: &str, files: &[(Vec<String>, Vec<u8>)]) -> HashMap<Vec<u8>, Value> {
+    let data: Vec<u8> = files.iter().flat_map(|(_, data)| data.clone()).collect();
+    let pieces: Vec<u8> = data
+        .chunks(PIECE_LENGTH)
+        .flat_map(|piece| Sha1::digest(piece).to_vec())
+        .collect();
+
+    HashMap::from([
+        (key("name"), bytes(name)),
+        (key("piece length"), Value::Int(PIECE_LENGTH as i64)),
+        (key("pieces"), Value::Bytes(pieces)),
+    ])
+}
+
+fn file_list(files: &[(Vec<String>, Vec<u8>)]) -> Value {
+    Value::List(
+        files
+            .iter()
+            .map(|(path, data)| {
+                let mut file = HashMap::from([
+                    (key("length"), Value::Int(data.len() as i64)),
+                    (
+                        key("path"),
+                        Value::List(path.iter().map(|part| bytes(part)).collect()),
+                    ),
+                ]);
+                if path.first().is_some_and(|part| part == ".pad") {
+                    file.insert(key("attr"), bytes("p"));
+                }
+                Value::Dict(file)
+            })
+            .collect(),
+    )
+}
+
+fn metainfo(info: HashMap<Vec<u8>, Value>, piece_layers: Option<Value>) -> Value {
+    let mut metainfo = HashMap::from([
+        (key("info"), Value::Dict(info)),
+        (key("created by"), bytes("btrs tests")),
+    ]);
+    if let Some(piece_layers) = piece_layers {
+        metainfo.insert(key("piece layers"), piece_layers);
+    }
+
+    Value::Dict(metainfo)
+}
+
+/// Writes the files under a new temporary directory.
+fn write(files: Vec<(Vec<String>, Vec<u8>)>, metainfo: Value) -> Fixture {
+    let fixture = Fixture {
+        dir: tempfile::tempdir().expect("Temporary directory should be created"),
+        bytes: serde_bencode::to_bytes(&metainfo).expect("Fixture should encode"),
+        files,
+    };
+
+    for (path, data) in &fixture.files {
+        let parts: Vec<&str> = path.iter().map(String::as_str).collect();
+        let file = fixture.path(&parts);
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(file, data).unwrap();
+    }
+
+    fixture
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// Root of the merkle tree over `leaves`, whose count is a power of two.
+fn merkle_root(mut layer: Vec<[u8; 32]>) -> [u8; 32] {
+    while layer.len() > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| sha256(&[pair[0], pair[1]].concat()))
+            .collect();
+    }
+
+    layer[0]
+}
+
+/// The v2 `file tree` of `files` and the `piece layers` of those longer
+/// than a piece.
+fn v2_tree(files: &[(Vec<String>, Vec<u8>)]) -> (Value, Value) {
+    let mut tree = HashMap::new();
+    let mut piece_layers = HashMap::new();
+
+    for (path, data) in files {
+        let mut leaves: Vec<[u8; 32]> = data.chunks(V2_BLOCK_SIZE).map(sha256).collect();
+        // Zero hashes fill the tree, BEP 52.
+        leaves.resize(leaves.len().next_power_of_two(), [0; 32]);
+        let root = merkle_root(leaves.clone());
+
+        if data.len() > PIECE_LENGTH {
+            let layer: Vec<u8> = leaves
+                .chunks(PIECE_LENGTH / V2_BLOCK_SIZE)
+                .take(data.len().div_ceil(PIECE_LENGTH))
+                .flat_map(|piece| merkle_root(piece.to_vec()))
+                .collect();
+            piece_layers.insert(root.to_vec(), Value::Bytes(layer));
+        }
+
+        let file = dict([(
+            "",
+            dict([
+                ("length", Value::Int(data.len() as i64)),
+                ("pieces root", Value::Bytes(root.to_vec())),
+            ]),
+        )]);
+        insert_path(&mut tree, path, file);
+    }
+
+    (Value::Dict(tree), Value::Dict(piece_layers))
+}
+
+fn insert_path(tree: &mut HashMap<Vec<u8>, Value>, path: &[String], file: Value) {
+    match path {
+        [] => {}
+        [name] => {
+            tree.insert(key(name), file);
+        }
+        [dir, rest @ ..] => {
+            let Value::Dict(subtree) = tree
+                .entry(key(dir))
+                .or_insert_with(|| Value::Dict(HashMap::new()))
+            else {
+                unreachable!("directories are dictionaries");
+            };
+            insert_path(subtree, rest, file);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::{io_stats::IoStats, metainfo::MetaInfo, verify};
+
+    const FILES: &[(&str, usize)] = &[("a.txt", 20_000), ("b/c.txt", 40_000), ("d.txt", 100)];
+
+    /// Whether every piece of `fixture` checks out against its data.
+    fn verifies(fixture: &Fixture) -> bool {
+        let metainfo = MetaInfo::from_bytes(&fixture.bytes).unwrap();
+        let have = verify::verify_pieces(fixture.root(), metainfo.info(), &IoStats::default());
+
+        !have.is_empty() && have.iter().all(|&valid| valid)
+    }
+
+    #[test]
+    fn test_fixtures_load_and_verify() {
+        for fixture in [
+            single_file(50_000),
+            multi_file(FILES),
+            padded(FILES),
+            hybrid(FILES),
+        ] {
+            let torrent = fixture.load();
+            assert_eq!(
+                torrent.metainfo.info().total_length() as usize,
+                fixture.data().len()
+            );
+            assert!(verifies(&fixture));
+        }
+
+        // Every file of a padded torrent starts a piece.
+        let padded = padded(FILES);
+        let sizes: Vec<usize> = padded.files.iter().map(|(_, data)| data.len()).collect();
+        assert_eq!(sizes, [20_000, 12_768, 40_000, 9_152, 100]);
+        assert_eq!(
+            fs::read(padded.path(&["b", "c.txt"])).unwrap(),
+            payload(40_000, 1)
+        );
+    }
+
+    #[test]
+    fn test_v2_file_tree() {
+        let fixture = v2(&[("a.txt", 100), ("b/c.txt", 3 * V2_BLOCK_SIZE)]);
+        // v2 only torrents aren't supported.
+        assert!(Torrent::load(&fixture.bytes, PEER_ID).is_err());
+
+        let metainfo: Value = serde_bencode::from_bytes(&fixture.bytes).unwrap();
+        let Value::Dict(metainfo) = metainfo else {
+            panic!("metainfo isn't a dictionary");
+        };
+        let Some(Value::Dict(layers)) = metainfo.get(&key("piece layers")) else {
+            panic!("no piece layers");
+        };
+        // Only the file longer than a piece has a layer, its 3 pieces.
+        let data = payload(3 * V2_BLOCK_SIZE, 1);
+        let leaves: Vec<_> = data.chunks(V2_BLOCK_SIZE).map(sha256).collect();
+        let root = merkle_root(vec![leaves[0], leaves[1], leaves[2], [0; 32]]);
+        assert_eq!(
+            layers.get(root.as_slice()),
+            Some(&Value::Bytes(leaves.concat()))
+        );
+        assert_eq!(layers.len(), 1);
+    }
+
+    #[test]
+    fn test_broken_fixtures_are_rejected() {
+        for kind in Broken::ALL {
+            assert!(
+                Torrent::load(&broken(kind), PEER_ID).is_err(),
+                "{kind:?} loaded"
+            );
+        }
+    }
+}
@@ -0,0 +1,225 @@
+//! Country and network (ASN) of peer addresses, from an optional
+//! database.
+//!
+//! Point `BTRS_GEOIP` at an IP to ASN table in the tab separated format of
+//! iptoasn.com, e.g. `ip2asn-combined.tsv`: one address range per line
+//! with its first and last address, AS number, country code and AS name.
+//! Without it peers are simply not located.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::OnceLock,
+};
+
+use anyhow::{Context, Error, anyhow};
+
+pub const GEOIP_ENV_VAR: &str = "BTRS_GEOIP";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    /// ISO 3166 country code.
+    pub country: String,
+    pub asn: u32,
+    /// Name of the autonomous system, usually the ISP.
+    pub network: String,
+}
+
+#[derive(Debug)]
+struct Range {
+    start: u128,
+    end: u128,
+    location: Location,
+}
+
+#[derive(Debug, Default)]
+pub struct GeoIp {
+    /// Sorted by start, not overlapping.
+    ranges: Vec<Range>,
+}
+
+/// Addresses as IPv6, with IPv4 mapped into it, so both families share
+/// one table.
+fn key(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u128::from(ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+impl GeoIp {
+    pub fn read(path: &Path) -> Result<Self, Error> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Cannot read GeoIP database {}", path.display()))?;
+
+        Self::parse(&text).with_context(|| format!("Invalid GeoIP database {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let mut ranges = vec![];
+        for (number, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.splitn(5, '\t').collect();
+            let [start, end, asn, country, network] = fields[..] else {
+                return Err(anyhow!("Line {} has too few fields", number + 1));
+            };
+            let parse_ip = |field: &str| {
+                field
+                    .parse::<IpAddr>()
+                    .with_context(|| format!("Line {}: invalid address {field}", number + 1))
+            };
+            let asn: u32 = asn
+                .parse()
+                .with_context(|| format!("Line {}: invalid AS number {asn}", number + 1))?;
+            // Unannounced ranges, nobody to attribute them to.
+            if asn == 0 {
+                continue;
+            }
+
+            ranges.push(Range {
+                start: key(parse_ip(start)?),
+                end: key(parse_ip(end)?),
+                location: Location {
+                    country: String::from(country),
+                    asn,
+                    network: String::from(network),
+                },
+            });
+        }
+        ranges.sort_by_key(|range| range.start);
+
+        Ok(Self { ranges })
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Option<&Location> {
+        let key = key(ip);
+        // Last range starting at or before the address.
+        let index = self.ranges.partition_point(|range| range.start <= key);
+        let range = &self.ranges[index.checked_sub(1)?];
+
+        (key <= range.end).then_some(&range.location)
+    }
+
+    /// Counts the peers at `addresses` per country and per network, most
+    /// common first. Addresses not in the database count as unknown.
+    pub fn distribution<'a>(&self, addresses: impl IntoIterator<Item = &'a str>) -> GeoStats {
+        let mut countries: HashMap<String, usize> = HashMap::new();
+        let mut networks: HashMap<String, usize> = HashMap::new();
+
+        for address in addresses {
+            let location = address
+                .parse::<SocketAddr>()
+                .map(|address| address.ip())
+                .or_else(|_| address.parse::<IpAddr>())
+                .ok()
+                .and_then(|ip| self.lookup(ip));
+            let (country, network) = match location {
+                Some(location) => (
+                    location.country.clone(),
+                    format!("AS{} {}", location.asn, location.network),
+                ),
+                None => (String::from("unknown"), String::from("unknown")),
+            };
+            *countries.entry(country).or_default() += 1;
+            *networks.entry(network).or_default() += 1;
+        }
+
+        GeoStats {
+            countries: by_count(countries),
+            networks: by_count(networks),
+        }
+    }
+}
+
+fn by_count(counts: HashMap<String, usize>) -> Vec<(String, usize)> {
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    counts
+}
+
+/// Connected peers per country and per network.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GeoStats {
+    pub countries: Vec<(String, usize)>,
+    pub networks: Vec<(String, usize)>,
+}
+
+/// The database named by [`GEOIP_ENV_VAR`], read on first use. `None`
+/// when it isn't set or can't be read.
+pub fn database() -> Option<&'static GeoIp> {
+    static DATABASE: OnceLock<Option<GeoIp>> = OnceLock::new();
+
+    DATABASE
+        .get_or_init(|| {
+            let path = std::env::var_os(GEOIP_ENV_VAR)?;
+            GeoIp::read(Path::new(&path))
+                .inspect_err(|e| eprintln!("[GeoIP] {e:#}"))
+                .ok()
+        })
+        .as_ref()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TABLE: &str = "\
+1.0.0.0\t1.0.0.255\t13335\tUS\tCLOUDFLARENET
+1.0.1.0\t1.0.3.255\t0\tNone\tNot routed
+2.16.0.0\t2.16.255.255\t20940\tDE\tAKAMAI-ASN1
+2001:db8::\t2001:db8::ffff\t64500\tNL\tEXAMPLE-V6
+";
+
+    #[test]
+    fn test_lookup() {
+        let geoip = GeoIp::parse(TABLE).unwrap();
+
+        let location = geoip.lookup("1.0.0.1".parse().unwrap()).unwrap();
+        assert_eq!(location.country, "US");
+        assert_eq!(location.asn, 13335);
+        assert_eq!(
+            geoip
+                .lookup("2001:db8::10".parse().unwrap())
+                .unwrap()
+                .country,
+            "NL"
+        );
+        assert!(geoip.lookup("1.0.2.1".parse().unwrap()).is_none());
+        assert!(geoip.lookup("0.0.0.1".parse().unwrap()).is_none());
+        assert!(geoip.lookup("9.9.9.9".parse().unwrap()).is_none());
+
+        assert!(GeoIp::parse("1.0.0.0\t1.0.0.255\t13335").is_err());
+    }
+
+    #[test]
+    fn test_distribution() {
+        let geoip = GeoIp::parse(TABLE).unwrap();
+
+        let stats = geoip.distribution([
+            "1.0.0.1:6881",
+            "1.0.0.2:51413",
+            "2.16.0.9:6881",
+            "[2001:db8::1]:6881",
+            "10.0.0.1:6881",
+        ]);
+
+        assert_eq!(
+            stats.countries,
+            vec![
+                (String::from("US"), 2),
+                (String::from("DE"), 1),
+                (String::from("NL"), 1),
+                (String::from("unknown"), 1),
+            ]
+        );
+        assert_eq!(
+            stats.networks[0],
+            (String::from("AS13335 CLOUDFLARENET"), 2)
+        );
+    }
+}
@@ -0,0 +1,149 @@
+//! Counters for disk reads and writes, used to tell whether a slow torrent
+//! is held back by the network or by the disk.
+
+use std::{
+    ops::Add,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Default)]
+pub struct IoStats {
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    reads: AtomicU64,
+    writes: AtomicU64,
+    read_micros: AtomicU64,
+    write_micros: AtomicU64,
+    in_flight: AtomicU64,
+}
+
+/// Point in time copy of [`IoStats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IoSnapshot {
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub reads: u64,
+    pub writes: u64,
+    pub read_time: Duration,
+    pub write_time: Duration,
+    /// Operations currently waiting on the disk.
+    pub queue_depth: u64,
+}
+
+/// Counts an operation as queued until dropped.
+pub struct InFlight<'a>(&'a IoStats);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl IoStats {
+    pub fn start_op(&self) -> InFlight<'_> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(self)
+    }
+
+    pub fn record_read(&self, bytes: u64, elapsed: Duration) {
+        self.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.read_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_write(&self, bytes: u64, elapsed: Duration) {
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.write_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Runs a read, recording the bytes it returns and how long it took.
+    pub fn timed_read<E>(&self, read: impl FnOnce() -> Result<usize, E>) -> Result<usize, E> {
+        let _op = self.start_op();
+        let started = Instant::now();
+        let result = read();
+
+        if let Ok(bytes) = &result {
+            self.record_read(*bytes as u64, started.elapsed());
+        }
+
+        result
+    }
+
+    pub fn snapshot(&self) -> IoSnapshot {
+        IoSnapshot {
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            reads: self.reads.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            read_time: Duration::from_micros(self.read_micros.load(Ordering::Relaxed)),
+            write_time: Duration::from_micros(self.write_micros.load(Ordering::Relaxed)),
+            queue_depth: self.in_flight.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl IoSnapshot {
+    pub fn average_read_latency(&self) -> Option<Duration> {
+        (self.reads > 0).then(|| self.read_time / self.reads as u32)
+    }
+
+    pub fn average_write_latency(&self) -> Option<Duration> {
+        (self.writes > 0).then(|| self.write_time / self.writes as u32)
+    }
+}
+
+impl Add for IoSnapshot {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            bytes_read: self.bytes_read + other.bytes_read,
+            bytes_written: self.bytes_written + other.bytes_written,
+            reads: self.reads + other.reads,
+            writes: self.writes + other.writes,
+            read_time: self.read_time + other.read_time,
+            write_time: self.write_time + other.write_time,
+            queue_depth: self.queue_depth + other.queue_depth,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records_and_averages() {
+        let stats = IoStats::default();
+
+        {
+            let _op = stats.start_op();
+            assert_eq!(stats.snapshot().queue_depth, 1);
+        }
+        stats.record_write(100, Duration::from_millis(2));
+        stats.record_write(300, Duration::from_millis(4));
+        assert_eq!(stats.timed_read(|| Ok::<_, ()>(50)), Ok(50));
+        assert!(stats.timed_read(|| Err(())).is_err());
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.queue_depth, 0);
+        assert_eq!(snapshot.bytes_written, 400);
+        assert_eq!(snapshot.bytes_read, 50);
+        assert_eq!(snapshot.reads, 1);
+        assert_eq!(
+            snapshot.average_write_latency(),
+            Some(Duration::from_millis(3))
+        );
+
+        let total = snapshot + snapshot;
+        assert_eq!(total.bytes_written, 800);
+        assert_eq!(
+            total.average_write_latency(),
+            Some(Duration::from_millis(3))
+        );
+    }
+}
@@ -0,0 +1,103 @@
+//! Caps on peer connections and upload slots.
+//!
+//! Set with `BTRS_MAX_CONNECTIONS` for the connections of every torrent
+//! together, `BTRS_MAX_CONNECTIONS_PER_TORRENT` for those of one torrent
+//! and `BTRS_MAX_UPLOAD_SLOTS` for the peers one torrent unchokes at once.
+//! Connections past a cap are refused rather than closing open ones, so
+//! lowering a cap takes effect as connections end.
+
+use std::sync::{Arc, Mutex};
+
+use crate::torrent::{choker::DEFAULT_MAX_SLOTS, tracker::MAX_PEERS};
+
+pub const MAX_CONNECTIONS_ENV_VAR: &str = "BTRS_MAX_CONNECTIONS";
+pub const MAX_CONNECTIONS_PER_TORRENT_ENV_VAR: &str = "BTRS_MAX_CONNECTIONS_PER_TORRENT";
+pub const MAX_UPLOAD_SLOTS_ENV_VAR: &str = "BTRS_MAX_UPLOAD_SLOTS";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Open connections over every torrent.
+    pub max_connections: usize,
+    /// Open connections of one torrent.
+    pub max_connections_per_torrent: usize,
+    /// Peers one torrent unchokes at once.
+    pub max_upload_slots: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_connections: 200,
+            max_connections_per_torrent: MAX_PEERS,
+            max_upload_slots: DEFAULT_MAX_SLOTS,
+        }
+    }
+}
+
+impl Limits {
+    /// Limits from the settings `var` looks up, the defaults for those
+    /// unset or invalid.
+    pub fn from_lookup(var: impl Fn(&str) -> Option<String>) -> Self {
+        let defaults = Self::default();
+        let limit = |name: &str, default: usize| {
+            var(name)
+                .and_then(|value| {
+                    value.parse().ok().filter(|&limit| limit > 0).or_else(|| {
+                        eprintln!("[Limits] Ignoring invalid {name}");
+                        None
+                    })
+                })
+                .unwrap_or(default)
+        };
+
+        Self {
+            max_connections: limit(MAX_CONNECTIONS_ENV_VAR, defaults.max_connections),
+            max_connections_per_torrent: limit(
+                MAX_CONNECTIONS_PER_TORRENT_ENV_VAR,
+                defaults.max_connections_per_torrent,
+            ),
+            max_upload_slots: limit(MAX_UPLOAD_SLOTS_ENV_VAR, defaults.max_upload_slots),
+        }
+    }
+}
+
+/// Shared handle to the limits, replaced when the config is reloaded.
+#[derive(Debug, Clone, Default)]
+pub struct SharedLimits(Arc<Mutex<Limits>>);
+
+impl SharedLimits {
+    pub fn new(limits: Limits) -> Self {
+        Self(Arc::new(Mutex::new(limits)))
+    }
+
+    pub fn get(&self) -> Limits {
+        *self.0.lock().unwrap()
+    }
+
+    pub fn set(&self, limits: Limits) {
+        *self.0.lock().unwrap() = limits;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_lookup() {
+        let limits = Limits::from_lookup(|name| match name {
+            MAX_CONNECTIONS_ENV_VAR => Some(String::from("500")),
+            MAX_CONNECTIONS_PER_TORRENT_ENV_VAR => Some(String::from("0")),
+            MAX_UPLOAD_SLOTS_ENV_VAR => Some(String::from("lots")),
+            _ => None,
+        });
+
+        assert_eq!(
+            limits,
+            Limits {
+                max_connections: 500,
+                ..Limits::default()
+            }
+        );
+    }
+}
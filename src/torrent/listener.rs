@@ -0,0 +1,466 @@
+//! Accepting connections peers open to us.
+//!
+//! Trackers hand our port out to other peers, `BTRS_PORT` or 6882 by
+//! default. Each connection is matched to a loaded torrent by the info
+//! hash in the peer's handshake, and only running torrents take it.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use anyhow::{Error, anyhow, bail};
+use tokio::{
+    io::AsyncReadExt,
+    net::{TcpListener, TcpStream},
+    sync::{Mutex, Notify, mpsc::channel},
+};
+
+use crate::torrent::{
+    ban_list::BanList,
+    block_reader::BlockReader,
+    limits::SharedLimits,
+    peer_session::{PeerSession, SessionHandle},
+    peer_store::PeerStore,
+    privacy,
+    state::TorrentState,
+    super_seed::SuperSeed,
+    tasks::{self, Subsystem},
+    timeout::{Timeouts, with_timeout},
+    transfer_stats::TransferStats,
+    verify::CheckStatus,
+};
+
+pub const PORT_ENV_VAR: &str = "BTRS_PORT";
+pub const DEFAULT_PORT: u16 = 6882;
+
+/// Port we listen on and announce, read from [`PORT_ENV_VAR`] once.
+pub fn port() -> u16 {
+    static PORT: OnceLock<u16> = OnceLock::new();
+
+    *PORT.get_or_init(|| match std::env::var(PORT_ENV_VAR) {
+        Ok(value) => value
+            .parse()
+            .inspect_err(|_| eprintln!("[Listener] Ignoring invalid {PORT_ENV_VAR}"))
+            .unwrap_or(DEFAULT_PORT),
+        Err(_) => DEFAULT_PORT,
+    })
+}
+
+/// Acceptors of every loaded torrent by info hash.
+pub type Acceptors = Arc<std::sync::Mutex<HashMap<[u8; 20], Arc<Acceptor>>>>;
+
+/// What a torrent needs to take over a connection opened for it, see
+/// `Torrent::acceptor`.
+pub struct Acceptor {
+    pub(crate) info_hash: [u8; 20],
+    pub(crate) peer_id: [u8; 20],
+    pub(crate) state: Arc<std::sync::Mutex<TorrentState>>,
+    pub(crate) check_status: Arc<Mutex<CheckStatus>>,
+    pub(crate) sessions: Arc<Mutex<HashMap<String, SessionHandle>>>,
+    pub(crate) transfer: Arc<TransferStats>,
+    pub(crate) peer_store: Arc<Mutex<PeerStore>>,
+    pub(crate) blocks: Arc<BlockReader>,
+    pub(crate) bans: BanList,
+    pub(crate) limits: SharedLimits,
+    pub(crate) choke_round: Arc<Notify>,
+    pub(crate) super_seed: Option<SuperSeed>,
+}
+
+impl Acceptor {
+    /// Answers the peer at `address`, whose `handshake` was read already,
+    /// and adds the connection to the torrent's sessions.
+    pub async fn accept(
+        &self,
+        stream: TcpStream,
+        address: String,
+        handshake: [u8; 68],
+    ) -> Result<(), Error> {
+        if !matches!(
+            *self.state.lock().unwrap(),
+            TorrentState::Downloading | TorrentState::Seeding
+        ) {
+            bail!("Torrent isn't running");
+        }
+        if self.bans.is_banned(&address) {
+            bail!("Peer is banned");
+        }
+        let max = self.limits.get().max_connections_per_torrent;
+        if self.connection_count().await >= max {
+            bail!("Torrent has {max} connections already");
+        }
+        if let CheckStatus::Checked { have } = &*self.check_status.lock().await {
+            self.blocks.set_verified(have.clone());
+        }
+
+        let mut session = PeerSession::new(&address, self.peer_id, self.info_hash).await?;
+        session.set_block_reader(Arc::clone(&self.blocks));
+        session.set_transfer_stats(Arc::clone(&self.transfer));
+        session.set_choker(Arc::clone(&self.choke_round));
+        if let Some(seed) = &self.super_seed
+            && self.blocks.is_complete()
+        {
+            session.set_super_seed(seed.clone());
+        }
+
+        // Incoming peers aren't handed download work yet, only served. The
+        // torrent's queue would lend them pieces nothing collects.
+        let (piece_tx, _) = channel(1);
+        let result = session
+            .accept(stream, handshake, Arc::default(), piece_tx)
+            .await;
+        self.peer_store.lock().await.record_incoming(result.is_ok());
+        result?;
+
+        self.sessions.lock().await.insert(address, session.handle());
+
+        Ok(())
+    }
+
+    /// Open connections of the torrent.
+    pub async fn connection_count(&self) -> usize {
+        self.sessions
+            .lock()
+            .await
+            .values()
+            .filter(|session| session.is_alive())
+            .count()
+    }
+}
+
+/// Binds the listening socket on [`port`], from `address` if given.
+pub async fn bind(address: Option<std::net::IpAddr>) -> Result<TcpListener, Error> {
+    let address = SocketAddr::new(address.unwrap_or([0, 0, 0, 0].into()), port());
+
+    TcpListener::bind(address)
+        .await
+        .map_err(|e| anyhow!("Cannot listen on {address}: {e}"))
+}
+
+/// Accepts connections until the task is aborted, handing each to the
+/// acceptor of the torrent its handshake names.
+pub async fn serve(listener: TcpListener, acceptors: Acceptors) {
+    loop {
+        let (stream, address) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                // Usually out of file descriptors, give some a chance to
+                // close.
+                eprintln!("[Listener] Accept failed: {e}");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let acceptors = Arc::clone(&acceptors);
+        tasks::spawn(Subsystem::Peer, async move {
+            let address = address.to_string();
+            if let Err(e) = accept_connection(stream, address.clone(), acceptors).await {
+                eprintln!(
+                    "[Listener] Dropping connection from {}: {e:#}",
+                    privacy::address(&address)
+                );
+            }
+        });
+    }
+}
+
+async fn accept_connection(
+    mut stream: TcpStream,
+    address: String,
+    acceptors: Acceptors,
+) -> Result<(), Error> {
+    let mut handshake = [0u8; 68];
+    with_timeout(
+        "peer handshake",
+        Timeouts::default().handshake,
+        stream.read_exact(&mut handshake),
+    )
+    .await?;
+    if handshake[0] != 19 || &handshake[1..20] != b"BitTorrent protocol" {
+        bail!("Not a BitTorrent handshake");
+    }
+
+    let info_hash: [u8; 20] = handshake[28..48].try_into()?;
+    let (acceptor, all): (_, Vec<_>) = {
+        let acceptors = acceptors.lock().unwrap();
+        (
+            acceptors.get(&info_hash).cloned(),
+            acceptors.values().cloned().collect(),
+        )
+    };
+    let acceptor = acceptor.ok_or_else(|| anyhow!("No torrent with the requested info hash"))?;
+
+    let max = acceptor.limits.get().max_connections;
+    let mut open = 0;
+    for acceptor in all {
+        open += acceptor.connection_count().await;
+    }
+    if open >= max {
+        bail!("{max} connections are open already");
+    }
+
+    acceptor.accept(stream, address, handshake).await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::torrent::{fixtures, limits::Limits, piece_manager::PieceRequest};
+
+    #[tokio::test]
+    async fn test_accepts_handshake_for_running_torrent() {
+        let fixture = fixtures::single_file(5);
+        let torrent = fixture.load();
+
+        let acceptor = torrent.acceptor(fixture.root(), *b"-RS0001-abcdefghijkl");
+        *acceptor.state.lock().unwrap() = TorrentState::Seeding;
+        let info_hash = *torrent.info_hash();
+        let acceptors = Acceptors::default();
+        acceptors
+            .lock()
+            .unwrap()
+            .insert(info_hash, Arc::new(acceptor));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener, Arc::clone(&acceptors)));
+
+        let mut peer = TcpStream::connect(address).await.unwrap();
+        let (mut reader, mut writer) = peer.split();
+        let mut handshake = vec![19u8];
+        handshake.extend_from_slice(b"BitTorrent protocol");
+        handshake.extend_from_slice(&[0; 8]);
+        handshake.extend_from_slice(&info_hash);
+        handshake.extend_from_slice(b"-MOCK0-1234567890123");
+        writer.write_all(&handshake).await.unwrap();
+
+        let mut reply = [0u8; 68];
+        reader.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply[28..48], &info_hash);
+        assert_eq!(&reply[48..68], b"-RS0001-abcdefghijkl");
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_announces_verified_pieces() {
+        let fixture = fixtures::single_file(5);
+        let mut torrent = fixture.load();
+
+        let acceptor = torrent.acceptor(fixture.root(), *b"-RS0001-abcdefghijkl");
+        torrent.start_tracker();
+        let blocks = Arc::clone(&acceptor.blocks);
+        let info_hash = *torrent.info_hash();
+        let acceptors = Acceptors::default();
+        acceptors
+            .lock()
+            .unwrap()
+            .insert(info_hash, Arc::new(acceptor));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener, Arc::clone(&acceptors)));
+
+        let mut peer = TcpStream::connect(address).await.unwrap();
+        let mut handshake = vec![19u8];
+        handshake.extend_from_slice(b"BitTorrent protocol");
+        handshake.extend_from_slice(&[0; 8]);
+        handshake.extend_from_slice(&info_hash);
+        handshake.extend_from_slice(b"-MOCK0-1234567890123");
+        peer.write_all(&handshake).await.unwrap();
+        let mut reply = [0u8; 68];
+        peer.read_exact(&mut reply).await.unwrap();
+
+        // Nothing verified yet, so no bitfield, only Interested. The peer
+        // is unchoked once it is interested too.
+        let mut message = [0u8; 5];
+        peer.read_exact(&mut message).await.unwrap();
+        assert_eq!(message, [0, 0, 0, 1, 2]);
+        peer.write_all(&[0, 0, 0, 1, 2]).await.unwrap();
+        with_timeout(
+            "unchoke",
+            Duration::from_secs(5),
+            peer.read_exact(&mut message),
+        )
+        .await
+        .unwrap();
+        assert_eq!(message, [0, 0, 0, 1, 1]);
+
+        blocks.mark_verified(0);
+        let mut have = [0u8; 9];
+        with_timeout("have", Duration::from_secs(5), peer.read_exact(&mut have))
+            .await
+            .unwrap();
+        assert_eq!(have, [0, 0, 0, 5, 4, 0, 0, 0, 0]);
+
+        server.abort();
+        drop(torrent.stop());
+    }
+
+    #[tokio::test]
+    async fn test_super_seeds_one_piece_at_a_time() {
+        let fixture = fixtures::single_file(3 * fixtures::PIECE_LENGTH);
+        let mut torrent = fixture.load();
+        torrent.set_super_seed(true);
+
+        let acceptor = torrent.acceptor(fixture.root(), *b"-RS0001-abcdefghijkl");
+        *acceptor.state.lock().unwrap() = TorrentState::Seeding;
+        acceptor.blocks.set_verified(vec![true; 3]);
+        let info_hash = *torrent.info_hash();
+        let acceptors = Acceptors::default();
+        acceptors
+            .lock()
+            .unwrap()
+            .insert(info_hash, Arc::new(acceptor));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener, Arc::clone(&acceptors)));
+
+        let mut handshake = vec![19u8];
+        handshake.extend_from_slice(b"BitTorrent protocol");
+        handshake.extend_from_slice(&[0; 8]);
+        handshake.extend_from_slice(&info_hash);
+        handshake.extend_from_slice(b"-MOCK0-1234567890123");
+        let connect = || async {
+            let mut peer = TcpStream::connect(address).await.unwrap();
+            peer.write_all(&handshake).await.unwrap();
+            let mut reply = [0u8; 68];
+            peer.read_exact(&mut reply).await.unwrap();
+            peer
+        };
+        async fn read_have(peer: &mut TcpStream) -> u32 {
+            let mut have = [0u8; 9];
+            with_timeout("have", Duration::from_secs(5), peer.read_exact(&mut have))
+                .await
+                .unwrap();
+            assert_eq!(have[..5], [0, 0, 0, 5, 4]);
+            u32::from_be_bytes(have[5..].try_into().unwrap())
+        }
+
+        // An empty bitfield, then a single piece.
+        let mut first = connect().await;
+        let mut bitfield = [0u8; 6];
+        first.read_exact(&mut bitfield).await.unwrap();
+        assert_eq!(bitfield, [0, 0, 0, 2, 5, 0]);
+        let piece = read_have(&mut first).await;
+        let mut length = [0u8; 4];
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), first.read_exact(&mut length))
+                .await
+                .is_err()
+        );
+
+        // Once another peer has it, the first is shown another.
+        let mut second = connect().await;
+        second
+            .write_all(&[&[0, 0, 0, 5, 4][..], &piece.to_be_bytes()].concat())
+            .await
+            .unwrap();
+        assert_ne!(read_have(&mut first).await, piece);
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_incoming_peers_take_no_download_work() {
+        let fixture = fixtures::single_file(5);
+        let torrent = fixture.load();
+        let work = torrent.work_queue();
+        for request in PieceRequest::all(torrent.metainfo.info()) {
+            work.lock().await.push(request);
+        }
+
+        let acceptor = torrent.acceptor(fixture.root(), *b"-RS0001-abcdefghijkl");
+        *acceptor.state.lock().unwrap() = TorrentState::Downloading;
+        let info_hash = *torrent.info_hash();
+        let acceptors = Acceptors::default();
+        acceptors
+            .lock()
+            .unwrap()
+            .insert(info_hash, Arc::new(acceptor));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener, Arc::clone(&acceptors)));
+
+        let mut peer = TcpStream::connect(address).await.unwrap();
+        let mut handshake = vec![19u8];
+        handshake.extend_from_slice(b"BitTorrent protocol");
+        handshake.extend_from_slice(&[0; 8]);
+        handshake.extend_from_slice(&info_hash);
+        handshake.extend_from_slice(b"-MOCK0-1234567890123");
+        peer.write_all(&handshake).await.unwrap();
+        let mut reply = [0u8; 68];
+        peer.read_exact(&mut reply).await.unwrap();
+        let mut message = [0u8; 5];
+        peer.read_exact(&mut message).await.unwrap();
+
+        // The peer has the piece and lets us ask for it, but isn't asked.
+        peer.write_all(&[0, 0, 0, 2, 5, 0x80, 0, 0, 0, 1, 1])
+            .await
+            .unwrap();
+        let mut length = [0u8; 4];
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), peer.read_exact(&mut length))
+                .await
+                .is_err()
+        );
+        assert!(work.lock().await.next_for(1, |_| true).is_some());
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_refuses_connections_past_cap() {
+        let fixture = fixtures::single_file(5);
+        let mut torrent = fixture.load();
+        torrent.set_limits(SharedLimits::new(Limits {
+            max_connections_per_torrent: 1,
+            ..Limits::default()
+        }));
+
+        let acceptor = torrent.acceptor(fixture.root(), *b"-RS0001-abcdefghijkl");
+        *acceptor.state.lock().unwrap() = TorrentState::Seeding;
+        let info_hash = *torrent.info_hash();
+        let acceptor = Arc::new(acceptor);
+        let acceptors = Acceptors::default();
+        acceptors
+            .lock()
+            .unwrap()
+            .insert(info_hash, Arc::clone(&acceptor));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener, Arc::clone(&acceptors)));
+
+        let mut handshake = vec![19u8];
+        handshake.extend_from_slice(b"BitTorrent protocol");
+        handshake.extend_from_slice(&[0; 8]);
+        handshake.extend_from_slice(&info_hash);
+        handshake.extend_from_slice(b"-MOCK0-1234567890123");
+        let mut reply = [0u8; 68];
+
+        let mut first = TcpStream::connect(address).await.unwrap();
+        first.write_all(&handshake).await.unwrap();
+        first.read_exact(&mut reply).await.unwrap();
+        while acceptor.connection_count().await == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // The second is closed without a handshake.
+        let mut second = TcpStream::connect(address).await.unwrap();
+        second.write_all(&handshake).await.unwrap();
+        let read = tokio::time::timeout(Duration::from_secs(5), second.read(&mut reply))
+            .await
+            .expect("connection left open");
+        assert_eq!(read.unwrap_or(0), 0);
+
+        server.abort();
+    }
+}
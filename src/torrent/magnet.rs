@@ -0,0 +1,164 @@
+//! Info hashes typed in by hand and the magnet links built from them.
+//!
+//! Indexer sites often list only a torrent's info hash, as 40 hex digits
+//! or, in older magnet links, 32 base32 characters.
+
+use anyhow::{Context, Error, bail};
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Parses an info hash given as hex or base32, ignoring surrounding
+/// whitespace and case.
+pub fn parse_info_hash(text: &str) -> Result<[u8; 20], Error> {
+    let text = text.trim();
+
+    match text.len() {
+        40 => decode_hex(text),
+        32 => decode_base32(text),
+        length => {
+            bail!("Info hash must be 40 hex or 32 base32 characters, got {length} characters")
+        }
+    }
+}
+
+/// Magnet link holding only the info hash, the metadata has to be fetched
+/// from peers.
+pub fn magnet_uri(info_hash: &[u8; 20]) -> String {
+    let hash: String = info_hash.iter().map(|byte| format!("{byte:02x}")).collect();
+
+    format!("magnet:?xt=urn:btih:{hash}")
+}
+
+/// What a magnet link names: the info hash, display name and trackers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+}
+
+/// Parses a `magnet:?xt=urn:btih:<hash>&dn=<name>&tr=<url>` link.
+pub fn parse_magnet(uri: &str) -> Result<Magnet, Error> {
+    let query = uri
+        .trim()
+        .strip_prefix("magnet:?")
+        .context("Not a magnet link")?;
+
+    let mut info_hash = None;
+    let mut name = None;
+    let mut trackers = vec![];
+    for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+        let value = urlencoding::decode(value)?.into_owned();
+        match key {
+            "xt" => {
+                if let Some(hash) = value.strip_prefix("urn:btih:") {
+                    info_hash = Some(parse_info_hash(hash)?);
+                }
+            }
+            "dn" => name = Some(value),
+            "tr" => trackers.push(value),
+            _ => {}
+        }
+    }
+
+    Ok(Magnet {
+        info_hash: info_hash.context("Magnet link has no BitTorrent info hash")?,
+        name,
+        trackers,
+    })
+}
+
+fn decode_hex(text: &str) -> Result<[u8; 20], Error> {
+    // from_str_radix would also take a sign.
+    if !text.bytes().all(|c| c.is_ascii_hexdigit()) {
+        bail!("Info hash {text} is not valid hex");
+    }
+
+    let mut hash = [0; 20];
+    for (byte, pair) in hash.iter_mut().zip(text.as_bytes().chunks(2)) {
+        let digit = |c: u8| (c as char).to_digit(16).unwrap_or_default() as u8;
+        *byte = digit(pair[0]) << 4 | digit(pair[1]);
+    }
+
+    Ok(hash)
+}
+
+fn decode_base32(text: &str) -> Result<[u8; 20], Error> {
+    let mut hash = [0; 20];
+    let mut buffer: u64 = 0;
+    let mut bits = 0;
+    let mut len = 0;
+
+    for c in text.bytes() {
+        let Some(value) = BASE32_ALPHABET
+            .iter()
+            .position(|&a| a == c.to_ascii_uppercase())
+        else {
+            bail!("Info hash {text} is not valid base32");
+        };
+
+        buffer = (buffer << 5) | value as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            hash[len] = (buffer >> bits) as u8;
+            len += 1;
+        }
+    }
+
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: [u8; 20] = [
+        0xda, 0xbf, 0x72, 0x01, 0x9d, 0xef, 0x4d, 0x30, 0xaf, 0x00, 0xf4, 0xbf, 0x4d, 0xdf, 0x8a,
+        0x69, 0x73, 0x0c, 0x02, 0xb4,
+    ];
+
+    #[test]
+    fn test_parse_hex_and_base32() {
+        assert_eq!(
+            parse_info_hash("DABF72019DEF4D30AF00F4BF4DDF8A69730C02B4").unwrap(),
+            HASH
+        );
+        assert_eq!(
+            parse_info_hash(" 3k7xeam555gtblya6s7u3x4knfzqyavu\n").unwrap(),
+            HASH
+        );
+
+        assert!(parse_info_hash("dabf72").is_err());
+        assert!(parse_info_hash("+abf72019def4d30af00f4bf4ddf8a69730c02b4").is_err());
+        assert!(parse_info_hash("zzbf72019def4d30af00f4bf4ddf8a69730c02b4").is_err());
+        assert!(parse_info_hash("3k7xeam555gtblya6s7u3x4knfzqya1u").is_err());
+    }
+
+    #[test]
+    fn test_parse_magnet() {
+        let magnet = parse_magnet(
+            "magnet:?xt=urn:btih:dabf72019def4d30af00f4bf4ddf8a69730c02b4\
+             &dn=my%20file.txt&tr=udp%3A%2F%2Fone.test%3A80&tr=http%3A%2F%2Ftwo.test%2Fannounce",
+        )
+        .unwrap();
+
+        assert_eq!(magnet.info_hash, HASH);
+        assert_eq!(magnet.name.as_deref(), Some("my file.txt"));
+        assert_eq!(
+            magnet.trackers,
+            ["udp://one.test:80", "http://two.test/announce"]
+        );
+
+        assert!(parse_magnet("magnet:?dn=nothing").is_err());
+        assert!(parse_magnet("http://tracker.test").is_err());
+    }
+
+    #[test]
+    fn test_magnet_uri() {
+        assert_eq!(
+            magnet_uri(&HASH),
+            "magnet:?xt=urn:btih:dabf72019def4d30af00f4bf4ddf8a69730c02b4"
+        );
+    }
+}
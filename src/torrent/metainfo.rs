@@ -6,6 +6,7 @@ use anyhow::Result;
 use info::InfoEnum;
 use serde_derive::{Deserialize, Serialize};
 
+pub mod health;
 pub mod info;
 
 /// Metadata for a torrent for clients to configure sessions.
@@ -14,7 +15,9 @@ pub mod info;
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 pub struct MetaInfo {
     pub(super) info: InfoEnum,
-    pub(super) announce: String,
+    /// Main tracker. Trackerless torrents, which find peers through other
+    /// means such as the DHT, leave it out.
+    pub(super) announce: Option<String>,
     #[serde(rename = "announce-list")]
     pub(super) announce_list: Option<Vec<Vec<String>>>,
     #[serde(rename = "creation date")]
@@ -35,11 +38,54 @@ impl MetaInfo {
     }
 
     pub fn info(&self) -> &InfoEnum {
-        return &self.info;
+        &self.info
     }
 
-    pub fn get_tracker_urls(&self) -> &str {
-        return &self.announce;
+    /// Checks the info dictionary for inconsistencies that would keep the
+    /// torrent from ever completing, see [`health::check`]. Returns
+    /// warnings about lesser problems.
+    pub fn check(&self) -> Result<Vec<String>, anyhow::Error> {
+        health::check(&self.info)
+    }
+
+    /// Every tracker in the torrent, `announce` first followed by the
+    /// announce-list tiers, without duplicates.
+    pub fn get_tracker_urls(&self) -> Vec<&str> {
+        let mut urls: Vec<&str> = vec![];
+
+        let announce_list = self.announce_list.iter().flatten().flatten();
+        for url in self.announce.iter().chain(announce_list) {
+            if !url.is_empty() && !urls.contains(&url.as_str()) {
+                urls.push(url);
+            }
+        }
+
+        urls
+    }
+
+    /// Trackers grouped into BEP 12 tiers, `announce` as a tier of its own
+    /// ahead of the announce-list when it isn't already listed. Empty tiers
+    /// and repeated URLs are dropped.
+    pub fn tracker_tiers(&self) -> Vec<Vec<String>> {
+        let mut seen: Vec<&str> = vec![];
+        let mut tiers = vec![];
+
+        let announce = self.announce.iter().map(std::slice::from_ref);
+        let announce_list = self.announce_list.iter().flatten().map(Vec::as_slice);
+        for tier in announce.chain(announce_list) {
+            let mut urls = vec![];
+            for url in tier {
+                if !url.is_empty() && !seen.contains(&url.as_str()) {
+                    seen.push(url);
+                    urls.push(url.clone());
+                }
+            }
+            if !urls.is_empty() {
+                tiers.push(urls);
+            }
+        }
+
+        tiers
     }
 }
 
@@ -52,7 +98,7 @@ mod metainfo_tests {
 
     fn mock_metainfo() -> MetaInfo {
         MetaInfo {
-            announce: "http://tracker.test/multi/announce".to_string(),
+            announce: Some("http://tracker.test/multi/announce".to_string()),
             announce_list: Some(vec![vec!["http://backup.tracker".to_string()]]),
             creation_date: Some(1_700_000_001),
             comment: Some("Multi file test".to_string()),
@@ -87,4 +133,48 @@ mod metainfo_tests {
         let test_info = mock_metainfo();
         assert_eq!(info, test_info.info);
     }
+
+    #[test]
+    fn test_tracker_urls() {
+        assert_eq!(
+            mock_metainfo().get_tracker_urls(),
+            vec![
+                "http://tracker.test/multi/announce",
+                "http://backup.tracker"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tracker_tiers() {
+        let mut metainfo = mock_metainfo();
+        metainfo.announce_list = Some(vec![
+            vec![
+                "http://tracker.test/multi/announce".to_string(),
+                "udp://tracker.test:6969".to_string(),
+            ],
+            vec![],
+            vec!["http://backup.tracker".to_string()],
+        ]);
+
+        assert_eq!(
+            metainfo.tracker_tiers(),
+            vec![
+                vec!["http://tracker.test/multi/announce".to_string()],
+                vec!["udp://tracker.test:6969".to_string()],
+                vec!["http://backup.tracker".to_string()],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_trackerless_torrent_parses() {
+        let bytes = b"d4:infod6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0ee";
+
+        let metainfo = MetaInfo::from_bytes(bytes).unwrap();
+
+        assert_eq!(metainfo.announce, None);
+        assert!(metainfo.get_tracker_urls().is_empty());
+        assert!(metainfo.tracker_tiers().is_empty());
+    }
 }
@@ -0,0 +1,201 @@
+//! Consistency checks of an info dictionary, run when a torrent is loaded.
+//!
+//! A torrent whose piece hashes don't cover its files, or whose files
+//! overlap or escape the download directory, can never complete, so it is
+//! rejected up front with the reason instead of failing mid-download.
+//! Oddities clients cope with, like a piece length that isn't a power of
+//! two, are only reported as warnings.
+
+use std::collections::HashSet;
+
+use anyhow::{Error, bail};
+
+use super::info::InfoEnum;
+
+/// Smallest piece length clients commonly accept, one request block.
+const MIN_PIECE_LENGTH: u64 = 16 * 1024;
+
+/// Checks that `info` describes a torrent that can be downloaded,
+/// returning warnings about anything unusual in it.
+pub fn check(info: &InfoEnum) -> Result<Vec<String>, Error> {
+    let mut warnings = vec![];
+
+    let piece_length = info.piece_length();
+    if piece_length == 0 {
+        bail!("Piece length is zero");
+    }
+    if !piece_length.is_power_of_two() {
+        warnings.push(format!("Piece length {piece_length} isn't a power of two"));
+    }
+    if piece_length < MIN_PIECE_LENGTH {
+        warnings.push(format!(
+            "Piece length {piece_length} is below {MIN_PIECE_LENGTH} bytes"
+        ));
+    }
+
+    let pieces = info.pieces().len();
+    if !pieces.is_multiple_of(20) {
+        bail!("Piece hashes are {pieces} bytes, not a multiple of 20");
+    }
+
+    let total = info.total_length();
+    if total == 0 {
+        bail!("Torrent has no data");
+    }
+    let expected = total.div_ceil(piece_length);
+    if (pieces / 20) as u64 != expected {
+        bail!(
+            "Torrent has {} piece hashes but {total} bytes in pieces of {piece_length} need {expected}",
+            pieces / 20
+        );
+    }
+
+    let (name, paths) = match info {
+        InfoEnum::SingleFile(info) => (&info.name, vec![]),
+        InfoEnum::MultiFile(info) => {
+            if info.files.is_empty() {
+                bail!("File list is empty");
+            }
+            (&info.name, info.files.iter().map(|f| &f.path).collect())
+        }
+    };
+    if !is_component(name) {
+        bail!("Name {name:?} isn't a plain file or directory name");
+    }
+
+    let mut seen = HashSet::new();
+    for path in &paths {
+        if path.is_empty() {
+            bail!("A file has an empty path");
+        }
+        if let Some(part) = path.iter().find(|part| !is_component(part)) {
+            bail!("File {} has {part:?} in its path", path.join("/"));
+        }
+        // BEP 47 pad files of the same size share a path.
+        if !seen.insert(path.as_slice()) && path[0] != ".pad" {
+            bail!("File {} is listed twice", path.join("/"));
+        }
+    }
+    // A file can't also be a directory other files are in.
+    for path in &paths {
+        for end in 1..path.len() {
+            if seen.contains(&path[..end]) {
+                bail!(
+                    "File {} is inside file {}",
+                    path.join("/"),
+                    path[..end].join("/")
+                );
+            }
+        }
+    }
+
+    Ok(warnings)
+}
+
+/// Whether `part` names a single entry within its directory, so it can't
+/// climb out of the download directory.
+fn is_component(part: &str) -> bool {
+    !part.is_empty()
+        && part != "."
+        && part != ".."
+        && !part.contains(['/', '\\'])
+        && !part.contains('\0')
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_bytes::ByteBuf;
+
+    use super::super::{
+        MetaInfo,
+        info::{FilesDict, InfoMultiFile, InfoSingleFile},
+    };
+    use super::*;
+    use crate::torrent::fixtures::{self, Broken};
+
+    fn single(length: u64, piece_length: u64, pieces: usize) -> InfoEnum {
+        InfoEnum::SingleFile(InfoSingleFile {
+            name: String::from("data.bin"),
+            length,
+            md5: None,
+            piece_length,
+            pieces: ByteBuf::from(vec![0; pieces]),
+        })
+    }
+
+    fn multi(paths: &[&[&str]]) -> InfoEnum {
+        InfoEnum::MultiFile(InfoMultiFile {
+            name: String::from("folder"),
+            piece_length: 32768,
+            pieces: ByteBuf::from(vec![0; 20]),
+            files: paths
+                .iter()
+                .map(|path| FilesDict {
+                    length: 100,
+                    md5: None,
+                    path: path.iter().map(|part| String::from(*part)).collect(),
+                })
+                .collect(),
+        })
+    }
+
+    fn error(info: &InfoEnum) -> String {
+        check(info).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_checks_pieces() {
+        assert_eq!(
+            check(&single(40000, 32768, 40)).unwrap(),
+            Vec::<String>::new()
+        );
+        assert_eq!(check(&single(100, 1000, 20)).unwrap().len(), 2);
+        assert_eq!(error(&single(0, 32768, 0)), "Torrent has no data");
+    }
+
+    #[test]
+    fn test_checks_paths() {
+        assert!(check(&multi(&[&["a", "b.txt"], &["c.txt"]])).is_ok());
+        assert!(check(&multi(&[&["a"], &[".pad", "10"], &["b"], &[".pad", "10"]])).is_ok());
+        assert_eq!(error(&multi(&[&[]])), "A file has an empty path");
+
+        // Pad files of torrents aligned to pieces are fine.
+        let files = [("a.txt", 20_000), ("b/c.txt", 40_000), ("d.txt", 100)];
+        for fixture in [fixtures::padded(&files), fixtures::hybrid(&files)] {
+            let metainfo = MetaInfo::from_bytes(&fixture.bytes).unwrap();
+            assert!(check(metainfo.info()).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_rejects_broken_torrents() {
+        let rejection = |broken| {
+            let metainfo = MetaInfo::from_bytes(&fixtures::broken(broken)).unwrap();
+            check(metainfo.info()).unwrap_err().to_string()
+        };
+
+        assert_eq!(rejection(Broken::ZeroPieceLength), "Piece length is zero");
+        assert_eq!(
+            rejection(Broken::TruncatedHashes),
+            "Piece hashes are 73 bytes, not a multiple of 20"
+        );
+        assert_eq!(
+            rejection(Broken::MissingHash),
+            "Torrent has 3 piece hashes but 50000 bytes in pieces of 16384 need 4"
+        );
+        assert_eq!(rejection(Broken::EmptyFileList), "Torrent has no data");
+        assert_eq!(
+            rejection(Broken::PathTraversal),
+            "File ../c.txt has \"..\" in its path"
+        );
+        assert_eq!(
+            rejection(Broken::DuplicatePath),
+            "File a.txt is listed twice"
+        );
+        assert_eq!(
+            rejection(Broken::FileInsideFile),
+            "File a.txt/c.txt is inside file a.txt"
+        );
+        assert!(MetaInfo::from_bytes(&fixtures::broken(Broken::NoInfo)).is_err());
+    }
+}
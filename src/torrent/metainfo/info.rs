@@ -1,5 +1,7 @@
 //! Submodule containing structures related to the `Info` dictionary.
 
+use std::path::{Path, PathBuf};
+
 use serde::{Deserialize, Deserializer, de};
 use serde_bencode::value::Value;
 use serde_bytes::ByteBuf;
@@ -71,3 +73,48 @@ impl<'de> Deserialize<'de> for InfoEnum {
         }
     }
 }
+
+impl InfoEnum {
+    pub fn piece_length(&self) -> u64 {
+        match self {
+            InfoEnum::MultiFile(info) => info.piece_length,
+            InfoEnum::SingleFile(info) => info.piece_length,
+        }
+    }
+
+    /// Concatenated SHA-1 hashes of every piece.
+    pub fn pieces(&self) -> &[u8] {
+        match self {
+            InfoEnum::MultiFile(info) => &info.pieces,
+            InfoEnum::SingleFile(info) => &info.pieces,
+        }
+    }
+
+    /// Size of the torrent's data across all files.
+    pub fn total_length(&self) -> u64 {
+        match self {
+            InfoEnum::MultiFile(info) => info.files.iter().map(|file| file.length).sum(),
+            InfoEnum::SingleFile(info) => info.length,
+        }
+    }
+
+    /// Every file's path when the data is stored under `root`, with its
+    /// length, in the order the files are concatenated into pieces.
+    pub fn layout(&self, root: &Path) -> Vec<(PathBuf, u64)> {
+        match self {
+            InfoEnum::SingleFile(info) => vec![(root.join(&info.name), info.length)],
+            InfoEnum::MultiFile(info) => {
+                let base = root.join(&info.name);
+                info.files
+                    .iter()
+                    .map(|file| {
+                        (
+                            file.path.iter().fold(base.clone(), |p, s| p.join(s)),
+                            file.length,
+                        )
+                    })
+                    .collect()
+            }
+        }
+    }
+}
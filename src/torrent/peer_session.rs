@@ -1,6 +1,12 @@
-use std::{collections::VecDeque, sync::Arc, time::Duration};
+use std::{
+    sync::{
+        Arc, Weak,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
 
-use anyhow::bail;
+use anyhow::{anyhow, bail};
 use bytes::BytesMut;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
@@ -9,26 +15,231 @@ use tokio::{
         tcp::{OwnedReadHalf, OwnedWriteHalf},
     },
     sync::{
-        Mutex,
+        Mutex, Notify, Semaphore,
+        broadcast::{self, error::RecvError},
         mpsc::{Receiver, Sender, channel},
+        watch,
     },
+    task::AbortHandle,
+    time::Instant,
 };
 
+pub mod capabilities;
+pub mod capture;
+pub mod extension;
+pub mod lifecycle;
 mod message;
+pub mod message_stats;
+pub mod peer_stats;
+mod pipeline;
+pub mod strict;
+pub mod upload;
 mod work;
 
+use capabilities::Capabilities;
+use capture::{CaptureHandle, Direction};
+use extension::{
+    ExtensionHandshake,
+    comment::{Comment, CommentMessage, MAX_COMMENTS},
+    holepunch::{HolepunchError, HolepunchKind, HolepunchMessage},
+    metadata::MetadataMessage,
+};
+use lifecycle::{EndReason, EndSlot, WorkGuard};
 use message::MessageType;
+use message_stats::{MessageCounts, MessageStatsHandle};
+use peer_stats::{PeerStatsHandle, PeerStatsSnapshot};
+use pipeline::{INITIAL_QUEUE_DEPTH, Pipeline};
+use strict::StrictHandle;
+use upload::{BlockRequest, MAX_UPLOAD_QUEUE, UploadQueue, UploadQueueSnapshot};
 use work::{BlockInfo, BlockResponse, BlockStatus, PieceWork};
 
-use crate::torrent::piece_manager::{PieceError, PieceRequest, PieceResponse};
+use crate::torrent::{
+    bind::BindConfig,
+    block_reader::BlockReader,
+    client_id::client_name,
+    piece_manager::{BlockArrival, Contributor, PieceResponse, SessionId, WorkQueue},
+    privacy, proxy,
+    super_seed::SuperSeed,
+    supervisor::{self, TaskExit},
+    tasks::{self, Subsystem},
+    timeout::{Timeouts, with_timeout},
+    transfer_stats::TransferStats,
+};
 
 const PSTR: &[u8; 19] = b"BitTorrent protocol";
 
+/// Idle time after which we send a KeepAlive, well within the two minutes
+/// peers wait before dropping a silent connection.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(100);
+
+/// Connections we open at once across every torrent. Hundreds of
+/// simultaneous SYNs can fill the connection table of home routers and NAT
+/// gateways, dropping established connections with them.
+const MAX_CONCURRENT_DIALS: usize = 32;
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Dials in progress, see [`MAX_CONCURRENT_DIALS`].
+static DIALS: Semaphore = Semaphore::const_new(MAX_CONCURRENT_DIALS);
+
 pub struct PeerSession {
+    id: SessionId,
     peer_id: [u8; 20],
     info_hash: [u8; 20],
     url: String,
+    /// The peer's address in the other address family, raced against
+    /// `url` when dialing.
+    alternate: Option<String>,
     peer_state: Arc<Mutex<PeerState>>,
+    timeouts: Timeouts,
+    hooks: WireHooks,
+    metadata: Option<Arc<Vec<u8>>>,
+    blocks: Option<Arc<BlockReader>>,
+    /// Pieces in the torrent, when known, to check the peer's bitfield
+    /// and Haves against.
+    piece_count: Option<u32>,
+    upload_only: bool,
+    /// The peer connected to us, see [`PeerSession::accept`].
+    incoming: bool,
+    transfer: Arc<TransferStats>,
+    /// Blocks the peer requested, waiting to be sent.
+    uploads: Arc<UploadQueue>,
+    /// Whether the torrent's choker wants the peer choked, see
+    /// [`SessionHandle::set_choking`].
+    choking: Arc<watch::Sender<bool>>,
+    /// Asks the torrent's choker for a round, when the peer's interest
+    /// changes. Without a choker every peer is unchoked.
+    choke_round: Option<Arc<Notify>>,
+    /// Reveals our pieces one at a time instead of sending a bitfield,
+    /// see [`PeerSession::set_super_seed`].
+    super_seed: Option<SuperSeed>,
+    tasks: Arc<std::sync::Mutex<Vec<AbortHandle>>>,
+    /// Why the session ended, once it has.
+    end: EndSlot,
+}
+
+/// Handle to a running session for code that doesn't own it, e.g. to
+/// inspect its state or close the connection.
+#[derive(Clone)]
+pub struct SessionHandle {
+    id: SessionId,
+    state: Weak<Mutex<PeerState>>,
+    tasks: Arc<std::sync::Mutex<Vec<AbortHandle>>>,
+    stats: MessageStatsHandle,
+    peer_stats: PeerStatsHandle,
+    uploads: Arc<UploadQueue>,
+    choking: Arc<watch::Sender<bool>>,
+    end: EndSlot,
+}
+
+impl SessionHandle {
+    pub fn id(&self) -> SessionId {
+        self.id
+    }
+
+    /// State of the connection, or `None` once the session has ended.
+    pub fn state(&self) -> Option<Arc<Mutex<PeerState>>> {
+        self.state.upgrade()
+    }
+
+    /// Messages exchanged with the peer so far, by type.
+    pub fn message_counts(&self) -> MessageCounts {
+        self.stats.counts()
+    }
+
+    /// Data and requests exchanged with the peer so far, and the current
+    /// rates.
+    pub fn peer_stats(&self) -> PeerStatsSnapshot {
+        self.peer_stats.snapshot()
+    }
+
+    /// Blocks the peer is waiting for us to send.
+    pub fn upload_queue(&self) -> UploadQueueSnapshot {
+        self.uploads.snapshot()
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.state.strong_count() > 0
+    }
+
+    /// Chokes or unchokes the peer. Requests it sent and we didn't serve
+    /// yet are dropped when it is choked.
+    pub fn set_choking(&self, choking: bool) {
+        self.choking.send_replace(choking);
+    }
+
+    /// Why the session ended, `None` while it runs.
+    pub fn end_reason(&self) -> Option<EndReason> {
+        self.end.get()
+    }
+
+    /// Stops the session's tasks, closing the connection.
+    pub fn kill(&self) {
+        self.end.set(EndReason::Killed);
+        for task in self.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+    }
+}
+
+/// Data the listener serves to the peer on request.
+#[derive(Clone)]
+struct ServedData {
+    /// Info dictionary for ut_metadata.
+    metadata: Option<Arc<Vec<u8>>>,
+    blocks: Option<Arc<BlockReader>>,
+    /// Requests waiting for the uploader.
+    uploads: Arc<UploadQueue>,
+    /// Whether we only upload, so the peer's pieces are never of interest.
+    upload_only: bool,
+    /// See [`PeerSession::set_choker`].
+    choke_round: Option<Arc<Notify>>,
+    /// Told the pieces the peer announces, see [`PeerSession::set_super_seed`].
+    super_seed: Option<SuperSeed>,
+}
+
+/// The peer's state, shared by the listener, which raises `changed` when
+/// the peer chokes, unchokes or gains pieces, and the requester.
+#[derive(Clone)]
+struct SharedState {
+    state: Arc<Mutex<PeerState>>,
+    changed: Arc<Notify>,
+    /// Torrent wide totals the data exchanged is added to.
+    transfer: Arc<TransferStats>,
+    /// Work queue of the torrent, told which pieces the peer has.
+    work: Arc<Mutex<WorkQueue>>,
+    session: SessionId,
+}
+
+/// Debugging observers that see every message sent to or received from
+/// the peer.
+#[derive(Clone)]
+struct WireHooks {
+    peer: String,
+    capture: CaptureHandle,
+    strict: StrictHandle,
+    stats: MessageStatsHandle,
+    peer_stats: PeerStatsHandle,
+    /// When we last sent the peer a message, for keep-alives.
+    last_sent: Arc<std::sync::Mutex<Instant>>,
+}
+
+impl WireHooks {
+    fn sent(&self, message: &MessageType) {
+        *self.last_sent.lock().unwrap() = Instant::now();
+        self.stats.record(Direction::Sent, message);
+        self.peer_stats.sent(message);
+        self.capture.record(Direction::Sent, message);
+        self.strict.observe(&self.peer, Direction::Sent, message);
+    }
+
+    fn received(&self, message: &MessageType) {
+        self.stats.record(Direction::Received, message);
+        self.peer_stats.received(message);
+        self.capture.record(Direction::Received, message);
+        self.strict
+            .observe(&self.peer, Direction::Received, message);
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -37,17 +248,155 @@ pub struct PeerState {
     pub is_choking: bool,
     pub is_peer_interested: bool,
     pub is_interested: bool,
+    /// Pieces the peer has, sized for `piece_count` once that is known.
     pub bitfield: Vec<u8>,
+    /// Pieces in the torrent, `None` if the session wasn't told.
+    pub piece_count: Option<u32>,
+    pub extension_handshake: Option<ExtensionHandshake>,
+    /// Client name and version decoded from the peer's handshake peer ID.
+    pub client: Option<String>,
+    /// Torrent comments the peer shared over ut_comment.
+    pub comments: Vec<Comment>,
+    /// Optional features both we and the peer advertised in the handshake.
+    pub capabilities: Capabilities,
+    /// Blocks we would keep requested from the peer, see [`pipeline`].
+    pub queue_depth: usize,
+    /// The peer kept our requests without sending a block for
+    /// [`pipeline::SNUB_TIMEOUT`]. Cleared by the next block it sends.
+    pub is_snubbed: bool,
+}
+
+impl Default for PeerState {
+    fn default() -> Self {
+        Self {
+            is_choked: true,
+            is_choking: true,
+            is_peer_interested: false,
+            is_interested: false,
+            bitfield: vec![],
+            piece_count: None,
+            extension_handshake: None,
+            client: None,
+            comments: vec![],
+            capabilities: Capabilities::default(),
+            queue_depth: INITIAL_QUEUE_DEPTH,
+            is_snubbed: false,
+        }
+    }
+}
+
+/// Waits until `deadline`, or forever without one.
+async fn sleep_until(deadline: Option<std::time::Instant>) {
+    match deadline {
+        Some(deadline) => {
+            tokio::time::sleep(deadline.saturating_duration_since(std::time::Instant::now())).await
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Whether the unfinished blocks of `pieces` can't keep `limit` requests
+/// in flight, so another piece should be taken. Pieces of one or a few
+/// blocks would otherwise cap the pipeline at their size.
+fn needs_more_pieces(pieces: &[PieceWork], limit: usize) -> bool {
+    pieces
+        .iter()
+        .map(PieceWork::unfinished_blocks)
+        .sum::<usize>()
+        < limit
 }
 
 impl PeerState {
+    /// Blocks we may have outstanding with this peer: our queue depth for
+    /// it, capped by the `reqq` from its extension handshake. A snubbing
+    /// peer is only trusted with one request at a time.
+    pub fn request_limit(&self) -> usize {
+        if self.is_snubbed {
+            return 1;
+        }
+
+        self.extension_handshake
+            .as_ref()
+            .and_then(|h| h.reqq)
+            .map_or(self.queue_depth, |reqq| {
+                self.queue_depth.min(reqq.max(1) as usize)
+            })
+    }
+
+    /// Whether the peer announced itself as a partial seed (BEP 21). Such
+    /// peers may never complete their bitfield.
+    pub fn is_upload_only(&self) -> bool {
+        self.extension_handshake
+            .as_ref()
+            .is_some_and(ExtensionHandshake::is_upload_only)
+    }
+
+    /// Sizes the bitfield for a torrent of `piece_count` pieces, which
+    /// bitfields and Haves from the peer are checked against from then on.
+    pub fn set_piece_count(&mut self, piece_count: u32) {
+        self.piece_count = Some(piece_count);
+        self.bitfield.resize(piece_count.div_ceil(8) as usize, 0);
+    }
+
+    /// Records a Bitfield from the peer, which must cover exactly the
+    /// torrent's pieces if their number is known.
+    pub fn set_bitfield(&mut self, bitfield: Vec<u8>) -> Result<(), anyhow::Error> {
+        if let Some(piece_count) = self.piece_count {
+            let expected = piece_count.div_ceil(8) as usize;
+            if bitfield.len() != expected {
+                bail!(
+                    "Bitfield of {} bytes, expected {expected} for {piece_count} pieces",
+                    bitfield.len()
+                );
+            }
+            // Bits past the last piece must be clear.
+            let spare = (expected * 8) as u32 - piece_count;
+            if spare > 0 && bitfield[expected - 1] & ((1 << spare) - 1) != 0 {
+                bail!("Bitfield has spare bits set past piece {piece_count}");
+            }
+        }
+
+        self.bitfield = bitfield;
+        Ok(())
+    }
+
+    /// Records a Have from the peer. Without a piece count the bitfield
+    /// grows if it sent none or a short one, with one a piece past the end
+    /// is an error.
+    pub fn set_piece(&mut self, piece_index: usize) -> Result<(), anyhow::Error> {
+        if let Some(piece_count) = self.piece_count
+            && piece_index >= piece_count as usize
+        {
+            bail!("Have for piece {piece_index} of {piece_count}");
+        }
+
+        let byte_offset = piece_index / 8;
+        if self.bitfield.len() <= byte_offset {
+            self.bitfield.resize(byte_offset + 1, 0);
+        }
+
+        self.bitfield[byte_offset] |= 0x80 >> (piece_index % 8);
+        Ok(())
+    }
+
+    /// Whether the peer has all `piece_count` pieces, i.e. is a seed.
+    pub fn has_all(&self, piece_count: u32) -> bool {
+        (0..piece_count as usize).all(|piece_index| {
+            self.bitfield
+                .get(piece_index / 8)
+                .is_some_and(|byte| byte & (0x80 >> (piece_index % 8)) != 0)
+        })
+    }
+
+    /// Whether the peer has `piece_index`, false for pieces past its
+    /// bitfield, e.g. before it sent one.
     pub fn has_piece(&self, piece_index: usize) -> bool {
         let bit_offset = 7 - (piece_index % 8); // assume Big Endian bytes
         let byte_offset = piece_index / 8;
 
-        let byte = self.bitfield[byte_offset];
-
-        byte & (1 << bit_offset) != 0
+        self.bitfield
+            .get(byte_offset)
+            .is_some_and(|byte| byte & (1 << bit_offset) != 0)
     }
 }
 
@@ -57,19 +406,36 @@ impl PeerSession {
         peer_id: [u8; 20],
         info_hash: [u8; 20],
     ) -> Result<PeerSession, anyhow::Error> {
-        let peer_state = PeerState {
-            is_choked: true,
-            is_choking: true,
-            is_peer_interested: false,
-            is_interested: false,
-            bitfield: vec![],
-        };
+        let peer_state = PeerState::default();
 
         Ok(PeerSession {
+            id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
             peer_id,
             info_hash,
             url: String::from(url),
+            alternate: None,
             peer_state: Arc::new(Mutex::new(peer_state)),
+            timeouts: Timeouts::default(),
+            hooks: WireHooks {
+                peer: String::from(url),
+                capture: CaptureHandle::default(),
+                strict: StrictHandle::from_env(),
+                stats: MessageStatsHandle::default(),
+                peer_stats: PeerStatsHandle::default(),
+                last_sent: Arc::new(std::sync::Mutex::new(Instant::now())),
+            },
+            metadata: None,
+            blocks: None,
+            piece_count: None,
+            upload_only: false,
+            incoming: false,
+            transfer: Arc::default(),
+            uploads: Arc::default(),
+            choking: Arc::new(watch::Sender::new(false)),
+            choke_round: None,
+            super_seed: None,
+            tasks: Arc::default(),
+            end: EndSlot::default(),
         })
     }
 
@@ -81,7 +447,7 @@ impl PeerSession {
         let mut request_bytes: Vec<u8> = Vec::new();
         request_bytes.push(19u8);
         request_bytes.extend_from_slice(PSTR);
-        request_bytes.extend_from_slice(&[0u8; 8]); // Reserved bytes
+        request_bytes.extend_from_slice(&Capabilities::ours().to_reserved());
         request_bytes.extend_from_slice(info_hash);
         request_bytes.extend_from_slice(peer_id);
 
@@ -91,6 +457,97 @@ impl PeerSession {
         Ok(())
     }
 
+    pub fn id(&self) -> SessionId {
+        self.id
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Shared state of the connection, updated as messages arrive.
+    pub fn state(&self) -> Arc<Mutex<PeerState>> {
+        Arc::clone(&self.peer_state)
+    }
+
+    pub fn handle(&self) -> SessionHandle {
+        SessionHandle {
+            id: self.id,
+            state: Arc::downgrade(&self.peer_state),
+            tasks: Arc::clone(&self.tasks),
+            stats: self.hooks.stats.clone(),
+            peer_stats: self.hooks.peer_stats.clone(),
+            uploads: Arc::clone(&self.uploads),
+            choking: Arc::clone(&self.choking),
+            end: self.end.clone(),
+        }
+    }
+
+    /// Dials `alternate` along with the session's address, keeping the
+    /// first to connect, for peers known by both IPv4 and IPv6. The
+    /// session takes on the address that connected.
+    pub fn set_alternate_address(&mut self, alternate: Option<String>) {
+        self.alternate = alternate;
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    /// Sets the bencoded info dictionary served to peers over ut_metadata.
+    pub fn set_metadata(&mut self, info_bytes: Arc<Vec<u8>>) {
+        self.metadata = Some(info_bytes);
+    }
+
+    /// Leaves choking the peer to the torrent's choker, which `round`
+    /// wakes. The peer starts choked until the choker gives it a slot.
+    pub fn set_choker(&mut self, round: Arc<Notify>) {
+        self.choking.send_replace(true);
+        self.choke_round = Some(round);
+    }
+
+    /// Super-seeds the peer (BEP 16): it gets an empty bitfield and a Have
+    /// for one piece at a time, as `seed` reveals them. Only for a
+    /// complete torrent.
+    pub fn set_super_seed(&mut self, seed: SuperSeed) {
+        self.super_seed = Some(seed);
+    }
+
+    /// Sets where blocks requested by the peer are read from. Without it
+    /// requests are ignored, and only its verified pieces are served.
+    pub fn set_block_reader(&mut self, blocks: Arc<BlockReader>) {
+        self.piece_count = Some(blocks.piece_count());
+        self.blocks = Some(blocks);
+    }
+
+    /// Sets how many pieces the torrent has, so a bitfield or Have that
+    /// doesn't fit it ends the session. Implied by a block reader.
+    pub fn set_piece_count(&mut self, piece_count: u32) {
+        self.piece_count = Some(piece_count);
+    }
+
+    /// Sets the torrent's transfer totals, which the data exchanged with
+    /// this peer is added to.
+    pub fn set_transfer_stats(&mut self, transfer: Arc<TransferStats>) {
+        self.transfer = transfer;
+    }
+
+    /// Announces ourselves as a partial seed (BEP 21) in the extension
+    /// handshake and stops telling the peer we are interested.
+    pub fn set_upload_only(&mut self, upload_only: bool) {
+        self.upload_only = upload_only;
+    }
+
+    /// Handle for toggling the wire log of this session, see [`capture`].
+    pub fn capture(&self) -> &CaptureHandle {
+        &self.hooks.capture
+    }
+
+    /// Handle for toggling strict protocol checks, see [`strict`].
+    pub fn strict(&self) -> &StrictHandle {
+        &self.hooks.strict
+    }
+
     pub async fn read_handshake(reader: &mut OwnedReadHalf) -> Result<[u8; 68], anyhow::Error> {
         let mut response_bytes = [0u8; 68];
         reader.readable().await?;
@@ -101,174 +558,701 @@ impl PeerSession {
 
     pub async fn start(
         &mut self,
-        piece_request_rx: Arc<Mutex<VecDeque<PieceRequest>>>,
+        piece_request_rx: Arc<Mutex<WorkQueue>>,
         piece_request_tx: Sender<PieceResponse>,
     ) -> Result<(), anyhow::Error> {
-        let (block_tx, block_rx) = channel::<BlockResponse>(100);
+        proxy::check_direct(&format!("peer connection to {}", self.url))?;
+        let connected = async {
+            // Only the connect counts against the limit, the timeout starts
+            // once it is our turn.
+            let (stream, address) = {
+                let _dial = DIALS.acquire().await?;
+                with_timeout(
+                    "peer connect",
+                    self.timeouts.connect,
+                    BindConfig::from_env().connect_either(&self.url, self.alternate.as_deref()),
+                )
+                .await?
+            };
+            if address != self.url {
+                self.hooks.peer.clone_from(&address);
+                self.url = address;
+            }
+            let (mut reader, mut writer) = stream.into_split();
 
-        let stream = TcpStream::connect(&self.url).await?;
-        let (mut reader, mut writer) = stream.into_split();
+            let handshake = with_timeout("peer handshake", self.timeouts.handshake, async {
+                PeerSession::send_handshake(&mut writer, &self.info_hash, &self.peer_id).await?;
+                PeerSession::read_handshake(&mut reader).await
+            })
+            .await?;
+
+            Ok::<_, anyhow::Error>((reader, writer, handshake))
+        }
+        .await;
+        let (reader, writer, handshake_response) = connected
+            .inspect_err(|e| self.end.set(EndReason::HandshakeFailed(format!("{e:#}"))))?;
+
+        self.run(
+            reader,
+            writer,
+            handshake_response,
+            piece_request_rx,
+            piece_request_tx,
+        )
+        .await
+    }
+
+    /// Continues a connection the peer opened, whose `handshake` was read
+    /// to find the torrent, by answering with ours.
+    pub async fn accept(
+        &mut self,
+        stream: TcpStream,
+        handshake: [u8; 68],
+        piece_request_rx: Arc<Mutex<WorkQueue>>,
+        piece_request_tx: Sender<PieceResponse>,
+    ) -> Result<(), anyhow::Error> {
+        let (reader, mut writer) = stream.into_split();
+        self.incoming = true;
+
+        with_timeout(
+            "peer handshake",
+            self.timeouts.handshake,
+            PeerSession::send_handshake(&mut writer, &self.info_hash, &self.peer_id),
+        )
+        .await
+        .inspect_err(|e| self.end.set(EndReason::HandshakeFailed(format!("{e:#}"))))?;
+
+        self.run(
+            reader,
+            writer,
+            handshake,
+            piece_request_rx,
+            piece_request_tx,
+        )
+        .await
+    }
+
+    /// Whether the peer opened the connection.
+    pub fn is_incoming(&self) -> bool {
+        self.incoming
+    }
 
-        PeerSession::send_handshake(&mut writer, &self.info_hash, &self.peer_id).await?;
-        let handshake_response = PeerSession::read_handshake(&mut reader).await?;
+    /// Checks the peer's handshake, then starts exchanging messages.
+    async fn run(
+        &mut self,
+        reader: OwnedReadHalf,
+        mut writer: OwnedWriteHalf,
+        handshake_response: [u8; 68],
+        piece_request_rx: Arc<Mutex<WorkQueue>>,
+        piece_request_tx: Sender<PieceResponse>,
+    ) -> Result<(), anyhow::Error> {
+        // Room for every block the pipeline may have in flight, a peer
+        // answering them all at once mustn't overflow it.
+        let (block_tx, block_rx) = channel::<BlockResponse>(pipeline::MAX_QUEUE_DEPTH);
         let resp = &handshake_response[28..48];
 
         if resp != self.info_hash {
             drop(reader);
             drop(writer);
+            self.end.set(EndReason::HandshakeFailed(String::from(
+                "peer is on another torrent",
+            )));
             bail!(
                 "Dropping connection to peer, info_hash invalid {resp:?}:{:?}",
                 self.info_hash
             );
         }
 
+        let capabilities = Capabilities::ours()
+            .negotiate(Capabilities::from_reserved(&handshake_response[20..28]));
+        {
+            let mut state = self.peer_state.lock().await;
+            state.client = client_name(&handshake_response[48..68]);
+            state.capabilities = capabilities;
+            if let Some(piece_count) = self.piece_count {
+                state.set_piece_count(piece_count);
+            }
+        }
+
+        // Our pieces, which may only be sent straight after the handshake.
+        // Pieces verified from here on are announced with Have messages.
+        // Super-seeding we claim none and reveal them one by one instead.
+        let (haves, bitfield) = match (&self.blocks, &self.super_seed) {
+            (Some(blocks), Some(_)) => (
+                None,
+                Some(vec![0; blocks.piece_count().div_ceil(8) as usize]),
+            ),
+            (Some(blocks), None) => (Some(blocks.subscribe()), blocks.bitfield()),
+            (None, _) => (None, None),
+        };
+        if let Some(bitfield) = bitfield {
+            let message = MessageType::Bitfield(bitfield);
+            writer.write_all(&message.to_bytes()).await?;
+            self.hooks.sent(&message);
+        }
+
+        if capabilities.extension_protocol {
+            let mut handshake = ExtensionHandshake::ours(self.metadata.as_ref().map(|m| m.len()));
+            handshake.upload_only = self.upload_only.then_some(1);
+            handshake.reqq = self.blocks.is_some().then_some(MAX_UPLOAD_QUEUE as u32);
+            let message = MessageType::Extended {
+                id: extension::HANDSHAKE_ID,
+                payload: handshake.to_bytes()?,
+            };
+
+            writer.writable().await?;
+            writer.write_all(&message.to_bytes()).await?;
+            self.hooks.sent(&message);
+        }
+
         // Communicate intention to download from peer synchronously before starting upload/download.
-        PeerSession::send_interested(&mut writer).await?;
-        PeerSession::send_unchoke(&mut writer).await?;
+        let seeding = self
+            .blocks
+            .as_ref()
+            .is_some_and(|blocks| blocks.is_complete());
+        if !self.upload_only && !seeding {
+            PeerSession::send_interested(&mut writer).await?;
+            self.hooks.sent(&MessageType::Interested);
+            self.peer_state.lock().await.is_interested = true;
+        }
+        if !*self.choking.borrow() {
+            PeerSession::send_unchoke(&mut writer).await?;
+            self.hooks.sent(&MessageType::Unchoke);
+            self.peer_state.lock().await.is_choking = false;
+        }
 
         // Start receiving messages from the peer.
+        let shared = SharedState {
+            state: self.peer_state.clone(),
+            changed: Arc::new(Notify::new()),
+            transfer: Arc::clone(&self.transfer),
+            work: Arc::clone(&piece_request_rx),
+            session: self.id,
+        };
+        let listener_shared = shared.clone();
         let reader = Arc::new(Mutex::new(reader));
-        let state_ref = self.peer_state.clone();
-        let reader_handle =
-            tokio::spawn(
-                async move { PeerSession::peer_listener(state_ref, reader, block_tx).await },
-            );
+        let message_timeout = self.timeouts.message;
+        let hooks = self.hooks.clone();
+        let writer = Arc::new(Mutex::new(writer));
+        let listener_writer = writer.clone();
+        // Tasks that only write to the peer, stopped with the listener.
+        let mut companions = vec![];
+        {
+            let writer = writer.clone();
+            let hooks = self.hooks.clone();
+            let name = format!("peer keep-alive {}", privacy::address(&self.url));
+            let keep_alive = tasks::spawn(Subsystem::Peer, async move {
+                supervisor::run(&name, PeerSession::peer_keep_alive(writer, hooks)).await
+            });
+            companions.push(keep_alive.abort_handle());
+        }
+        {
+            let peer = shared.clone();
+            let choking = self.choking.subscribe();
+            let uploads = Arc::clone(&self.uploads);
+            let writer = writer.clone();
+            let hooks = self.hooks.clone();
+            let name = format!("peer choker {}", privacy::address(&self.url));
+            let choker = tasks::spawn(Subsystem::Peer, async move {
+                supervisor::run(
+                    &name,
+                    PeerSession::peer_choker(peer, choking, uploads, writer, hooks),
+                )
+                .await
+            });
+            companions.push(choker.abort_handle());
+        }
+        if let (Some(haves), Some(blocks)) = (haves, self.blocks.clone()) {
+            let peer = shared.clone();
+            let writer = writer.clone();
+            let hooks = self.hooks.clone();
+            let name = format!("peer announcer {}", privacy::address(&self.url));
+            let announcer = tasks::spawn(Subsystem::Peer, async move {
+                supervisor::run(
+                    &name,
+                    PeerSession::peer_announcer(peer, haves, blocks, writer, hooks),
+                )
+                .await
+            });
+            companions.push(announcer.abort_handle());
+        }
+        if let Some(seed) = self.super_seed.clone() {
+            let id = self.id;
+            let writer = writer.clone();
+            let hooks = self.hooks.clone();
+            let name = format!("peer super-seeder {}", privacy::address(&self.url));
+            let revealer = tasks::spawn(Subsystem::Peer, async move {
+                supervisor::run(
+                    &name,
+                    PeerSession::peer_super_seeder(id, seed, writer, hooks),
+                )
+                .await
+            });
+            companions.push(revealer.abort_handle());
+        }
+        let listener_companions = companions.clone();
+        let uploads = Arc::clone(&self.uploads);
+        let served = ServedData {
+            metadata: self.metadata.clone(),
+            blocks: self.blocks.clone(),
+            uploads: Arc::clone(&uploads),
+            upload_only: self.upload_only,
+            choke_round: self.choke_round.clone(),
+            super_seed: self.super_seed.clone(),
+        };
+        let name = format!("peer listener {}", privacy::address(&self.url));
+        let listener_uploads = Arc::clone(&uploads);
+        let listener_seed = self.super_seed.clone();
+        let id = self.id;
+        let end = self.end.clone();
+        let listener = tasks::spawn(Subsystem::Peer, async move {
+            let listener_end = end.clone();
+            let exit = supervisor::run(&name, async move {
+                PeerSession::peer_listener(
+                    listener_shared,
+                    reader,
+                    block_tx,
+                    message_timeout,
+                    hooks,
+                    listener_writer,
+                    served,
+                )
+                .await
+                .inspect_err(|e| listener_end.set(EndReason::from_error(e)))
+            })
+            .await;
+            match &exit {
+                // The listener only finishes when it closes the connection.
+                TaskExit::Finished | TaskExit::Cancelled => end.set(EndReason::Killed),
+                TaskExit::Panicked(message) => end.set(EndReason::Crashed(message.clone())),
+                TaskExit::Failed(_) => {}
+            }
+            // Nobody is left to send blocks or messages to.
+            listener_uploads.close();
+            if let Some(seed) = listener_seed {
+                seed.remove_peer(id);
+            }
+            for companion in listener_companions {
+                companion.abort();
+            }
+            exit
+        });
+
+        // Serve the peer's requests.
+        if let Some(blocks) = self.blocks.clone() {
+            let peer = shared.clone();
+            let writer = writer.clone();
+            let hooks = self.hooks.clone();
+            let name = format!("peer uploader {}", privacy::address(&self.url));
+            let uploader = tasks::spawn(Subsystem::Peer, async move {
+                supervisor::run(
+                    &name,
+                    PeerSession::peer_uploader(peer, uploads, blocks, writer, hooks),
+                )
+                .await
+            });
+            self.tasks.lock().unwrap().push(uploader.abort_handle());
+        }
 
         // Start sending messages to the peer
-        let state_ref = self.peer_state.clone();
         let piece_queue = piece_request_rx.clone();
         let piece_tx = piece_request_tx.clone();
-        let writer = Arc::new(Mutex::new(writer));
-        let writer_handle = tokio::spawn(async move {
-            PeerSession::peer_requester(state_ref, piece_queue, piece_tx, writer, block_rx).await
+        let id = self.id;
+        let hooks = self.hooks.clone();
+        let name = format!("peer requester {}", privacy::address(&self.url));
+        let work = WorkGuard::new(Arc::clone(&piece_queue), id);
+        let requester = tasks::spawn(Subsystem::Peer, async move {
+            // Whatever ends the requester, its pieces go back to the queue.
+            let _work = work;
+            supervisor::run(
+                &name,
+                PeerSession::peer_requester(
+                    id,
+                    shared,
+                    piece_queue,
+                    piece_tx,
+                    writer,
+                    block_rx,
+                    hooks,
+                ),
+            )
+            .await
         });
 
+        self.tasks
+            .lock()
+            .unwrap()
+            .extend([listener.abort_handle(), requester.abort_handle()]);
+        self.tasks.lock().unwrap().extend(companions);
+
         Ok(())
     }
 
+    /// Requests blocks of assigned pieces from the peer. Sleeps until a
+    /// block arrives, the listener sees the peer's state change or the
+    /// work queue changes, and ends once the listener has.
     async fn peer_requester(
-        peer_state: Arc<Mutex<PeerState>>,
-        piece_queue: Arc<Mutex<VecDeque<PieceRequest>>>,
+        session_id: SessionId,
+        peer: SharedState,
+        piece_queue: Arc<Mutex<WorkQueue>>,
         piece_tx: Sender<PieceResponse>,
         writer: Arc<Mutex<OwnedWriteHalf>>,
         mut block_rx: Receiver<BlockResponse>,
+        hooks: WireHooks,
     ) -> Result<(), anyhow::Error> {
-        let mut piece_work: Option<PieceWork> = None;
-        let max_in_flight = 5;
+        let (work_changed, journal) = {
+            let queue = piece_queue.lock().await;
+            (queue.changed(), queue.journal())
+        };
+        // Credited for the blocks we receive, by address so a peer can be
+        // banned after disconnecting.
+        let me = Contributor {
+            session_id,
+            address: hooks.peer.clone(),
+        };
+        // Pieces assigned to this session, several at once when they are
+        // too small to fill the request pipeline on their own.
+        let mut pieces: Vec<PieceWork> = vec![];
+        let mut received: Vec<BlockResponse> = vec![];
+        let mut pipeline = Pipeline::new(std::time::Instant::now());
         loop {
+            // Listen before looking at the queue so no change is missed.
+            let mut work_ready = std::pin::pin!(work_changed.notified());
+            work_ready.as_mut().enable();
+
             // Clone latest peer state then unlock mutex, state information doesn't have to be realtime.
-            let state = { peer_state.lock().await.clone() };
+            let state = {
+                let mut state = peer.state.lock().await;
+                state.queue_depth = pipeline.depth();
+                state.clone()
+            };
+            if state.is_choked {
+                pipeline.reset(std::time::Instant::now());
+            }
+            let limit = state.request_limit();
 
-            // Fetch next piece to download from queue if not currently working on one.
-            if piece_work.is_none() {
+            // Take pieces from the queue until there are enough blocks to
+            // keep `limit` requests in flight. Only pieces the peer has are
+            // taken, the rest stay queued for other sessions.
+            let mut dropped = vec![];
+            let first_taken;
+            {
                 let mut piece_request_queue = piece_queue.lock().await;
-                let new_piece = piece_request_queue.pop_front();
+                // Drop pieces that are no longer ours, finished by another
+                // session in endgame or started over after failing their
+                // hash check, cancelling what we still asked for.
+                pieces.retain(|work| {
+                    let owned = piece_request_queue.owners(work.index).contains(&session_id);
+                    if !owned {
+                        for block in &work.blocks {
+                            if block.status == BlockStatus::InProgress {
+                                pipeline.cancelled(work.index, block.offset);
+                                dropped.push(MessageType::Cancel {
+                                    index: work.index,
+                                    begin: block.offset,
+                                    length: block.length,
+                                });
+                            }
+                        }
+                    }
+                    owned
+                });
+
+                first_taken = pieces.len();
+                while needs_more_pieces(&pieces, limit) {
+                    let Some(request) = piece_request_queue
+                        .next_for(session_id, |idx| state.has_piece(idx as usize))
+                    else {
+                        break;
+                    };
+                    pieces.push(PieceWork::from(request));
+                }
+            }
+            if !dropped.is_empty() {
+                PeerSession::send_cancels(&writer, &dropped, &hooks).await;
+            }
+            // Reading back a whole piece mustn't hold up the queue or the
+            // runtime.
+            if let Some(journal) = &journal {
+                for work in &mut pieces[first_taken..] {
+                    let (journal, index) = (journal.clone(), work.index);
+                    let restored =
+                        tasks::spawn_blocking(Subsystem::Disk, move || journal.restore(index))
+                            .await?;
+                    match restored {
+                        Ok(blocks) => {
+                            work.resume(blocks);
+                        }
+                        Err(e) => eprintln!("[Journal] {e:#}"),
+                    }
+                }
+            }
 
-                if let Some(piece_req) = new_piece {
-                    if state.has_piece(piece_req.piece_index as usize) {
-                        piece_work = Some(piece_req.into());
-                    } else {
-                        // Inform piece manager that piece is not available on this peer.
-                        piece_tx
-                            .send(PieceResponse {
-                                piece_index: piece_req.piece_index,
-                                result: Err(PieceError::PieceUnavailable),
+            // First consume all blocks from peer reader task channel if there are any.
+            let blocks: Vec<BlockResponse> = received
+                .drain(..)
+                .chain(std::iter::from_fn(|| block_rx.try_recv().ok()))
+                .collect();
+            if state.is_snubbed && !blocks.is_empty() {
+                piece_queue.lock().await.set_snubbed(session_id, false);
+                peer.state.lock().await.is_snubbed = false;
+            }
+            for block_response in blocks {
+                pipeline.received(
+                    block_response.index,
+                    block_response.begin,
+                    block_response.block.len(),
+                    std::time::Instant::now(),
+                );
+                let stored = match pieces
+                    .iter_mut()
+                    .find(|work| work.index == block_response.index)
+                {
+                    None => Err(anyhow!("Block is for piece {}", block_response.index)),
+                    Some(work)
+                        if piece_queue.lock().await.block_arrived(
+                            work.index,
+                            block_response.begin,
+                            &me,
+                            &block_response.block,
+                        ) == BlockArrival::Duplicate =>
+                    {
+                        // Another session got it first, its copy is taken below.
+                        Ok(())
+                    }
+                    Some(work) => {
+                        if let Some(journal) = &journal {
+                            let (journal, index, begin) =
+                                (journal.clone(), work.index, block_response.begin);
+                            let block = block_response.block.clone();
+                            let recorded = tasks::spawn_blocking(Subsystem::Disk, move || {
+                                journal.record(index, begin, &block)
                             })
                             .await?;
+                            // Losing the journal only costs the block on a
+                            // restart, keep the piece going.
+                            if let Err(e) = recorded {
+                                eprintln!("[Journal] {e:#}");
+                            }
+                        }
+                        work.credit(&me);
+                        work.store_block(block_response.begin, block_response.block)
+                    }
+                };
+
+                if let Err(e) = stored {
+                    eprintln!("WARNING: Received unexpected block response from peer: {e:#}");
+                }
+            }
+
+            // In endgame, use blocks other sessions already received for
+            // these pieces and cancel our requests for them.
+            let mut cancels = vec![];
+            for work in pieces.iter_mut() {
+                let arrived = {
+                    let queue = piece_queue.lock().await;
+                    if queue.is_endgame() {
+                        queue.arrived_blocks(work.index)
+                    } else {
+                        vec![]
+                    }
+                };
+                for (begin, sender, block) in arrived {
+                    let Some(info) = work
+                        .blocks
+                        .iter_mut()
+                        .find(|info| info.offset == begin && info.status != BlockStatus::Full)
+                    else {
+                        continue;
+                    };
+
+                    if info.status == BlockStatus::InProgress {
+                        pipeline.cancelled(work.index, begin);
+                        cancels.push(MessageType::Cancel {
+                            index: work.index,
+                            begin,
+                            length: info.length,
+                        });
+                    }
+                    info.status = BlockStatus::InProgress;
+                    work.credit(&sender);
+                    if let Err(e) = work.store_block(begin, block.to_vec()) {
+                        eprintln!("WARNING: Failed to use block from another peer: {e:#}");
                     }
                 }
             }
+            if !cancels.is_empty() {
+                PeerSession::send_cancels(&writer, &cancels, &hooks).await;
+            }
 
-            // Do work if there is work to do
-            if let Some(mut work) = piece_work.take() {
-                // Send piece to piece manager if it is complete
-                if work.is_complete() {
-                    if let Err(e) = piece_tx.send(work.to_piece_response()).await {
+            // Send complete pieces to the piece manager, then look for more
+            // work straight away.
+            let (complete, unfinished): (Vec<_>, Vec<_>) =
+                pieces.drain(..).partition(PieceWork::is_complete);
+            pieces = unfinished;
+            if !complete.is_empty() {
+                for work in complete {
+                    if let Err(e) = piece_tx.send(work.into_piece_response(session_id)).await {
                         eprintln!("ERROR: Failed to send piece to PieceManager: {e}")
                     }
-                    continue;
                 }
+                continue;
+            }
 
-                // First consume all blocks from peer reader task channel if there are any.
-                while let Ok(block_response) = block_rx.try_recv() {
-                    let offset = block_response.begin;
-
-                    let block = work.blocks.iter_mut().find(|block| {
-                        block.offset == offset && block.status == BlockStatus::InProgress
-                    });
-
-                    if let Some(block) = block {
-                        block.data = block_response.block;
-                        block.status = BlockStatus::Full;
-                    } else {
-                        eprintln!(
-                            "WARNING: Received block response from peer that did not match expected block offset."
-                        );
+            // A peer sitting on our requests gets them cancelled and its
+            // pieces handed back, for sessions that send.
+            if !state.is_snubbed && pipeline.is_snubbed(std::time::Instant::now()) {
+                println!("[Peer] {} is snubbing us", privacy::address(&hooks.peer));
+                let mut cancels = vec![];
+                let mut queue = piece_queue.lock().await;
+                for work in pieces.drain(..) {
+                    for block in &work.blocks {
+                        if block.status == BlockStatus::InProgress {
+                            pipeline.cancelled(work.index, block.offset);
+                            cancels.push(MessageType::Cancel {
+                                index: work.index,
+                                begin: block.offset,
+                                length: block.length,
+                            });
+                        }
                     }
+                    queue.release(work.index, session_id);
                 }
+                queue.set_snubbed(session_id, true);
+                drop(queue);
+                peer.state.lock().await.is_snubbed = true;
+
+                PeerSession::send_cancels(&writer, &cancels, &hooks).await;
+                continue;
+            }
 
-                // Only send requests if not choked.
+            // Only send requests if not choked.
+            if !state.is_choked {
+                // Top the pipeline back up to the peer's request limit,
+                // oldest piece first.
+                let in_flight: usize = pieces.iter().map(PieceWork::in_flight_blocks).sum();
+                let mut budget = limit.saturating_sub(in_flight);
+
+                let mut writer = writer.lock().await;
+                for work in pieces.iter_mut() {
+                    if budget == 0 {
+                        break;
+                    }
 
-                if !state.is_choked {
-                    // Get next 5 blocks (if there are 5 to get) and make requests to peer
-                    let next_blocks: Vec<&mut BlockInfo> = work
+                    let mut next_blocks: Vec<&mut BlockInfo> = work
                         .blocks
                         .iter_mut()
                         .filter(|block| block.status == BlockStatus::Empty)
-                        .take(max_in_flight)
-                        .map(|block| {
-                            block.status = BlockStatus::InProgress;
-                            block
-                        })
+                        .take(budget)
                         .collect();
+                    if next_blocks.is_empty() {
+                        continue;
+                    }
+                    budget -= next_blocks.len();
+                    for block in next_blocks.iter_mut() {
+                        block.status = BlockStatus::InProgress;
+                    }
 
-                    let mut writer = writer.lock().await;
                     let resp =
                         PeerSession::send_request(&mut writer, work.index, &next_blocks).await;
 
-                    if let Err(e) = resp {
-                        eprintln!("{e}");
+                    match resp {
+                        Ok(()) => {
+                            let now = std::time::Instant::now();
+                            for block in &next_blocks {
+                                pipeline.requested(work.index, block.offset, now);
+                                let request = MessageType::Request {
+                                    index: work.index,
+                                    begin: block.offset,
+                                    length: block.length,
+                                };
+                                hooks.sent(&request);
+                            }
+                        }
+                        Err(e) => eprintln!("{e}"),
                     }
                 }
-
-                // Give ownership back if work not complete yet
-                piece_work = Some(work);
             }
 
-            // Give other tasks some time to execute if there is no work
-            tokio::time::sleep(Duration::from_millis(100)).await;
+            tokio::select! {
+                block = block_rx.recv() => match block {
+                    Some(block) => received.push(block),
+                    // The listener stopped, so no more blocks will come.
+                    None => return Ok(()),
+                },
+                _ = peer.changed.notified() => {}
+                _ = work_ready => {}
+                _ = sleep_until(pipeline.snub_deadline()), if !state.is_snubbed => {}
+            }
         }
     }
 
     async fn peer_listener(
-        peer_state: Arc<Mutex<PeerState>>,
+        peer: SharedState,
         reader: Arc<Mutex<OwnedReadHalf>>,
         block_tx: Sender<BlockResponse>,
+        message_timeout: Duration,
+        hooks: WireHooks,
+        writer: Arc<Mutex<OwnedWriteHalf>>,
+        served: ServedData,
     ) -> Result<(), anyhow::Error> {
         loop {
             let msg = {
                 let mut reader = reader.lock().await;
-                PeerSession::read_message(&mut reader).await.unwrap()
+                with_timeout(
+                    "peer message",
+                    message_timeout,
+                    PeerSession::read_message(&mut reader),
+                )
+                .await?
             };
+            hooks.received(&msg);
+            // May let the requester ask for more, or tell it to stop.
+            let wakes_requester = matches!(
+                msg,
+                MessageType::Choke
+                    | MessageType::Unchoke
+                    | MessageType::Have(_)
+                    | MessageType::Bitfield(_)
+            );
+            let gains_pieces = matches!(msg, MessageType::Have(_) | MessageType::Bitfield(_));
             {
-                let mut state = peer_state.lock().await;
+                let mut state = peer.state.lock().await;
                 match msg {
                     MessageType::Choke => state.is_choked = true,
                     MessageType::Unchoke => state.is_choked = false,
-                    MessageType::Interested => state.is_peer_interested = true,
-                    MessageType::NotInterested => state.is_peer_interested = false,
-                    MessageType::Have(piece_id) => println!("Peer has {piece_id}"),
-                    MessageType::Bitfield(items) => state.bitfield = items,
+                    MessageType::Interested | MessageType::NotInterested => {
+                        state.is_peer_interested = matches!(msg, MessageType::Interested);
+                        if let Some(round) = &served.choke_round {
+                            round.notify_one();
+                        }
+                    }
+                    MessageType::Have(piece_id) => state.set_piece(piece_id as usize)?,
+                    MessageType::Bitfield(items) => state.set_bitfield(items)?,
+                    MessageType::Request { .. } if state.is_choking => {}
                     MessageType::Request {
                         index,
                         begin,
                         length,
-                    } => println!("Sorry buddy, but no"),
+                    } => {
+                        if let Some(blocks) = &served.blocks {
+                            PeerSession::queue_upload(
+                                blocks,
+                                &served.uploads,
+                                BlockRequest {
+                                    index,
+                                    begin,
+                                    length,
+                                },
+                            );
+                        }
+                    }
                     MessageType::Piece {
                         index,
                         begin,
                         block,
                     } => {
+                        peer.transfer.record_download(block.len() as u64);
                         // TODO: Handle errors correctly
                         // send to block manager task
                         block_tx.try_send(BlockResponse {
@@ -282,14 +1266,401 @@ impl PeerSession {
                         begin,
                         length,
                     } => {
-                        println!(
-                            "Cancelled block at index {index}, offset {begin} and length {length}"
-                        )
+                        served.uploads.cancel(BlockRequest {
+                            index,
+                            begin,
+                            length,
+                        });
                     }
                     MessageType::Port(port) => println!("Port request {port}"),
+                    MessageType::Extended { .. } if !state.capabilities.extension_protocol => {
+                        eprintln!(
+                            "WARNING: Peer sent an extended message without negotiating BEP 10"
+                        )
+                    }
+                    MessageType::Extended { id, payload } => {
+                        let reply = PeerSession::handle_extended(
+                            &mut state,
+                            id,
+                            &payload,
+                            served.metadata.as_deref().map(Vec::as_slice),
+                        );
+
+                        match reply {
+                            Ok(Some(reply)) => {
+                                let mut writer = writer.lock().await;
+                                writer.write_all(&reply.to_bytes()).await?;
+                                hooks.sent(&reply);
+                            }
+                            Ok(None) => {}
+                            Err(e) => eprintln!("WARNING: Bad extension message from peer: {e}"),
+                        }
+                    }
                     MessageType::KeepAlive => println!("Received keep alive!"),
                 }
             }
+
+            if gains_pieces {
+                let bitfield = peer.state.lock().await.bitfield.clone();
+                peer.work
+                    .lock()
+                    .await
+                    .set_peer_pieces(peer.session, &bitfield);
+                if let Some(seed) = &served.super_seed {
+                    seed.on_bitfield(peer.session, &bitfield);
+                }
+                if !served.upload_only {
+                    PeerSession::update_interest(&peer, served.blocks.as_deref(), &writer, &hooks)
+                        .await?;
+                }
+            }
+
+            if wakes_requester {
+                peer.changed.notify_one();
+            }
+
+            // Two seeds have nothing to exchange.
+            if gains_pieces
+                && let Some(blocks) = &served.blocks
+                && blocks.is_complete()
+                && peer.state.lock().await.has_all(blocks.piece_count())
+            {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Tells the peer whether we want any of its pieces, if that changed.
+    /// Without `blocks` of our own every piece is wanted.
+    async fn update_interest(
+        peer: &SharedState,
+        blocks: Option<&BlockReader>,
+        writer: &Mutex<OwnedWriteHalf>,
+        hooks: &WireHooks,
+    ) -> Result<(), anyhow::Error> {
+        let message = {
+            let mut state = peer.state.lock().await;
+            let wanted = match blocks {
+                Some(blocks) => (0..blocks.piece_count())
+                    .any(|index| state.has_piece(index as usize) && !blocks.has_piece(index)),
+                None => state.bitfield.iter().any(|byte| *byte != 0),
+            };
+            if wanted == state.is_interested {
+                return Ok(());
+            }
+
+            state.is_interested = wanted;
+            if wanted {
+                MessageType::Interested
+            } else {
+                MessageType::NotInterested
+            }
+        };
+
+        writer.lock().await.write_all(&message.to_bytes()).await?;
+        hooks.sent(&message);
+
+        Ok(())
+    }
+
+    /// Queues a request of the peer for the uploader if it is valid and
+    /// for a piece we have.
+    fn queue_upload(blocks: &BlockReader, uploads: &UploadQueue, request: BlockRequest) {
+        let BlockRequest {
+            index,
+            begin,
+            length,
+        } = request;
+
+        if let Err(e) = blocks.validate(index, begin, length) {
+            eprintln!("WARNING: Not serving request from peer: {e:#}");
+        } else if !blocks.has_piece(index) {
+            eprintln!("WARNING: Not serving request from peer, piece {index} isn't verified");
+        } else if !uploads.push(request) {
+            eprintln!(
+                "WARNING: Not serving request from peer, {MAX_UPLOAD_QUEUE} requests are queued already"
+            );
+        }
+    }
+
+    /// Reads the peer's queued requests from disk and sends them, oldest
+    /// first, until the listener closes the queue.
+    async fn peer_uploader(
+        peer: SharedState,
+        uploads: Arc<UploadQueue>,
+        blocks: Arc<BlockReader>,
+        writer: Arc<Mutex<OwnedWriteHalf>>,
+        hooks: WireHooks,
+    ) -> Result<(), anyhow::Error> {
+        while let Some(BlockRequest {
+            index,
+            begin,
+            length,
+        }) = uploads.next().await
+        {
+            let reader = Arc::clone(&blocks);
+            let block =
+                tasks::spawn_blocking(Subsystem::Disk, move || reader.read(index, begin, length))
+                    .await?;
+
+            match block {
+                Ok(block) => {
+                    let uploaded = block.len() as u64;
+                    let piece = MessageType::Piece {
+                        index,
+                        begin,
+                        block,
+                    };
+                    writer.lock().await.write_all(&piece.to_bytes()).await?;
+                    hooks.sent(&piece);
+                    peer.transfer.record_upload(uploaded);
+                }
+                Err(e) => eprintln!("WARNING: Not serving request from peer: {e:#}"),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends a KeepAlive whenever nothing else was sent to the peer for
+    /// [`KEEP_ALIVE_INTERVAL`].
+    async fn peer_keep_alive(
+        writer: Arc<Mutex<OwnedWriteHalf>>,
+        hooks: WireHooks,
+    ) -> Result<(), anyhow::Error> {
+        loop {
+            let idle = hooks.last_sent.lock().unwrap().elapsed();
+            if idle < KEEP_ALIVE_INTERVAL {
+                tokio::time::sleep(KEEP_ALIVE_INTERVAL - idle).await;
+                continue;
+            }
+
+            let message = MessageType::KeepAlive;
+            writer.lock().await.write_all(&message.to_bytes()).await?;
+            hooks.sent(&message);
+        }
+    }
+
+    /// Sends Choke or Unchoke whenever the torrent's choker changes its
+    /// mind about the peer.
+    async fn peer_choker(
+        peer: SharedState,
+        mut choking: watch::Receiver<bool>,
+        uploads: Arc<UploadQueue>,
+        writer: Arc<Mutex<OwnedWriteHalf>>,
+        hooks: WireHooks,
+    ) -> Result<(), anyhow::Error> {
+        while choking.changed().await.is_ok() {
+            let choke = *choking.borrow_and_update();
+            {
+                let mut state = peer.state.lock().await;
+                if state.is_choking == choke {
+                    continue;
+                }
+                state.is_choking = choke;
+            }
+            // A choked peer's requests are dropped, it asks again once
+            // unchoked.
+            if choke {
+                uploads.clear();
+            }
+
+            let message = if choke {
+                MessageType::Choke
+            } else {
+                MessageType::Unchoke
+            };
+            writer.lock().await.write_all(&message.to_bytes()).await?;
+            hooks.sent(&message);
+        }
+
+        Ok(())
+    }
+
+    /// Sends Have for every piece we verify while connected, skipping
+    /// pieces the peer has already.
+    async fn peer_announcer(
+        peer: SharedState,
+        mut haves: broadcast::Receiver<u32>,
+        blocks: Arc<BlockReader>,
+        writer: Arc<Mutex<OwnedWriteHalf>>,
+        hooks: WireHooks,
+    ) -> Result<(), anyhow::Error> {
+        loop {
+            let pieces = match haves.recv().await {
+                Ok(index) => vec![index],
+                // Some were missed, announce everything the peer lacks.
+                Err(RecvError::Lagged(_)) => (0..blocks.piece_count())
+                    .filter(|index| blocks.has_piece(*index))
+                    .collect(),
+                Err(RecvError::Closed) => return Ok(()),
+            };
+
+            let state = peer.state.lock().await.clone();
+            let messages: Vec<MessageType> = pieces
+                .into_iter()
+                .filter(|index| !state.has_piece(*index as usize))
+                .map(MessageType::Have)
+                .collect();
+            if messages.is_empty() {
+                continue;
+            }
+
+            let bytes: Vec<u8> = messages.iter().flat_map(MessageType::to_bytes).collect();
+            writer.lock().await.write_all(&bytes).await?;
+            messages.iter().for_each(|message| hooks.sent(message));
+        }
+    }
+
+    /// Sends Have for each piece super-seeding reveals to the peer, waiting
+    /// for the last one to spread before the next.
+    async fn peer_super_seeder(
+        session: SessionId,
+        seed: SuperSeed,
+        writer: Arc<Mutex<OwnedWriteHalf>>,
+        hooks: WireHooks,
+    ) -> Result<(), anyhow::Error> {
+        let spread = seed.spread();
+        loop {
+            // Listen before asking so no spread is missed.
+            let mut spread_ready = std::pin::pin!(spread.notified());
+            spread_ready.as_mut().enable();
+
+            if let Some(piece) = seed.next_piece(session) {
+                let message = MessageType::Have(piece);
+                writer.lock().await.write_all(&message.to_bytes()).await?;
+                hooks.sent(&message);
+            }
+            spread_ready.await;
+        }
+    }
+
+    /// Processes an extended message, returning the reply to send, if any.
+    fn handle_extended(
+        state: &mut PeerState,
+        id: u8,
+        payload: &[u8],
+        metadata: Option<&[u8]>,
+    ) -> Result<Option<MessageType>, anyhow::Error> {
+        if id == extension::HANDSHAKE_ID {
+            let handshake = ExtensionHandshake::from_bytes(payload)?;
+
+            // Ask for the peer's torrent comments as soon as we know it has some.
+            let request = match handshake.extension_id(extension::UT_COMMENT) {
+                Some(comment_id) => Some(MessageType::Extended {
+                    id: comment_id,
+                    payload: CommentMessage::Request {
+                        num: MAX_COMMENTS as u32,
+                    }
+                    .to_bytes()?,
+                }),
+                None => None,
+            };
+
+            state.extension_handshake = Some(handshake);
+            return Ok(request);
+        }
+
+        if id == extension::UT_COMMENT_ID {
+            return PeerSession::handle_comment(state, payload);
+        }
+
+        if id == extension::UT_HOLEPUNCH_ID {
+            return PeerSession::handle_holepunch(state, payload);
+        }
+
+        if id != extension::UT_METADATA_ID {
+            return Ok(None);
+        }
+
+        let MetadataMessage::Request { piece } = MetadataMessage::from_bytes(payload)? else {
+            // Fetching metadata from peers is not supported yet.
+            return Ok(None);
+        };
+
+        // Only reply on the id the peer asked for in its extension handshake.
+        let Some(reply_id) = state
+            .extension_handshake
+            .as_ref()
+            .and_then(|h| h.extension_id(extension::UT_METADATA))
+        else {
+            return Ok(None);
+        };
+
+        Ok(Some(MessageType::Extended {
+            id: reply_id,
+            payload: MetadataMessage::respond(metadata, piece).to_bytes()?,
+        }))
+    }
+
+    /// Processes a ut_comment message. We have no comments of our own to
+    /// share, so requests get an empty response.
+    fn handle_comment(
+        state: &mut PeerState,
+        payload: &[u8],
+    ) -> Result<Option<MessageType>, anyhow::Error> {
+        match CommentMessage::from_bytes(payload)? {
+            CommentMessage::Request { .. } => {
+                let Some(reply_id) = state
+                    .extension_handshake
+                    .as_ref()
+                    .and_then(|h| h.extension_id(extension::UT_COMMENT))
+                else {
+                    return Ok(None);
+                };
+
+                Ok(Some(MessageType::Extended {
+                    id: reply_id,
+                    payload: CommentMessage::Response { comments: vec![] }.to_bytes()?,
+                }))
+            }
+            CommentMessage::Response { mut comments } => {
+                comments.truncate(MAX_COMMENTS);
+                state.comments = comments;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Processes a ut_holepunch message from a peer that sends them
+    /// unasked, as we don't advertise the extension. We can't act as a
+    /// relay, so a rendezvous is refused.
+    fn handle_holepunch(
+        state: &PeerState,
+        payload: &[u8],
+    ) -> Result<Option<MessageType>, anyhow::Error> {
+        let message = HolepunchMessage::from_bytes(payload)?;
+
+        match message.kind {
+            HolepunchKind::Rendezvous => {
+                let Some(reply_id) = state
+                    .extension_handshake
+                    .as_ref()
+                    .and_then(|h| h.extension_id(extension::UT_HOLEPUNCH))
+                else {
+                    return Ok(None);
+                };
+
+                let reply = HolepunchMessage::error(message.addr, HolepunchError::NotConnected);
+                Ok(Some(MessageType::Extended {
+                    id: reply_id,
+                    payload: reply.to_bytes(),
+                }))
+            }
+            HolepunchKind::Connect => {
+                println!(
+                    "Peer asked us to holepunch to {}",
+                    privacy::address(&message.addr.to_string())
+                );
+                Ok(None)
+            }
+            HolepunchKind::Error(e) => {
+                println!(
+                    "Holepunch to {} failed: {e:?}",
+                    privacy::address(&message.addr.to_string())
+                );
+                Ok(None)
+            }
         }
     }
 
@@ -329,6 +1700,20 @@ impl PeerSession {
         Ok(())
     }
 
+    /// Sends `cancels` in one write, logging rather than failing, as the
+    /// listener notices a dead connection.
+    async fn send_cancels(
+        writer: &Mutex<OwnedWriteHalf>,
+        cancels: &[MessageType],
+        hooks: &WireHooks,
+    ) {
+        let bytes: Vec<u8> = cancels.iter().flat_map(MessageType::to_bytes).collect();
+        match writer.lock().await.write_all(&bytes).await {
+            Ok(()) => cancels.iter().for_each(|cancel| hooks.sent(cancel)),
+            Err(e) => eprintln!("{e}"),
+        }
+    }
+
     pub async fn send_request(
         writer: &mut OwnedWriteHalf,
         piece_index: u32,
@@ -357,6 +1742,7 @@ impl PeerSession {
 #[cfg(test)]
 mod peer_session_tests {
     use super::*;
+    use crate::torrent::piece_manager::PieceRequest;
 
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
     use tokio::net::{TcpListener, TcpStream};
@@ -376,7 +1762,7 @@ mod peer_session_tests {
 
             // Read incoming handshake (should be 68 bytes)
             let mut handshake = [0u8; 68];
-            let count = socket.read(&mut handshake).await.unwrap();
+            socket.read_exact(&mut handshake).await.unwrap();
 
             // Write a valid BitTorrent handshake request
             let mut request = Vec::new();
@@ -410,6 +1796,230 @@ mod peer_session_tests {
         PeerSession::read_handshake(&mut reader).await.unwrap();
     }
 
+    #[test]
+    fn test_request_limit_honours_reqq() {
+        let mut state = PeerState::default();
+        assert_eq!(state.request_limit(), INITIAL_QUEUE_DEPTH);
+
+        let mut handshake = ExtensionHandshake {
+            reqq: Some(2),
+            ..Default::default()
+        };
+        state.extension_handshake = Some(handshake.clone());
+        assert_eq!(state.request_limit(), 2);
+
+        // A larger queue doesn't raise our own pipeline depth.
+        handshake.reqq = Some(250);
+        state.extension_handshake = Some(handshake);
+        assert_eq!(state.request_limit(), INITIAL_QUEUE_DEPTH);
+
+        state.is_snubbed = true;
+        assert_eq!(state.request_limit(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keep_alive_after_idle_interval() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let stream = TcpStream::connect(address).await.unwrap();
+        let (mut peer, _) = listener.accept().await.unwrap();
+        let (_reader, writer) = stream.into_split();
+
+        let session = PeerSession::new(&address.to_string(), MOCK_CLIENT_ID, MOCK_INFO_HASH)
+            .await
+            .unwrap();
+        let hooks = session.hooks.clone();
+        let start = Instant::now();
+        let keep_alive = tokio::spawn(PeerSession::peer_keep_alive(
+            Arc::new(Mutex::new(writer)),
+            hooks.clone(),
+        ));
+
+        // Sending anything else puts the next KeepAlive off.
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        hooks.sent(&MessageType::Interested);
+
+        let mut message = [0u8; 4];
+        peer.read_exact(&mut message).await.unwrap();
+        assert_eq!(message, [0, 0, 0, 0]);
+        assert!(start.elapsed() >= Duration::from_secs(160));
+
+        keep_alive.abort();
+    }
+
+    #[test]
+    fn test_haves_complete_bitfield() {
+        let mut state = PeerState {
+            bitfield: vec![0b1110_0000],
+            ..Default::default()
+        };
+        assert!(!state.has_all(4));
+
+        state.set_piece(3).unwrap();
+        assert!(state.has_all(4));
+
+        // Pieces past the bitfield it sent grow it.
+        state.set_piece(9).unwrap();
+        assert!(state.has_piece(9));
+        assert!(!state.has_all(10));
+    }
+
+    #[test]
+    fn test_bitfield_checked_against_piece_count() {
+        let mut state = PeerState::default();
+        state.set_piece_count(10);
+        assert_eq!(state.bitfield, [0, 0]);
+        assert!(!state.has_piece(40));
+
+        assert!(state.set_bitfield(vec![0xff]).is_err());
+        assert!(state.set_bitfield(vec![0xff, 0xc0, 0]).is_err());
+        // Pieces 10 to 15 don't exist.
+        assert!(state.set_bitfield(vec![0xff, 0xe0]).is_err());
+        state.set_bitfield(vec![0xff, 0xc0]).unwrap();
+        assert!(state.has_all(10));
+
+        assert!(state.set_piece(10).is_err());
+        state.set_piece(9).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_interest_follows_peer_pieces() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let stream = TcpStream::connect(address).await.unwrap();
+        let (mut remote, _) = listener.accept().await.unwrap();
+        let (_reader, writer) = stream.into_split();
+        let writer = Mutex::new(writer);
+
+        let session = PeerSession::new(&address.to_string(), MOCK_CLIENT_ID, MOCK_INFO_HASH)
+            .await
+            .unwrap();
+        let peer = SharedState {
+            state: Arc::new(Mutex::new(PeerState {
+                is_interested: true,
+                bitfield: vec![0],
+                ..Default::default()
+            })),
+            changed: Arc::new(Notify::new()),
+            transfer: Arc::default(),
+            work: Arc::new(Mutex::new(WorkQueue::new())),
+            session: session.id,
+        };
+        let mut message = [0u8; 5];
+
+        // Nothing to get from the peer.
+        PeerSession::update_interest(&peer, None, &writer, &session.hooks)
+            .await
+            .unwrap();
+        remote.read_exact(&mut message).await.unwrap();
+        assert_eq!(message, [0, 0, 0, 1, 3]);
+
+        // Until a Have arrives.
+        peer.state.lock().await.set_piece(12).unwrap();
+        PeerSession::update_interest(&peer, None, &writer, &session.hooks)
+            .await
+            .unwrap();
+        remote.read_exact(&mut message).await.unwrap();
+        assert_eq!(message, [0, 0, 0, 1, 2]);
+        assert!(peer.state.lock().await.is_interested);
+    }
+
+    #[tokio::test]
+    async fn test_survives_burst_of_blocks() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let stream = TcpStream::connect(address).await.unwrap();
+        let (mut remote, _) = listener.accept().await.unwrap();
+
+        let mut session = PeerSession::new(&address.to_string(), MOCK_CLIENT_ID, MOCK_INFO_HASH)
+            .await
+            .unwrap();
+        let mut handshake = [0u8; 68];
+        handshake[0] = 19;
+        handshake[1..20].copy_from_slice(b"BitTorrent protocol");
+        handshake[28..48].copy_from_slice(&MOCK_INFO_HASH);
+        handshake[48..68].copy_from_slice(&MOCK_PEER_ID);
+        let work = Arc::new(Mutex::new(WorkQueue::new()));
+        let (piece_tx, _piece_rx) = channel(1);
+        session
+            .accept(stream, handshake, Arc::clone(&work), piece_tx)
+            .await
+            .unwrap();
+        remote.read_exact(&mut handshake).await.unwrap();
+
+        // A full pipeline of blocks in one go, as a fast peer answers a
+        // deep one, while the requester is kept from taking any.
+        let queue = work.lock().await;
+        let burst: Vec<u8> = (0..pipeline::MAX_QUEUE_DEPTH as u32)
+            .flat_map(|n| {
+                MessageType::Piece {
+                    index: 0,
+                    begin: n * 16384,
+                    block: vec![0],
+                }
+                .to_bytes()
+            })
+            .collect();
+        remote.write_all(&burst).await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(session.handle().end_reason(), None);
+        drop(queue);
+    }
+
+    #[test]
+    fn test_small_pieces_fill_pipeline() {
+        let piece = |length_bytes| {
+            PieceWork::from(PieceRequest {
+                piece_index: 0,
+                length_bytes,
+            })
+        };
+
+        // One 16 KiB piece is a single block, four more fit alongside.
+        let mut pieces = vec![piece(16 * 1024)];
+        while needs_more_pieces(&pieces, INITIAL_QUEUE_DEPTH) {
+            pieces.push(piece(16 * 1024));
+        }
+        assert_eq!(pieces.len(), INITIAL_QUEUE_DEPTH);
+
+        // A large piece fills it alone.
+        assert!(!needs_more_pieces(
+            &[piece(256 * 1024)],
+            INITIAL_QUEUE_DEPTH
+        ));
+        assert!(needs_more_pieces(&[], INITIAL_QUEUE_DEPTH));
+    }
+
+    #[test]
+    fn test_comments_requested_and_stored() {
+        let mut state = PeerState::default();
+
+        let reply = PeerSession::handle_extended(
+            &mut state,
+            extension::HANDSHAKE_ID,
+            b"d1:md10:ut_commenti7eee",
+            None,
+        )
+        .unwrap();
+        assert!(matches!(reply, Some(MessageType::Extended { id: 7, .. })));
+
+        let response = CommentMessage::Response {
+            comments: vec![Comment {
+                owner: String::new(),
+                text: String::from("seeded from a NAS"),
+            }],
+        };
+        PeerSession::handle_extended(
+            &mut state,
+            extension::UT_COMMENT_ID,
+            &response.to_bytes().unwrap(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(state.comments.len(), 1);
+    }
+
     #[tokio::test]
     #[ignore]
     pub async fn test_download() {
@@ -424,7 +2034,7 @@ mod peer_session_tests {
 
         let port = 6137;
 
-        let piece_request_rx = Arc::new(Mutex::new(VecDeque::new()));
+        let piece_request_rx = Arc::new(Mutex::new(WorkQueue::new()));
         let (piece_request_tx, mut piece_requester_rx) = channel::<PieceResponse>(100);
 
         // Connect to another client hosting the torrent locally for testing.
@@ -442,7 +2052,7 @@ mod peer_session_tests {
         for i in 0..num_pieces {
             let mut queue = piece_request_rx.lock().await;
 
-            queue.push_back(PieceRequest {
+            queue.push(PieceRequest {
                 piece_index: i,
                 length_bytes: piece_length as usize,
             });
@@ -0,0 +1,98 @@
+//! Optional protocol features advertised in the reserved bytes of the
+//! handshake.
+
+use super::extension::{EXTENSION_RESERVED_BIT, EXTENSION_RESERVED_BYTE};
+
+/// Reserved byte and bits of the DHT (BEP 5) and fast extension (BEP 6).
+const DHT_RESERVED_BYTE: usize = 7;
+const DHT_RESERVED_BIT: u8 = 0x01;
+const FAST_RESERVED_BYTE: usize = 7;
+const FAST_RESERVED_BIT: u8 = 0x04;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// The peer runs a DHT node and accepts Port messages.
+    pub dht: bool,
+    /// The peer supports the fast extension messages.
+    pub fast: bool,
+    /// The peer supports the BEP 10 extension protocol.
+    pub extension_protocol: bool,
+}
+
+impl Capabilities {
+    /// Features we support and advertise ourselves.
+    pub fn ours() -> Self {
+        Self {
+            dht: false,
+            fast: false,
+            extension_protocol: true,
+        }
+    }
+
+    pub fn from_reserved(reserved: &[u8]) -> Self {
+        let bit = |byte: usize, bit: u8| reserved.get(byte).is_some_and(|b| b & bit != 0);
+
+        Self {
+            dht: bit(DHT_RESERVED_BYTE, DHT_RESERVED_BIT),
+            fast: bit(FAST_RESERVED_BYTE, FAST_RESERVED_BIT),
+            extension_protocol: bit(EXTENSION_RESERVED_BYTE, EXTENSION_RESERVED_BIT),
+        }
+    }
+
+    pub fn to_reserved(self) -> [u8; 8] {
+        let mut reserved = [0u8; 8];
+
+        if self.dht {
+            reserved[DHT_RESERVED_BYTE] |= DHT_RESERVED_BIT;
+        }
+        if self.fast {
+            reserved[FAST_RESERVED_BYTE] |= FAST_RESERVED_BIT;
+        }
+        if self.extension_protocol {
+            reserved[EXTENSION_RESERVED_BYTE] |= EXTENSION_RESERVED_BIT;
+        }
+
+        reserved
+    }
+
+    /// Features both sides support, which are the only ones either may use.
+    pub fn negotiate(self, other: Self) -> Self {
+        Self {
+            dht: self.dht && other.dht,
+            fast: self.fast && other.fast,
+            extension_protocol: self.extension_protocol && other.extension_protocol,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reserved_round_trip() {
+        let all = Capabilities {
+            dht: true,
+            fast: true,
+            extension_protocol: true,
+        };
+
+        assert_eq!(all.to_reserved(), [0, 0, 0, 0, 0, 0x10, 0, 0x05]);
+        assert_eq!(Capabilities::from_reserved(&all.to_reserved()), all);
+        assert_eq!(
+            Capabilities::from_reserved(&[0; 8]),
+            Capabilities::default()
+        );
+    }
+
+    #[test]
+    fn test_negotiate_keeps_shared_features() {
+        let theirs = Capabilities::from_reserved(&[0, 0, 0, 0, 0, 0x10, 0, 0x05]);
+
+        assert_eq!(Capabilities::ours().negotiate(theirs), Capabilities::ours());
+        assert_eq!(
+            Capabilities::ours().negotiate(Capabilities::default()),
+            Capabilities::default()
+        );
+    }
+}
@@ -0,0 +1,148 @@
+//! Opt-in wire log of the messages exchanged with a single peer.
+//!
+//! Each message is written as a timestamped header line followed by a
+//! hex dump of its bytes, for troubleshooting interop problems with
+//! specific clients.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Error;
+use chrono::Utc;
+
+use super::message::MessageType;
+
+/// Bytes of each message included in the dump, so piece payloads don't
+/// balloon the log.
+const MAX_DUMP_BYTES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// Shared handle to a peer's capture, cheap to clone into the session's
+/// reader and writer tasks. Recording is a no-op while capture is off.
+#[derive(Clone, Default)]
+pub struct CaptureHandle {
+    inner: Arc<Mutex<Option<BufWriter<File>>>>,
+}
+
+impl CaptureHandle {
+    /// Starts writing the wire log to `path`, replacing any running capture.
+    pub fn start(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let file = File::create(path)?;
+        *self.inner.lock().unwrap() = Some(BufWriter::new(file));
+
+        Ok(())
+    }
+
+    /// Stops the capture and flushes everything written so far.
+    pub fn stop(&self) -> Result<(), Error> {
+        if let Some(mut writer) = self.inner.lock().unwrap().take() {
+            writer.flush()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.inner.lock().unwrap().is_some()
+    }
+
+    /// Appends one message to the log if capture is on.
+    pub(super) fn record(&self, direction: Direction, message: &MessageType) {
+        let mut guard = self.inner.lock().unwrap();
+        let Some(writer) = guard.as_mut() else {
+            return;
+        };
+
+        if let Err(e) = write_entry(writer, direction, message.name(), &message.to_bytes()) {
+            eprintln!("ERROR: Stopping peer capture, write failed: {e}");
+            *guard = None;
+        }
+    }
+}
+
+fn write_entry(
+    writer: &mut impl Write,
+    direction: Direction,
+    name: &str,
+    bytes: &[u8],
+) -> std::io::Result<()> {
+    let arrow = match direction {
+        Direction::Sent => ">>",
+        Direction::Received => "<<",
+    };
+
+    writeln!(
+        writer,
+        "{} {arrow} {name} ({} bytes)",
+        Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+        bytes.len()
+    )?;
+    write!(writer, "{}", hex_dump(bytes))?;
+
+    if bytes.len() > MAX_DUMP_BYTES {
+        writeln!(
+            writer,
+            "    ... {} more bytes",
+            bytes.len() - MAX_DUMP_BYTES
+        )?;
+    }
+
+    writer.flush()
+}
+
+/// Formats up to [`MAX_DUMP_BYTES`] as offset-prefixed lines of 16 bytes.
+fn hex_dump(bytes: &[u8]) -> String {
+    bytes[..bytes.len().min(MAX_DUMP_BYTES)]
+        .chunks(16)
+        .enumerate()
+        .map(|(line, chunk)| {
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{b:02x}")).collect();
+            format!("    {:04x}: {}\n", line * 16, hex.join(" "))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex_dump_lines() {
+        let bytes: Vec<u8> = (0..20).collect();
+
+        assert_eq!(
+            hex_dump(&bytes),
+            "    0000: 00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f\n    0010: 10 11 12 13\n"
+        );
+    }
+
+    #[test]
+    fn test_write_entry_truncates_large_messages() {
+        let mut out = vec![];
+        write_entry(&mut out, Direction::Received, "Piece", &[0u8; 100]).unwrap();
+
+        let text = String::from_utf8(out).unwrap();
+
+        assert!(text.contains("<< Piece (100 bytes)"));
+        assert_eq!(text.lines().filter(|l| l.contains(": 00")).count(), 4);
+        assert!(text.ends_with("    ... 36 more bytes\n"));
+    }
+
+    #[test]
+    fn test_record_is_noop_when_inactive() {
+        let handle = CaptureHandle::default();
+
+        handle.record(Direction::Sent, &MessageType::KeepAlive);
+
+        assert!(!handle.is_active());
+    }
+}
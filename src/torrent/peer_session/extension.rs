@@ -0,0 +1,135 @@
+//! BEP 10 extension protocol.
+//!
+//! Peers that set the extension bit in their handshake exchange an
+//! extension handshake (extended message id 0) mapping extension names to
+//! the message ids each side wants to receive them on.
+
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Error};
+use serde_derive::{Deserialize, Serialize};
+
+pub mod comment;
+pub mod holepunch;
+pub mod metadata;
+
+/// Reserved handshake byte and bit that advertise BEP 10 support.
+pub const EXTENSION_RESERVED_BYTE: usize = 5;
+pub const EXTENSION_RESERVED_BIT: u8 = 0x10;
+
+/// Extended message id of the extension handshake.
+pub const HANDSHAKE_ID: u8 = 0;
+
+/// Id we ask peers to use when sending us ut_metadata messages.
+pub const UT_METADATA_ID: u8 = 1;
+
+/// Id kept for ut_holepunch messages. Not advertised while we can neither
+/// relay a rendezvous nor dial the peer a relay points us to.
+pub const UT_HOLEPUNCH_ID: u8 = 2;
+
+/// Id we ask peers to use when sending us ut_comment messages.
+pub const UT_COMMENT_ID: u8 = 3;
+
+pub const UT_METADATA: &str = "ut_metadata";
+pub const UT_HOLEPUNCH: &str = "ut_holepunch";
+pub const UT_COMMENT: &str = "ut_comment";
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Default)]
+pub struct ExtensionHandshake {
+    /// Extension names mapped to the message id the sender receives them on.
+    #[serde(default)]
+    pub m: BTreeMap<String, u8>,
+    /// Size of the info dictionary in bytes, sent by peers that have it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<u64>,
+    /// Client name and version.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+    /// Set to 1 by partial seeds (BEP 21) that won't download anything.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload_only: Option<u8>,
+    /// Number of outstanding requests the sender will queue before
+    /// dropping new ones.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<u32>,
+}
+
+impl ExtensionHandshake {
+    /// The handshake we send, advertising the extensions we support and,
+    /// when we have the info dictionary, its size.
+    pub fn ours(metadata_size: Option<usize>) -> Self {
+        Self {
+            m: BTreeMap::from([
+                (String::from(UT_METADATA), UT_METADATA_ID),
+                (String::from(UT_COMMENT), UT_COMMENT_ID),
+            ]),
+            metadata_size: metadata_size.map(|size| size as u64),
+            v: Some(format!("btrs {}", env!("CARGO_PKG_VERSION"))),
+            upload_only: None,
+            reqq: None,
+        }
+    }
+
+    pub fn is_upload_only(&self) -> bool {
+        self.upload_only.is_some_and(|flag| flag != 0)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        serde_bencode::from_bytes(bytes).context("Invalid extension handshake")
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        serde_bencode::to_bytes(self).context("Failed to encode extension handshake")
+    }
+
+    /// Message id the remote peer wants `extension` messages sent on, if
+    /// it supports it. An id of 0 means the extension was disabled.
+    pub fn extension_id(&self, extension: &str) -> Option<u8> {
+        self.m.get(extension).copied().filter(|id| *id != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handshake_round_trip() {
+        let ours = ExtensionHandshake::ours(Some(1234));
+
+        let bytes = ours.to_bytes().unwrap();
+        let parsed = ExtensionHandshake::from_bytes(&bytes).unwrap();
+
+        assert_eq!(parsed, ours);
+        assert_eq!(parsed.extension_id(UT_METADATA), Some(UT_METADATA_ID));
+        assert_eq!(parsed.extension_id(UT_HOLEPUNCH), None);
+    }
+
+    #[test]
+    fn test_handshake_tolerates_unknown_keys() {
+        let bytes = b"d1:md11:ut_metadatai3e6:ut_pexi0ee13:metadata_sizei31235e4:reqqi250ee";
+
+        let parsed = ExtensionHandshake::from_bytes(bytes).unwrap();
+
+        assert_eq!(parsed.metadata_size, Some(31235));
+        assert_eq!(parsed.reqq, Some(250));
+        assert_eq!(parsed.extension_id(UT_METADATA), Some(3));
+        assert_eq!(parsed.extension_id("ut_pex"), None);
+        assert!(!parsed.is_upload_only());
+    }
+
+    #[test]
+    fn test_upload_only() {
+        let mut ours = ExtensionHandshake::ours(None);
+        ours.upload_only = Some(1);
+
+        let bytes = ours.to_bytes().unwrap();
+
+        assert!(String::from_utf8_lossy(&bytes).contains("11:upload_onlyi1e"));
+        assert!(
+            ExtensionHandshake::from_bytes(&bytes)
+                .unwrap()
+                .is_upload_only()
+        );
+    }
+}
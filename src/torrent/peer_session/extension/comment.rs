@@ -0,0 +1,102 @@
+//! ut_comment messages, used by some clients to share the comments their
+//! users left on a torrent.
+
+use anyhow::{Context, Error, bail};
+use serde_derive::{Deserialize, Serialize};
+
+/// Most comments we ask for, and keep, per peer.
+pub const MAX_COMMENTS: usize = 20;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Comment {
+    /// Name the commenter chose, if any.
+    #[serde(default)]
+    pub owner: String,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommentMessage {
+    Request { num: u32 },
+    Response { comments: Vec<Comment> },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct CommentPayload {
+    msg_type: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    num: Option<u32>,
+    #[serde(default)]
+    comments: Vec<Comment>,
+}
+
+impl CommentMessage {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let payload = match self {
+            Self::Request { num } => CommentPayload {
+                msg_type: 0,
+                num: Some(*num),
+                comments: vec![],
+            },
+            Self::Response { comments } => CommentPayload {
+                msg_type: 1,
+                num: None,
+                comments: comments.clone(),
+            },
+        };
+
+        serde_bencode::to_bytes(&payload).context("Failed to encode ut_comment message")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let payload: CommentPayload =
+            serde_bencode::from_bytes(bytes).context("Invalid ut_comment message")?;
+
+        Ok(match payload.msg_type {
+            0 => Self::Request {
+                num: payload.num.unwrap_or(MAX_COMMENTS as u32),
+            },
+            1 => Self::Response {
+                comments: payload.comments,
+            },
+            other => bail!("Unknown ut_comment msg_type {other}"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_response_round_trip() {
+        let msg = CommentMessage::Response {
+            comments: vec![Comment {
+                owner: String::from("alice"),
+                text: String::from("Good quality, thanks"),
+            }],
+        };
+
+        let bytes = msg.to_bytes().unwrap();
+
+        assert_eq!(CommentMessage::from_bytes(&bytes).unwrap(), msg);
+    }
+
+    #[test]
+    fn test_request_and_missing_owner() {
+        let request = CommentMessage::from_bytes(b"d8:msg_typei0e3:numi5ee").unwrap();
+        assert_eq!(request, CommentMessage::Request { num: 5 });
+
+        let response =
+            CommentMessage::from_bytes(b"d8:commentsld4:text2:hiee8:msg_typei1ee").unwrap();
+        assert_eq!(
+            response,
+            CommentMessage::Response {
+                comments: vec![Comment {
+                    owner: String::new(),
+                    text: String::from("hi"),
+                }]
+            }
+        );
+    }
+}
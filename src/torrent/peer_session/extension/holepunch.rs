@@ -0,0 +1,161 @@
+//! ut_holepunch (BEP 55) messages, used by two peers behind NAT to
+//! connect to each other through a relay peer both are connected to.
+//!
+//! Unlike other extensions the payload is binary rather than bencoded:
+//! message type, address type, address, port and error code.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{Error, bail};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HolepunchKind {
+    /// Sent to a relay, asking it to connect us with `addr`.
+    Rendezvous,
+    /// Sent by a relay to both ends, asking them to connect to `addr`.
+    Connect,
+    /// Sent by a relay that could not forward a rendezvous.
+    Error(HolepunchError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HolepunchError {
+    /// The target endpoint is invalid.
+    NoSuchPeer,
+    /// The relay is not connected to the target.
+    NotConnected,
+    /// The target does not support holepunching.
+    NoSupport,
+    /// The target is the sender of the rendezvous.
+    NoSelf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HolepunchMessage {
+    pub kind: HolepunchKind,
+    pub addr: SocketAddr,
+}
+
+impl HolepunchError {
+    fn code(self) -> u32 {
+        match self {
+            Self::NoSuchPeer => 1,
+            Self::NotConnected => 2,
+            Self::NoSupport => 3,
+            Self::NoSelf => 4,
+        }
+    }
+
+    fn from_code(code: u32) -> Result<Self, Error> {
+        Ok(match code {
+            1 => Self::NoSuchPeer,
+            2 => Self::NotConnected,
+            3 => Self::NoSupport,
+            4 => Self::NoSelf,
+            other => bail!("Unknown ut_holepunch error code {other}"),
+        })
+    }
+}
+
+impl HolepunchMessage {
+    /// Reply from a relay that can't forward a rendezvous for `addr`.
+    pub fn error(addr: SocketAddr, error: HolepunchError) -> Self {
+        Self {
+            kind: HolepunchKind::Error(error),
+            addr,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (msg_type, err_code) = match self.kind {
+            HolepunchKind::Rendezvous => (0, 0),
+            HolepunchKind::Connect => (1, 0),
+            HolepunchKind::Error(e) => (2, e.code()),
+        };
+
+        let mut bytes = vec![msg_type];
+        match self.addr.ip() {
+            IpAddr::V4(ip) => {
+                bytes.push(0);
+                bytes.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&ip.octets());
+            }
+        }
+        bytes.extend_from_slice(&self.addr.port().to_be_bytes());
+        bytes.extend_from_slice(&err_code.to_be_bytes());
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let [msg_type, addr_type, rest @ ..] = bytes else {
+            bail!("Truncated ut_holepunch message");
+        };
+
+        let (ip, rest): (IpAddr, _) = match addr_type {
+            0 if rest.len() >= 4 => {
+                let octets: [u8; 4] = rest[..4].try_into()?;
+                (Ipv4Addr::from(octets).into(), &rest[4..])
+            }
+            1 if rest.len() >= 16 => {
+                let octets: [u8; 16] = rest[..16].try_into()?;
+                (Ipv6Addr::from(octets).into(), &rest[16..])
+            }
+            0 | 1 => bail!("Truncated ut_holepunch address"),
+            other => bail!("Unknown ut_holepunch address type {other}"),
+        };
+
+        let [p0, p1, e0, e1, e2, e3] = rest else {
+            bail!("ut_holepunch message has wrong length");
+        };
+        let addr = SocketAddr::new(ip, u16::from_be_bytes([*p0, *p1]));
+        let err_code = u32::from_be_bytes([*e0, *e1, *e2, *e3]);
+
+        let kind = match msg_type {
+            0 => HolepunchKind::Rendezvous,
+            1 => HolepunchKind::Connect,
+            2 => HolepunchKind::Error(HolepunchError::from_code(err_code)?),
+            other => bail!("Unknown ut_holepunch msg_type {other}"),
+        };
+
+        Ok(Self { kind, addr })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rendezvous_v4_layout() {
+        let msg = HolepunchMessage {
+            kind: HolepunchKind::Rendezvous,
+            addr: "10.0.0.2:6881".parse().unwrap(),
+        };
+
+        let bytes = msg.to_bytes();
+
+        assert_eq!(bytes, vec![0, 0, 10, 0, 0, 2, 0x1a, 0xe1, 0, 0, 0, 0]);
+        assert_eq!(HolepunchMessage::from_bytes(&bytes).unwrap(), msg);
+    }
+
+    #[test]
+    fn test_error_v6_round_trip() {
+        let msg = HolepunchMessage::error("[::1]:51413".parse().unwrap(), HolepunchError::NoSelf);
+
+        let bytes = msg.to_bytes();
+
+        assert_eq!(bytes.len(), 24);
+        assert_eq!(HolepunchMessage::from_bytes(&bytes).unwrap(), msg);
+    }
+
+    #[test]
+    fn test_rejects_malformed() {
+        assert!(HolepunchMessage::from_bytes(&[0, 0, 10, 0]).is_err());
+        assert!(HolepunchMessage::from_bytes(&[0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).is_err());
+        assert!(HolepunchMessage::from_bytes(&[2, 0, 10, 0, 0, 2, 0, 1, 0, 0, 0, 9]).is_err());
+    }
+}
@@ -0,0 +1,199 @@
+//! ut_metadata (BEP 9) messages, used to transfer the info dictionary
+//! between peers in 16 KiB pieces.
+
+use anyhow::{Context, Error, bail};
+use serde_derive::{Deserialize, Serialize};
+
+pub const METADATA_PIECE_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataMessage {
+    Request {
+        piece: u32,
+    },
+    Data {
+        piece: u32,
+        total_size: u64,
+        data: Vec<u8>,
+    },
+    Reject {
+        piece: u32,
+    },
+}
+
+/// Bencoded dictionary at the start of every ut_metadata message.
+#[derive(Serialize, Deserialize, Debug)]
+struct MetadataHeader {
+    msg_type: u8,
+    piece: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_size: Option<u64>,
+}
+
+impl MetadataMessage {
+    /// Answers a request for `piece` of our info dictionary, rejecting it
+    /// if we don't have the metadata or the piece is out of range.
+    pub fn respond(info: Option<&[u8]>, piece: u32) -> Self {
+        let start = piece as usize * METADATA_PIECE_SIZE;
+
+        match info {
+            Some(info) if start < info.len() => {
+                let end = (start + METADATA_PIECE_SIZE).min(info.len());
+
+                Self::Data {
+                    piece,
+                    total_size: info.len() as u64,
+                    data: info[start..end].to_vec(),
+                }
+            }
+            _ => Self::Reject { piece },
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let (header, data) = match self {
+            Self::Request { piece } => (
+                MetadataHeader {
+                    msg_type: 0,
+                    piece: *piece,
+                    total_size: None,
+                },
+                None,
+            ),
+            Self::Data {
+                piece,
+                total_size,
+                data,
+            } => (
+                MetadataHeader {
+                    msg_type: 1,
+                    piece: *piece,
+                    total_size: Some(*total_size),
+                },
+                Some(data),
+            ),
+            Self::Reject { piece } => (
+                MetadataHeader {
+                    msg_type: 2,
+                    piece: *piece,
+                    total_size: None,
+                },
+                None,
+            ),
+        };
+
+        let mut bytes =
+            serde_bencode::to_bytes(&header).context("Failed to encode ut_metadata header")?;
+        if let Some(data) = data {
+            bytes.extend_from_slice(data);
+        }
+
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let header_len = bencode_len(bytes).context("Truncated ut_metadata header")?;
+        let header: MetadataHeader = serde_bencode::from_bytes(&bytes[..header_len])
+            .context("Invalid ut_metadata header")?;
+
+        Ok(match header.msg_type {
+            0 => Self::Request {
+                piece: header.piece,
+            },
+            1 => Self::Data {
+                piece: header.piece,
+                total_size: header
+                    .total_size
+                    .context("ut_metadata data without total_size")?,
+                data: bytes[header_len..].to_vec(),
+            },
+            2 => Self::Reject {
+                piece: header.piece,
+            },
+            other => bail!("Unknown ut_metadata msg_type {other}"),
+        })
+    }
+}
+
+/// Length in bytes of the bencoded value at the start of `bytes`, so that
+/// trailing raw data can be split off.
+fn bencode_len(bytes: &[u8]) -> Option<usize> {
+    match bytes.first()? {
+        b'i' => Some(bytes.iter().position(|b| *b == b'e')? + 1),
+        b'l' | b'd' => {
+            let mut pos = 1;
+            while *bytes.get(pos)? != b'e' {
+                pos += bencode_len(&bytes[pos..])?;
+            }
+            Some(pos + 1)
+        }
+        b'0'..=b'9' => {
+            let colon = bytes.iter().position(|b| *b == b':')?;
+            let len: usize = std::str::from_utf8(&bytes[..colon]).ok()?.parse().ok()?;
+            let end = colon + 1 + len;
+            (end <= bytes.len()).then_some(end)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_round_trip() {
+        let msg = MetadataMessage::Request { piece: 2 };
+
+        let bytes = msg.to_bytes().unwrap();
+
+        assert_eq!(bytes, b"d8:msg_typei0e5:piecei2ee");
+        assert_eq!(MetadataMessage::from_bytes(&bytes).unwrap(), msg);
+    }
+
+    #[test]
+    fn test_data_round_trip_keeps_trailing_bytes() {
+        let msg = MetadataMessage::Data {
+            piece: 0,
+            total_size: 5,
+            data: b"d1:ee".to_vec(),
+        };
+
+        let bytes = msg.to_bytes().unwrap();
+
+        assert_eq!(
+            bytes,
+            b"d8:msg_typei1e5:piecei0e10:total_sizei5eed1:ee".to_vec()
+        );
+        assert_eq!(MetadataMessage::from_bytes(&bytes).unwrap(), msg);
+    }
+
+    #[test]
+    fn test_respond_chunks_info_dict() {
+        let info = vec![7u8; METADATA_PIECE_SIZE + 10];
+
+        let MetadataMessage::Data {
+            total_size, data, ..
+        } = MetadataMessage::respond(Some(&info), 1)
+        else {
+            panic!("expected data");
+        };
+
+        assert_eq!(total_size, info.len() as u64);
+        assert_eq!(data.len(), 10);
+    }
+
+    #[test]
+    fn test_respond_rejects_out_of_range_or_missing() {
+        let info = vec![0u8; 100];
+
+        assert_eq!(
+            MetadataMessage::respond(Some(&info), 1),
+            MetadataMessage::Reject { piece: 1 }
+        );
+        assert_eq!(
+            MetadataMessage::respond(None, 0),
+            MetadataMessage::Reject { piece: 0 }
+        );
+    }
+}
@@ -0,0 +1,174 @@
+//! How a peer session ended, and returning its work when it does.
+//!
+//! The listener is the task that notices a connection going away, so its
+//! exit decides the [`EndReason`] the session reports through its handle.
+//! The requester holds a [`WorkGuard`], so the pieces it was working on go
+//! back to the queue however it ends, aborts included.
+
+use std::{
+    fmt,
+    io::ErrorKind,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::Mutex as AsyncMutex;
+
+use crate::torrent::{
+    piece_manager::{SessionId, WorkQueue},
+    tasks::{self, Subsystem},
+    timeout::TimeoutError,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EndReason {
+    /// The connection or handshake never completed.
+    HandshakeFailed(String),
+    /// The peer closed the connection.
+    Closed,
+    /// The connection broke, e.g. it was reset.
+    ConnectionReset(String),
+    /// The peer sent nothing for too long.
+    TimedOut,
+    /// The peer sent something we can't accept.
+    ProtocolViolation(String),
+    /// We closed the connection, e.g. as both sides are seeds or through
+    /// `SessionHandle::kill`.
+    Killed,
+    /// A bug on our side, the session's task panicked.
+    Crashed(String),
+}
+
+impl fmt::Display for EndReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EndReason::HandshakeFailed(e) => write!(f, "handshake failed: {e}"),
+            EndReason::Closed => write!(f, "closed by peer"),
+            EndReason::ConnectionReset(e) => write!(f, "connection lost: {e}"),
+            EndReason::TimedOut => write!(f, "timed out"),
+            EndReason::ProtocolViolation(e) => write!(f, "protocol violation: {e}"),
+            EndReason::Killed => write!(f, "closed by us"),
+            EndReason::Crashed(e) => write!(f, "crashed: {e}"),
+        }
+    }
+}
+
+impl EndReason {
+    /// Reason for the listener failing with `error`. Errors that aren't
+    /// about the connection come from decoding or checking the peer's
+    /// messages.
+    pub fn from_error(error: &anyhow::Error) -> Self {
+        if error.downcast_ref::<TimeoutError>().is_some() {
+            return EndReason::TimedOut;
+        }
+
+        match error
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<std::io::Error>())
+        {
+            Some(e) if e.kind() == ErrorKind::UnexpectedEof => EndReason::Closed,
+            Some(e) => EndReason::ConnectionReset(e.to_string()),
+            None => EndReason::ProtocolViolation(format!("{error:#}")),
+        }
+    }
+}
+
+/// Where a session records its [`EndReason`], shared with its handles.
+/// Only the first reason recorded is kept.
+#[derive(Debug, Clone, Default)]
+pub struct EndSlot(Arc<Mutex<Option<EndReason>>>);
+
+impl EndSlot {
+    pub fn set(&self, reason: EndReason) {
+        self.0.lock().unwrap().get_or_insert(reason);
+    }
+
+    pub fn get(&self) -> Option<EndReason> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Releases every piece held by a session when dropped.
+pub struct WorkGuard {
+    queue: Arc<AsyncMutex<WorkQueue>>,
+    session: SessionId,
+}
+
+impl WorkGuard {
+    pub fn new(queue: Arc<AsyncMutex<WorkQueue>>, session: SessionId) -> Self {
+        Self { queue, session }
+    }
+}
+
+impl Drop for WorkGuard {
+    fn drop(&mut self) {
+        let queue = Arc::clone(&self.queue);
+        let session = self.session;
+        // Dropping may happen outside the runtime's reach, e.g. while it
+        // shuts down, when nobody needs the work any more anyway.
+        if tokio::runtime::Handle::try_current().is_ok() {
+            tasks::spawn(Subsystem::Peer, async move {
+                queue.lock().await.release_session(session);
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::anyhow;
+
+    use super::*;
+    use crate::torrent::piece_manager::PieceRequest;
+
+    #[test]
+    fn test_reason_from_error() {
+        let timeout = anyhow::Error::from(TimeoutError {
+            operation: "peer message",
+            after: Duration::from_secs(120),
+        });
+        assert_eq!(EndReason::from_error(&timeout), EndReason::TimedOut);
+
+        let eof = anyhow::Error::from(std::io::Error::from(ErrorKind::UnexpectedEof));
+        assert_eq!(EndReason::from_error(&eof), EndReason::Closed);
+
+        let reset = anyhow::Error::from(std::io::Error::from(ErrorKind::ConnectionReset))
+            .context("reading message");
+        assert!(matches!(
+            EndReason::from_error(&reset),
+            EndReason::ConnectionReset(_)
+        ));
+
+        assert!(matches!(
+            EndReason::from_error(&anyhow!("Unknown message id 99")),
+            EndReason::ProtocolViolation(_)
+        ));
+
+        let slot = EndSlot::default();
+        slot.set(EndReason::Closed);
+        slot.set(EndReason::Killed);
+        assert_eq!(slot.get(), Some(EndReason::Closed));
+    }
+
+    #[tokio::test]
+    async fn test_guard_returns_work() {
+        let queue = Arc::new(AsyncMutex::new(WorkQueue::new()));
+        queue.lock().await.push(PieceRequest {
+            piece_index: 0,
+            length_bytes: 16384,
+        });
+        assert!(queue.lock().await.next_for(7, |_| true).is_some());
+        assert_eq!(queue.lock().await.pending_len(), 0);
+
+        drop(WorkGuard::new(Arc::clone(&queue), 7));
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while queue.lock().await.pending_len() == 0 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(queue.lock().await.assigned_len(), 0);
+    }
+}
@@ -25,10 +25,33 @@ pub enum MessageType {
         length: u32,
     },
     Port(u16),
+    /// BEP 10 extension message, `id` 0 is the extension handshake.
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
     KeepAlive,
 }
 
 impl MessageType {
+    /// Name of the message variant, without its payload.
+    pub fn name(&self) -> &'static str {
+        match self {
+            MessageType::Choke => "Choke",
+            MessageType::Unchoke => "Unchoke",
+            MessageType::Interested => "Interested",
+            MessageType::NotInterested => "NotInterested",
+            MessageType::Have(_) => "Have",
+            MessageType::Bitfield(_) => "Bitfield",
+            MessageType::Request { .. } => "Request",
+            MessageType::Piece { .. } => "Piece",
+            MessageType::Cancel { .. } => "Cancel",
+            MessageType::Port(_) => "Port",
+            MessageType::Extended { .. } => "Extended",
+            MessageType::KeepAlive => "KeepAlive",
+        }
+    }
+
     pub fn from_bytes(bytes: &mut BytesMut, id: u8, len: u32) -> Result<Self, anyhow::Error> {
         if bytes.len() < 4 {
             bail!("Message {bytes:?} invalid");
@@ -95,6 +118,12 @@ impl MessageType {
                 let port = bytes.get_u16();
                 Self::Port(port)
             }
+            20 => {
+                let id = bytes.get_u8();
+                let payload = bytes[..(len as usize - 2)].to_vec();
+
+                Self::Extended { id, payload }
+            }
             _ => bail!("Invalid message id {id}"),
         })
     }
@@ -169,6 +198,13 @@ impl MessageType {
                 message.push(9u8);
                 message.extend_from_slice(&port.to_be_bytes());
             }
+            MessageType::Extended { id, payload } => {
+                let len: u32 = 2 + payload.len() as u32;
+                message.extend_from_slice(&len.to_be_bytes());
+                message.push(20u8);
+                message.push(*id);
+                message.extend(payload);
+            }
             MessageType::KeepAlive => message.extend_from_slice(&0u32.to_be_bytes()),
         }
 
@@ -203,22 +239,22 @@ mod tests {
 
     #[test]
     fn test_choke_round_trip() {
-        round_trip(MessageType::Choke, &vec![0, 0, 0, 1, 0]);
+        round_trip(MessageType::Choke, &[0, 0, 0, 1, 0]);
     }
 
     #[test]
     fn test_unchoke_round_trip() {
-        round_trip(MessageType::Unchoke, &vec![0, 0, 0, 1, 1]);
+        round_trip(MessageType::Unchoke, &[0, 0, 0, 1, 1]);
     }
 
     #[test]
     fn test_interested_round_trip() {
-        round_trip(MessageType::Interested, &vec![0, 0, 0, 1, 2]);
+        round_trip(MessageType::Interested, &[0, 0, 0, 1, 2]);
     }
 
     #[test]
     fn test_not_interested_round_trip() {
-        round_trip(MessageType::NotInterested, &vec![0, 0, 0, 1, 3]);
+        round_trip(MessageType::NotInterested, &[0, 0, 0, 1, 3]);
     }
 
     #[test]
@@ -302,8 +338,19 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_extended_round_trip() {
+        round_trip(
+            MessageType::Extended {
+                id: 3,
+                payload: b"de".to_vec(),
+            },
+            &[0, 0, 0, 4, 20, 3, b'd', b'e'],
+        );
+    }
+
     #[test]
     fn test_keep_alive_round_trip() {
-        round_trip(MessageType::KeepAlive, &vec![0, 0, 0, 0]);
+        round_trip(MessageType::KeepAlive, &[0, 0, 0, 0]);
     }
 }
@@ -0,0 +1,107 @@
+//! Counts of the messages exchanged with peers, by message type.
+//!
+//! Each session keeps its own counts and every message is also added to
+//! a process wide total, which outlives the sessions. Useful to spot
+//! chatty or broken peers, e.g. one flooding Haves or KeepAlives.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use super::{capture::Direction, message::MessageType};
+
+static GLOBAL_COUNTS: Mutex<MessageCounts> = Mutex::new(MessageCounts::new());
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MessageCounts {
+    pub sent: BTreeMap<&'static str, u64>,
+    pub received: BTreeMap<&'static str, u64>,
+}
+
+impl MessageCounts {
+    pub const fn new() -> Self {
+        Self {
+            sent: BTreeMap::new(),
+            received: BTreeMap::new(),
+        }
+    }
+
+    fn counts(&self, direction: Direction) -> &BTreeMap<&'static str, u64> {
+        match direction {
+            Direction::Sent => &self.sent,
+            Direction::Received => &self.received,
+        }
+    }
+
+    pub fn record(&mut self, direction: Direction, message: &MessageType) {
+        let counts = match direction {
+            Direction::Sent => &mut self.sent,
+            Direction::Received => &mut self.received,
+        };
+
+        *counts.entry(message.name()).or_default() += 1;
+    }
+
+    pub fn total(&self, direction: Direction) -> u64 {
+        self.counts(direction).values().sum()
+    }
+
+    /// The `n` most frequent message types, most frequent first.
+    pub fn top(&self, direction: Direction, n: usize) -> Vec<(&'static str, u64)> {
+        let mut counts: Vec<_> = self
+            .counts(direction)
+            .iter()
+            .map(|(name, count)| (*name, *count))
+            .collect();
+        counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        counts.truncate(n);
+
+        counts
+    }
+}
+
+/// Counts of every message sent or received since the process started.
+pub fn global_counts() -> MessageCounts {
+    GLOBAL_COUNTS.lock().unwrap().clone()
+}
+
+/// Shared counts of one session, cheap to clone into its tasks.
+#[derive(Clone, Default)]
+pub struct MessageStatsHandle {
+    inner: Arc<Mutex<MessageCounts>>,
+}
+
+impl MessageStatsHandle {
+    /// Counts the message for this session and in the global total.
+    pub fn record(&self, direction: Direction, message: &MessageType) {
+        self.inner.lock().unwrap().record(direction, message);
+        GLOBAL_COUNTS.lock().unwrap().record(direction, message);
+    }
+
+    pub fn counts(&self) -> MessageCounts {
+        self.inner.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts_by_type() {
+        let stats = MessageStatsHandle::default();
+        let before = global_counts().total(Direction::Received);
+
+        stats.record(Direction::Sent, &MessageType::Interested);
+        for index in 0..3 {
+            stats.record(Direction::Received, &MessageType::Have(index));
+        }
+        stats.record(Direction::Received, &MessageType::KeepAlive);
+
+        let counts = stats.counts();
+        assert_eq!(counts.total(Direction::Sent), 1);
+        assert_eq!(counts.top(Direction::Received, 1), vec![("Have", 3)]);
+        assert!(global_counts().total(Direction::Received) >= before + 4);
+    }
+}
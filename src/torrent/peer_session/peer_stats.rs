@@ -0,0 +1,227 @@
+//! Data and requests exchanged with one peer, and how fast.
+//!
+//! The session records every message it sends or receives here, and
+//! anything deciding between peers, e.g. which to unchoke or which to ask
+//! for a piece, reads the same numbers. Rates are averaged over the last
+//! [`RATE_WINDOW`], in one second buckets, so they follow the peer within
+//! seconds without jumping with every block.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::message::MessageType;
+
+/// Time the rates are averaged over.
+pub const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// Bytes sent and received during one second of the session.
+#[derive(Debug, Clone, Copy, Default)]
+struct Bucket {
+    second: u64,
+    uploaded: u64,
+    downloaded: u64,
+}
+
+#[derive(Debug)]
+struct PeerStats {
+    start: Instant,
+    uploaded: u64,
+    downloaded: u64,
+    requests_sent: u64,
+    requests_received: u64,
+    last_activity: Instant,
+    /// The most recent seconds with any data, oldest first.
+    buckets: VecDeque<Bucket>,
+}
+
+impl PeerStats {
+    fn new(now: Instant) -> Self {
+        Self {
+            start: now,
+            uploaded: 0,
+            downloaded: 0,
+            requests_sent: 0,
+            requests_received: 0,
+            last_activity: now,
+            buckets: VecDeque::new(),
+        }
+    }
+
+    fn second(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.start).as_secs()
+    }
+
+    /// Drops the buckets that fell out of the window at `now`.
+    fn expire(&mut self, now: Instant) {
+        let oldest = self.second(now).saturating_sub(RATE_WINDOW.as_secs() - 1);
+        while self.buckets.front().is_some_and(|b| b.second < oldest) {
+            self.buckets.pop_front();
+        }
+    }
+
+    fn bucket(&mut self, now: Instant) -> &mut Bucket {
+        self.expire(now);
+        let second = self.second(now);
+        if self.buckets.back().is_none_or(|b| b.second != second) {
+            self.buckets.push_back(Bucket {
+                second,
+                ..Bucket::default()
+            });
+        }
+
+        self.buckets.back_mut().unwrap()
+    }
+
+    fn snapshot(&mut self, now: Instant) -> PeerStatsSnapshot {
+        self.expire(now);
+        // A session younger than the window is averaged over its age.
+        let seconds = now
+            .saturating_duration_since(self.start)
+            .clamp(Duration::from_secs(1), RATE_WINDOW)
+            .as_secs_f64();
+        let rate = |bytes: u64| (bytes as f64 / seconds) as u64;
+
+        PeerStatsSnapshot {
+            uploaded: self.uploaded,
+            downloaded: self.downloaded,
+            upload_rate: rate(self.buckets.iter().map(|b| b.uploaded).sum()),
+            download_rate: rate(self.buckets.iter().map(|b| b.downloaded).sum()),
+            requests_sent: self.requests_sent,
+            requests_received: self.requests_received,
+            idle: now.saturating_duration_since(self.last_activity),
+        }
+    }
+}
+
+/// The numbers of one peer at a point in time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerStatsSnapshot {
+    /// Bytes of block data sent to the peer.
+    pub uploaded: u64,
+    /// Bytes of block data received from the peer.
+    pub downloaded: u64,
+    /// Bytes per second over the last [`RATE_WINDOW`].
+    pub upload_rate: u64,
+    pub download_rate: u64,
+    /// Blocks we asked the peer for.
+    pub requests_sent: u64,
+    /// Blocks the peer asked us for.
+    pub requests_received: u64,
+    /// Time since the peer last sent us anything.
+    pub idle: Duration,
+}
+
+/// Shared stats of one session, cheap to clone into its tasks.
+#[derive(Debug, Clone)]
+pub struct PeerStatsHandle {
+    inner: Arc<Mutex<PeerStats>>,
+}
+
+impl Default for PeerStatsHandle {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(PeerStats::new(Instant::now()))),
+        }
+    }
+}
+
+impl PeerStatsHandle {
+    pub fn sent(&self, message: &MessageType) {
+        self.sent_at(message, Instant::now());
+    }
+
+    pub fn received(&self, message: &MessageType) {
+        self.received_at(message, Instant::now());
+    }
+
+    fn sent_at(&self, message: &MessageType, now: Instant) {
+        let mut stats = self.inner.lock().unwrap();
+        match message {
+            MessageType::Piece { block, .. } => {
+                stats.uploaded += block.len() as u64;
+                stats.bucket(now).uploaded += block.len() as u64;
+            }
+            MessageType::Request { .. } => stats.requests_sent += 1,
+            _ => {}
+        }
+    }
+
+    fn received_at(&self, message: &MessageType, now: Instant) {
+        let mut stats = self.inner.lock().unwrap();
+        stats.last_activity = now;
+        match message {
+            MessageType::Piece { block, .. } => {
+                stats.downloaded += block.len() as u64;
+                stats.bucket(now).downloaded += block.len() as u64;
+            }
+            MessageType::Request { .. } => stats.requests_received += 1,
+            _ => {}
+        }
+    }
+
+    pub fn snapshot(&self) -> PeerStatsSnapshot {
+        self.snapshot_at(Instant::now())
+    }
+
+    fn snapshot_at(&self, now: Instant) -> PeerStatsSnapshot {
+        self.inner.lock().unwrap().snapshot(now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn piece(length: usize) -> MessageType {
+        MessageType::Piece {
+            index: 0,
+            begin: 0,
+            block: vec![0; length],
+        }
+    }
+
+    #[test]
+    fn test_rolling_rates() {
+        let start = Instant::now();
+        let stats = PeerStatsHandle {
+            inner: Arc::new(Mutex::new(PeerStats::new(start))),
+        };
+        let at = |seconds: u64| start + Duration::from_secs(seconds);
+
+        // 10 KiB a second down for 10 seconds, 2 KiB a second up for the
+        // first 2.
+        for second in 0..10 {
+            stats.received_at(&piece(10 * 1024), at(second));
+            if second < 2 {
+                stats.sent_at(&piece(2 * 1024), at(second));
+            }
+        }
+        stats.sent_at(
+            &MessageType::Request {
+                index: 0,
+                begin: 0,
+                length: 16384,
+            },
+            at(9),
+        );
+
+        let snapshot = stats.snapshot_at(at(10));
+        assert_eq!(snapshot.downloaded, 100 * 1024);
+        assert_eq!(snapshot.uploaded, 4 * 1024);
+        // Seconds 6 to 9 are in the window, the upload long gone.
+        assert_eq!(snapshot.download_rate, 4 * 10 * 1024 / 5);
+        assert_eq!(snapshot.upload_rate, 0);
+        assert_eq!(snapshot.requests_sent, 1);
+        assert_eq!(snapshot.idle, Duration::from_secs(1));
+
+        // A young session is averaged over its age.
+        let young = PeerStatsHandle {
+            inner: Arc::new(Mutex::new(PeerStats::new(start))),
+        };
+        young.received_at(&piece(4096), at(1));
+        assert_eq!(young.snapshot_at(at(2)).download_rate, 2048);
+    }
+}
@@ -0,0 +1,212 @@
+//! How many block requests to keep in flight with a peer.
+//!
+//! Too few and a fast peer idles between our requests, too many and a slow
+//! peer holds on to blocks other peers would send sooner. Like libtorrent,
+//! the depth is a few seconds worth of blocks at the rate the peer sends
+//! at, and at least enough to cover the round trip of a request, so the
+//! pipe stays full however far away the peer is. Until a rate is measured
+//! a small fixed depth is used.
+//!
+//! A peer that keeps our requests without sending anything for
+//! [`SNUB_TIMEOUT`] is snubbing us, and its work is better given to others.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use super::work::BLOCK_SIZE;
+
+/// Requests in flight before the peer's rate is known.
+pub const INITIAL_QUEUE_DEPTH: usize = 5;
+pub const MIN_QUEUE_DEPTH: usize = 2;
+pub const MAX_QUEUE_DEPTH: usize = 250;
+
+/// Data kept requested, in seconds of the peer's rate.
+const QUEUE_TIME: Duration = Duration::from_secs(3);
+
+/// Shortest time a rate sample is taken over.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Weight of a new sample in the moving averages of rate and round trip.
+const SMOOTHING: f64 = 0.3;
+
+/// Time without a block while requests are outstanding after which the
+/// peer counts as snubbing us.
+pub const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub struct Pipeline {
+    /// When each outstanding block, by piece and offset, was requested.
+    requested: HashMap<(u32, u32), Instant>,
+    /// Bytes per second, smoothed.
+    rate: Option<f64>,
+    /// Time from a request to its block, smoothed.
+    round_trip: Option<Duration>,
+    window_start: Instant,
+    window_bytes: u64,
+    /// When the last block arrived, or the pipeline was started or reset.
+    last_block: Instant,
+}
+
+fn smooth(average: Option<f64>, sample: f64) -> f64 {
+    average.map_or(sample, |average| average + (sample - average) * SMOOTHING)
+}
+
+impl Pipeline {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            requested: HashMap::new(),
+            rate: None,
+            round_trip: None,
+            window_start: now,
+            window_bytes: 0,
+            last_block: now,
+        }
+    }
+
+    pub fn requested(&mut self, index: u32, begin: u32, now: Instant) {
+        self.requested.insert((index, begin), now);
+    }
+
+    /// Measures a block of `length` bytes that arrived at `now`.
+    pub fn received(&mut self, index: u32, begin: u32, length: usize, now: Instant) {
+        if let Some(sent) = self.requested.remove(&(index, begin)) {
+            let sample = now.saturating_duration_since(sent).as_secs_f64();
+            let average = self.round_trip.map(|rtt| rtt.as_secs_f64());
+            self.round_trip = Some(Duration::from_secs_f64(smooth(average, sample)));
+        }
+
+        self.last_block = now;
+        self.window_bytes += length as u64;
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed >= RATE_WINDOW {
+            let sample = self.window_bytes as f64 / elapsed.as_secs_f64();
+            self.rate = Some(smooth(self.rate, sample));
+            self.window_start = now;
+            self.window_bytes = 0;
+        }
+    }
+
+    /// Forgets a request we cancelled.
+    pub fn cancelled(&mut self, index: u32, begin: u32) {
+        self.requested.remove(&(index, begin));
+    }
+
+    /// Forgets every outstanding request, e.g. when the peer chokes us and
+    /// drops them. The time spent choked doesn't count towards the rate.
+    pub fn reset(&mut self, now: Instant) {
+        self.requested.clear();
+        self.window_start = now;
+        self.window_bytes = 0;
+        self.last_block = now;
+    }
+
+    /// When the peer will count as snubbing us unless a block arrives,
+    /// `None` while nothing is requested.
+    pub fn snub_deadline(&self) -> Option<Instant> {
+        let oldest = self.requested.values().min()?;
+
+        Some((*oldest).max(self.last_block) + SNUB_TIMEOUT)
+    }
+
+    pub fn is_snubbed(&self, now: Instant) -> bool {
+        self.snub_deadline().is_some_and(|deadline| now >= deadline)
+    }
+
+    /// Blocks to keep requested from the peer.
+    pub fn depth(&self) -> usize {
+        let Some(rate) = self.rate else {
+            return INITIAL_QUEUE_DEPTH;
+        };
+
+        let seconds = self
+            .round_trip
+            .unwrap_or_default()
+            .max(QUEUE_TIME)
+            .as_secs_f64();
+        let blocks = (rate * seconds / BLOCK_SIZE as f64).ceil() as usize;
+
+        blocks.clamp(MIN_QUEUE_DEPTH, MAX_QUEUE_DEPTH)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `seconds` of blocks arriving at `blocks_per_second`, each one
+    /// requested `round_trip` before it arrives.
+    fn transfer(
+        pipeline: &mut Pipeline,
+        start: Instant,
+        seconds: u64,
+        blocks_per_second: u32,
+        round_trip: Duration,
+    ) -> Instant {
+        let gap = Duration::from_secs(1) / blocks_per_second;
+        let mut now = start;
+        for index in 0..seconds as u32 * blocks_per_second {
+            pipeline.requested(index, 0, now.checked_sub(round_trip).unwrap_or(start));
+            now += gap;
+            pipeline.received(index, 0, BLOCK_SIZE, now);
+        }
+
+        now
+    }
+
+    #[test]
+    fn test_depth_follows_rate() {
+        let start = Instant::now();
+        let mut pipeline = Pipeline::new(start);
+        assert_eq!(pipeline.depth(), INITIAL_QUEUE_DEPTH);
+
+        // 50 blocks a second keeps three seconds worth requested.
+        let mut now = transfer(&mut pipeline, start, 20, 50, Duration::from_millis(50));
+        assert!((140..=160).contains(&pipeline.depth()));
+
+        // A peer slowing to a block every few seconds keeps the minimum.
+        for index in 0..20 {
+            pipeline.requested(index, 0, now);
+            now += Duration::from_secs(4);
+            pipeline.received(index, 0, BLOCK_SIZE, now);
+        }
+        assert_eq!(pipeline.depth(), MIN_QUEUE_DEPTH);
+    }
+
+    #[test]
+    fn test_depth_covers_round_trip() {
+        let start = Instant::now();
+        let mut pipeline = Pipeline::new(start);
+
+        // 10 blocks a second over a 6 second round trip.
+        transfer(&mut pipeline, start, 30, 10, Duration::from_secs(6));
+        assert!((55..=65).contains(&pipeline.depth()));
+
+        // Cancelled and dropped requests aren't measured.
+        pipeline.requested(999, 0, start);
+        pipeline.cancelled(999, 0);
+        pipeline.requested(998, 0, start);
+        pipeline.reset(start);
+        assert!(pipeline.requested.is_empty());
+    }
+
+    #[test]
+    fn test_snubbed_without_blocks() {
+        let start = Instant::now();
+        let mut pipeline = Pipeline::new(start);
+        let later = |seconds| start + Duration::from_secs(seconds);
+        assert_eq!(pipeline.snub_deadline(), None);
+
+        pipeline.requested(0, 0, later(10));
+        pipeline.requested(0, BLOCK_SIZE as u32, later(20));
+        assert!(!pipeline.is_snubbed(later(69)));
+        assert!(pipeline.is_snubbed(later(70)));
+
+        // Each block the peer sends restarts the clock.
+        pipeline.received(0, 0, BLOCK_SIZE, later(65));
+        assert_eq!(pipeline.snub_deadline(), Some(later(125)));
+        pipeline.cancelled(0, BLOCK_SIZE as u32);
+        assert!(!pipeline.is_snubbed(later(200)));
+    }
+}
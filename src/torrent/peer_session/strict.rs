@@ -0,0 +1,300 @@
+//! Strict protocol mode.
+//!
+//! A debugging aid that follows the messages exchanged with a peer and
+//! warns whenever either side breaks an expectation of the spec, such as
+//! requesting while choked or sending a bitfield out of order. Enable it
+//! for every session by setting the `BTRS_STRICT` environment variable.
+
+use std::{
+    collections::HashSet,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use super::{capture::Direction, message::MessageType};
+use crate::torrent::privacy;
+
+/// Largest block size clients are expected to request or send.
+pub const MAX_BLOCK_SIZE: u32 = 16 * 1024;
+
+pub const STRICT_ENV_VAR: &str = "BTRS_STRICT";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    /// A bitfield was sent after other messages, it must come first.
+    LateBitfield(Direction),
+    /// A request was sent to a peer that is choking the requester.
+    RequestWhileChoked(Direction),
+    /// A request or piece exceeded [`MAX_BLOCK_SIZE`].
+    OversizedBlock(Direction, u32),
+    /// The peer sent a block we never requested.
+    UnrequestedBlock { index: u32, begin: u32, length: u32 },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let side = |direction: &Direction| match direction {
+            Direction::Sent => "we",
+            Direction::Received => "peer",
+        };
+
+        match self {
+            Violation::LateBitfield(d) => {
+                write!(
+                    f,
+                    "{} sent a bitfield that was not the first message",
+                    side(d)
+                )
+            }
+            Violation::RequestWhileChoked(d) => {
+                write!(f, "{} sent a request while choked", side(d))
+            }
+            Violation::OversizedBlock(d, length) => write!(
+                f,
+                "{} sent a {length} byte block, larger than {MAX_BLOCK_SIZE}",
+                side(d)
+            ),
+            Violation::UnrequestedBlock {
+                index,
+                begin,
+                length,
+            } => write!(
+                f,
+                "peer sent unrequested block {index}:{begin} ({length} bytes)"
+            ),
+        }
+    }
+}
+
+/// Tracks both sides' view of the connection to validate each message.
+#[derive(Debug)]
+pub struct ProtocolChecker {
+    sent_any: bool,
+    received_any: bool,
+    we_choke_peer: bool,
+    peer_chokes_us: bool,
+    outstanding: HashSet<(u32, u32, u32)>,
+}
+
+impl Default for ProtocolChecker {
+    fn default() -> Self {
+        Self {
+            sent_any: false,
+            received_any: false,
+            we_choke_peer: true,
+            peer_chokes_us: true,
+            outstanding: HashSet::new(),
+        }
+    }
+}
+
+impl ProtocolChecker {
+    /// Checks a message against the connection state, then applies it.
+    pub fn check(&mut self, direction: Direction, message: &MessageType) -> Vec<Violation> {
+        let mut violations = vec![];
+
+        let (first, choked) = match direction {
+            Direction::Sent => (!self.sent_any, self.peer_chokes_us),
+            Direction::Received => (!self.received_any, self.we_choke_peer),
+        };
+
+        match message {
+            MessageType::Bitfield(_) if !first => {
+                violations.push(Violation::LateBitfield(direction));
+            }
+            MessageType::Request { length, .. } => {
+                if choked {
+                    violations.push(Violation::RequestWhileChoked(direction));
+                }
+                if *length > MAX_BLOCK_SIZE {
+                    violations.push(Violation::OversizedBlock(direction, *length));
+                }
+            }
+            MessageType::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                let length = block.len() as u32;
+                if length > MAX_BLOCK_SIZE {
+                    violations.push(Violation::OversizedBlock(direction, length));
+                }
+                if direction == Direction::Received
+                    && !self.outstanding.remove(&(*index, *begin, length))
+                {
+                    violations.push(Violation::UnrequestedBlock {
+                        index: *index,
+                        begin: *begin,
+                        length,
+                    });
+                }
+            }
+            _ => {}
+        }
+
+        self.apply(direction, message);
+
+        violations
+    }
+
+    fn apply(&mut self, direction: Direction, message: &MessageType) {
+        if matches!(message, MessageType::KeepAlive) {
+            return;
+        }
+
+        match (direction, message) {
+            (Direction::Sent, MessageType::Choke) => self.we_choke_peer = true,
+            (Direction::Sent, MessageType::Unchoke) => self.we_choke_peer = false,
+            (Direction::Received, MessageType::Choke) => {
+                // A choke discards every request the peer had queued.
+                self.peer_chokes_us = true;
+                self.outstanding.clear();
+            }
+            (Direction::Received, MessageType::Unchoke) => self.peer_chokes_us = false,
+            (
+                Direction::Sent,
+                MessageType::Request {
+                    index,
+                    begin,
+                    length,
+                },
+            ) => {
+                self.outstanding.insert((*index, *begin, *length));
+            }
+            (
+                Direction::Sent,
+                MessageType::Cancel {
+                    index,
+                    begin,
+                    length,
+                },
+            ) => {
+                self.outstanding.remove(&(*index, *begin, *length));
+            }
+            _ => {}
+        }
+
+        match direction {
+            Direction::Sent => self.sent_any = true,
+            Direction::Received => self.received_any = true,
+        }
+    }
+}
+
+/// Shared handle to a session's checker. Checking is skipped entirely
+/// while strict mode is off.
+#[derive(Clone, Default)]
+pub struct StrictHandle {
+    inner: Arc<Mutex<Option<ProtocolChecker>>>,
+}
+
+impl StrictHandle {
+    /// A handle that is enabled when [`STRICT_ENV_VAR`] is set.
+    pub fn from_env() -> Self {
+        let handle = Self::default();
+        handle.set_enabled(std::env::var_os(STRICT_ENV_VAR).is_some());
+        handle
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        let mut checker = self.inner.lock().unwrap();
+        match (enabled, checker.is_some()) {
+            (true, false) => *checker = Some(ProtocolChecker::default()),
+            (false, true) => *checker = None,
+            _ => {}
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.inner.lock().unwrap().is_some()
+    }
+
+    /// Logs a warning for each violation `message` causes.
+    pub(super) fn observe(&self, peer: &str, direction: Direction, message: &MessageType) {
+        if let Some(checker) = self.inner.lock().unwrap().as_mut() {
+            for violation in checker.check(direction, message) {
+                eprintln!("WARNING: [strict] {}: {violation}", privacy::address(peer));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(length: u32) -> MessageType {
+        MessageType::Request {
+            index: 0,
+            begin: 0,
+            length,
+        }
+    }
+
+    #[test]
+    fn test_request_while_choked() {
+        let mut checker = ProtocolChecker::default();
+
+        assert_eq!(
+            checker.check(Direction::Sent, &request(16384)),
+            vec![Violation::RequestWhileChoked(Direction::Sent)]
+        );
+
+        checker.check(Direction::Received, &MessageType::Unchoke);
+        assert!(checker.check(Direction::Sent, &request(16384)).is_empty());
+    }
+
+    #[test]
+    fn test_oversized_request() {
+        let mut checker = ProtocolChecker::default();
+        checker.check(Direction::Sent, &MessageType::Unchoke);
+
+        assert_eq!(
+            checker.check(Direction::Received, &request(32768)),
+            vec![Violation::OversizedBlock(Direction::Received, 32768)]
+        );
+    }
+
+    #[test]
+    fn test_late_bitfield() {
+        let mut checker = ProtocolChecker::default();
+
+        assert!(
+            checker
+                .check(Direction::Received, &MessageType::Bitfield(vec![0xff]))
+                .is_empty()
+        );
+
+        let mut checker = ProtocolChecker::default();
+        checker.check(Direction::Received, &MessageType::Unchoke);
+
+        assert_eq!(
+            checker.check(Direction::Received, &MessageType::Bitfield(vec![0xff])),
+            vec![Violation::LateBitfield(Direction::Received)]
+        );
+    }
+
+    #[test]
+    fn test_unrequested_block() {
+        let mut checker = ProtocolChecker::default();
+        checker.check(Direction::Received, &MessageType::Unchoke);
+        checker.check(Direction::Sent, &request(4));
+
+        let piece = MessageType::Piece {
+            index: 0,
+            begin: 0,
+            block: vec![0; 4],
+        };
+
+        assert!(checker.check(Direction::Received, &piece).is_empty());
+        // The same block again was not requested twice.
+        assert_eq!(
+            checker.check(Direction::Received, &piece),
+            vec![Violation::UnrequestedBlock {
+                index: 0,
+                begin: 0,
+                length: 4
+            }]
+        );
+    }
+}
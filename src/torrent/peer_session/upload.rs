@@ -0,0 +1,178 @@
+//! Blocks the peer requested from us, waiting to be read from disk and
+//! sent.
+//!
+//! The listener queues requests as they arrive and drops those the peer
+//! cancels before they are sent, while a separate task serves the queue
+//! in order, so a slow disk read never holds up reading the peer's
+//! messages.
+
+use std::{collections::VecDeque, sync::Mutex};
+
+use tokio::sync::Notify;
+
+/// Requests queued per peer, advertised as our `reqq`. Further requests
+/// are dropped until the queue drains.
+pub const MAX_UPLOAD_QUEUE: usize = 250;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockRequest {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    requests: VecDeque<BlockRequest>,
+    /// Requests refused as the queue was full.
+    dropped: u64,
+    closed: bool,
+}
+
+/// A peer's claim on our upload at a point in time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UploadQueueSnapshot {
+    /// Blocks waiting to be sent.
+    pub blocks: usize,
+    /// Bytes of those blocks.
+    pub bytes: u64,
+    /// Requests refused so far because [`MAX_UPLOAD_QUEUE`] were queued.
+    pub dropped: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct UploadQueue {
+    queue: Mutex<Queue>,
+    changed: Notify,
+}
+
+impl UploadQueue {
+    /// Queues `request` unless it already is. Returns false if the queue
+    /// is full.
+    pub fn push(&self, request: BlockRequest) -> bool {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.requests.contains(&request) {
+            return true;
+        }
+        if queue.requests.len() >= MAX_UPLOAD_QUEUE {
+            queue.dropped += 1;
+            return false;
+        }
+
+        queue.requests.push_back(request);
+        self.changed.notify_one();
+        true
+    }
+
+    /// Drops `request` if it hasn't been sent yet, returning whether it
+    /// was queued.
+    pub fn cancel(&self, request: BlockRequest) -> bool {
+        let mut queue = self.queue.lock().unwrap();
+        let before = queue.requests.len();
+        queue.requests.retain(|queued| *queued != request);
+
+        queue.requests.len() < before
+    }
+
+    /// Drops every queued request, e.g. once we choke the peer.
+    pub fn clear(&self) {
+        self.queue.lock().unwrap().requests.clear();
+    }
+
+    /// Ends [`UploadQueue::next`] for good, once the connection is gone.
+    pub fn close(&self) {
+        self.queue.lock().unwrap().closed = true;
+        self.changed.notify_one();
+    }
+
+    /// The oldest queued request, waiting for one if there is none.
+    /// `None` once closed.
+    pub async fn next(&self) -> Option<BlockRequest> {
+        loop {
+            {
+                let mut queue = self.queue.lock().unwrap();
+                if queue.closed {
+                    return None;
+                }
+                if let Some(request) = queue.requests.pop_front() {
+                    return Some(request);
+                }
+            }
+
+            self.changed.notified().await;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.lock().unwrap().requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn snapshot(&self) -> UploadQueueSnapshot {
+        let queue = self.queue.lock().unwrap();
+
+        UploadQueueSnapshot {
+            blocks: queue.requests.len(),
+            bytes: queue.requests.iter().map(|r| r.length as u64).sum(),
+            dropped: queue.dropped,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(index: u32) -> BlockRequest {
+        BlockRequest {
+            index,
+            begin: 0,
+            length: 16 * 1024,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_serves_in_order_and_drops_cancelled() {
+        let queue = UploadQueue::default();
+        assert!(queue.push(request(0)));
+        assert!(queue.push(request(1)));
+        assert!(queue.push(request(2)));
+        // A repeated request is only served once.
+        assert!(queue.push(request(1)));
+        assert_eq!(queue.len(), 3);
+
+        assert!(queue.cancel(request(1)));
+        assert!(!queue.cancel(request(7)));
+
+        assert_eq!(queue.next().await, Some(request(0)));
+        assert_eq!(queue.next().await, Some(request(2)));
+
+        queue.close();
+        assert_eq!(queue.next().await, None);
+    }
+
+    #[test]
+    fn test_full_queue_drops_requests() {
+        let queue = UploadQueue::default();
+        for index in 0..MAX_UPLOAD_QUEUE as u32 {
+            assert!(queue.push(request(index)));
+        }
+
+        assert!(!queue.push(request(MAX_UPLOAD_QUEUE as u32)));
+        assert_eq!(
+            queue.snapshot(),
+            UploadQueueSnapshot {
+                blocks: MAX_UPLOAD_QUEUE,
+                bytes: MAX_UPLOAD_QUEUE as u64 * 16 * 1024,
+                dropped: 1,
+            }
+        );
+
+        queue.clear();
+        assert!(queue.is_empty());
+        assert!(queue.push(request(MAX_UPLOAD_QUEUE as u32)));
+    }
+}
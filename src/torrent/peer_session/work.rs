@@ -1,6 +1,19 @@
-use crate::torrent::piece_manager::{PieceError, PieceRequest, PieceResponse};
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+};
 
-const BLOCK_SIZE: usize = 16 * 1024;
+use anyhow::{Context, Error, bail};
+
+use crate::torrent::piece_manager::{
+    Contributor, PieceError, PieceRequest, PieceResponse, SessionId,
+};
+
+pub const BLOCK_SIZE: usize = 16 * 1024;
+
+/// Pieces larger than this keep their received blocks in a temporary file
+/// instead of memory, so many large pieces in flight don't exhaust RAM.
+pub const SPILL_THRESHOLD: usize = 4 * 1024 * 1024;
 
 #[derive(Debug, Clone)]
 pub struct BlockInfo {
@@ -19,8 +32,13 @@ pub enum BlockStatus {
 pub struct PieceWork {
     pub index: u32,
     pub length: usize,
-    pub block_size: usize,
     pub blocks: Vec<BlockInfo>,
+    /// Backing file for received blocks, created on the first block of a
+    /// piece larger than [`SPILL_THRESHOLD`]. Deleted when dropped.
+    spill: Option<File>,
+    /// Sessions whose blocks went into the piece, blamed if it fails its
+    /// hash check.
+    contributors: Vec<Contributor>,
 }
 pub struct BlockResponse {
     pub index: u32,
@@ -57,30 +75,159 @@ impl From<PieceRequest> for PieceWork {
         Self {
             index: value.piece_index,
             length: value.length_bytes,
-            block_size: BLOCK_SIZE,
             blocks,
+            spill: None,
+            contributors: vec![],
         }
     }
 }
 
 impl PieceWork {
+    /// Records that a block from `contributor` went into the piece.
+    pub fn credit(&mut self, contributor: &Contributor) {
+        if !self
+            .contributors
+            .iter()
+            .any(|c| c.session_id == contributor.session_id)
+        {
+            self.contributors.push(contributor.clone());
+        }
+    }
+
     pub fn is_complete(&self) -> bool {
         self.blocks
             .iter()
             .all(|block| block.status == BlockStatus::Full)
     }
 
-    pub fn to_piece_response(self) -> PieceResponse {
-        let bytes: Vec<u8> = self
+    /// Blocks not received yet, requested or not.
+    pub fn unfinished_blocks(&self) -> usize {
+        self.blocks
+            .iter()
+            .filter(|block| block.status != BlockStatus::Full)
+            .count()
+    }
+
+    /// Blocks requested and not received yet.
+    pub fn in_flight_blocks(&self) -> usize {
+        self.blocks
+            .iter()
+            .filter(|block| block.status == BlockStatus::InProgress)
+            .count()
+    }
+
+    pub fn spills_to_disk(&self) -> bool {
+        self.length > SPILL_THRESHOLD
+    }
+
+    /// Stores the data of the in progress block starting at `offset`,
+    /// writing it to the spill file for large pieces.
+    pub fn store_block(&mut self, offset: u32, data: Vec<u8>) -> Result<(), Error> {
+        let spills = self.spills_to_disk();
+
+        let Some(block) = self
             .blocks
-            .into_iter()
-            .map(|block| block.data)
-            .flatten()
-            .collect();
+            .iter_mut()
+            .find(|block| block.offset == offset && block.status == BlockStatus::InProgress)
+        else {
+            bail!(
+                "No block in progress at offset {offset} of piece {}",
+                self.index
+            );
+        };
+
+        if data.len() != block.length as usize {
+            bail!(
+                "Block at offset {offset} of piece {} has length {}, expected {}",
+                self.index,
+                data.len(),
+                block.length
+            );
+        }
+
+        if spills {
+            if self.spill.is_none() {
+                self.spill =
+                    Some(tempfile::tempfile().context("Failed to create piece spill file")?);
+            }
+            let file = self.spill.as_mut().unwrap();
+            file.seek(SeekFrom::Start(offset as u64))?;
+            file.write_all(&data)
+                .context("Failed to write block to spill file")?;
+        } else {
+            block.data = data;
+        }
+
+        block.status = BlockStatus::Full;
+        Ok(())
+    }
+
+    /// Fills in blocks received before, e.g. by an earlier run, see
+    /// [`PieceJournal`](crate::torrent::piece_journal::PieceJournal).
+    /// Returns the number of blocks used.
+    pub fn resume(&mut self, blocks: Vec<(u32, Vec<u8>)>) -> usize {
+        let mut resumed = 0;
+        for (begin, data) in blocks {
+            let Some(block) = self
+                .blocks
+                .iter_mut()
+                .find(|block| block.offset == begin && block.status == BlockStatus::Empty)
+            else {
+                continue;
+            };
+
+            block.status = BlockStatus::InProgress;
+            match self.store_block(begin, data) {
+                Ok(()) => resumed += 1,
+                Err(e) => {
+                    eprintln!("[Journal] Dropping block of piece {}: {e:#}", self.index);
+                    if let Some(block) = self.blocks.iter_mut().find(|b| b.offset == begin) {
+                        block.status = BlockStatus::Empty;
+                    }
+                }
+            }
+        }
+
+        resumed
+    }
+
+    fn read_spill(spill: Option<File>, length: usize) -> Result<Vec<u8>, Error> {
+        let mut file = spill.context("Spilled piece has no data")?;
+        let mut bytes = Vec::with_capacity(length);
+
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut bytes)
+            .context("Failed to read piece back from spill file")?;
+
+        Ok(bytes)
+    }
+
+    pub fn into_piece_response(mut self, session_id: SessionId) -> PieceResponse {
+        let contributors = std::mem::take(&mut self.contributors);
+        let bytes: Vec<u8> = if self.spills_to_disk() {
+            match Self::read_spill(self.spill, self.length) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    return PieceResponse {
+                        piece_index: self.index,
+                        session_id,
+                        contributors,
+                        result: Err(PieceError::InvalidData(format!("{e:#}"))),
+                    };
+                }
+            }
+        } else {
+            self.blocks
+                .into_iter()
+                .flat_map(|block| block.data)
+                .collect()
+        };
 
         if bytes.len() != self.length {
             PieceResponse {
                 piece_index: self.index,
+                session_id,
+                contributors,
                 result: Err(PieceError::InvalidData(String::from(
                     "piece data is malformed",
                 ))),
@@ -88,8 +235,96 @@ impl PieceWork {
         } else {
             PieceResponse {
                 piece_index: self.index,
+                session_id,
+                contributors,
                 result: Ok(bytes),
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fill(work: &mut PieceWork) {
+        let offsets: Vec<u32> = work.blocks.iter().map(|b| b.offset).collect();
+        for block in work.blocks.iter_mut() {
+            block.status = BlockStatus::InProgress;
+        }
+        for (i, offset) in offsets.into_iter().enumerate() {
+            let length = work.blocks[i].length as usize;
+            work.store_block(offset, vec![i as u8; length]).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_small_piece_stays_in_memory() {
+        let mut work = PieceWork::from(PieceRequest {
+            piece_index: 0,
+            length_bytes: BLOCK_SIZE + 10,
+        });
+
+        fill(&mut work);
+
+        assert!(work.spill.is_none());
+        assert!(work.is_complete());
+        let data = work.into_piece_response(1).result.unwrap();
+        assert_eq!(data.len(), BLOCK_SIZE + 10);
+        assert_eq!(data[BLOCK_SIZE], 1);
+    }
+
+    #[test]
+    fn test_large_piece_spills_to_disk() {
+        let length = SPILL_THRESHOLD + BLOCK_SIZE;
+        let mut work = PieceWork::from(PieceRequest {
+            piece_index: 3,
+            length_bytes: length,
+        });
+
+        fill(&mut work);
+
+        assert!(work.spill.is_some());
+        assert!(work.blocks.iter().all(|b| b.data.is_empty()));
+
+        let data = work.into_piece_response(1).result.unwrap();
+        assert_eq!(data.len(), length);
+        assert_eq!(data[0], 0);
+        assert_eq!(data[length - 1], (length / BLOCK_SIZE - 1) as u8);
+    }
+
+    #[test]
+    fn test_resume_skips_unknown_blocks() {
+        let mut work = PieceWork::from(PieceRequest {
+            piece_index: 0,
+            length_bytes: 2 * BLOCK_SIZE,
+        });
+
+        let resumed = work.resume(vec![
+            (0, vec![7; BLOCK_SIZE]),
+            // Wrong length, and an offset that isn't a block.
+            (BLOCK_SIZE as u32, vec![7; 10]),
+            (5, vec![7; BLOCK_SIZE]),
+        ]);
+
+        assert_eq!(resumed, 1);
+        assert_eq!(work.blocks[0].status, BlockStatus::Full);
+        assert_eq!(work.blocks[1].status, BlockStatus::Empty);
+        assert_eq!(work.unfinished_blocks(), 1);
+    }
+
+    #[test]
+    fn test_store_block_rejects_unexpected_block() {
+        let mut work = PieceWork::from(PieceRequest {
+            piece_index: 0,
+            length_bytes: BLOCK_SIZE,
+        });
+
+        // Not requested yet.
+        assert!(work.store_block(0, vec![0; BLOCK_SIZE]).is_err());
+
+        work.blocks[0].status = BlockStatus::InProgress;
+        assert!(work.store_block(0, vec![0; 10]).is_err());
+        assert!(work.store_block(0, vec![0; BLOCK_SIZE]).is_ok());
+    }
+}
@@ -0,0 +1,510 @@
+//! Every peer heard of for a torrent, whichever way it was found.
+//!
+//! Peers are keyed by address, so a peer returned by several trackers or
+//! announces is only stored once, along with every source that named it
+//! and when it was last named. Peers nobody has mentioned for a while are
+//! forgotten.
+//!
+//! Connection outcomes are counted per source, so it shows which discovery
+//! mechanisms find peers that can actually be reached.
+//!
+//! Failed connections are also remembered per peer. A peer that couldn't be
+//! reached isn't offered again until a backoff doubling with every failure
+//! has passed, and after [`MAX_CONNECT_FAILURES`] in a row not at all. The
+//! memory goes with the peer once it is forgotten.
+//!
+//! Popular torrents name far more peers than can ever be dialed, so the
+//! store keeps at most [`MAX_STORED_PEERS`], dropping peers given up on
+//! and then those named longest ago to make room.
+//!
+//! A peer known by both its IPv4 and IPv6 address, under the same peer ID,
+//! is offered once by its IPv6 address, with the IPv4 one as the
+//! [`PeerStore::alternate`] to race against it.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use crate::torrent::Peer;
+
+/// How long a peer is kept after a source last named it.
+pub const PEER_MAX_AGE: Duration = Duration::from_secs(2 * 60 * 60);
+
+/// Most peers kept per torrent.
+pub const MAX_STORED_PEERS: usize = 2000;
+
+/// Wait before retrying a peer after its first failed connection.
+pub const RETRY_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Longest wait between retries.
+pub const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30 * 60);
+
+/// Failed connections in a row after which a peer isn't retried.
+pub const MAX_CONNECT_FAILURES: u32 = 8;
+
+/// Where a peer was learned from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PeerSource {
+    Tracker,
+    Dht,
+    /// Peer exchange with a connected peer.
+    Pex,
+    /// Added by hand.
+    Manual,
+    /// Connected to us.
+    Incoming,
+}
+
+impl fmt::Display for PeerSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerSource::Tracker => write!(f, "tracker"),
+            PeerSource::Dht => write!(f, "DHT"),
+            PeerSource::Pex => write!(f, "PEX"),
+            PeerSource::Manual => write!(f, "manual"),
+            PeerSource::Incoming => write!(f, "incoming"),
+        }
+    }
+}
+
+/// Peers named by one source and how connecting to them went.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SourceStats {
+    /// Peers currently known through the source.
+    pub peers: usize,
+    /// Connections attempted to peers the source named.
+    pub attempted: u64,
+    /// Attempts that got as far as a handshake.
+    pub connected: u64,
+}
+
+struct StoredPeer {
+    peer: Peer,
+    sources: Vec<PeerSource>,
+    last_seen: Instant,
+    /// Failed connections since the last successful one.
+    failures: u32,
+    /// When the peer may be dialed again after failing.
+    retry_at: Option<Instant>,
+}
+
+impl StoredPeer {
+    fn can_dial(&self, now: Instant) -> bool {
+        self.failures < MAX_CONNECT_FAILURES && self.retry_at.is_none_or(|at| at <= now)
+    }
+}
+
+/// Key of the peer at `address`, an `ip:port` with IPv6 addresses in
+/// brackets.
+fn key(address: &str) -> Option<(String, u64)> {
+    if let Ok(address) = address.parse::<SocketAddr>() {
+        return Some((address.ip().to_string(), address.port() as u64));
+    }
+    let (ip, port) = address.rsplit_once(':')?;
+
+    Some((String::from(ip), port.parse().ok()?))
+}
+
+fn is_ipv6(peer: &Peer) -> bool {
+    matches!(peer.ip.parse(), Ok(IpAddr::V6(_)))
+}
+
+/// Wait before retrying a peer that failed `failures` times in a row.
+fn backoff(failures: u32) -> Duration {
+    let doublings = failures.saturating_sub(1).min(16);
+
+    (RETRY_BACKOFF * 2u32.pow(doublings)).min(MAX_RETRY_BACKOFF)
+}
+
+#[derive(Default)]
+pub struct PeerStore {
+    peers: BTreeMap<(String, u64), StoredPeer>,
+    /// `(attempted, connected)` per source, kept when peers are forgotten.
+    outcomes: BTreeMap<PeerSource, (u64, u64)>,
+}
+
+impl PeerStore {
+    /// Records `peers` as just seen through `source`. Returns how many of
+    /// them weren't known yet.
+    pub fn add(&mut self, peers: impl IntoIterator<Item = Peer>, source: PeerSource) -> usize {
+        let now = Instant::now();
+        let mut added = 0;
+
+        for peer in peers {
+            let key = (peer.ip.clone(), peer.port);
+            match self.peers.get_mut(&key) {
+                Some(stored) => {
+                    stored.last_seen = now;
+                    if !stored.sources.contains(&source) {
+                        stored.sources.push(source);
+                    }
+                    if stored.peer.client.is_none() {
+                        stored.peer.client = peer.client;
+                    }
+                    if stored.peer.peer_id.is_none() {
+                        stored.peer.peer_id = peer.peer_id;
+                    }
+                }
+                None => {
+                    self.peers.insert(
+                        key,
+                        StoredPeer {
+                            peer,
+                            sources: vec![source],
+                            last_seen: now,
+                            failures: 0,
+                            retry_at: None,
+                        },
+                    );
+                    added += 1;
+                }
+            }
+        }
+        self.evict_over(MAX_STORED_PEERS);
+
+        added
+    }
+
+    /// Every known peer, ordered by address.
+    pub fn peers(&self) -> Vec<Peer> {
+        self.peers
+            .values()
+            .map(|stored| stored.peer.clone())
+            .collect()
+    }
+
+    /// Sources that named the peer at `ip` and `port`, empty if unknown.
+    pub fn sources(&self, ip: &str, port: u64) -> &[PeerSource] {
+        self.peers
+            .get(&(String::from(ip), port))
+            .map_or(&[], |stored| &stored.sources)
+    }
+
+    /// Counts a connection attempt to the peer at `address` towards every
+    /// source that named it, and backs off from the peer if it failed.
+    /// Peers the store doesn't know are ignored.
+    pub fn record_connection(&mut self, address: &str, connected: bool) {
+        let Some(stored) = key(address).and_then(|key| self.peers.get_mut(&key)) else {
+            return;
+        };
+
+        if connected {
+            stored.failures = 0;
+            stored.retry_at = None;
+        } else {
+            stored.failures += 1;
+            stored.retry_at = Some(Instant::now() + backoff(stored.failures));
+        }
+
+        for source in &stored.sources {
+            let (attempted, succeeded) = self.outcomes.entry(*source).or_default();
+            *attempted += 1;
+            if connected {
+                *succeeded += 1;
+            }
+        }
+    }
+
+    /// Counts a connection a peer opened to us, `connected` once its
+    /// handshake was answered.
+    pub fn record_incoming(&mut self, connected: bool) {
+        let (attempted, succeeded) = self.outcomes.entry(PeerSource::Incoming).or_default();
+        *attempted += 1;
+        if connected {
+            *succeeded += 1;
+        }
+    }
+
+    /// Statistics of every source that named a peer or had one connected
+    /// to.
+    pub fn source_stats(&self) -> Vec<(PeerSource, SourceStats)> {
+        let mut stats: BTreeMap<PeerSource, SourceStats> = BTreeMap::new();
+
+        for stored in self.peers.values() {
+            for source in &stored.sources {
+                stats.entry(*source).or_default().peers += 1;
+            }
+        }
+        for (source, (attempted, connected)) in &self.outcomes {
+            let entry = stats.entry(*source).or_default();
+            entry.attempted = *attempted;
+            entry.connected = *connected;
+        }
+
+        stats.into_iter().collect()
+    }
+
+    /// Up to `limit` `ip:port` addresses to connect to, most recently seen
+    /// first, skipping those `connected` accepts and those backed off from.
+    pub fn candidates(&self, connected: impl Fn(&str) -> bool, limit: usize) -> Vec<String> {
+        let now = Instant::now();
+        let mut candidates: Vec<_> = self
+            .peers
+            .values()
+            .filter(|stored| stored.can_dial(now))
+            .map(|stored| (stored.last_seen, stored.peer.address()))
+            // Dialed along with their IPv6 address.
+            .filter(|(_, address)| address.starts_with('[') || self.alternate(address).is_none())
+            .filter(|(_, address)| !connected(address))
+            .collect();
+        candidates.sort_by_key(|(last_seen, _)| std::cmp::Reverse(*last_seen));
+
+        candidates
+            .into_iter()
+            .take(limit)
+            .map(|(_, address)| address)
+            .collect()
+    }
+
+    /// Address of the peer at `address` in the other address family, when
+    /// the same peer ID was seen at both and it may be dialed.
+    pub fn alternate(&self, address: &str) -> Option<String> {
+        let stored = self.peers.get(&key(address)?)?;
+        let peer_id = stored.peer.peer_id.as_ref()?;
+        let now = Instant::now();
+
+        self.peers
+            .values()
+            .find(|other| {
+                other.peer.peer_id.as_ref() == Some(peer_id)
+                    && is_ipv6(&other.peer) != is_ipv6(&stored.peer)
+                    && other.can_dial(now)
+            })
+            .map(|other| other.peer.address())
+    }
+
+    /// Forgets peers no source has named in [`PEER_MAX_AGE`].
+    pub fn forget_stale(&mut self) {
+        if let Some(cutoff) = Instant::now().checked_sub(PEER_MAX_AGE) {
+            self.forget_seen_before(cutoff);
+        }
+    }
+
+    fn forget_seen_before(&mut self, cutoff: Instant) {
+        self.peers.retain(|_, stored| stored.last_seen >= cutoff);
+    }
+
+    /// Forgets peers until at most `limit` are left, first those given up
+    /// on, then those named longest ago.
+    fn evict_over(&mut self, limit: usize) {
+        let Some(excess) = self.peers.len().checked_sub(limit).filter(|&n| n > 0) else {
+            return;
+        };
+
+        let mut keys: Vec<_> = self
+            .peers
+            .iter()
+            .map(|(key, stored)| {
+                let given_up = stored.failures >= MAX_CONNECT_FAILURES;
+                ((!given_up, stored.last_seen), key.clone())
+            })
+            .collect();
+        keys.sort_unstable();
+
+        for (_, key) in keys.into_iter().take(excess) {
+            self.peers.remove(&key);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.peers.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(ip: &str, port: u64) -> Peer {
+        Peer {
+            ip: String::from(ip),
+            port,
+            client: None,
+            comments: vec![],
+            stats: None,
+            upload_queue: None,
+            peer_id: None,
+        }
+    }
+
+    #[test]
+    fn test_merges_sources_by_address() {
+        let mut store = PeerStore::default();
+
+        let added = store.add(
+            [peer("10.0.0.1", 6881), peer("10.0.0.2", 6881)],
+            PeerSource::Tracker,
+        );
+        assert_eq!(added, 2);
+
+        let mut known = peer("10.0.0.1", 6881);
+        known.client = Some(String::from("btrs 0.1.0"));
+        let added = store.add([known, peer("10.0.0.1", 6882)], PeerSource::Pex);
+        assert_eq!(added, 1);
+
+        assert_eq!(store.len(), 3);
+        assert_eq!(
+            store.sources("10.0.0.1", 6881),
+            [PeerSource::Tracker, PeerSource::Pex]
+        );
+        assert_eq!(store.peers()[0].client.as_deref(), Some("btrs 0.1.0"));
+
+        // Seen again by the same source, nothing new.
+        assert_eq!(store.add([peer("10.0.0.2", 6881)], PeerSource::Tracker), 0);
+        assert_eq!(store.sources("10.0.0.2", 6881), [PeerSource::Tracker]);
+    }
+
+    #[test]
+    fn test_source_stats() {
+        let mut store = PeerStore::default();
+        store.add(
+            [peer("10.0.0.1", 6881), peer("10.0.0.2", 6881)],
+            PeerSource::Tracker,
+        );
+        store.add([peer("10.0.0.2", 6881)], PeerSource::Pex);
+
+        store.record_connection("10.0.0.1:6881", false);
+        store.record_connection("10.0.0.2:6881", true);
+        store.record_connection("10.0.0.9:6881", true);
+
+        let tracker = SourceStats {
+            peers: 2,
+            attempted: 2,
+            connected: 1,
+        };
+        let pex = SourceStats {
+            peers: 1,
+            attempted: 1,
+            connected: 1,
+        };
+        assert_eq!(
+            store.source_stats(),
+            [(PeerSource::Tracker, tracker), (PeerSource::Pex, pex)]
+        );
+
+        store.record_incoming(true);
+        assert_eq!(store.source_stats()[2].1.connected, 1);
+
+        // Outcomes outlive the peers.
+        store.clear();
+        assert_eq!(store.source_stats()[0].1.attempted, 2);
+        assert_eq!(store.source_stats()[0].1.peers, 0);
+    }
+
+    #[test]
+    fn test_backs_off_failed_peers() {
+        assert_eq!(backoff(1), RETRY_BACKOFF);
+        assert_eq!(backoff(3), RETRY_BACKOFF * 4);
+        assert_eq!(backoff(MAX_CONNECT_FAILURES), MAX_RETRY_BACKOFF);
+
+        let mut store = PeerStore::default();
+        store.add(
+            [peer("10.0.0.1", 6881), peer("10.0.0.2", 6881)],
+            PeerSource::Tracker,
+        );
+
+        store.record_connection("10.0.0.1:6881", false);
+        assert_eq!(store.candidates(|_| false, 5), ["10.0.0.2:6881"]);
+
+        // Due again once the backoff has passed, until the attempts run out.
+        let key = (String::from("10.0.0.1"), 6881);
+        let stored = store.peers.get_mut(&key).unwrap();
+        stored.retry_at = Some(Instant::now());
+        assert_eq!(store.candidates(|_| false, 5).len(), 2);
+        store.peers.get_mut(&key).unwrap().failures = MAX_CONNECT_FAILURES;
+        store.peers.get_mut(&key).unwrap().retry_at = None;
+        assert_eq!(store.candidates(|_| false, 5), ["10.0.0.2:6881"]);
+
+        // A connection clears the memory.
+        store.record_connection("10.0.0.1:6881", true);
+        assert_eq!(store.candidates(|_| false, 5).len(), 2);
+    }
+
+    #[test]
+    fn test_dual_homed_peer_offered_once() {
+        let mut store = PeerStore::default();
+        let with_id = |ip, id: &[u8]| Peer {
+            peer_id: Some(id.to_vec()),
+            ..peer(ip, 6881)
+        };
+        store.add(
+            [
+                with_id("10.0.0.1", b"a"),
+                with_id("2001:db8::1", b"a"),
+                with_id("10.0.0.2", b"b"),
+            ],
+            PeerSource::Tracker,
+        );
+
+        let mut candidates = store.candidates(|_| false, 5);
+        candidates.sort();
+        assert_eq!(candidates, ["10.0.0.2:6881", "[2001:db8::1]:6881"]);
+        assert_eq!(
+            store.alternate("[2001:db8::1]:6881").as_deref(),
+            Some("10.0.0.1:6881")
+        );
+        assert_eq!(store.alternate("10.0.0.2:6881"), None);
+
+        // Backed off IPv6 leaves the IPv4 address to be dialed alone.
+        store.record_connection("[2001:db8::1]:6881", false);
+        assert_eq!(store.candidates(|_| false, 5).len(), 2);
+        assert_eq!(
+            store.alternate("[2001:db8::1]:6881").as_deref(),
+            Some("10.0.0.1:6881")
+        );
+        assert_eq!(store.alternate("10.0.0.1:6881"), None);
+    }
+
+    #[test]
+    fn test_candidates_skip_connected_and_forget_stale() {
+        let mut store = PeerStore::default();
+        store.add([peer("10.0.0.1", 6881)], PeerSource::Tracker);
+        std::thread::sleep(Duration::from_millis(2));
+        let cutoff = Instant::now();
+        std::thread::sleep(Duration::from_millis(2));
+        store.add([peer("10.0.0.2", 6881)], PeerSource::Manual);
+
+        assert_eq!(
+            store.candidates(|_| false, 5),
+            ["10.0.0.2:6881", "10.0.0.1:6881"]
+        );
+        assert_eq!(
+            store.candidates(|address| address == "10.0.0.2:6881", 5),
+            ["10.0.0.1:6881"]
+        );
+        assert_eq!(store.candidates(|_| false, 1).len(), 1);
+
+        store.forget_seen_before(cutoff);
+        assert_eq!(store.candidates(|_| false, 5), ["10.0.0.2:6881"]);
+    }
+
+    #[test]
+    fn test_evicts_given_up_then_oldest() {
+        let mut store = PeerStore::default();
+        for last in 1..=4 {
+            store.add([peer(&format!("10.0.0.{last}"), 6881)], PeerSource::Tracker);
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        for _ in 0..MAX_CONNECT_FAILURES {
+            store.record_connection("10.0.0.4:6881", false);
+        }
+
+        store.evict_over(2);
+        let left: Vec<_> = store.peers().iter().map(Peer::address).collect();
+        assert_eq!(left, ["10.0.0.2:6881", "10.0.0.3:6881"]);
+
+        store.evict_over(2);
+        assert_eq!(store.len(), 2);
+    }
+}
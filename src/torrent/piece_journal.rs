@@ -0,0 +1,167 @@
+//! Blocks of pieces still being downloaded, kept on disk so a restart
+//! doesn't throw them away.
+//!
+//! Each unfinished piece has its own file in the journal directory,
+//! holding every block received so far as a record of its offset, length
+//! and data. Records are only ever appended, so a crash can only cut the
+//! last one short. Restoring drops such a record from the file, so blocks
+//! appended after it are read back whole. The file is deleted once the
+//! piece is verified, or fails its hash check and has to start over.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Error};
+
+/// Offset and length in front of every block.
+const RECORD_HEADER: usize = 8;
+
+#[derive(Debug, Clone)]
+pub struct PieceJournal {
+    dir: PathBuf,
+}
+
+impl PieceJournal {
+    /// Journal stored in `dir`, created on the first block recorded.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path(&self, piece_index: u32) -> PathBuf {
+        self.dir.join(format!("{piece_index}.part"))
+    }
+
+    /// Appends the block at `begin` of `piece_index`.
+    pub fn record(&self, piece_index: u32, begin: u32, block: &[u8]) -> Result<(), Error> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Cannot create {}", self.dir.display()))?;
+        let path = self.path(piece_index);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Cannot open {}", path.display()))?;
+
+        let mut record = Vec::with_capacity(RECORD_HEADER + block.len());
+        record.extend_from_slice(&begin.to_be_bytes());
+        record.extend_from_slice(&(block.len() as u32).to_be_bytes());
+        record.extend_from_slice(block);
+        file.write_all(&record)
+            .with_context(|| format!("Cannot write to {}", path.display()))
+    }
+
+    /// Blocks recorded for `piece_index` by offset, none if the piece has
+    /// no journal. A record cut short is cut from the file too.
+    pub fn restore(&self, piece_index: u32) -> Result<Vec<(u32, Vec<u8>)>, Error> {
+        let path = self.path(piece_index);
+        let mut bytes = vec![];
+        let mut file = match OpenOptions::new().read(true).write(true).open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e).with_context(|| format!("Cannot open {}", path.display())),
+        };
+        file.read_to_end(&mut bytes)
+            .with_context(|| format!("Cannot read {}", path.display()))?;
+
+        let mut blocks = vec![];
+        let mut rest = bytes.as_slice();
+        while rest.len() >= RECORD_HEADER {
+            let begin = u32::from_be_bytes(rest[0..4].try_into()?);
+            let length = u32::from_be_bytes(rest[4..8].try_into()?) as usize;
+            // The last record was cut short, nothing after it was written.
+            let Some(block) = rest.get(RECORD_HEADER..RECORD_HEADER + length) else {
+                break;
+            };
+
+            blocks.push((begin, block.to_vec()));
+            rest = &rest[RECORD_HEADER + length..];
+        }
+        // Records appended later would be read as the torn one's data.
+        if !rest.is_empty() {
+            file.set_len((bytes.len() - rest.len()) as u64)
+                .with_context(|| format!("Cannot truncate {}", path.display()))?;
+        }
+
+        Ok(blocks)
+    }
+
+    /// Forgets the blocks of `piece_index`.
+    pub fn remove(&self, piece_index: u32) {
+        let path = self.path(piece_index);
+        if let Err(e) = fs::remove_file(&path)
+            && e.kind() != ErrorKind::NotFound
+        {
+            eprintln!("[Journal] Cannot remove {}: {e}", path.display());
+        }
+    }
+
+    /// Deletes the whole journal, e.g. once the torrent is complete.
+    pub fn clear(&self) -> Result<(), Error> {
+        match fs::remove_dir_all(&self.dir) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("Cannot remove {}", self.dir.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restores_recorded_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = PieceJournal::new(dir.path().join("journal"));
+        assert!(journal.restore(3).unwrap().is_empty());
+
+        journal.record(3, 0, b"first").unwrap();
+        journal.record(3, 16384, b"second").unwrap();
+        journal.record(4, 0, b"other").unwrap();
+        assert_eq!(
+            journal.restore(3).unwrap(),
+            vec![(0, b"first".to_vec()), (16384, b"second".to_vec())]
+        );
+
+        journal.remove(3);
+        assert!(journal.restore(3).unwrap().is_empty());
+        assert_eq!(journal.restore(4).unwrap().len(), 1);
+
+        journal.clear().unwrap();
+        assert!(!journal.dir().exists());
+    }
+
+    #[test]
+    fn test_ignores_block_cut_short() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = PieceJournal::new(dir.path());
+        journal.record(0, 0, b"whole").unwrap();
+        journal.record(0, 5, b"torn block").unwrap();
+
+        let path = journal.path(0);
+        let length = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(length - 3)
+            .unwrap();
+
+        assert_eq!(journal.restore(0).unwrap(), vec![(0, b"whole".to_vec())]);
+
+        // Blocks recorded after the torn one are read back as they were.
+        journal.record(0, 5, b"again").unwrap();
+        assert_eq!(
+            journal.restore(0).unwrap(),
+            vec![(0, b"whole".to_vec()), (5, b"again".to_vec())]
+        );
+    }
+}
@@ -1,11 +1,47 @@
-use std::{collections::VecDeque, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
 
-use tokio::sync::{Mutex, mpsc::Receiver};
+use sha1::{Digest, Sha1};
+use tokio::sync::{Mutex, Notify, mpsc::Receiver};
+
+use crate::torrent::{
+    ban_list::BanList, block_reader::BlockReader, metainfo::info::InfoEnum,
+    peer_session::SessionHandle, piece_journal::PieceJournal,
+};
+
+/// Identifies a single peer session for the lifetime of the process.
+pub type SessionId = u64;
+
+/// Hash failures after which a piece is parked instead of retried.
+pub const MAX_HASH_FAILURES: u32 = 5;
+
+/// Pieces failing their hash check a peer may have sent blocks of before it
+/// is banned. Allows for a peer unlucky enough to share a piece with the
+/// one sending bad data.
+pub const MAX_CORRUPT_PIECES: u32 = 3;
 
 pub struct PieceManager {
-    work_queue: Arc<Mutex<VecDeque<PieceRequest>>>,
+    work_queue: Arc<Mutex<WorkQueue>>,
     results: Receiver<PieceResponse>,
     piece_metadata: Vec<PieceMetadata>,
+    hash_failures: HashMap<u32, u32>,
+    verified: HashSet<u32>,
+    /// Bytes still to download, lowered as pieces are verified and read by
+    /// the tracker before each announce.
+    left: Option<Arc<AtomicU64>>,
+    /// Our pieces as served to peers, whose sessions announce every piece
+    /// verified here.
+    blocks: Option<Arc<BlockReader>>,
+    /// Pieces failing their hash check each session sent blocks of.
+    corrupt_pieces: HashMap<SessionId, u32>,
+    /// The torrent's sessions by address, to close those of banned peers.
+    sessions: Arc<Mutex<HashMap<String, SessionHandle>>>,
+    bans: BanList,
 }
 
 pub struct PieceMetadata {
@@ -16,21 +52,546 @@ pub struct PieceMetadata {
 }
 
 impl PieceManager {
-    pub fn new(
-        work_queue: Arc<Mutex<VecDeque<PieceRequest>>>,
-        results: Receiver<PieceResponse>,
-    ) -> Self {
+    pub fn new(work_queue: Arc<Mutex<WorkQueue>>, results: Receiver<PieceResponse>) -> Self {
         Self {
             work_queue,
             results,
             piece_metadata: vec![],
+            hash_failures: HashMap::new(),
+            verified: HashSet::new(),
+            left: None,
+            blocks: None,
+            corrupt_pieces: HashMap::new(),
+            sessions: Arc::default(),
+            bans: BanList::default(),
         }
     }
 
+    pub fn piece_metadata(&self) -> &[PieceMetadata] {
+        &self.piece_metadata
+    }
+
+    /// Sets the expected piece hashes. Pieces without metadata are accepted
+    /// unchecked.
+    pub fn set_piece_metadata(&mut self, piece_metadata: Vec<PieceMetadata>) {
+        self.piece_metadata = piece_metadata;
+    }
+
+    /// Shares the bytes left to download, see [`Torrent::left_handle`](crate::torrent::Torrent::left_handle).
+    pub fn set_left(&mut self, left: Arc<AtomicU64>) {
+        self.left = Some(left);
+    }
+
+    /// Marks verified pieces in `blocks`, so sessions send Have for them.
+    pub fn set_block_reader(&mut self, blocks: Arc<BlockReader>) {
+        self.blocks = Some(blocks);
+    }
+
+    /// Bans peers of `sessions` that sent blocks of more than
+    /// [`MAX_CORRUPT_PIECES`] bad pieces, and closes their sessions.
+    pub fn set_ban_list(
+        &mut self,
+        sessions: Arc<Mutex<HashMap<String, SessionHandle>>>,
+        bans: BanList,
+    ) {
+        self.sessions = sessions;
+        self.bans = bans;
+    }
+
+    /// Number of bad pieces `session_id` sent blocks of.
+    pub fn corrupt_pieces(&self, session_id: SessionId) -> u32 {
+        self.corrupt_pieces.get(&session_id).copied().unwrap_or(0)
+    }
+
+    /// Number of times `piece_index` failed its hash check.
+    pub fn hash_failures(&self, piece_index: u32) -> u32 {
+        self.hash_failures.get(&piece_index).copied().unwrap_or(0)
+    }
+
     pub async fn run(&mut self) {
         // Receive completed pieces
-        while let Some(result) = self.results.recv().await {
-            println!("Got piece: {:?}", result.piece_index);
+        while let Some(response) = self.results.recv().await {
+            self.handle_response(response).await;
+        }
+    }
+
+    async fn handle_response(&mut self, response: PieceResponse) {
+        let index = response.piece_index;
+        let work_queue = Arc::clone(&self.work_queue);
+        let mut queue = work_queue.lock().await;
+        let mut banned = vec![];
+
+        match response.result {
+            Ok(data) if self.verify(index, &data) => {
+                println!("Got piece: {index:?}");
+                if let Some(journal) = queue.journal() {
+                    journal.remove(index);
+                }
+                self.hash_failures.remove(&index);
+                self.piece_verified(index);
+                queue.complete(index);
+            }
+            Ok(_) if !queue.owners(index).contains(&response.session_id) => {
+                // Started over or finished since this copy was taken,
+                // e.g. by another owner's copy holding the same bad
+                // endgame block, which was already counted.
+            }
+            Ok(_) => {
+                // Some journaled or shared endgame block was bad, start
+                // the piece over for every owner.
+                if let Some(journal) = queue.journal() {
+                    journal.remove(index);
+                }
+                let failures = self.hash_failures.entry(index).or_default();
+                *failures += 1;
+
+                if *failures >= MAX_HASH_FAILURES {
+                    eprintln!(
+                        "WARNING: Piece {index} failed its hash check {failures} times, skipping it"
+                    );
+                    queue.park(index);
+                } else {
+                    queue.restart(index);
+                }
+
+                for contributor in response.contributors {
+                    let corrupt = self
+                        .corrupt_pieces
+                        .entry(contributor.session_id)
+                        .or_default();
+                    *corrupt += 1;
+                    if *corrupt > MAX_CORRUPT_PIECES {
+                        banned.push(contributor);
+                    }
+                }
+            }
+            Err(_) => queue.release(index, response.session_id),
+        }
+
+        // Once every remaining piece is already in flight, allow idle
+        // sessions to duplicate work so slow peers don't stall the tail.
+        if queue.pending_len() == 0 && queue.assigned_len() > 0 {
+            queue.set_endgame(true);
+        }
+        drop(queue);
+
+        for contributor in banned {
+            self.ban(&contributor).await;
+        }
+    }
+
+    /// Lowers the bytes left by the length of a newly verified piece and
+    /// has it announced.
+    fn piece_verified(&mut self, piece_index: u32) {
+        if !self.verified.insert(piece_index) {
+            return;
+        }
+        if let Some(blocks) = &self.blocks {
+            blocks.mark_verified(piece_index);
+        }
+
+        let length = self
+            .piece_metadata
+            .iter()
+            .find(|piece| piece.index == piece_index)
+            .map_or(0, |piece| piece.length as u64);
+        if let Some(left) = &self.left {
+            // Never wraps, a failed update means left is already too low.
+            let _ = left.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                left.checked_sub(length)
+            });
+        }
+    }
+
+    /// Bans the peer of `contributor` and closes its session if it is
+    /// still open. A peer that already disconnected is banned all the same.
+    async fn ban(&self, contributor: &Contributor) {
+        let address = &contributor.address;
+        if self.bans.ban(address) {
+            println!(
+                "[Peer] Banned {address} for sending more than {MAX_CORRUPT_PIECES} bad pieces"
+            );
+        }
+
+        let sessions = self.sessions.lock().await;
+        if let Some(session) = sessions
+            .values()
+            .find(|session| session.id() == contributor.session_id)
+        {
+            session.kill();
+        }
+    }
+
+    fn verify(&self, piece_index: u32, data: &[u8]) -> bool {
+        self.piece_metadata
+            .iter()
+            .find(|piece| piece.index == piece_index)
+            .is_none_or(|piece| Sha1::digest(data).as_slice() == piece.hash)
+    }
+}
+
+/// A piece that has been handed out to one or more peer sessions.
+#[derive(Debug)]
+struct Assignment {
+    request: PieceRequest,
+    owners: Vec<SessionId>,
+}
+
+/// Shared queue of piece work, and the authoritative record of which
+/// session is working on which piece.
+///
+/// Sessions take work through [`WorkQueue::next_for`], which moves the
+/// piece into the assignment table in the same critical section, so a
+/// piece can never be held by two sessions unless endgame is enabled.
+#[derive(Debug, Default)]
+pub struct WorkQueue {
+    pending: VecDeque<PieceRequest>,
+    assigned: HashMap<u32, Assignment>,
+    /// Pieces taken out of rotation, e.g. after repeated hash failures.
+    parked: BTreeMap<u32, PieceRequest>,
+    endgame: bool,
+    /// In endgame, the first copy of each block received for a piece and
+    /// the session it came from, by offset, so every owner of the piece
+    /// can use it.
+    arrived: HashMap<u32, BTreeMap<u32, ArrivedBlock>>,
+    /// Bytes of duplicate blocks received in endgame and thrown away.
+    wasted_bytes: u64,
+    /// Wakes idle sessions when there may be new work for them.
+    changed: Arc<Notify>,
+    /// Where sessions keep the blocks of unfinished pieces.
+    journal: Option<Arc<PieceJournal>>,
+    /// Bitfield of each session's peer, for [`WorkQueue::availability`].
+    peer_pieces: HashMap<SessionId, Vec<u8>>,
+    /// Sessions whose peer stopped sending us blocks, see
+    /// [`WorkQueue::set_snubbed`].
+    snubbed: HashSet<SessionId>,
+}
+
+/// A block received in endgame and the session that received it.
+type ArrivedBlock = (Contributor, Arc<Vec<u8>>);
+
+/// Outcome of [`WorkQueue::block_arrived`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockArrival {
+    /// First copy of the block, or not in endgame.
+    Accepted,
+    /// Another session already received the block, its data was wasted.
+    Duplicate,
+}
+
+impl WorkQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a piece to the back of the pending queue.
+    pub fn push(&mut self, request: PieceRequest) {
+        debug_assert!(
+            !self.assigned.contains_key(&request.piece_index),
+            "piece {} queued while assigned",
+            request.piece_index
+        );
+        debug_assert!(
+            !self
+                .pending
+                .iter()
+                .any(|r| r.piece_index == request.piece_index),
+            "piece {} queued twice",
+            request.piece_index
+        );
+
+        self.pending.push_back(request);
+        self.changed.notify_waiters();
+    }
+
+    /// Keeps received blocks in `journal` until their piece is verified.
+    pub fn set_journal(&mut self, journal: PieceJournal) {
+        self.journal = Some(Arc::new(journal));
+    }
+
+    pub fn journal(&self) -> Option<Arc<PieceJournal>> {
+        self.journal.clone()
+    }
+
+    /// Notified whenever pieces or endgame blocks become available, so
+    /// sessions can wait for work instead of polling the queue.
+    pub fn changed(&self) -> Arc<Notify> {
+        Arc::clone(&self.changed)
+    }
+
+    /// Assigns the first pending piece accepted by `available` to `session`.
+    ///
+    /// In endgame, once nothing is pending, a piece already assigned to
+    /// another session may be handed out again. Snubbed sessions take from
+    /// the back of the queue instead and get no endgame work, leaving the
+    /// pieces wanted first to peers that send.
+    pub fn next_for(
+        &mut self,
+        session: SessionId,
+        available: impl Fn(u32) -> bool,
+    ) -> Option<PieceRequest> {
+        let snubbed = self.snubbed.contains(&session);
+        let position = if snubbed {
+            self.pending.iter().rposition(|r| available(r.piece_index))
+        } else {
+            self.pending.iter().position(|r| available(r.piece_index))
+        };
+        if let Some(pos) = position {
+            let request = self.pending.remove(pos)?;
+            self.assigned.insert(
+                request.piece_index,
+                Assignment {
+                    request: request.clone(),
+                    owners: vec![session],
+                },
+            );
+            self.check_invariants();
+            return Some(request);
+        }
+
+        if !self.endgame || snubbed {
+            return None;
+        }
+
+        let assignment = self
+            .assigned
+            .values_mut()
+            .find(|a| !a.owners.contains(&session) && available(a.request.piece_index))?;
+        assignment.owners.push(session);
+
+        Some(assignment.request.clone())
+    }
+
+    /// Removes `session` as an owner of a piece, returning the piece to the
+    /// front of the queue if no other session is still working on it.
+    pub fn release(&mut self, piece_index: u32, session: SessionId) {
+        let Some(assignment) = self.assigned.get_mut(&piece_index) else {
+            return;
+        };
+
+        assignment.owners.retain(|owner| *owner != session);
+
+        if assignment.owners.is_empty()
+            && let Some(assignment) = self.assigned.remove(&piece_index)
+        {
+            self.arrived.remove(&piece_index);
+            self.pending.push_front(assignment.request);
+            self.changed.notify_waiters();
+        }
+        self.check_invariants();
+    }
+
+    /// Returns a piece that failed its hash check to the front of the
+    /// queue, dropping every session's claim on it and the blocks received
+    /// for it in endgame, so no owner reuses a bad block.
+    pub fn restart(&mut self, piece_index: u32) {
+        self.arrived.remove(&piece_index);
+        if let Some(assignment) = self.assigned.remove(&piece_index) {
+            self.pending.push_front(assignment.request);
+            self.changed.notify_waiters();
+        }
+        self.check_invariants();
+    }
+
+    /// Records the pieces the peer of `session` has, replacing what it
+    /// had before, as its Bitfield and Have messages arrive.
+    pub fn set_peer_pieces(&mut self, session: SessionId, bitfield: &[u8]) {
+        self.peer_pieces.insert(session, bitfield.to_vec());
+    }
+
+    /// How many connected peers have `piece_index`.
+    pub fn availability(&self, piece_index: u32) -> usize {
+        let (byte, mask) = (piece_index as usize / 8, 0x80 >> (piece_index % 8));
+
+        self.peer_pieces
+            .values()
+            .filter(|bitfield| bitfield.get(byte).is_some_and(|b| b & mask != 0))
+            .count()
+    }
+
+    /// Marks the peer of `session` as snubbing us or not, which puts it
+    /// last in line for work.
+    pub fn set_snubbed(&mut self, session: SessionId, snubbed: bool) {
+        if snubbed {
+            self.snubbed.insert(session);
+        } else {
+            self.snubbed.remove(&session);
+        }
+    }
+
+    /// Releases every piece held by `session`, e.g. when its connection
+    /// dies, and forgets the pieces of its peer.
+    pub fn release_session(&mut self, session: SessionId) {
+        self.peer_pieces.remove(&session);
+        self.snubbed.remove(&session);
+
+        let held: Vec<u32> = self
+            .assigned
+            .iter()
+            .filter(|(_, a)| a.owners.contains(&session))
+            .map(|(index, _)| *index)
+            .collect();
+
+        for index in held {
+            self.release(index, session);
+        }
+    }
+
+    /// Moves the pending `pieces` to the front of the queue, in the order
+    /// given, so sessions take them next. Assigned and parked pieces are
+    /// left alone.
+    pub fn prioritize(&mut self, pieces: &[u32]) {
+        let mut urgent = vec![];
+        for index in pieces {
+            if let Some(pos) = self.pending.iter().position(|r| r.piece_index == *index) {
+                urgent.extend(self.pending.remove(pos));
+            }
+        }
+        if urgent.is_empty() {
+            return;
+        }
+
+        for request in urgent.into_iter().rev() {
+            self.pending.push_front(request);
+        }
+        self.changed.notify_waiters();
+    }
+
+    /// Takes a piece out of rotation until [`WorkQueue::unpark`] is called,
+    /// dropping every session's claim on it.
+    pub fn park(&mut self, piece_index: u32) {
+        self.arrived.remove(&piece_index);
+        let request = match self.assigned.remove(&piece_index) {
+            Some(assignment) => Some(assignment.request),
+            None => self
+                .pending
+                .iter()
+                .position(|r| r.piece_index == piece_index)
+                .and_then(|pos| self.pending.remove(pos)),
+        };
+
+        if let Some(request) = request {
+            self.parked.insert(piece_index, request);
+        }
+    }
+
+    /// Puts a parked piece back at the front of the queue.
+    pub fn unpark(&mut self, piece_index: u32) {
+        if let Some(request) = self.parked.remove(&piece_index) {
+            self.pending.push_front(request);
+            self.changed.notify_waiters();
+        }
+    }
+
+    pub fn parked(&self) -> impl Iterator<Item = u32> + '_ {
+        self.parked.keys().copied()
+    }
+
+    /// Marks a piece as done, dropping every session's claim on it.
+    ///
+    /// Returns the sessions that were still working on it, which in
+    /// endgame should cancel their outstanding requests.
+    pub fn complete(&mut self, piece_index: u32) -> Vec<SessionId> {
+        self.arrived.remove(&piece_index);
+        self.assigned
+            .remove(&piece_index)
+            .map(|a| a.owners)
+            .unwrap_or_default()
+    }
+
+    pub fn set_endgame(&mut self, endgame: bool) {
+        self.endgame = endgame;
+
+        if !endgame {
+            // Leaving endgame, keep only the first owner of each piece.
+            for assignment in self.assigned.values_mut() {
+                assignment.owners.truncate(1);
+            }
+            self.arrived.clear();
+        }
+        self.check_invariants();
+        self.changed.notify_waiters();
+    }
+
+    pub fn is_endgame(&self) -> bool {
+        self.endgame
+    }
+
+    /// Records a block `sender` received for an assigned piece.
+    ///
+    /// In endgame the first copy of a block is kept for the other owners of
+    /// the piece and later copies are counted as wasted.
+    pub fn block_arrived(
+        &mut self,
+        piece_index: u32,
+        begin: u32,
+        sender: &Contributor,
+        block: &[u8],
+    ) -> BlockArrival {
+        if !self.endgame || !self.assigned.contains_key(&piece_index) {
+            return BlockArrival::Accepted;
+        }
+
+        let blocks = self.arrived.entry(piece_index).or_default();
+        if blocks.contains_key(&begin) {
+            self.wasted_bytes += block.len() as u64;
+            return BlockArrival::Duplicate;
+        }
+
+        blocks.insert(begin, (sender.clone(), Arc::new(block.to_vec())));
+        self.changed.notify_waiters();
+        BlockArrival::Accepted
+    }
+
+    /// Blocks of `piece_index` already received by any session in endgame,
+    /// by offset, with the session that received them.
+    pub fn arrived_blocks(&self, piece_index: u32) -> Vec<(u32, Contributor, Arc<Vec<u8>>)> {
+        self.arrived
+            .get(&piece_index)
+            .map(|blocks| {
+                blocks
+                    .iter()
+                    .map(|(begin, (sender, block))| (*begin, sender.clone(), Arc::clone(block)))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Bytes of duplicate endgame blocks thrown away so far.
+    pub fn wasted_bytes(&self) -> u64 {
+        self.wasted_bytes
+    }
+
+    pub fn owners(&self, piece_index: u32) -> &[SessionId] {
+        self.assigned
+            .get(&piece_index)
+            .map(|a| a.owners.as_slice())
+            .unwrap_or(&[])
+    }
+
+    pub fn pending_len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn assigned_len(&self) -> usize {
+        self.assigned.len()
+    }
+
+    fn check_invariants(&self) {
+        for (index, assignment) in &self.assigned {
+            debug_assert!(
+                !assignment.owners.is_empty(),
+                "piece {index} assigned to nobody"
+            );
+            debug_assert!(
+                self.endgame || assignment.owners.len() == 1,
+                "piece {index} assigned to {:?} outside endgame",
+                assignment.owners
+            );
+            debug_assert!(
+                !self.pending.iter().any(|r| r.piece_index == *index),
+                "piece {index} is both pending and assigned"
+            );
         }
     }
 }
@@ -41,11 +602,44 @@ pub struct PieceRequest {
     pub length_bytes: usize,
 }
 
+impl PieceRequest {
+    /// A request for every piece of the torrent, in order.
+    pub fn all(info: &InfoEnum) -> Vec<Self> {
+        Self::for_length(info.total_length(), info.piece_length())
+    }
+
+    /// Splits `total_length` bytes into pieces of `piece_length`, the final
+    /// piece holding whatever is left.
+    fn for_length(total_length: u64, piece_length: u64) -> Vec<Self> {
+        if piece_length == 0 {
+            return vec![];
+        }
+
+        (0..total_length.div_ceil(piece_length))
+            .map(|index| Self {
+                piece_index: index as u32,
+                length_bytes: piece_length.min(total_length - index * piece_length) as usize,
+            })
+            .collect()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct PieceResponse {
     pub piece_index: u32,
+    pub session_id: SessionId,
+    /// Sessions whose peers sent blocks of the piece.
+    pub contributors: Vec<Contributor>,
     pub result: Result<Vec<u8>, PieceError>,
 }
+
+/// A session that sent blocks of a piece, with the address of its peer so
+/// the peer can be banned after the session is gone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Contributor {
+    pub session_id: SessionId,
+    pub address: String,
+}
 #[derive(Debug, Clone)]
 pub enum PieceError {
     Timeout,
@@ -54,3 +648,503 @@ pub enum PieceError {
     ConnectionLost,
     PieceUnavailable,
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+
+    use super::*;
+    use crate::torrent::peer_session::{PeerSession, lifecycle::EndReason};
+
+    fn peer(session_id: SessionId) -> Contributor {
+        Contributor {
+            session_id,
+            address: format!("10.0.0.{session_id}:6881"),
+        }
+    }
+
+    fn queue_with(pieces: u32) -> WorkQueue {
+        let mut queue = WorkQueue::new();
+        for piece_index in 0..pieces {
+            queue.push(PieceRequest {
+                piece_index,
+                length_bytes: 16384,
+            });
+        }
+        queue
+    }
+
+    #[test]
+    fn test_piece_never_assigned_twice() {
+        let mut queue = queue_with(2);
+
+        let a = queue.next_for(1, |_| true).unwrap();
+        let b = queue.next_for(2, |_| true).unwrap();
+
+        assert_ne!(a.piece_index, b.piece_index);
+        assert!(queue.next_for(3, |_| true).is_none());
+        assert_eq!(queue.owners(a.piece_index), &[1]);
+        assert_eq!(queue.owners(b.piece_index), &[2]);
+    }
+
+    #[test]
+    fn test_prioritized_pieces_go_first() {
+        let mut queue = queue_with(5);
+        let first = queue.next_for(1, |_| true).unwrap();
+
+        queue.prioritize(&[3, first.piece_index, 2]);
+
+        let order: Vec<u32> = std::iter::from_fn(|| queue.next_for(2, |_| true))
+            .map(|request| request.piece_index)
+            .collect();
+        assert_eq!(order, vec![3, 2, 1, 4]);
+    }
+
+    #[test]
+    fn test_snubbed_sessions_take_last() {
+        let mut queue = queue_with(3);
+        queue.set_snubbed(1, true);
+
+        assert_eq!(queue.next_for(1, |_| true).unwrap().piece_index, 2);
+        assert_eq!(queue.next_for(2, |_| true).unwrap().piece_index, 0);
+
+        // Its piece goes back to the front for others.
+        queue.release_session(1);
+        assert_eq!(queue.next_for(3, |_| true).unwrap().piece_index, 2);
+        assert!(queue.snubbed.is_empty());
+    }
+
+    #[test]
+    fn test_availability_follows_peers() {
+        let mut queue = WorkQueue::new();
+        queue.set_peer_pieces(1, &[0b1100_0000]);
+        queue.set_peer_pieces(2, &[0b1000_0000]);
+        assert_eq!(queue.availability(0), 2);
+        assert_eq!(queue.availability(1), 1);
+        assert_eq!(queue.availability(20), 0);
+
+        // A Have grows the peer's pieces, a closed session takes them away.
+        queue.set_peer_pieces(2, &[0b1000_0000, 0b0000_1000]);
+        assert_eq!(queue.availability(12), 1);
+        queue.release_session(1);
+        assert_eq!(queue.availability(0), 1);
+        assert_eq!(queue.availability(1), 0);
+    }
+
+    #[test]
+    fn test_next_for_skips_unavailable_pieces() {
+        let mut queue = queue_with(3);
+
+        let req = queue.next_for(1, |idx| idx == 2).unwrap();
+
+        assert_eq!(req.piece_index, 2);
+        assert_eq!(queue.pending_len(), 2);
+    }
+
+    #[test]
+    fn test_release_requeues_piece() {
+        let mut queue = queue_with(1);
+
+        let req = queue.next_for(1, |_| true).unwrap();
+        queue.release(req.piece_index, 1);
+
+        assert_eq!(queue.assigned_len(), 0);
+        assert_eq!(queue.next_for(2, |_| true).unwrap().piece_index, 0);
+    }
+
+    #[test]
+    fn test_release_session_returns_all_work() {
+        let mut queue = queue_with(3);
+
+        queue.next_for(1, |_| true);
+        queue.next_for(1, |_| true);
+        queue.next_for(2, |_| true);
+        queue.release_session(1);
+
+        assert_eq!(queue.assigned_len(), 1);
+        assert_eq!(queue.pending_len(), 2);
+    }
+
+    #[test]
+    fn test_endgame_allows_duplicate_assignment() {
+        let mut queue = queue_with(1);
+
+        queue.next_for(1, |_| true);
+        assert!(queue.next_for(2, |_| true).is_none());
+
+        queue.set_endgame(true);
+        let dup = queue.next_for(2, |_| true).unwrap();
+
+        assert_eq!(dup.piece_index, 0);
+        assert_eq!(queue.owners(0), &[1, 2]);
+        // A session never gets the same piece twice.
+        assert!(queue.next_for(2, |_| true).is_none());
+
+        assert_eq!(queue.complete(0), vec![1, 2]);
+        assert_eq!(queue.assigned_len(), 0);
+    }
+
+    #[test]
+    fn test_endgame_release_keeps_piece_with_other_owner() {
+        let mut queue = queue_with(1);
+
+        queue.next_for(1, |_| true);
+        queue.set_endgame(true);
+        queue.next_for(2, |_| true);
+        queue.release(0, 1);
+
+        assert_eq!(queue.owners(0), &[2]);
+        assert_eq!(queue.pending_len(), 0);
+    }
+
+    #[test]
+    fn test_endgame_keeps_first_block_copy() {
+        let mut queue = queue_with(1);
+
+        queue.next_for(1, |_| true);
+        assert_eq!(
+            queue.block_arrived(0, 0, &peer(1), b"early"),
+            BlockArrival::Accepted
+        );
+        assert!(queue.arrived_blocks(0).is_empty());
+
+        queue.set_endgame(true);
+        queue.next_for(2, |_| true);
+        assert_eq!(
+            queue.block_arrived(0, 0, &peer(2), b"first"),
+            BlockArrival::Accepted
+        );
+        assert_eq!(
+            queue.block_arrived(0, 0, &peer(1), b"again"),
+            BlockArrival::Duplicate
+        );
+        assert_eq!(
+            queue.block_arrived(0, 16384, &peer(1), b"next"),
+            BlockArrival::Accepted
+        );
+
+        let arrived = queue.arrived_blocks(0);
+        assert_eq!(arrived.len(), 2);
+        assert_eq!(
+            (
+                arrived[0].0,
+                arrived[0].1.session_id,
+                arrived[0].2.as_slice()
+            ),
+            (0, 2, &b"first"[..])
+        );
+        assert_eq!(queue.wasted_bytes(), 5);
+
+        queue.complete(0);
+        assert!(queue.arrived_blocks(0).is_empty());
+    }
+
+    #[test]
+    fn test_changes_wake_waiting_sessions() {
+        let mut queue = WorkQueue::new();
+        let changed = queue.changed();
+
+        let mut notified = std::pin::pin!(changed.notified());
+        notified.as_mut().enable();
+        queue.push(PieceRequest {
+            piece_index: 0,
+            length_bytes: 16384,
+        });
+        assert!(notified.now_or_never().is_some());
+
+        let mut notified = std::pin::pin!(changed.notified());
+        notified.as_mut().enable();
+        assert!(queue.next_for(1, |_| true).is_some());
+        assert!(notified.as_mut().now_or_never().is_none());
+        queue.release(0, 1);
+        assert!(notified.now_or_never().is_some());
+    }
+
+    #[test]
+    fn test_park_and_unpark() {
+        let mut queue = queue_with(2);
+
+        queue.next_for(1, |_| true);
+        queue.park(0);
+        queue.park(1);
+
+        assert_eq!(queue.parked().collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(queue.assigned_len(), 0);
+        assert!(queue.next_for(1, |_| true).is_none());
+
+        queue.unpark(1);
+        assert_eq!(queue.next_for(1, |_| true).unwrap().piece_index, 1);
+    }
+
+    #[tokio::test]
+    async fn test_piece_parked_after_repeated_hash_failures() {
+        let queue = Arc::new(Mutex::new(queue_with(1)));
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let mut manager = PieceManager::new(queue.clone(), rx);
+        manager.set_piece_metadata(vec![PieceMetadata {
+            index: 0,
+            hash: Sha1::digest(b"good").into(),
+            length: 4,
+            offset: 0,
+        }]);
+
+        for attempt in 1..=MAX_HASH_FAILURES {
+            let session = queue.lock().await.next_for(1, |_| true).unwrap();
+            manager
+                .handle_response(PieceResponse {
+                    piece_index: session.piece_index,
+                    session_id: 1,
+                    contributors: vec![peer(1)],
+                    result: Ok(b"bad!".to_vec()),
+                })
+                .await;
+            assert_eq!(manager.hash_failures(0), attempt);
+        }
+
+        let mut queue = queue.lock().await;
+        assert_eq!(queue.parked().collect::<Vec<_>>(), vec![0]);
+        assert!(queue.next_for(1, |_| true).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_endgame_block_starts_piece_over() {
+        let queue = Arc::new(Mutex::new(queue_with(1)));
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let mut manager = PieceManager::new(queue.clone(), rx);
+        manager.set_piece_metadata(vec![PieceMetadata {
+            index: 0,
+            hash: Sha1::digest(b"good").into(),
+            length: 4,
+            offset: 0,
+        }]);
+        let response = |session_id, data: &[u8]| PieceResponse {
+            piece_index: 0,
+            session_id,
+            contributors: vec![peer(1)],
+            result: Ok(data.to_vec()),
+        };
+
+        // Session 1 receives a bad block that session 2 adopts.
+        {
+            let mut queue = queue.lock().await;
+            queue.next_for(1, |_| true);
+            queue.set_endgame(true);
+            queue.next_for(2, |_| true);
+            queue.block_arrived(0, 0, &peer(1), b"bad!");
+        }
+        manager.handle_response(response(1, b"bad!")).await;
+
+        {
+            let queue = queue.lock().await;
+            assert!(queue.arrived_blocks(0).is_empty());
+            assert!(queue.owners(0).is_empty());
+            assert_eq!(queue.pending_len(), 1);
+        }
+
+        // Session 2's copy of the bad block isn't counted again.
+        manager.handle_response(response(2, b"bad!")).await;
+        assert_eq!(manager.hash_failures(0), 1);
+
+        {
+            let mut queue = queue.lock().await;
+            assert_eq!(queue.next_for(2, |_| true).unwrap().piece_index, 0);
+            assert!(queue.arrived_blocks(0).is_empty());
+        }
+
+        let mut good = response(2, b"good");
+        good.contributors = vec![peer(2)];
+        manager.handle_response(good).await;
+
+        let queue = queue.lock().await;
+        assert_eq!(queue.assigned_len(), 0);
+        assert_eq!(queue.pending_len(), 0);
+        assert_eq!(manager.hash_failures(0), 0);
+        assert_eq!(manager.corrupt_pieces(1), 1);
+    }
+
+    #[tokio::test]
+    async fn test_peer_banned_after_repeated_bad_pieces() {
+        let queue = Arc::new(Mutex::new(queue_with(MAX_CORRUPT_PIECES + 1)));
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let mut manager = PieceManager::new(queue.clone(), rx);
+        manager.set_piece_metadata(
+            (0..=MAX_CORRUPT_PIECES)
+                .map(|index| PieceMetadata {
+                    index,
+                    hash: Sha1::digest(b"good").into(),
+                    length: 4,
+                    offset: 0,
+                })
+                .collect(),
+        );
+
+        let bad = PeerSession::new("10.0.0.9:6881", [0; 20], [1; 20])
+            .await
+            .unwrap();
+        let honest = bad.id() + 1000;
+        let sessions = Arc::new(Mutex::new(HashMap::from([(
+            String::from(bad.url()),
+            bad.handle(),
+        )])));
+        let bans = BanList::default();
+        manager.set_ban_list(sessions.clone(), bans.clone());
+
+        for piece_index in 0..=MAX_CORRUPT_PIECES {
+            assert!(!bans.is_banned("10.0.0.9:6881"));
+            queue
+                .lock()
+                .await
+                .next_for(bad.id(), |idx| idx == piece_index);
+            // An honest peer sent part of the first piece.
+            let mut contributors = vec![Contributor {
+                session_id: bad.id(),
+                address: String::from(bad.url()),
+            }];
+            if piece_index == 0 {
+                contributors.push(Contributor {
+                    session_id: honest,
+                    address: String::from("10.0.0.8:6881"),
+                });
+            }
+            manager
+                .handle_response(PieceResponse {
+                    piece_index,
+                    session_id: bad.id(),
+                    contributors,
+                    result: Ok(b"bad!".to_vec()),
+                })
+                .await;
+        }
+
+        // Banned from every port, its session closed.
+        assert!(bans.is_banned("10.0.0.9:51413"));
+        assert_eq!(
+            sessions.lock().await["10.0.0.9:6881"].end_reason(),
+            Some(EndReason::Killed)
+        );
+        assert_eq!(manager.corrupt_pieces(honest), 1);
+        assert!(!bans.is_banned("10.0.0.8:6881"));
+    }
+
+    #[tokio::test]
+    async fn test_disconnected_peer_still_banned() {
+        let queue = Arc::new(Mutex::new(queue_with(MAX_CORRUPT_PIECES + 1)));
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let mut manager = PieceManager::new(queue.clone(), rx);
+        manager.set_piece_metadata(
+            (0..=MAX_CORRUPT_PIECES)
+                .map(|index| PieceMetadata {
+                    index,
+                    hash: Sha1::digest(b"good").into(),
+                    length: 4,
+                    offset: 0,
+                })
+                .collect(),
+        );
+        // The peer's session is already gone from the torrent.
+        let bans = BanList::default();
+        manager.set_ban_list(Arc::default(), bans.clone());
+
+        for piece_index in 0..=MAX_CORRUPT_PIECES {
+            queue.lock().await.next_for(1, |idx| idx == piece_index);
+            manager
+                .handle_response(PieceResponse {
+                    piece_index,
+                    session_id: 1,
+                    contributors: vec![peer(7)],
+                    result: Ok(b"bad!".to_vec()),
+                })
+                .await;
+        }
+
+        assert!(bans.is_banned("10.0.0.7:6881"));
+    }
+
+    #[tokio::test]
+    async fn test_valid_piece_completes() {
+        let queue = Arc::new(Mutex::new(queue_with(1)));
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let mut manager = PieceManager::new(queue.clone(), rx);
+        manager.set_piece_metadata(vec![PieceMetadata {
+            index: 0,
+            hash: Sha1::digest(b"good").into(),
+            length: 4,
+            offset: 0,
+        }]);
+
+        queue.lock().await.next_for(1, |_| true);
+        manager
+            .handle_response(PieceResponse {
+                piece_index: 0,
+                session_id: 1,
+                contributors: vec![peer(1)],
+                result: Ok(b"good".to_vec()),
+            })
+            .await;
+
+        let queue = queue.lock().await;
+        assert_eq!(queue.assigned_len(), 0);
+        assert_eq!(queue.pending_len(), 0);
+        assert_eq!(manager.hash_failures(0), 0);
+    }
+
+    #[tokio::test]
+    async fn test_verified_piece_lowers_left() {
+        let queue = Arc::new(Mutex::new(queue_with(1)));
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let left = Arc::new(AtomicU64::new(10));
+        let mut manager = PieceManager::new(queue.clone(), rx);
+        manager.set_left(left.clone());
+        manager.set_piece_metadata(vec![PieceMetadata {
+            index: 0,
+            hash: Sha1::digest(b"good").into(),
+            length: 4,
+            offset: 0,
+        }]);
+
+        for _ in 0..2 {
+            manager
+                .handle_response(PieceResponse {
+                    piece_index: 0,
+                    session_id: 1,
+                    contributors: vec![peer(1)],
+                    result: Ok(b"good".to_vec()),
+                })
+                .await;
+        }
+
+        assert_eq!(left.load(Ordering::Relaxed), 6);
+    }
+
+    #[test]
+    fn test_final_piece_length() {
+        let lengths = |total, piece_length| {
+            PieceRequest::for_length(total, piece_length)
+                .iter()
+                .map(|r| r.length_bytes)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(lengths(32, 16), vec![16, 16]);
+        assert_eq!(lengths(31, 16), vec![16, 15]);
+        assert_eq!(lengths(33, 16), vec![16, 16, 1]);
+        assert_eq!(lengths(5, 16), vec![5]);
+        assert_eq!(lengths(0, 16), Vec::<usize>::new());
+
+        let requests = PieceRequest::for_length(33, 16);
+        assert_eq!(requests.last().unwrap().piece_index, 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "queued while assigned")]
+    #[cfg(debug_assertions)]
+    fn test_push_assigned_piece_panics() {
+        let mut queue = queue_with(1);
+
+        queue.next_for(1, |_| true);
+        queue.push(PieceRequest {
+            piece_index: 0,
+            length_bytes: 16384,
+        });
+    }
+}
@@ -0,0 +1,124 @@
+//! Privacy mode.
+//!
+//! Masks peer addresses, info hashes, torrent and file names and tracker
+//! URLs, which may carry a passkey, in the TUI and in log lines, so the
+//! terminal can be streamed or screenshotted for a bug report. Toggled
+//! with a key in the TUI, and on from the start when the `BTRS_PRIVACY`
+//! environment variable is set.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+pub const PRIVACY_ENV_VAR: &str = "BTRS_PRIVACY";
+
+/// Shown in place of anything masked entirely.
+const HIDDEN: &str = "[hidden]";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turns privacy mode on if [`PRIVACY_ENV_VAR`] is set.
+pub fn init_from_env() {
+    set_enabled(std::env::var_os(PRIVACY_ENV_VAR).is_some());
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn toggle() {
+    ENABLED.fetch_xor(true, Ordering::Relaxed);
+}
+
+/// A peer's `ip` or `ip:port`, keeping the port and the last part of
+/// the address so peers can still be told apart.
+pub fn address(address: &str) -> String {
+    if !is_enabled() {
+        return String::from(address);
+    }
+
+    mask_address(address)
+}
+
+/// An info hash, or any other identifier of the torrent.
+pub fn info_hash(info_hash: &str) -> String {
+    if !is_enabled() {
+        return String::from(info_hash);
+    }
+
+    String::from(HIDDEN)
+}
+
+/// A torrent, file or directory name, keeping only the file extension.
+pub fn name(name: &str) -> String {
+    if !is_enabled() {
+        return String::from(name);
+    }
+
+    mask_name(name)
+}
+
+/// A tracker URL, keeping only the scheme so the kind of tracker shows.
+pub fn tracker_url(url: &str) -> String {
+    if !is_enabled() {
+        return String::from(url);
+    }
+
+    match url.split_once("://") {
+        Some((scheme, _)) => format!("{scheme}://{HIDDEN}"),
+        None => String::from(HIDDEN),
+    }
+}
+
+fn mask_address(address: &str) -> String {
+    let (ip, port) = match address.parse::<SocketAddr>() {
+        Ok(socket) => (socket.ip(), Some(socket.port())),
+        Err(_) => match address.parse::<IpAddr>() {
+            Ok(ip) => (ip, None),
+            Err(_) => return String::from(HIDDEN),
+        },
+    };
+
+    let ip = match ip {
+        IpAddr::V4(ip) => format!("*.*.*.{}", ip.octets()[3]),
+        IpAddr::V6(ip) => format!("*:{:x}", ip.segments()[7]),
+    };
+
+    match port {
+        Some(port) => format!("{ip}:{port}"),
+        None => ip,
+    }
+}
+
+fn mask_name(name: &str) -> String {
+    match name.rsplit_once('.') {
+        Some((stem, extension))
+            if !stem.is_empty() && extension.chars().all(char::is_alphanumeric) =>
+        {
+            format!("{HIDDEN}.{extension}")
+        }
+        _ => String::from(HIDDEN),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_masks() {
+        assert_eq!(mask_address("192.168.1.23:6881"), "*.*.*.23:6881");
+        assert_eq!(mask_address("10.0.0.1"), "*.*.*.1");
+        assert_eq!(mask_address("[2001:db8::1:2]:51413"), "*:2:51413");
+        assert_eq!(mask_address("not an address"), HIDDEN);
+
+        assert_eq!(mask_name("holiday.mkv"), "[hidden].mkv");
+        assert_eq!(mask_name("Some Folder"), HIDDEN);
+        assert_eq!(mask_name(".hidden"), HIDDEN);
+    }
+}
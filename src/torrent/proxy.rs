@@ -0,0 +1,180 @@
+//! Proxy settings and protection against leaking our real address.
+//!
+//! Setting `BTRS_PROXY` (e.g. `socks5://127.0.0.1:9050` or
+//! `http://proxy:3128`) sends HTTP tracker announces through the proxy.
+//! Connections that can't go through it, peer connections and UDP
+//! trackers, are refused so our address isn't exposed behind the user's
+//! back. Set `BTRS_PROXY_ALLOW_DIRECT` to make them directly anyway.
+//! SOCKS proxies need the `socks` feature, which is on by default.
+//!
+//! `BTRS_TRACKER_PROXY` sets a proxy for tracker traffic only, taking
+//! precedence over `BTRS_PROXY` for announces while peers connect directly.
+//! UDP trackers are then refused in the same way. Credentials can be given
+//! in the URL or with `BTRS_TRACKER_PROXY_USER` and
+//! `BTRS_TRACKER_PROXY_PASSWORD`.
+
+use std::fmt;
+
+pub const PROXY_ENV_VAR: &str = "BTRS_PROXY";
+pub const ALLOW_DIRECT_ENV_VAR: &str = "BTRS_PROXY_ALLOW_DIRECT";
+pub const TRACKER_PROXY_ENV_VAR: &str = "BTRS_TRACKER_PROXY";
+pub const TRACKER_PROXY_USER_ENV_VAR: &str = "BTRS_TRACKER_PROXY_USER";
+pub const TRACKER_PROXY_PASSWORD_ENV_VAR: &str = "BTRS_TRACKER_PROXY_PASSWORD";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyConfig {
+    pub url: String,
+    /// Allow connections the proxy can't carry to bypass it.
+    pub allow_direct: bool,
+    pub auth: Option<ProxyAuth>,
+}
+
+/// Credentials sent to the proxy with basic authentication.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ProxyAuth {
+    pub username: String,
+    pub password: String,
+}
+
+impl fmt::Debug for ProxyAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProxyAuth")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Error returned for a connection that would bypass the proxy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeakError {
+    pub connection: String,
+    pub proxy: String,
+}
+
+impl fmt::Display for LeakError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Refusing {}: it would bypass proxy {} and expose our address, \
+             set {ALLOW_DIRECT_ENV_VAR} to allow",
+            self.connection, self.proxy
+        )
+    }
+}
+
+impl std::error::Error for LeakError {}
+
+impl ProxyConfig {
+    pub fn from_env() -> Option<Self> {
+        let url = std::env::var(PROXY_ENV_VAR)
+            .ok()
+            .filter(|url| !url.is_empty())?;
+
+        Some(Self {
+            url,
+            allow_direct: std::env::var_os(ALLOW_DIRECT_ENV_VAR).is_some(),
+            auth: None,
+        })
+    }
+
+    /// The proxy for tracker traffic: `BTRS_TRACKER_PROXY` if set, or else
+    /// the general proxy.
+    pub fn tracker_from_env() -> Option<Self> {
+        let Some(url) = std::env::var(TRACKER_PROXY_ENV_VAR)
+            .ok()
+            .filter(|url| !url.is_empty())
+        else {
+            return Self::from_env();
+        };
+
+        let auth = std::env::var(TRACKER_PROXY_USER_ENV_VAR)
+            .ok()
+            .map(|username| ProxyAuth {
+                username,
+                password: std::env::var(TRACKER_PROXY_PASSWORD_ENV_VAR).unwrap_or_default(),
+            });
+
+        Some(Self {
+            url,
+            allow_direct: std::env::var_os(ALLOW_DIRECT_ENV_VAR).is_some(),
+            auth,
+        })
+    }
+
+    /// The proxy as understood by the tracker HTTP client.
+    pub fn to_reqwest(&self) -> Result<reqwest::Proxy, reqwest::Error> {
+        let proxy = reqwest::Proxy::all(&self.url)?;
+
+        Ok(match &self.auth {
+            Some(auth) => proxy.basic_auth(&auth.username, &auth.password),
+            None => proxy,
+        })
+    }
+
+    /// Fails unless direct connections were explicitly allowed.
+    pub fn check_direct(&self, connection: &str) -> Result<(), LeakError> {
+        if self.allow_direct {
+            return Ok(());
+        }
+
+        Err(LeakError {
+            connection: String::from(connection),
+            proxy: self.url.clone(),
+        })
+    }
+}
+
+/// Checks a connection that is always made directly against the proxy
+/// settings in the environment.
+pub fn check_direct(connection: &str) -> Result<(), LeakError> {
+    match ProxyConfig::from_env() {
+        Some(proxy) => proxy.check_direct(connection),
+        None => Ok(()),
+    }
+}
+
+/// Like [`check_direct`], for tracker connections the tracker proxy
+/// can't carry.
+pub fn check_direct_tracker(connection: &str) -> Result<(), LeakError> {
+    match ProxyConfig::tracker_from_env() {
+        Some(proxy) => proxy.check_direct(connection),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_direct_connections_refused_unless_allowed() {
+        let mut proxy = ProxyConfig {
+            url: String::from("socks5://127.0.0.1:9050"),
+            allow_direct: false,
+            auth: None,
+        };
+
+        let error = proxy.check_direct("peer connection to 1.2.3.4:6881");
+        assert!(error.unwrap_err().to_string().contains("1.2.3.4:6881"));
+
+        proxy.allow_direct = true;
+        assert!(proxy.check_direct("peer connection").is_ok());
+    }
+
+    #[test]
+    fn test_auth_not_in_debug_output() {
+        let proxy = ProxyConfig {
+            url: String::from("http://proxy.test:3128"),
+            allow_direct: false,
+            auth: Some(ProxyAuth {
+                username: String::from("user"),
+                password: String::from("hunter2"),
+            }),
+        };
+
+        assert!(proxy.to_reqwest().is_ok());
+        let debug = format!("{proxy:?}");
+        assert!(debug.contains("user"));
+        assert!(!debug.contains("hunter2"));
+    }
+}
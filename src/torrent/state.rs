@@ -0,0 +1,97 @@
+//! What a torrent is doing, as one explicit state.
+//!
+//! The torrent moves between states itself as it is checked, started,
+//! stopped or fails. Every move goes through [`TorrentState::transition`],
+//! which refuses moves that make no sense, e.g. from checking straight to
+//! fetching metadata, so a bug shows up as a logged error instead of a
+//! wrong status.
+
+use std::fmt;
+
+use anyhow::{Error, bail};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TorrentState {
+    /// Waiting for its data check, or for a slot to start in.
+    Queued,
+    Checking,
+    /// Added from a magnet link, getting the info dictionary from peers.
+    FetchingMetadata,
+    Downloading,
+    /// Has all wanted data and is uploading.
+    Seeding,
+    /// Stopped by the user, or not started yet.
+    Paused,
+    Error(String),
+}
+
+impl TorrentState {
+    /// Moves to `next`, leaving the state unchanged if the move isn't
+    /// allowed. Moving to the current state is a no-op.
+    pub fn transition(&mut self, next: TorrentState) -> Result<(), Error> {
+        use TorrentState::*;
+
+        let allowed = match (&*self, &next) {
+            (current, next) if current == next => true,
+            // Anything can fail, and a failed torrent can be retried.
+            (_, Error(_)) | (Error(_), _) => true,
+            (Queued, Checking | Paused) => true,
+            (Checking, Queued | Downloading | Seeding | Paused) => true,
+            (FetchingMetadata, Queued | Checking | Downloading | Paused) => true,
+            (Downloading, Checking | Seeding | Paused) => true,
+            (Seeding, Checking | Downloading | Paused) => true,
+            (Paused, Queued | Checking | FetchingMetadata | Downloading | Seeding) => true,
+            _ => false,
+        };
+        if !allowed {
+            bail!("Torrent can't go from {self} to {next}");
+        }
+
+        *self = next;
+        Ok(())
+    }
+
+    pub fn is_error(&self) -> bool {
+        matches!(self, TorrentState::Error(_))
+    }
+}
+
+impl fmt::Display for TorrentState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TorrentState::Queued => write!(f, "Queued"),
+            TorrentState::Checking => write!(f, "Checking"),
+            TorrentState::FetchingMetadata => write!(f, "Fetching metadata"),
+            TorrentState::Downloading => write!(f, "Downloading"),
+            TorrentState::Seeding => write!(f, "Seeding"),
+            TorrentState::Paused => write!(f, "Paused"),
+            TorrentState::Error(reason) => write!(f, "Error: {reason}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transitions() {
+        let mut state = TorrentState::Paused;
+
+        state.transition(TorrentState::Queued).unwrap();
+        assert!(state.transition(TorrentState::Seeding).is_err());
+        assert_eq!(state, TorrentState::Queued);
+
+        state.transition(TorrentState::Checking).unwrap();
+        assert!(state.transition(TorrentState::FetchingMetadata).is_err());
+        state.transition(TorrentState::Downloading).unwrap();
+        state.transition(TorrentState::Seeding).unwrap();
+
+        state
+            .transition(TorrentState::Error(String::from("tracker panicked")))
+            .unwrap();
+        assert!(state.is_error());
+        state.transition(TorrentState::Downloading).unwrap();
+        state.transition(TorrentState::Downloading).unwrap();
+    }
+}
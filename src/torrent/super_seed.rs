@@ -0,0 +1,218 @@
+//! Super-seeding (BEP 16) bookkeeping.
+//!
+//! In super-seed mode the initial seed advertises an empty bitfield and
+//! reveals one piece at a time to each peer via a Have message. A peer
+//! is only shown a new piece once the piece it was given has been seen
+//! on some *other* peer, proving it was uploaded onward. Pieces with the
+//! fewest known copies are revealed first.
+//!
+//! Set `BTRS_SUPER_SEED` to super-seed torrents that are complete when a
+//! peer connects. Peers of a torrent still downloading get our bitfield
+//! and Haves as usual.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::Notify;
+
+use crate::torrent::piece_manager::SessionId;
+
+pub const SUPER_SEED_ENV_VAR: &str = "BTRS_SUPER_SEED";
+
+/// Whether [`SUPER_SEED_ENV_VAR`] asks for super-seeding.
+pub fn enabled() -> bool {
+    std::env::var_os(SUPER_SEED_ENV_VAR).is_some()
+}
+
+#[derive(Debug, Default)]
+struct PeerProgress {
+    /// Piece currently revealed to the peer and not yet seen elsewhere.
+    offered: Option<u32>,
+    /// Pieces the peer is known to have from its bitfield and Haves.
+    has: HashSet<u32>,
+}
+
+#[derive(Debug)]
+pub struct SuperSeeder {
+    /// Known copies of each piece across the connected peers.
+    availability: Vec<u32>,
+    peers: HashMap<SessionId, PeerProgress>,
+}
+
+impl SuperSeeder {
+    pub fn new(num_pieces: usize) -> Self {
+        Self {
+            availability: vec![0; num_pieces],
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Picks the next piece to reveal to `peer`, or `None` if the piece it
+    /// was last given hasn't spread to another peer yet.
+    pub fn next_piece(&mut self, peer: SessionId) -> Option<u32> {
+        let offered_elsewhere: HashSet<u32> = self
+            .peers
+            .iter()
+            .filter(|(id, _)| **id != peer)
+            .filter_map(|(_, p)| p.offered)
+            .collect();
+
+        let progress = self.peers.entry(peer).or_default();
+        if progress.offered.is_some() {
+            return None;
+        }
+
+        let piece = (0..self.availability.len() as u32)
+            .filter(|idx| !progress.has.contains(idx) && !offered_elsewhere.contains(idx))
+            .min_by_key(|idx| self.availability[*idx as usize])?;
+
+        progress.offered = Some(piece);
+
+        Some(piece)
+    }
+
+    /// Records that `peer` announced `piece` through a Have or its bitfield.
+    ///
+    /// If another peer had been given that piece, it has now been uploaded
+    /// onward and that peer becomes eligible for a new one.
+    pub fn on_have(&mut self, peer: SessionId, piece: u32) {
+        let Some(count) = self.availability.get_mut(piece as usize) else {
+            return;
+        };
+
+        let progress = self.peers.entry(peer).or_default();
+        if !progress.has.insert(piece) {
+            return;
+        }
+        *count += 1;
+
+        for (id, other) in self.peers.iter_mut() {
+            if *id != peer && other.offered == Some(piece) {
+                other.offered = None;
+            }
+        }
+    }
+
+    /// Forgets a disconnected peer, freeing the piece it was offered.
+    pub fn remove_peer(&mut self, peer: SessionId) {
+        if let Some(progress) = self.peers.remove(&peer) {
+            for piece in progress.has {
+                self.availability[piece as usize] -= 1;
+            }
+        }
+    }
+}
+
+/// A torrent's [`SuperSeeder`], shared by its sessions, which wait on
+/// [`SuperSeed::spread`] for their next piece.
+#[derive(Debug, Clone)]
+pub struct SuperSeed {
+    seeder: Arc<Mutex<SuperSeeder>>,
+    /// Raised whenever a peer may be due a new piece.
+    spread: Arc<Notify>,
+}
+
+impl SuperSeed {
+    pub fn new(num_pieces: usize) -> Self {
+        Self {
+            seeder: Arc::new(Mutex::new(SuperSeeder::new(num_pieces))),
+            spread: Arc::default(),
+        }
+    }
+
+    /// See [`SuperSeeder::next_piece`].
+    pub fn next_piece(&self, peer: SessionId) -> Option<u32> {
+        self.seeder.lock().unwrap().next_piece(peer)
+    }
+
+    /// Records every piece set in `bitfield`, the pieces `peer` announced
+    /// through its Bitfield and Haves so far.
+    pub fn on_bitfield(&self, peer: SessionId, bitfield: &[u8]) {
+        let mut seeder = self.seeder.lock().unwrap();
+        for piece in 0..bitfield.len() as u32 * 8 {
+            if bitfield[piece as usize / 8] & (0x80 >> (piece % 8)) != 0 {
+                seeder.on_have(peer, piece);
+            }
+        }
+        drop(seeder);
+        self.spread.notify_waiters();
+    }
+
+    /// See [`SuperSeeder::remove_peer`].
+    pub fn remove_peer(&self, peer: SessionId) {
+        self.seeder.lock().unwrap().remove_peer(peer);
+        self.spread.notify_waiters();
+    }
+
+    pub fn spread(&self) -> Arc<Notify> {
+        Arc::clone(&self.spread)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_one_piece_revealed_at_a_time() {
+        let mut seeder = SuperSeeder::new(4);
+
+        let piece = seeder.next_piece(1).unwrap();
+
+        assert_eq!(seeder.next_piece(1), None);
+        // The peer downloading its own piece doesn't unlock the next one.
+        seeder.on_have(1, piece);
+        assert_eq!(seeder.next_piece(1), None);
+    }
+
+    #[test]
+    fn test_piece_seen_elsewhere_unlocks_next() {
+        let mut seeder = SuperSeeder::new(4);
+
+        let piece = seeder.next_piece(1).unwrap();
+        seeder.on_have(1, piece);
+        seeder.on_have(2, piece);
+
+        let next = seeder.next_piece(1).unwrap();
+        assert_ne!(next, piece);
+    }
+
+    #[test]
+    fn test_peers_get_different_rarest_pieces() {
+        let mut seeder = SuperSeeder::new(3);
+        seeder.on_have(9, 0);
+        seeder.on_have(9, 1);
+
+        assert_eq!(seeder.next_piece(1), Some(2));
+        // Piece 2 is already out with peer 1, so the next rarest is offered.
+        let second = seeder.next_piece(2).unwrap();
+        assert!(second == 0 || second == 1);
+    }
+
+    #[test]
+    fn test_remove_peer_frees_offer() {
+        let mut seeder = SuperSeeder::new(1);
+
+        assert_eq!(seeder.next_piece(1), Some(0));
+        assert_eq!(seeder.next_piece(2), None);
+
+        seeder.remove_peer(1);
+        assert_eq!(seeder.next_piece(2), Some(0));
+    }
+
+    #[test]
+    fn test_bitfield_counts_as_haves() {
+        let seed = SuperSeed::new(10);
+
+        let piece = seed.next_piece(1).unwrap();
+        let mut bitfield = vec![0u8; 2];
+        bitfield[piece as usize / 8] |= 0x80 >> (piece % 8);
+        // Bits past the last piece are ignored.
+        bitfield[1] |= 0x01;
+        seed.on_bitfield(2, &bitfield);
+
+        assert!(seed.next_piece(1).is_some_and(|next| next != piece));
+    }
+}
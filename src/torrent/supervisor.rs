@@ -0,0 +1,156 @@
+//! Supervision of long running tasks.
+//!
+//! A task run through [`run`] has its panic or error logged with the task's
+//! name instead of vanishing with its `JoinHandle`. [`supervise`] also
+//! restarts a task that failed, waiting longer after each failure.
+
+use std::{fmt, future::Future, time::Duration};
+
+use tokio::task::AbortHandle;
+
+/// Wait before the first restart, doubled for each further failure.
+const BASE_RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// How a supervised task ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TaskExit {
+    Finished,
+    Failed(String),
+    Panicked(String),
+    Cancelled,
+}
+
+impl TaskExit {
+    /// Whether the task ended because something went wrong.
+    pub fn is_error(&self) -> bool {
+        matches!(self, TaskExit::Failed(_) | TaskExit::Panicked(_))
+    }
+}
+
+impl fmt::Display for TaskExit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TaskExit::Finished => write!(f, "finished"),
+            TaskExit::Failed(error) => write!(f, "failed: {error}"),
+            TaskExit::Panicked(message) => write!(f, "panicked: {message}"),
+            TaskExit::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+/// Aborts the supervised task when the supervisor itself is dropped, e.g.
+/// because its own task was aborted.
+struct AbortOnDrop(AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Runs `task` to completion on its own tokio task, logging how it ended
+/// unless it simply finished.
+pub async fn run<F>(name: &str, task: F) -> TaskExit
+where
+    F: Future<Output = Result<(), anyhow::Error>> + Send + 'static,
+{
+    let handle = tokio::spawn(task);
+    let _guard = AbortOnDrop(handle.abort_handle());
+
+    let exit = match handle.await {
+        Ok(Ok(())) => TaskExit::Finished,
+        Ok(Err(e)) => TaskExit::Failed(format!("{e:#}")),
+        Err(e) if e.is_panic() => {
+            let payload = e.into_panic();
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|message| String::from(*message))
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| String::from("unknown panic"));
+            TaskExit::Panicked(message)
+        }
+        Err(_) => TaskExit::Cancelled,
+    };
+
+    if exit != TaskExit::Finished {
+        eprintln!("[Supervisor] task={name} exit={exit}");
+    }
+
+    exit
+}
+
+/// Runs the task made by `make` until it finishes, restarting it with
+/// backoff whenever it fails or panics. `on_exit` sees every exit.
+pub async fn supervise<M, F>(name: &str, mut make: M, mut on_exit: impl FnMut(&TaskExit))
+where
+    M: FnMut() -> F,
+    F: Future<Output = Result<(), anyhow::Error>> + Send + 'static,
+{
+    let mut failures = 0;
+
+    loop {
+        let exit = run(name, make()).await;
+        on_exit(&exit);
+        if !exit.is_error() {
+            return;
+        }
+
+        failures += 1;
+        let delay = restart_delay(failures);
+        eprintln!("[Supervisor] task={name} restart_in={delay:?} failures={failures}");
+        tokio::time::sleep(delay).await;
+    }
+}
+
+fn restart_delay(failures: u32) -> Duration {
+    let exponent = failures.saturating_sub(1).min(16);
+
+    BASE_RESTART_DELAY
+        .saturating_mul(1 << exponent)
+        .min(MAX_RESTART_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    };
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_restarts_after_panic_and_error() {
+        let attempts = Arc::new(AtomicU32::new(0));
+        let mut exits = vec![];
+
+        let make = || {
+            let attempts = Arc::clone(&attempts);
+            async move {
+                match attempts.fetch_add(1, Ordering::Relaxed) {
+                    0 => panic!("boom"),
+                    1 => anyhow::bail!("broken"),
+                    _ => Ok(()),
+                }
+            }
+        };
+        supervise("test", make, |exit| exits.push(exit.clone())).await;
+
+        assert_eq!(
+            exits,
+            vec![
+                TaskExit::Panicked(String::from("boom")),
+                TaskExit::Failed(String::from("broken")),
+                TaskExit::Finished,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_restart_delay() {
+        assert_eq!(restart_delay(1), BASE_RESTART_DELAY);
+        assert_eq!(restart_delay(3), BASE_RESTART_DELAY * 4);
+        assert_eq!(restart_delay(100), MAX_RESTART_DELAY);
+    }
+}
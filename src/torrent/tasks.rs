@@ -0,0 +1,139 @@
+//! Counts of running tasks by subsystem.
+//!
+//! Tasks spawned through [`spawn`] and [`spawn_blocking`] are counted from
+//! start until they finish or are aborted. The counts are shown in the
+//! debug tab, so tasks that never end, e.g. the workers of a peer session
+//! nobody kills, show up as a number that only grows.
+
+use std::{
+    future::Future,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use tokio::task::JoinHandle;
+
+/// Part of the client a task belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsystem {
+    /// Announce loops of running torrents.
+    Tracker,
+    /// Background `stopped` announces and connection teardown.
+    Stop,
+    /// Listener and requester of each peer connection.
+    Peer,
+    /// Queued data checks.
+    Check,
+    /// Blocking reads and hashing of piece data.
+    Disk,
+}
+
+impl Subsystem {
+    const ALL: [Subsystem; 5] = [
+        Subsystem::Tracker,
+        Subsystem::Stop,
+        Subsystem::Peer,
+        Subsystem::Check,
+        Subsystem::Disk,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Subsystem::Tracker => "tracker",
+            Subsystem::Stop => "stop",
+            Subsystem::Peer => "peer",
+            Subsystem::Check => "check",
+            Subsystem::Disk => "disk",
+        }
+    }
+
+    fn running(self) -> &'static AtomicUsize {
+        static RUNNING: [AtomicUsize; 5] = [const { AtomicUsize::new(0) }; 5];
+
+        &RUNNING[self as usize]
+    }
+}
+
+/// Counts a task as running until dropped, which also happens when the
+/// task is aborted.
+struct Running(&'static AtomicUsize);
+
+impl Running {
+    fn start(counter: &'static AtomicUsize) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Self(counter)
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// [`tokio::spawn`], counting the task under `subsystem`.
+pub fn spawn<F>(subsystem: Subsystem, task: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    spawn_counted(subsystem.running(), task)
+}
+
+/// [`tokio::task::spawn_blocking`], counting the job under `subsystem`.
+pub fn spawn_blocking<F, R>(subsystem: Subsystem, job: F) -> JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let running = Running::start(subsystem.running());
+
+    tokio::task::spawn_blocking(move || {
+        let _running = running;
+        job()
+    })
+}
+
+fn spawn_counted<F>(counter: &'static AtomicUsize, task: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let running = Running::start(counter);
+
+    tokio::spawn(async move {
+        let _running = running;
+        task.await
+    })
+}
+
+/// Tasks running right now in every subsystem.
+pub fn running_counts() -> Vec<(&'static str, usize)> {
+    Subsystem::ALL
+        .iter()
+        .map(|subsystem| {
+            (
+                subsystem.name(),
+                subsystem.running().load(Ordering::Relaxed),
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_counts_until_finished_or_aborted() {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let pending = spawn_counted(&COUNTER, std::future::pending::<()>());
+        let finished = spawn_counted(&COUNTER, async {});
+        finished.await.unwrap();
+        assert_eq!(COUNTER.load(Ordering::Relaxed), 1);
+
+        pending.abort();
+        assert!(pending.await.unwrap_err().is_cancelled());
+        assert_eq!(COUNTER.load(Ordering::Relaxed), 0);
+    }
+}
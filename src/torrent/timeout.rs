@@ -0,0 +1,95 @@
+//! Deadlines for awaited network operations.
+//!
+//! Every await on a socket or HTTP request goes through [`with_timeout`]
+//! so that a silent peer or hung tracker surfaces as a [`TimeoutError`]
+//! instead of blocking its task forever.
+
+use std::{fmt, future::Future, time::Duration};
+
+/// Configurable deadlines for each kind of network operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Opening a TCP connection to a peer.
+    pub connect: Duration,
+    /// Sending and receiving the peer handshake.
+    pub handshake: Duration,
+    /// Waiting for the next message from a connected peer.
+    pub message: Duration,
+    /// A full tracker announce round trip.
+    pub tracker: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(10),
+            handshake: Duration::from_secs(10),
+            // Peers must keep-alive at least every two minutes, a peer
+            // that sent nothing for longer is gone.
+            message: Duration::from_secs(120),
+            tracker: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Error returned when an operation does not complete before its deadline.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeoutError {
+    pub operation: &'static str,
+    pub after: Duration,
+}
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} timed out after {:?}", self.operation, self.after)
+    }
+}
+
+impl std::error::Error for TimeoutError {}
+
+/// Awaits `future`, failing with a [`TimeoutError`] if it takes longer than `after`.
+///
+/// Errors from the future itself are passed through unchanged, callers can
+/// tell the two apart with `err.downcast_ref::<TimeoutError>()`.
+pub async fn with_timeout<T, E>(
+    operation: &'static str,
+    after: Duration,
+    future: impl Future<Output = Result<T, E>>,
+) -> Result<T, anyhow::Error>
+where
+    E: Into<anyhow::Error>,
+{
+    match tokio::time::timeout(after, future).await {
+        Ok(result) => result.map_err(Into::into),
+        Err(_) => Err(TimeoutError { operation, after }.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_with_timeout_passes_result_through() {
+        let result = with_timeout("test", Duration::from_secs(1), async {
+            Ok::<_, anyhow::Error>(42)
+        })
+        .await;
+
+        assert_eq!(result.unwrap(), 42);
+    }
+
+    #[tokio::test]
+    async fn test_with_timeout_expires() {
+        let err = with_timeout("test", Duration::from_millis(10), async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok::<_, anyhow::Error>(())
+        })
+        .await
+        .unwrap_err();
+
+        let timeout = err.downcast_ref::<TimeoutError>().unwrap();
+        assert_eq!(timeout.operation, "test");
+        assert_eq!(timeout.after, Duration::from_millis(10));
+    }
+}
@@ -3,108 +3,626 @@
 
 use std::fmt;
 use std::net::IpAddr;
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use rand::Rng;
 use serde::{Deserialize, Deserializer};
 use serde_bytes::ByteBuf;
 use serde_derive::{Deserialize, Serialize};
+use urlencoding::encode_binary;
 
 use serde::de;
 use serde::de::Visitor;
 
 use crate::torrent::Peer;
+use crate::torrent::listener;
 use crate::torrent::metainfo::MetaInfo;
+use crate::torrent::proxy;
+use crate::torrent::timeout::{Timeouts, with_timeout};
+use crate::torrent::tracker::external_ip::ExternalIp;
+use crate::torrent::tracker::udp::UdpTracker;
+
+pub mod external_ip;
+pub mod http;
+pub mod udp;
+
+/// Wait before the first retry of a failed announce, doubled for each
+/// further failure up to [`MAX_RETRY_DELAY`].
+const BASE_RETRY_DELAY: Duration = Duration::from_secs(15);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30 * 60);
+pub mod tls;
+
+/// Outcome of the most recent announce.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum TrackerStatus {
+    #[default]
+    NotContacted,
+    Working,
+    Failed(String),
+    /// The TLS connection to an `https://` tracker failed, see [`tls`].
+    TlsFailed(String),
+}
+
+impl fmt::Display for TrackerStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrackerStatus::NotContacted => write!(f, "not contacted"),
+            TrackerStatus::Working => write!(f, "working"),
+            TrackerStatus::Failed(error) => write!(f, "failed: {error}"),
+            TrackerStatus::TlsFailed(error) => write!(f, "TLS error: {error}"),
+        }
+    }
+}
+
+/// Peers asked for in a normal announce.
+const DEFAULT_NUMWANT: u64 = 50;
+/// Peers asked for while we have fewer than [`LOW_PEERS`] connected.
+const LOW_PEERS_NUMWANT: u64 = 200;
+/// Connected peers below which more are asked for.
+pub const LOW_PEERS: usize = 10;
+/// Peer connections kept per torrent unless configured otherwise, see
+/// [`limits`](crate::torrent::limits).
+pub const MAX_PEERS: usize = 50;
+/// Gap between announces when the tracker doesn't give an `interval`.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+/// Shortest gap between announces when out of peers and the tracker gave
+/// no `min interval`.
+const DEFAULT_MIN_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Longest `interval` or `min interval` taken from a tracker, so a bogus
+/// value can't overflow the announce schedule.
+const MAX_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A tracker's state as shown in the UI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackerStats {
+    pub url: String,
+    pub status: TrackerStatus,
+    /// Unix time of the last successful announce.
+    pub last_announce: Option<u64>,
+    /// Time left until the next announce, `None` while stopped.
+    pub next_announce: Option<Duration>,
+    /// Peers in the last successful response.
+    pub peers: usize,
+    pub seeders: Option<u64>,
+    pub leechers: Option<u64>,
+    /// Most recent announce error, kept after later successes.
+    pub last_error: Option<String>,
+    /// `warning message` of the last response, e.g. about the passkey or
+    /// ratio on private trackers.
+    pub warning: Option<String>,
+}
 
 pub struct TrackerSession {
     pub started: bool,
-    pub info_hash: String,
+    pub info_hash: [u8; 20],
     pub peer_id: String,
-    pub url: String,
+    /// Tracker announced to, `None` for trackerless torrents.
+    pub url: Option<String>,
+    /// Every tracker of the torrent in BEP 12 tiers, including ones added
+    /// while running.
+    pub tiers: Vec<Vec<String>>,
     pub interval: Duration,
     pub min_interval: Option<Duration>,
     pub next_announce: Instant,
     pub downloaded: u64,
     pub uploaded: u64,
     pub left: u64,
+    /// Event sent with the next announce, cleared once it succeeds.
     pub event: Option<TrackerEvent>,
+    /// Whether `completed` was already queued, it is only sent once.
+    completed_sent: bool,
     pub tracker_id: Option<String>,
-    pub(super) peer_list: Vec<Peer>,
+    pub timeouts: Timeouts,
+    /// Only some files are wanted and all of them are complete, so we
+    /// announce as a partial seed (BEP 21) rather than a leecher.
+    pub partial_seed: bool,
+    pub status: TrackerStatus,
+    /// Announces that failed in a row, used to back off.
+    pub failures: u32,
+    /// Ask for compact peer lists, turned off if the tracker rejects it.
+    pub compact: bool,
+    /// Random value sent with every announce so the tracker can tell us
+    /// apart from other clients even if our address changes.
+    pub key: String,
+    /// Peers currently connected, decides how many more to ask for.
+    pub active_peers: usize,
+    /// Connections the torrent may keep, no more are asked for once
+    /// seeding with this many.
+    pub max_peers: usize,
+    /// When the last announce succeeded.
+    pub last_announce: Option<Instant>,
+    /// Swarm counts from the last response that had them.
+    pub seeders: Option<u64>,
+    pub leechers: Option<u64>,
+    pub peers_returned: usize,
+    pub last_error: Option<String>,
+    /// `warning message` of the last successful response.
+    pub warning: Option<String>,
+    /// Peers from the last response, until taken with
+    /// [`TrackerSession::take_peers`].
+    peer_list: Vec<Peer>,
     client: reqwest::Client,
 }
 
 impl TrackerSession {
-    pub fn new(metainfo: &MetaInfo, info_hash: &str, peer_id: &str) -> Self {
-        let client = reqwest::Client::new();
+    pub fn new(metainfo: &MetaInfo, info_hash: [u8; 20], peer_id: &str) -> Self {
+        let client = tls::tracker_client();
+        let tiers = metainfo.tracker_tiers();
 
         Self {
             started: false,
-            info_hash: String::from(info_hash),
+            info_hash,
             peer_id: String::from(peer_id),
-            url: metainfo.announce.clone(),
+            url: first_url(&tiers),
+            tiers,
             interval: Duration::ZERO,
             min_interval: None,
             next_announce: Instant::now(),
             downloaded: 0,
             uploaded: 0,
             left: 0,
-            event: None,
+            event: Some(TrackerEvent::Started),
+            completed_sent: false,
             tracker_id: None,
+            timeouts: Timeouts::default(),
+            partial_seed: false,
+            status: TrackerStatus::default(),
+            failures: 0,
+            compact: true,
+            key: random_key(),
+            active_peers: 0,
+            max_peers: MAX_PEERS,
+            last_announce: None,
+            seeders: None,
+            leechers: None,
+            peers_returned: 0,
+            last_error: None,
+            warning: None,
             client,
             peer_list: vec![],
         }
     }
 
-    pub async fn update(&mut self) -> Result<(), anyhow::Error> {
-        let request = self.create_request();
+    /// Announces to the tracker, recording the outcome in
+    /// [`TrackerSession::status`]. Our address is sent from and learned
+    /// into `external_ip`. After a failure the next announce is pushed
+    /// back exponentially.
+    pub async fn update(&mut self, external_ip: &ExternalIp) -> Result<(), anyhow::Error> {
+        let mut result = self.announce(external_ip).await;
+
+        if let Err(e) = &result
+            && self.compact
+            && e.downcast_ref::<CompactRejected>().is_some()
+        {
+            self.compact = false;
+            result = self.announce(external_ip).await;
+        }
 
-        let url = format!("{}?{}", self.url, request.to_query_string());
+        match &result {
+            Ok(()) => {
+                self.event = None;
+                self.failures = 0;
+            }
+            Err(e) => {
+                self.last_error = Some(format!("{e:#}"));
+                self.failures += 1;
+                self.next_announce = (Instant::now()
+                    + retry_delay(self.failures, rand::rng().random()))
+                .max(self.earliest_announce());
+            }
+        }
 
-        let res = self.client.get(url).send().await?;
-        let bytes = res.bytes().await?;
+        self.status = match &result {
+            Ok(()) => TrackerStatus::Working,
+            Err(e) => match e.downcast_ref::<tls::TlsError>() {
+                Some(tls_error) => TrackerStatus::TlsFailed(tls_error.message.clone()),
+                None => TrackerStatus::Failed(format!("{e:#}")),
+            },
+        };
 
-        let response: TrackerResponse = serde_bencode::from_bytes(&bytes.to_vec())?;
+        result
+    }
 
-        if let Some(peers) = response.peers {
-            self.peer_list = peers.into();
+    async fn announce(&mut self, external_ip: &ExternalIp) -> Result<(), anyhow::Error> {
+        let Some(url) = &self.url else {
+            anyhow::bail!("Torrent has no tracker to announce to");
+        };
+        let mut request = self.create_request();
+        request.ip = external_ip.get();
+
+        let timeout = self.timeouts.tracker;
+        let response = if url.starts_with("udp://") {
+            // UDP can't go through the HTTP or SOCKS proxy client.
+            proxy::check_direct_tracker(&format!("UDP tracker {url}"))?;
+            with_timeout(
+                "tracker announce",
+                timeout,
+                UdpTracker.announce(url, &request),
+            )
+            .await?
+        } else {
+            with_timeout(
+                "tracker announce",
+                timeout,
+                self.client.announce(url, &request),
+            )
+            .await?
+        };
+
+        if let Some(reason) = response.failure_reason {
+            if self.compact && reason.to_lowercase().contains("compact") {
+                return Err(CompactRejected(reason).into());
+            }
+            anyhow::bail!("Tracker refused announce: {reason}");
         }
-
-        if let Some(time) = response.interval {
-            self.interval = Duration::from_secs(time);
+        self.warning = response.warning_message;
+
+        if let Some(ip) = response
+            .external_ip
+            .as_ref()
+            .and_then(|bytes| external_ip::ip_from_bytes(bytes))
+        {
+            external_ip.report(ip);
         }
 
-        self.next_announce = Instant::now() + self.interval;
+        if response.peers.is_some() || response.peers6.is_some() {
+            let mut peers: Vec<Peer> = response.peers.map(Vec::from).unwrap_or_default();
+            if let Some(peers6) = &response.peers6 {
+                peers.extend(Peer::from_compact6(peers6));
+            }
+            self.peer_list = peers;
+            self.peers_returned = self.peer_list.len();
+        }
+        if response.complete.is_some() || response.incomplete.is_some() {
+            self.seeders = response.complete;
+            self.leechers = response.incomplete;
+        }
 
+        if let Some(time) = response.interval {
+            self.interval = tracker_interval(time);
+        }
         if let Some(time) = response.min_interval {
-            self.min_interval = Some(Duration::from_secs(time));
+            self.min_interval = Some(tracker_interval(time));
         }
 
+        self.last_announce = Some(Instant::now());
+        self.next_announce =
+            Instant::now() + announce_delay(self.interval, self.min_interval, rand::rng().random());
+
         Ok(())
     }
 
     pub fn create_request(&self) -> TrackerRequest {
-        let mut request = TrackerRequest::new(&self.info_hash, &self.peer_id);
-        request.event = Some(TrackerEvent::Started);
+        let mut request = TrackerRequest::new(self.info_hash, &self.peer_id);
+        request.event = match self.event {
+            None | Some(TrackerEvent::Started) if self.partial_seed => Some(TrackerEvent::Paused),
+            event => event,
+        };
         request.uploaded = self.uploaded;
         request.downloaded = self.downloaded;
         request.left = self.left;
+        request.key = Some(self.key.clone());
+        request.numwant = self.numwant();
+        if !self.compact {
+            request.compact = None;
+            request.no_peer_id = None;
+        }
 
         request
     }
+
+    /// State of every tracker for display, in tier order. Only the one
+    /// announced to has been contacted.
+    pub fn stats(&self) -> Vec<TrackerStats> {
+        let last_announce = self.last_announce.and_then(|at| {
+            let at = SystemTime::now().checked_sub(at.elapsed())?;
+            Some(at.duration_since(UNIX_EPOCH).ok()?.as_secs())
+        });
+
+        self.tiers
+            .iter()
+            .flatten()
+            .map(|url| {
+                if self.url.as_ref() != Some(url) {
+                    return TrackerStats {
+                        url: url.clone(),
+                        status: TrackerStatus::NotContacted,
+                        last_announce: None,
+                        next_announce: None,
+                        peers: 0,
+                        seeders: None,
+                        leechers: None,
+                        last_error: None,
+                        warning: None,
+                    };
+                }
+
+                TrackerStats {
+                    url: url.clone(),
+                    status: self.status.clone(),
+                    last_announce,
+                    next_announce: self
+                        .started
+                        .then(|| self.next_announce.saturating_duration_since(Instant::now())),
+                    peers: self.peers_returned,
+                    seeders: self.seeders,
+                    leechers: self.leechers,
+                    last_error: self.last_error.clone(),
+                    warning: self.warning.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Adds `url` as a new tier after the existing ones. Torrents without
+    /// a tracker start announcing to it. Returns `false` if it was
+    /// already known.
+    pub fn add_tracker(&mut self, url: &str) -> bool {
+        if self.tiers.iter().flatten().any(|known| known == url) {
+            return false;
+        }
+
+        self.tiers.push(vec![String::from(url)]);
+        if self.url.is_none() {
+            self.switch_tracker();
+        }
+        true
+    }
+
+    /// Removes `url` from its tier, dropping the tier once empty. If it was
+    /// the tracker announced to, the next one takes over from scratch.
+    /// Returns `false` if it wasn't known.
+    pub fn remove_tracker(&mut self, url: &str) -> bool {
+        let before = self.tiers.iter().map(Vec::len).sum::<usize>();
+        for tier in &mut self.tiers {
+            tier.retain(|known| known != url);
+        }
+        self.tiers.retain(|tier| !tier.is_empty());
+        if self.tiers.iter().map(Vec::len).sum::<usize>() == before {
+            return false;
+        }
+
+        if self.url.as_deref() == Some(url) {
+            self.switch_tracker();
+        }
+        true
+    }
+
+    /// Replaces every tracker, moving to the first of `tiers` unless the
+    /// current one is still among them.
+    pub fn set_tiers(&mut self, tiers: Vec<Vec<String>>) {
+        let kept = self
+            .url
+            .as_ref()
+            .is_some_and(|url| tiers.iter().flatten().any(|known| known == url));
+
+        self.tiers = tiers;
+        if !kept {
+            self.switch_tracker();
+        }
+    }
+
+    /// Moves to the first tracker of the tiers, forgetting everything
+    /// learned from the previous one. It's announced to straight away and
+    /// hears `started` as a new tracker would.
+    fn switch_tracker(&mut self) {
+        self.url = first_url(&self.tiers);
+        self.interval = Duration::ZERO;
+        self.min_interval = None;
+        self.next_announce = Instant::now();
+        self.tracker_id = None;
+        self.status = TrackerStatus::default();
+        self.failures = 0;
+        self.compact = true;
+        self.last_announce = None;
+        self.seeders = None;
+        self.leechers = None;
+        self.peers_returned = 0;
+        self.last_error = None;
+        self.warning = None;
+        if self.started {
+            self.event = Some(TrackerEvent::Started);
+        }
+    }
+
+    /// Peers from the last response, passed on to the torrent's peer store.
+    pub fn take_peers(&mut self) -> Vec<Peer> {
+        std::mem::take(&mut self.peer_list)
+    }
+
+    /// Announces as soon as the tracker's `min interval` allows, skipping
+    /// the regular interval and any backoff.
+    pub fn force_announce(&mut self) {
+        self.next_announce = self.earliest_announce().max(Instant::now());
+    }
+
+    /// The tracker's `min interval` after the last successful announce,
+    /// no announce is scheduled before it.
+    fn earliest_announce(&self) -> Instant {
+        match (self.last_announce, self.min_interval) {
+            (Some(last_announce), Some(min_interval)) => last_announce + min_interval,
+            _ => Instant::now(),
+        }
+    }
+
+    /// Peers to ask for: none when seeding with every slot taken, more
+    /// when we have few.
+    pub fn numwant(&self) -> u64 {
+        if self.left == 0 && self.active_peers >= self.max_peers {
+            0
+        } else if self.active_peers < LOW_PEERS {
+            LOW_PEERS_NUMWANT
+        } else {
+            DEFAULT_NUMWANT
+        }
+    }
+
+    /// Brings the next announce forward when we've run out of peers, but
+    /// no earlier than the tracker's `min interval` allows. Returns whether
+    /// it was moved.
+    pub fn announce_early(&mut self) -> bool {
+        let Some(last_announce) = self.last_announce else {
+            return false;
+        };
+        if self.active_peers > 0 || self.failures > 0 {
+            return false;
+        }
+
+        let earliest = last_announce + self.min_interval.unwrap_or(DEFAULT_MIN_INTERVAL);
+        if earliest >= self.next_announce {
+            return false;
+        }
+
+        self.next_announce = earliest;
+        true
+    }
+
+    /// Queues the `completed` event for the next announce, unless it was
+    /// already sent for this torrent.
+    pub fn mark_completed(&mut self) {
+        if !self.completed_sent {
+            self.completed_sent = true;
+            self.event = Some(TrackerEvent::Completed);
+        }
+    }
+
+    /// Resets after a stop that was cut short, e.g. by starting again, so
+    /// the next announce is a `started` one.
+    pub fn cancel_stopped(&mut self) {
+        if self.event == Some(TrackerEvent::Stopped) {
+            self.event = Some(TrackerEvent::Started);
+        }
+        self.started = false;
+    }
+
+    /// Announces that we stopped, then resets so the next start sends
+    /// `started` again.
+    pub async fn announce_stopped(
+        &mut self,
+        external_ip: &ExternalIp,
+    ) -> Result<(), anyhow::Error> {
+        self.event = Some(TrackerEvent::Stopped);
+        let result = self.update(external_ip).await;
+
+        self.event = Some(TrackerEvent::Started);
+        self.started = false;
+
+        result
+    }
+}
+
+fn first_url(tiers: &[Vec<String>]) -> Option<String> {
+    tiers.iter().flatten().next().cloned()
+}
+
+/// A new announce `key`, 8 random hex digits.
+fn random_key() -> String {
+    format!("{:08x}", rand::rng().random::<u32>())
+}
+
+/// The tracker refused an announce because it doesn't support compact
+/// peer lists.
+#[derive(Debug)]
+struct CompactRejected(String);
+
+impl fmt::Display for CompactRejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Tracker refused compact announce: {}", self.0)
+    }
+}
+
+impl std::error::Error for CompactRejected {}
+
+/// An interval of `seconds` given by a tracker, capped at [`MAX_INTERVAL`].
+fn tracker_interval(seconds: u64) -> Duration {
+    Duration::from_secs(seconds).min(MAX_INTERVAL)
+}
+
+/// Delay until the next regular announce: the tracker's `interval`, or
+/// [`DEFAULT_INTERVAL`] without one, stretched by up to a tenth with
+/// `jitter` in `[0, 1)` so torrents added together don't announce together.
+/// Never shorter than `min_interval`.
+fn announce_delay(interval: Duration, min_interval: Option<Duration>, jitter: f64) -> Duration {
+    let interval = if interval.is_zero() {
+        DEFAULT_INTERVAL
+    } else {
+        interval
+    };
+
+    interval
+        .mul_f64(1.0 + jitter / 10.0)
+        .max(min_interval.unwrap_or_default())
+}
+
+/// Delay before retrying after `failures` failed announces in a row.
+/// `jitter` in `[0, 1)` spreads it by up to a quarter either way, so many
+/// torrents on one tracker don't retry in lockstep.
+fn retry_delay(failures: u32, jitter: f64) -> Duration {
+    let exponent = failures.saturating_sub(1).min(16);
+    let delay = BASE_RETRY_DELAY
+        .saturating_mul(1 << exponent)
+        .min(MAX_RETRY_DELAY);
+
+    delay.mul_f64(0.75 + jitter / 2.0)
+}
+
+/// Sends announces to trackers of one protocol, `reqwest::Client` for
+/// HTTP(S) and [`UdpTracker`] for UDP. [`TrackerSession`] picks one by URL
+/// scheme and handles the response the same way for both.
+pub trait TrackerClient {
+    fn announce(
+        &self,
+        url: &str,
+        request: &TrackerRequest,
+    ) -> impl Future<Output = Result<TrackerResponse, anyhow::Error>> + Send;
+
+    /// Swarm counts of one torrent, without announcing ourselves.
+    fn scrape(
+        &self,
+        url: &str,
+        info_hash: &[u8; 20],
+    ) -> impl Future<Output = Result<ScrapeStats, anyhow::Error>> + Send;
+}
+
+/// Swarm counts from a scrape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrapeStats {
+    pub seeders: u64,
+    pub leechers: u64,
+    /// Downloads the tracker has seen finish.
+    pub completed: u64,
+}
+
+/// Scrapes the tracker at `url` for the torrent with `info_hash`, giving
+/// up after `timeout`. `client` is used for HTTP(S) trackers.
+pub async fn scrape(
+    client: &reqwest::Client,
+    url: &str,
+    info_hash: &[u8; 20],
+    timeout: Duration,
+) -> Result<ScrapeStats, anyhow::Error> {
+    if url.starts_with("udp://") {
+        proxy::check_direct_tracker(&format!("UDP tracker {url}"))?;
+        with_timeout("tracker scrape", timeout, UdpTracker.scrape(url, info_hash)).await
+    } else {
+        with_timeout("tracker scrape", timeout, client.scrape(url, info_hash)).await
+    }
 }
 
 /// Struct for making a request to a Tracker
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 pub struct TrackerRequest {
+    /// Raw SHA-1 of the info dictionary, only encoded when building the
+    /// query string.
     #[serde(skip_serializing)]
-    pub info_hash: String,
+    pub info_hash: [u8; 20],
     pub peer_id: String,
     pub port: u64,
     pub uploaded: u64,
     pub downloaded: u64,
     pub left: u64,
     pub compact: Option<u64>,
-    pub no_peer_id: Option<bool>,
+    pub no_peer_id: Option<u64>,
     pub numwant: u64,
     pub event: Option<TrackerEvent>,
     pub ip: Option<IpAddr>,
@@ -114,33 +632,32 @@ pub struct TrackerRequest {
 
 impl TrackerRequest {
     // TODO: TrackerSession to manage these fields
-    pub fn new(info_hash: &str, peer_id: &str) -> Self {
+    pub fn new(info_hash: [u8; 20], peer_id: &str) -> Self {
         Self {
-            info_hash: String::from(info_hash),
+            info_hash,
             peer_id: String::from(peer_id),
-            port: 6882,
+            port: listener::port() as u64,
             uploaded: 0,
             downloaded: 0,
             left: 0,
             event: Some(TrackerEvent::Started),
-            compact: None,
-            no_peer_id: None,
+            compact: Some(1),
+            no_peer_id: Some(1),
             ip: None,
-            numwant: 50,
+            numwant: DEFAULT_NUMWANT,
             key: None,
             trackerid: None,
         }
     }
     pub fn to_query_string(&self) -> String {
-        let mut encoded = serde_urlencoded::to_string(&self).unwrap();
-        encoded.push_str("&info_hash=");
-
-        encoded.push_str(self.info_hash.as_str());
+        // serde_urlencoded only takes UTF-8, so the binary hash is encoded
+        // separately.
+        let encoded = serde_urlencoded::to_string(self).unwrap();
 
-        encoded
+        format!("{encoded}&info_hash={}", encode_binary(&self.info_hash))
     }
 }
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
 pub enum TrackerEvent {
     #[serde(rename = "started")]
     Started,
@@ -148,9 +665,13 @@ pub enum TrackerEvent {
     Stopped,
     #[serde(rename = "completed")]
     Completed,
+    /// BEP 21: we are a partial seed and won't download anything more.
+    #[serde(rename = "paused")]
+    Paused,
 }
 
-/// Struct for deserializing the response from a tracker.
+/// Struct for deserializing the response from a tracker. Every key is
+/// optional, a failed announce may carry only `failure reason`.
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 pub struct TrackerResponse {
     #[serde(rename = "failure reason")]
@@ -165,6 +686,11 @@ pub struct TrackerResponse {
     pub complete: Option<u64>,
     pub incomplete: Option<u64>,
     pub peers: Option<PeersEnum>,
+    /// BEP 7: compact IPv6 peers, sent alongside `peers`.
+    pub peers6: Option<ByteBuf>,
+    /// BEP 24: our address as the tracker sees it.
+    #[serde(rename = "external ip")]
+    pub external_ip: Option<ByteBuf>,
 }
 
 #[derive(Serialize, PartialEq, Eq, Debug)]
@@ -175,8 +701,9 @@ pub enum PeersEnum {
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 pub struct PeersDict {
-    #[serde(rename = "peer id")]
-    pub peer_id: ByteBuf,
+    /// Left out by trackers honouring `no_peer_id`.
+    #[serde(rename = "peer id", default, skip_serializing_if = "Option::is_none")]
+    pub peer_id: Option<ByteBuf>,
     pub ip: String,
     pub port: u64,
 }
@@ -221,17 +748,245 @@ impl<'de> Deserialize<'de> for PeersEnum {
 
 #[cfg(test)]
 mod tracker_tests {
+    use std::net::Ipv6Addr;
+
     use super::*;
 
     #[test]
     fn test_to_query_string() {
         let request = TrackerRequest::new(
-            "%DA%BFr%01%9D%EFM0%AF%00%F4%BFM%DF%8Ais%0C%02%B4",
+            [
+                0xDA, 0xBF, b'r', 0x01, 0x9D, 0xEF, b'M', b'0', 0xAF, 0x00, 0xF4, 0xBF, b'M', 0xDF,
+                0x8A, b'i', b's', 0x0C, 0x02, 0xB4,
+            ],
             "-RS0001-kONXltkhXIr5",
         );
 
-        let expected_result = "peer_id=-RS0001-kONXltkhXIr5&port=6882&uploaded=0&downloaded=0&left=0&numwant=50&event=started&info_hash=%DA%BFr%01%9D%EFM0%AF%00%F4%BFM%DF%8Ais%0C%02%B4";
+        let expected_result = "peer_id=-RS0001-kONXltkhXIr5&port=6882&uploaded=0&downloaded=0&left=0&compact=1&no_peer_id=1&numwant=50&event=started&info_hash=%DA%BFr%01%9D%EFM0%AF%00%F4%BFM%DF%8Ais%0C%02%B4";
 
         assert_eq!(request.to_query_string(), expected_result);
     }
+
+    fn session() -> TrackerSession {
+        let metainfo = MetaInfo::from_bytes(
+            b"d8:announce19:http://tracker.test4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces0:ee",
+        )
+        .unwrap();
+
+        TrackerSession::new(&metainfo, [0; 20], "-RS0001-abcdefghijkl")
+    }
+
+    #[test]
+    fn test_response_tolerates_missing_keys() {
+        let response: TrackerResponse =
+            serde_bencode::from_bytes(b"d14:failure reason6:bannede").unwrap();
+        assert_eq!(response.failure_reason.as_deref(), Some("banned"));
+        assert_eq!((response.interval, response.peers), (None, None));
+
+        let response: TrackerResponse =
+            serde_bencode::from_bytes(b"d8:intervali1800e5:peersld2:ip9:127.0.0.14:porti6881eeee")
+                .unwrap();
+        assert_eq!(
+            response.peers,
+            Some(PeersEnum::Dict(vec![PeersDict {
+                peer_id: None,
+                ip: String::from("127.0.0.1"),
+                port: 6881,
+            }]))
+        );
+
+        let mut bytes = b"d5:peers0:6:peers618:".to_vec();
+        bytes.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        bytes.extend_from_slice(&6881u16.to_be_bytes());
+        bytes.push(b'e');
+        let response: TrackerResponse = serde_bencode::from_bytes(&bytes).unwrap();
+        let peers = Peer::from_compact6(response.peers6.as_deref().unwrap());
+        assert_eq!(peers[0].address(), "[::1]:6881");
+    }
+
+    #[test]
+    fn test_retry_delay_backs_off() {
+        assert_eq!(retry_delay(1, 0.5), BASE_RETRY_DELAY);
+        assert_eq!(retry_delay(3, 0.5), BASE_RETRY_DELAY * 4);
+        assert_eq!(retry_delay(40, 0.5), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(1, 0.0), BASE_RETRY_DELAY.mul_f64(0.75));
+        assert!(retry_delay(1, 0.999) < BASE_RETRY_DELAY.mul_f64(1.25));
+    }
+
+    #[test]
+    fn test_announce_delay_honours_min_interval() {
+        let interval = Duration::from_secs(600);
+
+        assert_eq!(announce_delay(interval, None, 0.0), interval);
+        assert_eq!(announce_delay(Duration::ZERO, None, 0.0), DEFAULT_INTERVAL);
+        assert!(announce_delay(interval, None, 0.999) < interval.mul_f64(1.1));
+        assert_eq!(
+            announce_delay(interval, Some(Duration::from_secs(900)), 0.5),
+            Duration::from_secs(900)
+        );
+    }
+
+    #[test]
+    fn test_huge_tracker_intervals_are_capped() {
+        let interval = tracker_interval(u64::MAX);
+        assert_eq!(interval, MAX_INTERVAL);
+        assert_eq!(tracker_interval(1800), Duration::from_secs(1800));
+
+        let delay = announce_delay(interval, Some(interval), 0.999);
+        assert!(delay < MAX_INTERVAL.mul_f64(1.1));
+        assert!(Instant::now().checked_add(delay).is_some());
+    }
+
+    #[test]
+    fn test_events() {
+        let mut session = session();
+        assert_eq!(session.create_request().event, Some(TrackerEvent::Started));
+
+        // A successful announce clears the pending event.
+        session.event = None;
+        assert_eq!(session.create_request().event, None);
+
+        session.mark_completed();
+        assert_eq!(
+            session.create_request().event,
+            Some(TrackerEvent::Completed)
+        );
+        session.event = None;
+        session.mark_completed();
+        assert_eq!(session.create_request().event, None);
+
+        session.partial_seed = true;
+        assert_eq!(session.create_request().event, Some(TrackerEvent::Paused));
+    }
+
+    #[test]
+    fn test_key_sent_with_every_announce() {
+        let session = session();
+
+        assert_eq!(session.key.len(), 8);
+        assert_ne!(session.key, self::session().key);
+        let query = session.create_request().to_query_string();
+        assert!(query.contains(&format!("key={}", session.key)));
+    }
+
+    #[test]
+    fn test_numwant_follows_peer_count() {
+        let mut session = session();
+        session.left = 100;
+
+        assert_eq!(session.create_request().numwant, LOW_PEERS_NUMWANT);
+        session.active_peers = LOW_PEERS;
+        assert_eq!(session.numwant(), DEFAULT_NUMWANT);
+        session.active_peers = MAX_PEERS;
+        assert_eq!(session.numwant(), DEFAULT_NUMWANT);
+        session.left = 0;
+        assert_eq!(session.numwant(), 0);
+        session.max_peers = MAX_PEERS + 1;
+        assert_eq!(session.numwant(), DEFAULT_NUMWANT);
+    }
+
+    #[test]
+    fn test_announce_early_respects_min_interval() {
+        let mut session = session();
+        assert!(!session.announce_early());
+
+        let now = Instant::now();
+        session.last_announce = Some(now);
+        session.next_announce = now + Duration::from_secs(1800);
+        session.min_interval = Some(Duration::from_secs(60));
+
+        session.active_peers = 3;
+        assert!(!session.announce_early());
+
+        session.active_peers = 0;
+        assert!(session.announce_early());
+        assert_eq!(session.next_announce, now + Duration::from_secs(60));
+        assert!(!session.announce_early());
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut session = session();
+        let stats = session.stats().remove(0);
+        assert_eq!(stats.url, "http://tracker.test");
+        assert_eq!((stats.last_announce, stats.next_announce), (None, None));
+
+        session.started = true;
+        session.last_announce = Some(Instant::now());
+        session.next_announce = Instant::now() + Duration::from_secs(60);
+        session.warning = Some(String::from("ratio below 0.3"));
+        let stats = session.stats().remove(0);
+        assert!(stats.last_announce.is_some());
+        assert!(stats.next_announce.unwrap() <= Duration::from_secs(60));
+        assert_eq!(stats.warning.as_deref(), Some("ratio below 0.3"));
+
+        session.add_tracker("udp://backup.test:6969");
+        let stats = session.stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[1].status, TrackerStatus::NotContacted);
+        assert_eq!(stats[1].last_announce, None);
+        assert_eq!(stats[1].warning, None);
+    }
+
+    #[test]
+    fn test_add_and_remove_tracker() {
+        let mut session = session();
+        assert!(!session.add_tracker("http://tracker.test"));
+        assert!(session.add_tracker("http://backup.test"));
+        assert_eq!(
+            session.tiers,
+            vec![
+                vec![String::from("http://tracker.test")],
+                vec![String::from("http://backup.test")],
+            ]
+        );
+
+        session.started = true;
+        session.event = None;
+        session.failures = 3;
+        session.tracker_id = Some(String::from("abc"));
+        assert!(session.remove_tracker("http://tracker.test"));
+        assert_eq!(session.url.as_deref(), Some("http://backup.test"));
+        assert_eq!(session.failures, 0);
+        assert_eq!(session.tracker_id, None);
+        assert_eq!(session.event, Some(TrackerEvent::Started));
+
+        assert!(!session.remove_tracker("http://tracker.test"));
+        assert!(session.remove_tracker("http://backup.test"));
+        assert_eq!(session.url, None);
+        assert!(session.tiers.is_empty());
+
+        assert!(session.add_tracker("http://tracker.test"));
+        assert_eq!(session.url.as_deref(), Some("http://tracker.test"));
+    }
+
+    #[test]
+    fn test_force_announce_respects_min_interval() {
+        let mut session = session();
+        let now = Instant::now();
+        session.next_announce = now + Duration::from_secs(1800);
+        session.force_announce();
+        assert!(session.next_announce <= Instant::now());
+
+        session.last_announce = Some(now);
+        session.min_interval = Some(Duration::from_secs(60));
+        session.force_announce();
+        assert_eq!(session.next_announce, now + Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_compact_fallback() {
+        let mut session = session();
+        assert!(
+            session
+                .create_request()
+                .to_query_string()
+                .contains("compact=1")
+        );
+
+        session.compact = false;
+        let query = session.create_request().to_query_string();
+        assert!(!query.contains("compact"));
+        assert!(!query.contains("no_peer_id"));
+    }
 }
@@ -0,0 +1,92 @@
+//! Our address as seen from the internet.
+//!
+//! Trackers report it in the `external ip` key of announce responses
+//! (BEP 24). Users behind a VPN or with several interfaces can instead fix
+//! it with the `BTRS_EXTERNAL_IP` environment variable, which always wins.
+//! The address is sent in the `ip` parameter of announces.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{Arc, Mutex},
+};
+
+pub const EXTERNAL_IP_ENV_VAR: &str = "BTRS_EXTERNAL_IP";
+
+/// Shared handle to the external address, updated by every tracker.
+#[derive(Debug, Clone, Default)]
+pub struct ExternalIp {
+    configured: Option<IpAddr>,
+    discovered: Arc<Mutex<Option<IpAddr>>>,
+}
+
+impl ExternalIp {
+    pub fn new(configured: Option<IpAddr>) -> Self {
+        Self {
+            configured,
+            discovered: Arc::default(),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        let configured = std::env::var(EXTERNAL_IP_ENV_VAR).ok().and_then(|value| {
+            value
+                .parse()
+                .inspect_err(|_| eprintln!("[Tracker] Ignoring invalid {EXTERNAL_IP_ENV_VAR}"))
+                .ok()
+        });
+
+        Self::new(configured)
+    }
+
+    /// The configured address, or else the one most recently reported.
+    pub fn get(&self) -> Option<IpAddr> {
+        self.configured
+            .or_else(|| *self.discovered.lock().expect("External IP lock poisoned"))
+    }
+
+    /// Records an address reported by a tracker.
+    pub fn report(&self, ip: IpAddr) {
+        *self.discovered.lock().expect("External IP lock poisoned") = Some(ip);
+    }
+}
+
+/// Decodes an address in its 4 or 16 byte network order form.
+pub fn ip_from_bytes(bytes: &[u8]) -> Option<IpAddr> {
+    if let Ok(octets) = <[u8; 4]>::try_from(bytes) {
+        Some(IpAddr::V4(Ipv4Addr::from(octets)))
+    } else if let Ok(octets) = <[u8; 16]>::try_from(bytes) {
+        Some(IpAddr::V6(Ipv6Addr::from(octets)))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_configured_ip_wins() {
+        let discovered = ExternalIp::default();
+        assert_eq!(discovered.get(), None);
+        discovered.clone().report(IpAddr::from([1, 2, 3, 4]));
+        assert_eq!(discovered.get(), Some(IpAddr::from([1, 2, 3, 4])));
+
+        let configured = ExternalIp::new(Some(IpAddr::from([10, 0, 0, 1])));
+        configured.report(IpAddr::from([1, 2, 3, 4]));
+        assert_eq!(configured.get(), Some(IpAddr::from([10, 0, 0, 1])));
+    }
+
+    #[test]
+    fn test_ip_from_bytes() {
+        assert_eq!(
+            ip_from_bytes(&[1, 2, 3, 4]),
+            Some(IpAddr::from([1, 2, 3, 4]))
+        );
+        assert_eq!(
+            ip_from_bytes(&[0; 16]),
+            Some(IpAddr::V6(Ipv6Addr::UNSPECIFIED))
+        );
+        assert_eq!(ip_from_bytes(&[1, 2, 3]), None);
+    }
+}
@@ -0,0 +1,212 @@
+//! HTTP(S) tracker announces, and the settings of their client.
+//!
+//! - `BTRS_TRACKER_USER_AGENT`: User-Agent sent with announces, some
+//!   trackers block unknown or default ones.
+//! - `BTRS_TRACKER_CONNECT_TIMEOUT`: seconds to wait for a connection.
+//! - `BTRS_TRACKER_READ_TIMEOUT`: seconds to wait for each read.
+//! - `BTRS_TRACKER_MAX_REDIRECTS`: redirects followed before giving up.
+//! - `BTRS_TRACKER_KEEP_ALIVE`: seconds an idle connection is kept for
+//!   reuse, `0` opens a new connection for every announce.
+
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::{Context, bail};
+use reqwest::{Client, ClientBuilder, redirect::Policy};
+use serde_bytes::ByteBuf;
+use serde_derive::Deserialize;
+use urlencoding::encode_binary;
+
+use crate::torrent::tracker::{ScrapeStats, TrackerClient, TrackerRequest, TrackerResponse, tls};
+
+pub const USER_AGENT_ENV_VAR: &str = "BTRS_TRACKER_USER_AGENT";
+pub const CONNECT_TIMEOUT_ENV_VAR: &str = "BTRS_TRACKER_CONNECT_TIMEOUT";
+pub const READ_TIMEOUT_ENV_VAR: &str = "BTRS_TRACKER_READ_TIMEOUT";
+pub const MAX_REDIRECTS_ENV_VAR: &str = "BTRS_TRACKER_MAX_REDIRECTS";
+pub const KEEP_ALIVE_ENV_VAR: &str = "BTRS_TRACKER_KEEP_ALIVE";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpSettings {
+    pub user_agent: String,
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration,
+    pub max_redirects: usize,
+    /// How long idle connections are kept, `None` to never reuse them.
+    pub keep_alive: Option<Duration>,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        Self {
+            user_agent: format!("btrs/{}", env!("CARGO_PKG_VERSION")),
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(30),
+            max_redirects: 5,
+            keep_alive: Some(Duration::from_secs(90)),
+        }
+    }
+}
+
+impl HttpSettings {
+    /// Reads the settings from the environment, using the default for any
+    /// that are unset or invalid.
+    pub fn from_env() -> Self {
+        fn parse<T: std::str::FromStr>(var: &str) -> Option<T> {
+            std::env::var(var).ok().and_then(|value| {
+                value
+                    .parse()
+                    .inspect_err(|_| eprintln!("[Tracker] Ignoring invalid {var}"))
+                    .ok()
+            })
+        }
+        let seconds = |var| parse(var).map(Duration::from_secs);
+
+        let defaults = Self::default();
+        Self {
+            user_agent: std::env::var(USER_AGENT_ENV_VAR)
+                .ok()
+                .filter(|user_agent| !user_agent.is_empty())
+                .unwrap_or(defaults.user_agent),
+            connect_timeout: seconds(CONNECT_TIMEOUT_ENV_VAR).unwrap_or(defaults.connect_timeout),
+            read_timeout: seconds(READ_TIMEOUT_ENV_VAR).unwrap_or(defaults.read_timeout),
+            max_redirects: parse(MAX_REDIRECTS_ENV_VAR).unwrap_or(defaults.max_redirects),
+            keep_alive: match seconds(KEEP_ALIVE_ENV_VAR) {
+                Some(Duration::ZERO) => None,
+                Some(keep_alive) => Some(keep_alive),
+                None => defaults.keep_alive,
+            },
+        }
+    }
+
+    pub fn apply(&self, builder: ClientBuilder) -> ClientBuilder {
+        let builder = builder
+            .user_agent(&self.user_agent)
+            .connect_timeout(self.connect_timeout)
+            .read_timeout(self.read_timeout)
+            .redirect(Policy::limited(self.max_redirects));
+
+        match self.keep_alive {
+            Some(keep_alive) => builder.pool_idle_timeout(keep_alive),
+            None => builder.pool_max_idle_per_host(0),
+        }
+    }
+}
+
+impl TrackerClient for Client {
+    async fn announce(
+        &self,
+        url: &str,
+        request: &TrackerRequest,
+    ) -> Result<TrackerResponse, anyhow::Error> {
+        let url = format!("{url}?{}", request.to_query_string());
+
+        let response = self.get(url).send().await.map_err(tls::classify)?;
+        let bytes = response.bytes().await?;
+
+        Ok(serde_bencode::from_bytes(&bytes)?)
+    }
+
+    async fn scrape(&self, url: &str, info_hash: &[u8; 20]) -> Result<ScrapeStats, anyhow::Error> {
+        let scrape_url =
+            scrape_url(url).with_context(|| format!("{url} doesn't support scrape"))?;
+        let url = format!("{scrape_url}?info_hash={}", encode_binary(info_hash));
+
+        let response = self.get(url).send().await.map_err(tls::classify)?;
+        let response: ScrapeResponse = serde_bencode::from_bytes(&response.bytes().await?)?;
+
+        if let Some(reason) = response.failure_reason {
+            bail!("Tracker refused scrape: {reason}");
+        }
+        let file = response
+            .files
+            .get(&ByteBuf::from(info_hash.to_vec()))
+            .context("Tracker doesn't know the torrent")?;
+
+        Ok(ScrapeStats {
+            seeders: file.complete,
+            leechers: file.incomplete,
+            completed: file.downloaded,
+        })
+    }
+}
+
+/// Scrape URL of the tracker announced to at `announce_url`. By
+/// convention it is only known when the last path segment starts with
+/// `announce`, which is replaced with `scrape`.
+pub fn scrape_url(announce_url: &str) -> Option<String> {
+    let (path_end, query) = match announce_url.find('?') {
+        Some(start) => announce_url.split_at(start),
+        None => (announce_url, ""),
+    };
+    let (base, segment) = path_end.rsplit_once('/')?;
+    let rest = segment.strip_prefix("announce")?;
+
+    Some(format!("{base}/scrape{rest}{query}"))
+}
+
+#[derive(Deserialize)]
+struct ScrapeResponse {
+    #[serde(default)]
+    files: HashMap<ByteBuf, ScrapeFile>,
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ScrapeFile {
+    #[serde(default)]
+    complete: u64,
+    #[serde(default)]
+    incomplete: u64,
+    #[serde(default)]
+    downloaded: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scrape_url() {
+        assert_eq!(
+            scrape_url("http://tracker.test/announce").as_deref(),
+            Some("http://tracker.test/scrape")
+        );
+        assert_eq!(
+            scrape_url("https://tracker.test/x/announce.php?passkey=abc").as_deref(),
+            Some("https://tracker.test/x/scrape.php?passkey=abc")
+        );
+        assert_eq!(scrape_url("http://tracker.test/a"), None);
+    }
+
+    #[test]
+    fn test_scrape_response() {
+        let response: ScrapeResponse = serde_bencode::from_bytes(
+            b"d5:filesd20:aaaaaaaaaaaaaaaaaaaad8:completei5e10:downloadedi50e10:incompletei2eeee",
+        )
+        .unwrap();
+
+        let file = &response.files[&ByteBuf::from(b"aaaaaaaaaaaaaaaaaaaa".to_vec())];
+        assert_eq!(
+            (file.complete, file.incomplete, file.downloaded),
+            (5, 2, 50)
+        );
+    }
+
+    #[test]
+    fn test_settings_build_client() {
+        let settings = HttpSettings::default();
+        assert!(settings.user_agent.starts_with("btrs/"));
+        assert!(settings.apply(reqwest::Client::builder()).build().is_ok());
+
+        let no_keep_alive = HttpSettings {
+            keep_alive: None,
+            ..settings
+        };
+        assert!(
+            no_keep_alive
+                .apply(reqwest::Client::builder())
+                .build()
+                .is_ok()
+        );
+    }
+}
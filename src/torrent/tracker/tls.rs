@@ -0,0 +1,134 @@
+//! TLS settings for `https://` trackers.
+//!
+//! Private trackers often use certificates signed by their own CA, or
+//! self-signed ones. Point `BTRS_TRACKER_CA_FILE` at a PEM bundle to trust
+//! extra roots, or set `BTRS_TRACKER_INSECURE` to skip verification
+//! entirely.
+
+use std::{error::Error as StdError, fmt, fs};
+
+use anyhow::{Context, Error};
+
+use crate::torrent::{bind::BindConfig, proxy::ProxyConfig, tracker::http::HttpSettings};
+
+pub const CA_FILE_ENV_VAR: &str = "BTRS_TRACKER_CA_FILE";
+pub const INSECURE_ENV_VAR: &str = "BTRS_TRACKER_INSECURE";
+
+/// Error returned when the TLS connection to a tracker fails, kept apart
+/// from other announce errors as it usually needs the user to act.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsError {
+    pub message: String,
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TLS error: {}", self.message)
+    }
+}
+
+impl StdError for TlsError {}
+
+/// HTTP client for announcing, honouring the TLS environment variables,
+/// the proxy from [`ProxyConfig`], the [`HttpSettings`] and the
+/// [`BindConfig`]. Falls back to a client with only the HTTP and bind
+/// settings if the CA bundle or proxy can't be used.
+pub fn tracker_client() -> reqwest::Client {
+    let ca_file = std::env::var(CA_FILE_ENV_VAR).ok();
+    let insecure = std::env::var_os(INSECURE_ENV_VAR).is_some();
+    let proxy = ProxyConfig::tracker_from_env();
+    let http = HttpSettings::from_env();
+    let bind = BindConfig::from_env();
+
+    match build_client(ca_file.as_deref(), insecure, proxy.as_ref(), &http, &bind) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("[Tracker] Ignoring TLS and proxy settings: {e:#}");
+            bind.apply(http.apply(reqwest::Client::builder()))
+                .build()
+                .unwrap_or_default()
+        }
+    }
+}
+
+fn build_client(
+    ca_file: Option<&str>,
+    insecure: bool,
+    proxy: Option<&ProxyConfig>,
+    http: &HttpSettings,
+    bind: &BindConfig,
+) -> Result<reqwest::Client, Error> {
+    let mut builder = bind
+        .apply(http.apply(reqwest::Client::builder()))
+        .danger_accept_invalid_certs(insecure);
+
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(
+            proxy
+                .to_reqwest()
+                .with_context(|| format!("Invalid proxy {}", proxy.url))?,
+        );
+    }
+
+    if let Some(path) = ca_file {
+        let pem = fs::read(path).with_context(|| format!("Cannot read CA bundle {path}"))?;
+        let certificates = reqwest::Certificate::from_pem_bundle(&pem)
+            .with_context(|| format!("Invalid CA bundle {path}"))?;
+
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+
+    builder.build().context("Failed to build tracker client")
+}
+
+/// Converts a failed request into a [`TlsError`] when the failure came
+/// from the TLS handshake or certificate verification.
+pub fn classify(error: reqwest::Error) -> Error {
+    let mut source: Option<&dyn StdError> = Some(&error);
+
+    while let Some(e) = source {
+        let message = e.to_string();
+        let lower = message.to_lowercase();
+        if ["certificate", "tls", "ssl", "handshake"]
+            .iter()
+            .any(|word| lower.contains(word))
+        {
+            return TlsError { message }.into();
+        }
+        source = e.source();
+    }
+
+    error.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_client() {
+        let http = HttpSettings::default();
+        let bind = BindConfig::default();
+        assert!(build_client(None, true, None, &http, &bind).is_ok());
+        assert!(build_client(Some("/nonexistent/ca.pem"), false, None, &http, &bind).is_err());
+
+        let proxy = ProxyConfig {
+            url: String::from("http://127.0.0.1:3128"),
+            allow_direct: false,
+            auth: None,
+        };
+        assert!(build_client(None, false, Some(&proxy), &http, &bind).is_ok());
+
+        #[cfg(feature = "socks")]
+        {
+            let proxy = ProxyConfig {
+                url: String::from("socks5://127.0.0.1:9050"),
+                allow_direct: false,
+                auth: None,
+            };
+            assert!(build_client(None, false, Some(&proxy), &http, &bind).is_ok());
+        }
+    }
+}
@@ -0,0 +1,548 @@
+//! UDP tracker protocol (BEP 15).
+//!
+//! Every UDP tracker is announced to through one socket per address
+//! family. A task reads the socket and hands each response to the request
+//! with the same transaction ID. Connection IDs are cached per tracker for
+//! the minute they are valid, so announces of several torrents to the same
+//! tracker share one. Requests nothing answers are sent again after
+//! 15 × 2^n seconds, n counting the retransmissions, until the announce
+//! deadline in [`Timeouts::tracker`](crate::torrent::timeout::Timeouts)
+//! runs out.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, bail};
+use reqwest::Url;
+use tokio::{
+    net::{UdpSocket, lookup_host},
+    sync::{OnceCell, oneshot},
+};
+
+use crate::torrent::{
+    bind::BindConfig,
+    tasks::{self, Subsystem},
+    tracker::{
+        PeersDict, PeersEnum, ScrapeStats, TrackerClient, TrackerEvent, TrackerRequest,
+        TrackerResponse,
+    },
+};
+
+/// Magic connection ID of connect requests.
+const PROTOCOL_ID: u64 = 0x417_2710_1980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+/// How long a tracker accepts a connection ID after handing it out.
+const CONNECTION_ID_TTL: Duration = Duration::from_secs(60);
+/// Wait for a response before the first retransmission, doubled for each
+/// further one.
+const RETRANSMIT_BASE: Duration = Duration::from_secs(15);
+const MAX_RETRANSMITS: u32 = 8;
+
+/// Largest response read, enough for an announce with a few hundred peers.
+const MAX_RESPONSE_LEN: usize = 4096;
+
+static CLIENT_V4: OnceCell<UdpTrackerClient> = OnceCell::const_new();
+static CLIENT_V6: OnceCell<UdpTrackerClient> = OnceCell::const_new();
+
+/// Requests waiting for a response, by transaction ID, with the tracker
+/// they were sent to.
+type Pending = Arc<Mutex<HashMap<u32, (SocketAddr, oneshot::Sender<Vec<u8>>)>>>;
+
+/// Announces to `udp://` trackers through the shared clients.
+pub struct UdpTracker;
+
+impl TrackerClient for UdpTracker {
+    async fn announce(
+        &self,
+        url: &str,
+        request: &TrackerRequest,
+    ) -> Result<TrackerResponse, anyhow::Error> {
+        let (tracker, client) = resolve(url).await?;
+
+        client.announce(tracker, request).await
+    }
+
+    async fn scrape(&self, url: &str, info_hash: &[u8; 20]) -> Result<ScrapeStats, anyhow::Error> {
+        let (tracker, client) = resolve(url).await?;
+
+        client.scrape(tracker, info_hash).await
+    }
+}
+
+/// Address of the tracker at `url` and the client to reach it with.
+async fn resolve(url: &str) -> Result<(SocketAddr, &'static UdpTrackerClient), anyhow::Error> {
+    let parsed = Url::parse(url).with_context(|| format!("Invalid tracker URL {url}"))?;
+    let (Some(host), Some(port)) = (parsed.host_str(), parsed.port()) else {
+        bail!("UDP tracker URL {url} needs a host and port");
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    let tracker = lookup_host((host, port))
+        .await?
+        .next()
+        .with_context(|| format!("{host} resolved to no addresses"))?;
+
+    Ok((tracker, shared_client(tracker).await?))
+}
+
+/// The client for trackers of `tracker`'s address family, bound to
+/// [`BindConfig`] when first used.
+async fn shared_client(tracker: SocketAddr) -> Result<&'static UdpTrackerClient, anyhow::Error> {
+    let bind = BindConfig::from_env();
+    let (cell, unspecified) = match tracker {
+        SocketAddr::V4(_) => (&CLIENT_V4, IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        SocketAddr::V6(_) => (&CLIENT_V6, IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+    };
+    let local = match bind.address {
+        Some(address) if address.is_ipv4() != tracker.is_ipv4() => {
+            bail!("Can't reach UDP tracker {tracker} from bind address {address}")
+        }
+        Some(address) => address,
+        None => unspecified,
+    };
+
+    cell.get_or_try_init(|| async {
+        let socket = UdpSocket::bind(SocketAddr::new(local, 0)).await?;
+        #[cfg(target_os = "linux")]
+        if let Some(interface) = &bind.interface {
+            socket.bind_device(Some(interface.as_bytes()))?;
+        }
+
+        Ok(UdpTrackerClient::new(socket, RETRANSMIT_BASE))
+    })
+    .await
+}
+
+/// A UDP socket shared by announces to every UDP tracker.
+pub struct UdpTrackerClient {
+    socket: Arc<UdpSocket>,
+    pending: Pending,
+    /// Connection IDs by tracker, with when they were handed out.
+    connections: Mutex<HashMap<SocketAddr, (u64, Instant)>>,
+    retransmit_base: Duration,
+}
+
+impl UdpTrackerClient {
+    /// Starts reading responses from `socket`. Must be called within a
+    /// Tokio runtime.
+    pub fn new(socket: UdpSocket, retransmit_base: Duration) -> Self {
+        let socket = Arc::new(socket);
+        let pending = Pending::default();
+
+        tasks::spawn(
+            Subsystem::Tracker,
+            Self::receive_loop(Arc::clone(&socket), Arc::clone(&pending)),
+        );
+
+        Self {
+            socket,
+            pending,
+            connections: Mutex::default(),
+            retransmit_base,
+        }
+    }
+
+    async fn receive_loop(socket: Arc<UdpSocket>, pending: Pending) {
+        let mut buf = vec![0; MAX_RESPONSE_LEN];
+
+        loop {
+            let (len, from) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    eprintln!("[Tracker] UDP receive failed: {e}");
+                    continue;
+                }
+            };
+            if len < 8 {
+                continue;
+            }
+
+            let transaction_id = read_u32(&buf[4..8]);
+            let mut pending = pending.lock().unwrap();
+            // Ignore responses from anyone but the tracker asked.
+            if pending
+                .get(&transaction_id)
+                .is_some_and(|(tracker, _)| *tracker == from)
+                && let Some((_, response)) = pending.remove(&transaction_id)
+            {
+                let _ = response.send(buf[..len].to_vec());
+            }
+        }
+    }
+
+    /// Announces to `tracker`, connecting first unless a connection ID
+    /// from the last minute is cached.
+    pub async fn announce(
+        &self,
+        tracker: SocketAddr,
+        request: &TrackerRequest,
+    ) -> Result<TrackerResponse, anyhow::Error> {
+        let body = self
+            .request(tracker, ACTION_ANNOUNCE, |connection_id, transaction_id| {
+                announce_packet(connection_id, transaction_id, request)
+            })
+            .await?;
+
+        parse_announce(&body, tracker.is_ipv6())
+    }
+
+    /// Swarm counts of the torrent with `info_hash` at `tracker`.
+    pub async fn scrape(
+        &self,
+        tracker: SocketAddr,
+        info_hash: &[u8; 20],
+    ) -> Result<ScrapeStats, anyhow::Error> {
+        let body = self
+            .request(tracker, ACTION_SCRAPE, |connection_id, transaction_id| {
+                let mut packet = header(connection_id, ACTION_SCRAPE, transaction_id);
+                packet.extend_from_slice(info_hash);
+                packet
+            })
+            .await?;
+
+        if body.len() < 12 {
+            bail!("Scrape response too short");
+        }
+        Ok(ScrapeStats {
+            seeders: read_u32(&body[..4]) as u64,
+            completed: read_u32(&body[4..8]) as u64,
+            leechers: read_u32(&body[8..12]) as u64,
+        })
+    }
+
+    /// Sends the packet `build` makes from a connection and transaction
+    /// ID, connecting first unless a connection ID is cached, and returns
+    /// the body of the response to `action`.
+    async fn request(
+        &self,
+        tracker: SocketAddr,
+        action: u32,
+        build: impl Fn(u64, u32) -> Vec<u8>,
+    ) -> Result<Vec<u8>, anyhow::Error> {
+        for attempt in 0..=MAX_RETRANSMITS {
+            let wait = self.retransmit_base * 2u32.pow(attempt);
+
+            let connection_id = match self.cached_connection(tracker) {
+                Some(connection_id) => connection_id,
+                None => {
+                    let Some(response) = self
+                        .send(tracker, wait, |transaction_id| {
+                            header(PROTOCOL_ID, ACTION_CONNECT, transaction_id)
+                        })
+                        .await?
+                    else {
+                        continue;
+                    };
+
+                    let body = body(&response, ACTION_CONNECT)?;
+                    let connection_id = u64::from_be_bytes(
+                        body.get(..8)
+                            .context("Connect response too short")?
+                            .try_into()?,
+                    );
+                    self.connections
+                        .lock()
+                        .unwrap()
+                        .insert(tracker, (connection_id, Instant::now()));
+                    connection_id
+                }
+            };
+
+            let Some(response) = self
+                .send(tracker, wait, |transaction_id| {
+                    build(connection_id, transaction_id)
+                })
+                .await?
+            else {
+                continue;
+            };
+
+            return body(&response, action)
+                .map(<[u8]>::to_vec)
+                .inspect_err(|_| {
+                    // The tracker may have refused an expired ID.
+                    self.connections.lock().unwrap().remove(&tracker);
+                });
+        }
+
+        bail!("UDP tracker {tracker} did not respond")
+    }
+
+    fn cached_connection(&self, tracker: SocketAddr) -> Option<u64> {
+        let mut connections = self.connections.lock().unwrap();
+        connections.retain(|_, (_, connected)| connected.elapsed() < CONNECTION_ID_TTL);
+
+        connections
+            .get(&tracker)
+            .map(|(connection_id, _)| *connection_id)
+    }
+
+    /// Sends the packet `build` makes for a new transaction ID, and waits
+    /// `wait` for the response. `None` if there was none in time.
+    async fn send(
+        &self,
+        tracker: SocketAddr,
+        wait: Duration,
+        build: impl FnOnce(u32) -> Vec<u8>,
+    ) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        let (tx, rx) = oneshot::channel();
+        let transaction = Transaction::register(&self.pending, tracker, tx);
+
+        self.socket.send_to(&build(transaction.id), tracker).await?;
+
+        Ok(tokio::time::timeout(wait, rx)
+            .await
+            .ok()
+            .and_then(Result::ok))
+    }
+}
+
+/// A request's entry in [`Pending`], removed when dropped so abandoned
+/// requests don't pile up.
+struct Transaction {
+    id: u32,
+    pending: Pending,
+}
+
+impl Transaction {
+    fn register(pending: &Pending, tracker: SocketAddr, tx: oneshot::Sender<Vec<u8>>) -> Self {
+        let mut requests = pending.lock().unwrap();
+        let id = loop {
+            let id = rand::random();
+            if !requests.contains_key(&id) {
+                break id;
+            }
+        };
+        requests.insert(id, (tracker, tx));
+
+        Self {
+            id,
+            pending: Arc::clone(pending),
+        }
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.id);
+    }
+}
+
+fn header(connection_id: u64, action: u32, transaction_id: u32) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(98);
+    packet.extend_from_slice(&connection_id.to_be_bytes());
+    packet.extend_from_slice(&action.to_be_bytes());
+    packet.extend_from_slice(&transaction_id.to_be_bytes());
+
+    packet
+}
+
+fn announce_packet(connection_id: u64, transaction_id: u32, request: &TrackerRequest) -> Vec<u8> {
+    let mut packet = header(connection_id, ACTION_ANNOUNCE, transaction_id);
+
+    let mut peer_id = [0; 20];
+    let len = request.peer_id.len().min(20);
+    peer_id[..len].copy_from_slice(&request.peer_id.as_bytes()[..len]);
+    let event: u32 = match request.event {
+        None => 0,
+        Some(TrackerEvent::Completed) => 1,
+        Some(TrackerEvent::Started) => 2,
+        Some(TrackerEvent::Stopped) => 3,
+        // BEP 21 extends the UDP events with paused.
+        Some(TrackerEvent::Paused) => 4,
+    };
+    let ip = match request.ip {
+        Some(IpAddr::V4(ip)) => u32::from(ip),
+        _ => 0,
+    };
+    let key = request
+        .key
+        .as_deref()
+        .and_then(|key| u32::from_str_radix(key, 16).ok())
+        .unwrap_or_default();
+
+    packet.extend_from_slice(&request.info_hash);
+    packet.extend_from_slice(&peer_id);
+    packet.extend_from_slice(&request.downloaded.to_be_bytes());
+    packet.extend_from_slice(&request.left.to_be_bytes());
+    packet.extend_from_slice(&request.uploaded.to_be_bytes());
+    packet.extend_from_slice(&event.to_be_bytes());
+    packet.extend_from_slice(&ip.to_be_bytes());
+    packet.extend_from_slice(&key.to_be_bytes());
+    packet.extend_from_slice(&i32::try_from(request.numwant).unwrap_or(-1).to_be_bytes());
+    packet.extend_from_slice(
+        &u16::try_from(request.port)
+            .unwrap_or_default()
+            .to_be_bytes(),
+    );
+
+    packet
+}
+
+/// The response after its action and transaction ID, failing if the
+/// tracker sent an error instead of `action`.
+fn body(response: &[u8], action: u32) -> Result<&[u8], anyhow::Error> {
+    if response.len() < 8 {
+        bail!("UDP tracker response too short");
+    }
+
+    match read_u32(&response[..4]) {
+        ACTION_ERROR => bail!(
+            "Tracker refused announce: {}",
+            String::from_utf8_lossy(&response[8..])
+        ),
+        received if received != action => {
+            bail!("UDP tracker answered action {action} with {received}")
+        }
+        _ => Ok(&response[8..]),
+    }
+}
+
+/// Interval, swarm counts and peers of an announce response. IPv6
+/// trackers send 18 byte peers, IPv4 ones 6 byte peers.
+fn parse_announce(body: &[u8], ipv6: bool) -> Result<TrackerResponse, anyhow::Error> {
+    if body.len() < 12 {
+        bail!("Announce response too short");
+    }
+
+    let address_len = if ipv6 { 16 } else { 4 };
+    let peers = body[12..]
+        .chunks_exact(address_len + 2)
+        .map(|chunk| {
+            let (address, port) = chunk.split_at(address_len);
+            let ip = match <[u8; 16]>::try_from(address) {
+                Ok(octets) => IpAddr::from(octets),
+                Err(_) => IpAddr::from([address[0], address[1], address[2], address[3]]),
+            };
+
+            PeersDict {
+                peer_id: None,
+                ip: ip.to_string(),
+                port: u16::from_be_bytes([port[0], port[1]]) as u64,
+            }
+        })
+        .collect();
+
+    Ok(TrackerResponse {
+        failure_reason: None,
+        warning_message: None,
+        interval: Some(read_u32(&body[..4]) as u64),
+        min_interval: None,
+        tracker_id: None,
+        complete: Some(read_u32(&body[8..12]) as u64),
+        incomplete: Some(read_u32(&body[4..8]) as u64),
+        peers: Some(PeersEnum::Dict(peers)),
+        peers6: None,
+        external_ip: None,
+    })
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    /// Mock tracker that drops the first announce it gets, to be
+    /// retransmitted, and answers the rest with one peer.
+    async fn mock_tracker(connects: Arc<AtomicUsize>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            let mut dropped = false;
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let (action, transaction_id) = (read_u32(&buf[8..12]), &buf[12..16]);
+
+                let mut response = vec![];
+                if action == ACTION_CONNECT {
+                    assert_eq!(
+                        u64::from_be_bytes(buf[..8].try_into().unwrap()),
+                        PROTOCOL_ID
+                    );
+                    connects.fetch_add(1, Ordering::Relaxed);
+                    response.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+                    response.extend_from_slice(transaction_id);
+                    response.extend_from_slice(&42u64.to_be_bytes());
+                } else if action == ACTION_SCRAPE {
+                    assert_eq!(&buf[16..len], &[1; 20]);
+                    response.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+                    response.extend_from_slice(transaction_id);
+                    for value in [7u32, 12, 3] {
+                        response.extend_from_slice(&value.to_be_bytes());
+                    }
+                } else {
+                    assert_eq!(len, 98);
+                    assert_eq!(u64::from_be_bytes(buf[..8].try_into().unwrap()), 42);
+                    if !dropped {
+                        dropped = true;
+                        continue;
+                    }
+                    response.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+                    response.extend_from_slice(transaction_id);
+                    for value in [1800u32, 3, 7] {
+                        response.extend_from_slice(&value.to_be_bytes());
+                    }
+                    response.extend_from_slice(&[10, 0, 0, 1, 0x1A, 0xE1]);
+                }
+                socket.send_to(&response, from).await.unwrap();
+            }
+        });
+
+        address
+    }
+
+    #[tokio::test]
+    async fn test_announce_retransmits_and_reuses_connection() {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let tracker = mock_tracker(Arc::clone(&connects)).await;
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UdpTrackerClient::new(socket, Duration::from_millis(50));
+        let request = TrackerRequest::new([1; 20], "-RS0001-abcdefghijkl");
+
+        let response = client.announce(tracker, &request).await.unwrap();
+        assert_eq!(response.interval, Some(1800));
+        assert_eq!((response.incomplete, response.complete), (Some(3), Some(7)));
+        assert_eq!(
+            response.peers,
+            Some(PeersEnum::Dict(vec![PeersDict {
+                peer_id: None,
+                ip: String::from("10.0.0.1"),
+                port: 6881,
+            }]))
+        );
+
+        client.announce(tracker, &request).await.unwrap();
+        let stats = client.scrape(tracker, &[1; 20]).await.unwrap();
+        assert_eq!((stats.seeders, stats.completed, stats.leechers), (7, 12, 3));
+        assert_eq!(connects.load(Ordering::Relaxed), 1);
+        assert!(client.pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_error_response() {
+        let mut response = ACTION_ERROR.to_be_bytes().to_vec();
+        response.extend_from_slice(&[0; 4]);
+        response.extend_from_slice(b"unregistered torrent");
+
+        let error = body(&response, ACTION_ANNOUNCE).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Tracker refused announce: unregistered torrent"
+        );
+    }
+}
@@ -0,0 +1,89 @@
+//! Bytes of piece data exchanged with all peers of a torrent.
+//!
+//! Every peer session of the torrent adds to the same counters, which
+//! outlive the sessions, so the totals reported to the tracker include
+//! peers that already disconnected. Private trackers keep ratios from
+//! these.
+
+use std::{
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+/// Shortest time rates are measured over, so they don't jump with every
+/// block.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Default)]
+pub struct TransferStats {
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    /// Totals when the rates were last measured.
+    sample: Mutex<Option<RateSample>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RateSample {
+    at: Instant,
+    totals: (u64, u64),
+    rates: (u64, u64),
+}
+
+impl TransferStats {
+    pub fn record_upload(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn record_download(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Total `(uploaded, downloaded)` bytes.
+    pub fn totals(&self) -> (u64, u64) {
+        (
+            self.uploaded.load(Ordering::Relaxed),
+            self.downloaded.load(Ordering::Relaxed),
+        )
+    }
+
+    /// `(upload, download)` bytes per second since the previous call at
+    /// least [`RATE_WINDOW`] ago. Zero on the first call.
+    pub fn rates(&self) -> (u64, u64) {
+        let now = Instant::now();
+        let totals = self.totals();
+        let mut sample = self.sample.lock().unwrap();
+
+        match *sample {
+            Some(previous) if now - previous.at < RATE_WINDOW => previous.rates,
+            previous => {
+                let rates = previous.map_or((0, 0), |previous| {
+                    let seconds = (now - previous.at).as_secs_f64();
+                    let rate = |total: u64, before: u64| {
+                        (total.saturating_sub(before) as f64 / seconds) as u64
+                    };
+                    (
+                        rate(totals.0, previous.totals.0),
+                        rate(totals.1, previous.totals.1),
+                    )
+                });
+                *sample = Some(RateSample {
+                    at: now,
+                    totals,
+                    rates,
+                });
+                rates
+            }
+        }
+    }
+
+    /// Continues counting from totals of a previous session.
+    pub fn restore(&self, uploaded: u64, downloaded: u64) {
+        self.uploaded.store(uploaded, Ordering::Relaxed);
+        self.downloaded.store(downloaded, Ordering::Relaxed);
+        // Not transferred now, so not part of any rate.
+        *self.sample.lock().unwrap() = None;
+    }
+}
@@ -0,0 +1,137 @@
+//! Checking which pieces of a torrent are already present on disk.
+
+use std::{fs::File, io::Read, path::Path};
+
+use sha1::{Digest, Sha1};
+
+use crate::torrent::{io_stats::IoStats, metainfo::info::InfoEnum, piece_manager::PieceRequest};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum CheckStatus {
+    /// Never checked, e.g. a newly added torrent with nothing on disk.
+    #[default]
+    Unchecked,
+    /// Waiting for other torrents to finish checking.
+    Queued,
+    Checking,
+    /// Checked, holding whether each piece is present and valid.
+    Checked {
+        have: Vec<bool>,
+    },
+}
+
+impl CheckStatus {
+    /// Whether the torrent is waiting for or in the middle of a check, and
+    /// so can't be started yet.
+    pub fn is_pending(&self) -> bool {
+        matches!(self, CheckStatus::Queued | CheckStatus::Checking)
+    }
+}
+
+/// Bytes of the pieces not marked in `have`, i.e. still to download.
+pub fn left(info: &InfoEnum, have: &[bool]) -> u64 {
+    PieceRequest::all(info)
+        .iter()
+        .filter(|request| !have.get(request.piece_index as usize).is_some_and(|&h| h))
+        .map(|request| request.length_bytes as u64)
+        .sum()
+}
+
+/// Hashes the torrent's data under `root`, returning whether each piece
+/// matches. Pieces overlapping missing or short files are invalid. Reads
+/// are recorded in `stats`.
+pub fn verify_pieces(root: &Path, info: &InfoEnum, stats: &IoStats) -> Vec<bool> {
+    let piece_length = info.piece_length() as usize;
+    let hashes: Vec<&[u8]> = info.pieces().chunks_exact(20).collect();
+
+    let mut have = Vec::with_capacity(hashes.len());
+    let mut piece = Vec::with_capacity(piece_length);
+    // Cleared when part of the current piece could not be read.
+    let mut complete = true;
+
+    let mut finish_piece = |piece: &mut Vec<u8>, complete: &mut bool| {
+        let valid = hashes
+            .get(have.len())
+            .is_some_and(|hash| *complete && Sha1::digest(&piece[..]).as_slice() == *hash);
+        have.push(valid);
+        piece.clear();
+        *complete = true;
+    };
+
+    for (path, length) in info.layout(root) {
+        let mut file = File::open(&path).ok();
+        let mut remaining = length;
+
+        while remaining > 0 {
+            let wanted = (piece_length - piece.len()).min(remaining as usize);
+            let start = piece.len();
+
+            let read = match file.as_mut() {
+                Some(file) => stats
+                    .timed_read(|| file.take(wanted as u64).read_to_end(&mut piece))
+                    .unwrap_or(0),
+                None => 0,
+            };
+            if read < wanted {
+                complete = false;
+                file = None;
+                piece.resize(start + wanted, 0);
+            }
+            remaining -= wanted as u64;
+
+            if piece.len() == piece_length {
+                finish_piece(&mut piece, &mut complete);
+            }
+        }
+    }
+
+    if !piece.is_empty() {
+        finish_piece(&mut piece, &mut complete);
+    }
+
+    have.resize(hashes.len(), false);
+    have
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::torrent::{builder::TorrentBuilder, metainfo::MetaInfo};
+
+    #[test]
+    fn test_verify_detects_damaged_and_missing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("album");
+        fs::create_dir(&root).unwrap();
+        fs::write(root.join("a.bin"), vec![1u8; 20_000]).unwrap();
+        fs::write(root.join("b.bin"), vec![2u8; 40_000]).unwrap();
+
+        let bytes = TorrentBuilder::new(&root).build().unwrap();
+        let metainfo = MetaInfo::from_bytes(&bytes).unwrap();
+        let info = metainfo.info();
+        let stats = IoStats::default();
+
+        assert_eq!(verify_pieces(dir.path(), info, &stats), vec![true; 4]);
+
+        let mut damaged = vec![2u8; 40_000];
+        damaged[39_000] = 0;
+        fs::write(root.join("b.bin"), damaged).unwrap();
+        assert_eq!(
+            verify_pieces(dir.path(), info, &stats),
+            vec![true, true, true, false]
+        );
+
+        fs::remove_file(root.join("a.bin")).unwrap();
+        assert_eq!(
+            verify_pieces(dir.path(), info, &stats),
+            vec![false, false, true, false]
+        );
+        assert_eq!(stats.snapshot().bytes_read, 3 * 60_000 - 20_000);
+
+        assert_eq!(left(info, &[true, true, true, false]), 60_000 - 3 * 16_384);
+        assert_eq!(left(info, &[false, false, true, false]), 60_000 - 16_384);
+        assert_eq!(left(info, &[]), 60_000);
+    }
+}
@@ -1,30 +1,61 @@
+use std::io::Write;
+
 use anyhow::Error;
+use base64::{Engine, engine::general_purpose::STANDARD};
 use ratatui::{
     Frame,
     crossterm::event::{KeyCode, KeyEvent},
     layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Style},
-    text::Text,
+    text::{Line, Span, Text},
     widgets::{Block, BorderType, Borders, Paragraph},
 };
 use tokio::sync::mpsc::Sender;
 
 use crate::{
     AppEvent, AppEventType,
-    app::ui_models::TorrentItem,
-    tui::{torrent_details::TorrentDetails, torrents_table::TorrentsTable},
+    app::{
+        CurrentScreen,
+        ui_models::{ConnectionItem, DiskItem, SessionStatus, TorrentItem},
+    },
+    torrent::privacy,
+    tui::{
+        add_dialog::{AddAction, AddDialog},
+        connections_table::ConnectionsTable,
+        create_dialog::{CreateDialog, DialogAction},
+        terminal_title::TerminalTitle,
+        torrent_details::TorrentDetails,
+        torrents_table::{TableRow, TorrentsTable},
+        tracker_dialog::TrackerDialog,
+    },
 };
 
+mod add_dialog;
+mod connections_table;
+mod create_dialog;
+mod disk_stats_table;
+mod format;
+mod terminal_title;
 mod torrent_details;
 mod torrents_table;
+mod tracker_dialog;
 
-const INFO_TEXT: &str = "(Esc) quit | (⏎) toggle torrent start/stop | (↑) move up | (↓) move down";
+const INFO_TEXT: &str = "(Esc) quit | (⏎) toggle torrent start/stop | (↑) move up | (↓) move down | (E) export session | (I) import session | (O) add torrent | (C) create torrent | (M) copy magnet link | (A) reannounce | (U) edit trackers | (L) allocation | (H) privacy mode | (G) connections | (D) disk stats | (S) sort | (Y) group | (K) units";
 
 pub struct Tui {
     torrents_table: TorrentsTable,
     torrent_details: TorrentDetails,
     focused_pane: FocusedPane,
     torrent_items: Vec<TorrentItem>,
+    /// Lines of the torrents table, see [`TorrentsTable::rows`].
+    rows: Vec<TableRow>,
+    connections_table: ConnectionsTable,
+    connections: Vec<ConnectionItem>,
+    screen: CurrentScreen,
+    create_dialog: Option<CreateDialog>,
+    add_dialog: Option<AddDialog>,
+    tracker_dialog: Option<TrackerDialog>,
+    terminal_title: TerminalTitle,
     event_tx: Sender<AppEvent>,
 }
 
@@ -42,18 +73,35 @@ pub enum FocusedPane {
 
 impl Tui {
     pub fn new(event_tx: Sender<AppEvent>) -> Self {
+        format::init_from_env();
+
         Self {
-            torrents_table: TorrentsTable { selected: 0 },
+            torrents_table: TorrentsTable::default(),
             torrent_details: TorrentDetails {
                 selected: 0,
                 selected_tab: 0,
             },
             torrent_items: vec![],
+            rows: vec![],
+            connections_table: ConnectionsTable::default(),
+            connections: vec![],
+            screen: CurrentScreen::Main,
             focused_pane: FocusedPane::Left,
+            create_dialog: None,
+            add_dialog: None,
+            tracker_dialog: None,
+            terminal_title: TerminalTitle::from_env(),
             event_tx,
         }
     }
-    pub fn draw(&mut self, frame: &mut Frame, torrent_items: &[TorrentItem]) {
+    pub fn draw(
+        &mut self,
+        frame: &mut Frame,
+        torrent_items: &[TorrentItem],
+        connections: &[ConnectionItem],
+        disk_items: &[DiskItem],
+        status: &SessionStatus,
+    ) {
         let vertical_chunks = Layout::default()
             .direction(Direction::Vertical)
             .constraints([
@@ -72,29 +120,96 @@ impl Tui {
             .borders(Borders::ALL)
             .style(Style::default());
 
-        let title = Paragraph::new(Text::styled("BTRS", Style::default().fg(Color::Green)))
-            .centered()
-            .block(title_block);
+        let external_ip = match status.external_ip {
+            Some(ip) => privacy::address(&ip.to_string()),
+            None => String::from("unknown"),
+        };
+        let title = Paragraph::new(Line::from(vec![
+            Span::styled("BTRS", Style::default().fg(Color::Green)),
+            Span::raw(format!(" | External IP: {external_ip}")),
+        ]))
+        .centered()
+        .block(title_block);
 
         frame.render_widget(title, vertical_chunks[0]);
 
-        self.torrent_items = torrent_items.to_vec();
+        self.torrent_items = self.torrents_table.sorted(torrent_items);
+        self.rows = self.torrents_table.rows(&self.torrent_items);
+        self.terminal_title.update(torrent_items);
+        self.connections = self.connections_table.sorted(connections);
+
+        if self.screen == CurrentScreen::Connections {
+            self.connections_table
+                .render(frame, vertical_chunks[1], &self.connections);
+            Self::render_footer(frame, vertical_chunks[2]);
+            return;
+        }
+
+        if self.screen == CurrentScreen::DiskStats {
+            disk_stats_table::render(frame, vertical_chunks[1], disk_items);
+            Self::render_footer(frame, vertical_chunks[2]);
+            return;
+        }
 
         self.torrents_table.render(
             frame,
             middle_chunks[0],
             &self.torrent_items,
+            &self.rows,
             self.focused_pane == FocusedPane::Left,
         );
 
-        self.torrent_details.render_tabs(
-            frame,
-            middle_chunks[1],
-            &torrent_items[self.torrents_table.selected],
-            self.focused_pane == FocusedPane::Right,
-        );
+        // Torrents can be removed while selected.
+        self.torrents_table.selected = self
+            .torrents_table
+            .selected
+            .min(self.rows.len().saturating_sub(1));
+
+        if let Some(item) = Self::row_item(
+            &self.rows,
+            &self.torrent_items,
+            self.torrents_table.selected,
+        ) {
+            self.torrent_details.render_tabs(
+                frame,
+                middle_chunks[1],
+                item,
+                status,
+                self.focused_pane == FocusedPane::Right,
+            );
+        }
 
         Self::render_footer(frame, vertical_chunks[2]);
+
+        if let Some(dialog) = &self.create_dialog {
+            dialog.render(frame, frame.area());
+        }
+        if let Some(dialog) = &self.add_dialog {
+            dialog.render(frame, frame.area(), status.swarm_preview.as_ref());
+        }
+        if let Some(dialog) = &self.tracker_dialog {
+            dialog.render(frame, frame.area());
+        }
+    }
+
+    /// Torrent on the selected row, `None` on a group header.
+    fn selected_item(&self) -> Option<&TorrentItem> {
+        Self::row_item(
+            &self.rows,
+            &self.torrent_items,
+            self.torrents_table.selected,
+        )
+    }
+
+    fn row_item<'a>(
+        rows: &[TableRow],
+        items: &'a [TorrentItem],
+        row: usize,
+    ) -> Option<&'a TorrentItem> {
+        match rows.get(row)? {
+            TableRow::Torrent(index) => items.get(*index),
+            TableRow::Group { .. } => None,
+        }
     }
 
     fn render_footer(frame: &mut Frame, area: Rect) {
@@ -109,9 +224,9 @@ impl Tui {
         match direction {
             NavDirection::Up => match self.focused_pane {
                 FocusedPane::Left => {
-                    if self.torrent_items.len() > 0 {
+                    if !self.rows.is_empty() {
                         self.torrents_table.selected =
-                            self.torrents_table.selected.wrapping_sub(1) % self.torrent_items.len();
+                            self.torrents_table.selected.wrapping_sub(1) % self.rows.len();
                     }
                 }
                 FocusedPane::Right => {
@@ -120,9 +235,9 @@ impl Tui {
             },
             NavDirection::Down => match self.focused_pane {
                 FocusedPane::Left => {
-                    if self.torrent_items.len() > 0 {
+                    if !self.rows.is_empty() {
                         self.torrents_table.selected =
-                            (self.torrents_table.selected + 1) % self.torrent_items.len();
+                            (self.torrents_table.selected + 1) % self.rows.len();
                     }
                 }
                 FocusedPane::Right => self.torrent_details.selected += 1,
@@ -133,6 +248,85 @@ impl Tui {
     }
 
     pub async fn handle_key(&mut self, key_event: KeyEvent) -> Result<(), Error> {
+        // An open dialog takes all input until it is closed.
+        if let Some(dialog) = &mut self.create_dialog {
+            match dialog.handle_key(key_event.code) {
+                DialogAction::Submit => {
+                    let dialog = self.create_dialog.take().unwrap();
+                    self.event_tx
+                        .send(AppEvent::Custom(AppEventType::CreateTorrent {
+                            path: dialog.path,
+                            tracker: dialog.tracker,
+                        }))
+                        .await?;
+                }
+                DialogAction::Cancel => self.create_dialog = None,
+                DialogAction::None => {}
+            }
+
+            return Ok(());
+        }
+        if let Some(dialog) = &mut self.add_dialog {
+            match dialog.handle_key(key_event.code) {
+                AddAction::Add => {
+                    let dialog = self.add_dialog.take().unwrap();
+                    self.event_tx
+                        .send(AppEvent::Custom(AppEventType::AddTorrent(dialog.source)))
+                        .await?;
+                }
+                AddAction::Preview => {
+                    self.event_tx
+                        .send(AppEvent::Custom(AppEventType::PreviewSwarm(
+                            dialog.source.clone(),
+                        )))
+                        .await?;
+                }
+                AddAction::Cancel => self.add_dialog = None,
+                AddAction::None => {}
+            }
+
+            return Ok(());
+        }
+        if let Some(dialog) = &mut self.tracker_dialog {
+            match dialog.handle_key(key_event.code) {
+                DialogAction::Submit => {
+                    let dialog = self.tracker_dialog.take().unwrap();
+                    let event = match dialog.selected_tracker() {
+                        Some(url) if dialog.remove => AppEventType::RemoveTracker {
+                            info_hash: dialog.info_hash.clone(),
+                            url: url.clone(),
+                        },
+                        _ => AppEventType::AddTracker {
+                            info_hash: dialog.info_hash,
+                            url: dialog.url,
+                        },
+                    };
+                    self.event_tx.send(AppEvent::Custom(event)).await?;
+                }
+                DialogAction::Cancel => self.tracker_dialog = None,
+                DialogAction::None => {}
+            }
+
+            return Ok(());
+        }
+
+        if self.screen == CurrentScreen::Connections {
+            return self.handle_connections_key(key_event).await;
+        }
+
+        if self.screen == CurrentScreen::DiskStats {
+            match key_event.code {
+                KeyCode::Char('D') | KeyCode::Esc => self.screen = CurrentScreen::Main,
+                KeyCode::Char('q') => {
+                    self.event_tx
+                        .send(AppEvent::Custom(AppEventType::Exit))
+                        .await?
+                }
+                _ => (),
+            }
+            return Ok(());
+        }
+
         match key_event.code {
             KeyCode::Up | KeyCode::Char('j') => {
                 self.navigate(NavDirection::Up);
@@ -147,13 +341,17 @@ impl Tui {
                 self.navigate(NavDirection::Left);
             }
             KeyCode::Enter => {
-                let key = self.torrent_items[self.torrents_table.selected]
-                    .info_hash
-                    .clone();
-
-                self.event_tx
-                    .send(AppEvent::Custom(AppEventType::Download(key)))
-                    .await?;
+                if let Some(TableRow::Group { name, .. }) =
+                    self.rows.get(self.torrents_table.selected)
+                {
+                    self.torrents_table.toggle_group(name);
+                } else if let Some(item) = self.selected_item() {
+                    self.event_tx
+                        .send(AppEvent::Custom(AppEventType::Download(
+                            item.info_hash.clone(),
+                        )))
+                        .await?;
+                }
             }
             KeyCode::Esc | KeyCode::Char('q') => {
                 self.event_tx
@@ -168,10 +366,121 @@ impl Tui {
                 self.focused_pane = FocusedPane::Right;
                 self.torrent_details.selected_tab = 1;
             }
+            KeyCode::Char('N') => {
+                self.focused_pane = FocusedPane::Right;
+                self.torrent_details.selected_tab = 2;
+            }
+            KeyCode::Char('B') => {
+                self.focused_pane = FocusedPane::Right;
+                self.torrent_details.selected_tab = 3;
+            }
+            KeyCode::Char('R') => {
+                self.focused_pane = FocusedPane::Right;
+                self.torrent_details.selected_tab = 4;
+            }
+            KeyCode::Char('V') => {
+                self.focused_pane = FocusedPane::Right;
+                self.torrent_details.selected_tab = 5;
+            }
             KeyCode::Char('T') => self.focused_pane = FocusedPane::Left,
+            KeyCode::Char('S') => self.torrents_table.cycle_sort(),
+            KeyCode::Char('Y') => self.torrents_table.cycle_grouping(),
+            KeyCode::Char('E') => {
+                self.event_tx
+                    .send(AppEvent::Custom(AppEventType::ExportSession))
+                    .await?
+            }
+            KeyCode::Char('M') => {
+                if let Some(item) = self.selected_item() {
+                    self.event_tx
+                        .send(AppEvent::Custom(AppEventType::CopyMagnet(
+                            item.info_hash.clone(),
+                        )))
+                        .await?
+                }
+            }
+            KeyCode::Char('A') => {
+                if let Some(item) = self.selected_item() {
+                    self.event_tx
+                        .send(AppEvent::Custom(AppEventType::ForceAnnounce(
+                            item.info_hash.clone(),
+                        )))
+                        .await?
+                }
+            }
+            KeyCode::Char('U') => {
+                if let Some(item) = self.selected_item() {
+                    let trackers = item.trackers.iter().map(|t| t.url.clone()).collect();
+                    self.tracker_dialog =
+                        Some(TrackerDialog::new(item.info_hash.clone(), trackers));
+                }
+            }
+            KeyCode::Char('L') => {
+                if let Some(item) = self.selected_item() {
+                    self.event_tx
+                        .send(AppEvent::Custom(AppEventType::CycleAllocation(
+                            item.info_hash.clone(),
+                        )))
+                        .await?
+                }
+            }
+            KeyCode::Char('H') => privacy::toggle(),
+            KeyCode::Char('K') => format::toggle_units(),
+            KeyCode::Char('G') => self.screen = CurrentScreen::Connections,
+            KeyCode::Char('D') => self.screen = CurrentScreen::DiskStats,
+            KeyCode::Char('C') => self.create_dialog = Some(CreateDialog::default()),
+            KeyCode::Char('O') => self.add_dialog = Some(AddDialog::default()),
+            KeyCode::Char('I') => {
+                self.event_tx
+                    .send(AppEvent::Custom(AppEventType::ImportSession))
+                    .await?
+            }
             _ => (),
         }
 
         Ok(())
     }
 }
+
+impl Tui {
+    async fn handle_connections_key(&mut self, key_event: KeyEvent) -> Result<(), Error> {
+        let table = &mut self.connections_table;
+
+        match key_event.code {
+            KeyCode::Up | KeyCode::Char('j') => table.selected = table.selected.saturating_sub(1),
+            KeyCode::Down | KeyCode::Char('k') if table.selected + 1 < self.connections.len() => {
+                table.selected += 1
+            }
+            KeyCode::Char('s') => table.cycle_sort(),
+            KeyCode::Char('x') => {
+                if let Some(connection) = self.connections.get(table.selected) {
+                    self.event_tx
+                        .send(AppEvent::Custom(AppEventType::KillConnection {
+                            info_hash: connection.info_hash.clone(),
+                            address: connection.address.clone(),
+                        }))
+                        .await?
+                }
+            }
+            KeyCode::Char('G') | KeyCode::Esc => self.screen = CurrentScreen::Main,
+            KeyCode::Char('q') => {
+                self.event_tx
+                    .send(AppEvent::Custom(AppEventType::Exit))
+                    .await?
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+}
+
+/// Copies `text` to the system clipboard through the terminal, using the
+/// OSC 52 escape sequence. Terminals without support ignore it.
+pub fn copy_to_clipboard(text: &str) -> Result<(), Error> {
+    let mut stdout = std::io::stdout();
+    write!(stdout, "\x1b]52;c;{}\x07", STANDARD.encode(text))?;
+    stdout.flush()?;
+
+    Ok(())
+}
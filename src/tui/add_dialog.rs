@@ -0,0 +1,86 @@
+use ratatui::{
+    crossterm::event::KeyCode,
+    prelude::*,
+    widgets::{Block, Borders, Clear, Paragraph},
+};
+
+use crate::{app::swarm_preview::SwarmPreview, torrent::privacy, tui::create_dialog::centered};
+
+pub enum AddAction {
+    Add,
+    /// Scrape the trackers of the source without adding it.
+    Preview,
+    Cancel,
+    None,
+}
+
+/// Form for adding a .torrent file or magnet link, showing its swarm once
+/// previewed.
+#[derive(Default)]
+pub struct AddDialog {
+    pub source: String,
+}
+
+impl AddDialog {
+    pub fn handle_key(&mut self, code: KeyCode) -> AddAction {
+        match code {
+            KeyCode::Esc => return AddAction::Cancel,
+            KeyCode::Enter if !self.source.is_empty() => return AddAction::Add,
+            KeyCode::Tab if !self.source.is_empty() => return AddAction::Preview,
+            KeyCode::Backspace => {
+                self.source.pop();
+            }
+            KeyCode::Char(c) => self.source.push(c),
+            _ => (),
+        }
+
+        AddAction::None
+    }
+
+    pub fn render(&self, f: &mut Frame, area: Rect, preview: Option<&SwarmPreview>) {
+        // A preview of an earlier source is stale once the source is edited.
+        let preview = preview.filter(|p| p.source == self.source);
+        let trackers = preview.map_or(0, |p| p.trackers.len() + 1);
+        let popup = centered(area, 60, trackers as u16 + 5);
+
+        let mut text = vec![Line::styled(
+            format!("Source:  {}", self.source),
+            Style::default().fg(Color::LightBlue),
+        )];
+        if let Some(preview) = preview {
+            text.push(Line::from(format!(
+                "Name:    {}",
+                privacy::name(&preview.name)
+            )));
+            for tracker in &preview.trackers {
+                let (result, style) = match &tracker.result {
+                    None => ("scraping…".to_owned(), Style::default()),
+                    Some(Ok(stats)) => (
+                        format!(
+                            "seeders {}, leechers {}, completed {}",
+                            stats.seeders, stats.leechers, stats.completed
+                        ),
+                        Style::default().fg(Color::Green),
+                    ),
+                    Some(Err(e)) => (format!("error: {e}"), Style::default().fg(Color::Red)),
+                };
+                text.push(Line::from(vec![
+                    Span::raw(format!("{}  ", privacy::tracker_url(&tracker.url))),
+                    Span::styled(result, style),
+                ]));
+            }
+        }
+        text.push(Line::from(""));
+        text.push(Line::from("(Tab) check swarm | (⏎) add | (Esc) cancel"));
+
+        let dialog = Paragraph::new(text).block(
+            Block::default()
+                .title("Add torrent")
+                .borders(Borders::ALL)
+                .border_set(symbols::border::ROUNDED),
+        );
+
+        f.render_widget(Clear, popup);
+        f.render_widget(dialog, popup);
+    }
+}
@@ -0,0 +1,176 @@
+use std::cmp::Reverse;
+
+use ratatui::{
+    prelude::*,
+    widgets::{Block, Borders, Cell, HighlightSpacing, Row, Table, TableState},
+};
+
+use crate::{app::ui_models::ConnectionItem, torrent::privacy, tui::format};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortColumn {
+    #[default]
+    Torrent,
+    Address,
+    Client,
+    Downloaded,
+}
+
+impl SortColumn {
+    fn next(self) -> Self {
+        match self {
+            Self::Torrent => Self::Address,
+            Self::Address => Self::Client,
+            Self::Client => Self::Downloaded,
+            Self::Downloaded => Self::Torrent,
+        }
+    }
+}
+
+/// Every open peer connection across all torrents.
+#[derive(Default)]
+pub struct ConnectionsTable {
+    pub selected: usize,
+    pub sort: SortColumn,
+}
+
+impl ConnectionsTable {
+    pub fn cycle_sort(&mut self) {
+        self.sort = self.sort.next();
+    }
+
+    /// Orders connections by the current sort column.
+    pub fn sorted(&self, connections: &[ConnectionItem]) -> Vec<ConnectionItem> {
+        let mut connections = connections.to_vec();
+
+        match self.sort {
+            SortColumn::Torrent => connections
+                .sort_by(|a, b| (&a.torrent_name, &a.address).cmp(&(&b.torrent_name, &b.address))),
+            SortColumn::Address => connections.sort_by(|a, b| a.address.cmp(&b.address)),
+            SortColumn::Client => connections.sort_by(|a, b| a.client.cmp(&b.client)),
+            SortColumn::Downloaded => connections.sort_by_key(|c| Reverse(c.downloaded)),
+        }
+
+        connections
+    }
+
+    pub fn render(&self, f: &mut Frame, area: Rect, connections: &[ConnectionItem]) {
+        let titles = [
+            ("Torrent", Some(SortColumn::Torrent)),
+            ("Address", Some(SortColumn::Address)),
+            ("Client", Some(SortColumn::Client)),
+            ("State", None),
+            ("Downloaded", Some(SortColumn::Downloaded)),
+            ("Down", None),
+            ("Up", None),
+        ];
+
+        let header = Row::new(titles.iter().map(|(title, column)| {
+            if *column == Some(self.sort) {
+                Cell::from(format!("{title} ▼"))
+            } else {
+                Cell::from(*title)
+            }
+        }))
+        .style(
+            Style::default()
+                .fg(Color::Yellow)
+                .add_modifier(Modifier::BOLD),
+        );
+
+        let rows: Vec<Row> = connections
+            .iter()
+            .map(|c| {
+                Row::new(vec![
+                    Cell::from(privacy::name(&c.torrent_name)),
+                    Cell::from(privacy::address(&c.address)),
+                    Cell::from(c.client.clone()),
+                    Cell::from(c.state.clone()),
+                    Cell::from(format::size(c.downloaded)),
+                    Cell::from(format::rate(c.download_rate)),
+                    Cell::from(format::rate(c.upload_rate)),
+                ])
+            })
+            .collect();
+
+        let widths = [
+            Constraint::Percentage(20),
+            Constraint::Percentage(18),
+            Constraint::Percentage(14),
+            Constraint::Percentage(18),
+            Constraint::Percentage(10),
+            Constraint::Percentage(10),
+            Constraint::Percentage(10),
+        ];
+
+        let table = Table::new(rows, widths)
+            .header(header)
+            .block(
+                Block::default()
+                    .title("Connections (s) sort | (x) kill | (G) back")
+                    .borders(Borders::ALL)
+                    .border_set(symbols::border::ROUNDED)
+                    .border_style(Style::new().blue()),
+            )
+            .row_highlight_style(
+                Style::default()
+                    .add_modifier(Modifier::REVERSED)
+                    .fg(Color::LightBlue),
+            )
+            .highlight_symbol(" > ")
+            .highlight_spacing(HighlightSpacing::Always);
+
+        let mut state = TableState::default();
+        if !connections.is_empty() {
+            state.select(Some(self.selected.min(connections.len() - 1)));
+        }
+
+        f.render_stateful_widget(table, area, &mut state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(torrent: &str, address: &str, downloaded: u64) -> ConnectionItem {
+        ConnectionItem {
+            torrent_name: String::from(torrent),
+            info_hash: String::new(),
+            address: String::from(address),
+            client: String::new(),
+            state: String::new(),
+            downloaded,
+            download_rate: 0,
+            upload_rate: 0,
+        }
+    }
+
+    #[test]
+    fn test_sort_cycles_columns() {
+        let mut table = ConnectionsTable::default();
+        let connections = vec![
+            item("b", "10.0.0.1:1", 5),
+            item("a", "10.0.0.2:1", 1),
+            item("a", "10.0.0.0:1", 9),
+        ];
+
+        let addresses = |items: Vec<ConnectionItem>| -> Vec<String> {
+            items.into_iter().map(|c| c.address).collect()
+        };
+
+        assert_eq!(
+            addresses(table.sorted(&connections)),
+            vec!["10.0.0.0:1", "10.0.0.2:1", "10.0.0.1:1"]
+        );
+
+        table.cycle_sort();
+        table.cycle_sort();
+        table.cycle_sort();
+        assert_eq!(table.sort, SortColumn::Downloaded);
+        assert_eq!(
+            addresses(table.sorted(&connections)),
+            vec!["10.0.0.0:1", "10.0.0.1:1", "10.0.0.2:1"]
+        );
+    }
+}
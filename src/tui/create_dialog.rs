@@ -0,0 +1,93 @@
+use ratatui::{
+    crossterm::event::KeyCode,
+    prelude::*,
+    widgets::{Block, Borders, Clear, Paragraph},
+};
+
+/// Form for creating a torrent from a local file or directory.
+#[derive(Default)]
+pub struct CreateDialog {
+    pub path: String,
+    pub tracker: String,
+    editing_tracker: bool,
+}
+
+pub enum DialogAction {
+    Submit,
+    Cancel,
+    None,
+}
+
+impl CreateDialog {
+    pub fn handle_key(&mut self, code: KeyCode) -> DialogAction {
+        let complete = !self.path.is_empty();
+        let field = if self.editing_tracker {
+            &mut self.tracker
+        } else {
+            &mut self.path
+        };
+
+        match code {
+            KeyCode::Esc => return DialogAction::Cancel,
+            KeyCode::Enter if complete => {
+                return DialogAction::Submit;
+            }
+            KeyCode::Tab | KeyCode::BackTab | KeyCode::Enter => {
+                self.editing_tracker = !self.editing_tracker
+            }
+            KeyCode::Backspace => {
+                field.pop();
+            }
+            KeyCode::Char(c) => field.push(c),
+            _ => (),
+        }
+
+        DialogAction::None
+    }
+
+    pub fn render(&self, f: &mut Frame, area: Rect) {
+        let popup = centered(area, 60, 6);
+
+        let style = |active: bool| {
+            if active {
+                Style::default().fg(Color::LightBlue)
+            } else {
+                Style::default()
+            }
+        };
+
+        let text = vec![
+            Line::styled(
+                format!("Path:    {}", self.path),
+                style(!self.editing_tracker),
+            ),
+            Line::styled(
+                format!("Tracker: {}", self.tracker),
+                style(self.editing_tracker),
+            ),
+            Line::from(""),
+            Line::from("(Tab) next field | (⏎) create | (Esc) cancel"),
+        ];
+
+        let dialog = Paragraph::new(text).block(
+            Block::default()
+                .title("Create torrent")
+                .borders(Borders::ALL)
+                .border_set(symbols::border::ROUNDED),
+        );
+
+        f.render_widget(Clear, popup);
+        f.render_widget(dialog, popup);
+    }
+}
+
+pub(super) fn centered(area: Rect, percent_x: u16, height: u16) -> Rect {
+    let width = area.width * percent_x / 100;
+
+    Rect {
+        x: area.x + (area.width - width) / 2,
+        y: area.y + area.height.saturating_sub(height) / 2,
+        width,
+        height: height.min(area.height),
+    }
+}
@@ -0,0 +1,72 @@
+use std::time::Duration;
+
+use ratatui::{
+    prelude::*,
+    widgets::{Block, Borders, Cell, Row, Table},
+};
+
+use crate::{app::ui_models::DiskItem, torrent::io_stats::IoSnapshot, tui::format};
+
+/// Disk reads and writes per torrent, with a total across all of them.
+pub fn render(f: &mut Frame, area: Rect, disk_items: &[DiskItem]) {
+    let header = Row::new(vec![
+        Cell::from("Torrent"),
+        Cell::from("Read"),
+        Cell::from("Written"),
+        Cell::from("Avg read"),
+        Cell::from("Avg write"),
+        Cell::from("Queue"),
+    ])
+    .style(
+        Style::default()
+            .fg(Color::Yellow)
+            .add_modifier(Modifier::BOLD),
+    );
+
+    let total = disk_items
+        .iter()
+        .fold(IoSnapshot::default(), |total, item| total + item.stats);
+
+    let mut rows: Vec<Row> = disk_items
+        .iter()
+        .map(|item| stats_row(&item.torrent_name, &item.stats))
+        .collect();
+    rows.push(stats_row("Total", &total).style(Style::default().add_modifier(Modifier::BOLD)));
+
+    let widths = [
+        Constraint::Percentage(30),
+        Constraint::Percentage(14),
+        Constraint::Percentage(14),
+        Constraint::Percentage(14),
+        Constraint::Percentage(14),
+        Constraint::Percentage(14),
+    ];
+
+    let table = Table::new(rows, widths).header(header).block(
+        Block::default()
+            .title("Disk I/O (D) back")
+            .borders(Borders::ALL)
+            .border_set(symbols::border::ROUNDED)
+            .border_style(Style::new().blue()),
+    );
+
+    f.render_widget(table, area);
+}
+
+fn stats_row<'a>(name: &str, stats: &IoSnapshot) -> Row<'a> {
+    Row::new(vec![
+        Cell::from(String::from(name)),
+        Cell::from(format::size(stats.bytes_read)),
+        Cell::from(format::size(stats.bytes_written)),
+        Cell::from(latency(stats.average_read_latency())),
+        Cell::from(latency(stats.average_write_latency())),
+        Cell::from(stats.queue_depth.to_string()),
+    ])
+}
+
+fn latency(latency: Option<Duration>) -> String {
+    match latency {
+        Some(latency) => format!("{:.1} ms", latency.as_secs_f64() * 1000.0),
+        None => String::from("-"),
+    }
+}
@@ -0,0 +1,118 @@
+//! How sizes, rates, durations and times are written across the TUI, so
+//! every view rounds the same way.
+//!
+//! Sizes use binary units (KiB, MiB, ...) unless the `BTRS_DECIMAL_UNITS`
+//! environment variable is set, and a key toggles between the two. Times
+//! are shown in the local time zone.
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use chrono::{DateTime, Local};
+
+pub const DECIMAL_UNITS_ENV_VAR: &str = "BTRS_DECIMAL_UNITS";
+
+const BINARY_UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+const DECIMAL_UNITS: [&str; 6] = ["B", "kB", "MB", "GB", "TB", "PB"];
+
+static DECIMAL: AtomicBool = AtomicBool::new(false);
+
+/// Switches to decimal units if [`DECIMAL_UNITS_ENV_VAR`] is set.
+pub fn init_from_env() {
+    DECIMAL.store(
+        std::env::var_os(DECIMAL_UNITS_ENV_VAR).is_some(),
+        Ordering::Relaxed,
+    );
+}
+
+pub fn toggle_units() {
+    DECIMAL.fetch_xor(true, Ordering::Relaxed);
+}
+
+/// `bytes` in the largest unit that keeps the value at least one, e.g.
+/// `1.5 MiB`. Whole bytes are shown without decimals.
+pub fn size(bytes: u64) -> String {
+    let (base, units) = if DECIMAL.load(Ordering::Relaxed) {
+        (1000.0, DECIMAL_UNITS)
+    } else {
+        (1024.0, BINARY_UNITS)
+    };
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= base && unit < units.len() - 1 {
+        value /= base;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{bytes} B"),
+        _ => format!("{value:.1} {}", units[unit]),
+    }
+}
+
+/// A transfer rate in bytes per second, e.g. `320.0 KiB/s`.
+pub fn rate(bytes_per_second: u64) -> String {
+    format!("{}/s", size(bytes_per_second))
+}
+
+/// The two largest units of `duration`, e.g. `1h 05m` or `42s`.
+pub fn duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let (days, hours, minutes) = (seconds / 86400, seconds / 3600 % 24, seconds / 60 % 60);
+
+    if days > 0 {
+        format!("{days}d {hours:02}h")
+    } else if hours > 0 {
+        format!("{hours}h {minutes:02}m")
+    } else if minutes > 0 {
+        format!("{minutes}m {:02}s", seconds % 60)
+    } else {
+        format!("{seconds}s")
+    }
+}
+
+/// Time left to transfer `remaining` bytes at `rate` bytes per second,
+/// `∞` while nothing is moving.
+pub fn eta(remaining: u64, rate: u64) -> String {
+    match remaining.checked_div(rate) {
+        Some(seconds) => duration(Duration::from_secs(seconds)),
+        None => String::from("∞"),
+    }
+}
+
+/// A unix time as a local date and time.
+pub fn date(unix_time: u64) -> String {
+    match DateTime::from_timestamp(unix_time as i64, 0) {
+        Some(time) => time
+            .with_timezone(&Local)
+            .format("%Y-%m-%d %H:%M")
+            .to_string(),
+        None => String::from("-"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_formats() {
+        // Binary units, the default.
+        assert_eq!(size(0), "0 B");
+        assert_eq!(size(1023), "1023 B");
+        assert_eq!(size(1536), "1.5 KiB");
+        assert_eq!(size(5 * 1024 * 1024 * 1024), "5.0 GiB");
+        assert_eq!(rate(16 * 1024), "16.0 KiB/s");
+
+        assert_eq!(duration(Duration::from_secs(42)), "42s");
+        assert_eq!(duration(Duration::from_secs(185)), "3m 05s");
+        assert_eq!(duration(Duration::from_secs(3900)), "1h 05m");
+        assert_eq!(duration(Duration::from_secs(2 * 86400 + 7200)), "2d 02h");
+
+        assert_eq!(eta(1000, 0), "∞");
+        assert_eq!(eta(1000, 10), "1m 40s");
+    }
+}
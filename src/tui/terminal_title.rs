@@ -0,0 +1,110 @@
+//! Overall progress in the terminal title, so it shows in the tab or tmux
+//! pane title without switching to btrs. Enabled by setting the
+//! `BTRS_TERMINAL_TITLE` environment variable.
+
+use std::io::Write;
+
+use crate::app::ui_models::TorrentItem;
+
+pub const TERMINAL_TITLE_ENV_VAR: &str = "BTRS_TERMINAL_TITLE";
+
+pub struct TerminalTitle {
+    enabled: bool,
+    /// Title last written, to avoid rewriting it every frame.
+    current: Option<String>,
+}
+
+impl TerminalTitle {
+    pub fn from_env() -> Self {
+        Self {
+            enabled: std::env::var_os(TERMINAL_TITLE_ENV_VAR).is_some(),
+            current: None,
+        }
+    }
+
+    /// Sets the title from the current torrents if it changed.
+    pub fn update(&mut self, torrent_items: &[TorrentItem]) {
+        if !self.enabled {
+            return;
+        }
+
+        let title = title_text(torrent_items);
+        if self.current.as_ref() != Some(&title) {
+            write_title(&title);
+            self.current = Some(title);
+        }
+    }
+}
+
+impl Drop for TerminalTitle {
+    fn drop(&mut self) {
+        // An empty title hands it back to the shell or terminal default.
+        if self.current.is_some() {
+            write_title("");
+        }
+    }
+}
+
+/// Mean progress of all torrents along with how many there are, e.g.
+/// `btrs 42% (3 torrents)`.
+fn title_text(torrent_items: &[TorrentItem]) -> String {
+    if torrent_items.is_empty() {
+        return String::from("btrs");
+    }
+
+    let progress =
+        torrent_items.iter().map(|t| t.progress).sum::<f64>() / torrent_items.len() as f64;
+    let plural = if torrent_items.len() == 1 { "" } else { "s" };
+
+    format!(
+        "btrs {:.0}% ({} torrent{plural})",
+        progress * 100.0,
+        torrent_items.len()
+    )
+}
+
+/// Writes the OSC 2 sequence, which tmux also uses for the pane title.
+fn write_title(title: &str) {
+    let mut stdout = std::io::stdout();
+    let _ = write!(stdout, "\x1b]2;{title}\x07").and_then(|_| stdout.flush());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::{files::FileEntry, state::TorrentState};
+
+    fn item(progress: f64) -> TorrentItem {
+        TorrentItem {
+            name: String::new(),
+            progress,
+            state: TorrentState::Paused,
+            tracker_status: String::new(),
+            download_rate: 0,
+            upload_rate: 0,
+            downloaded: 0,
+            uploaded: 0,
+            left: 0,
+            info_hash: String::new(),
+            peer_list: vec![],
+            files: FileEntry::new("."),
+            added: 0,
+            completed: None,
+            peer_messages: vec![],
+            trackers: vec![],
+            peer_sources: vec![],
+            peer_locations: None,
+            allocation: None,
+        }
+    }
+
+    #[test]
+    fn test_title_text() {
+        assert_eq!(title_text(&[]), "btrs");
+        assert_eq!(title_text(&[item(0.5)]), "btrs 50% (1 torrent)");
+        assert_eq!(
+            title_text(&[item(1.0), item(0.0), item(0.26)]),
+            "btrs 42% (3 torrents)"
+        );
+    }
+}
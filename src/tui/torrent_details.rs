@@ -2,18 +2,28 @@ use ratatui::{
     Frame,
     layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
-    text::Span,
+    text::{Line, Span},
     widgets::{
-        Cell, List, ListItem, ListState, Row, Scrollbar, ScrollbarState, Table, TableState, Tabs,
+        Bar, BarChart, BarGroup, Block, Cell, List, ListItem, ListState, Paragraph, Row, Scrollbar,
+        ScrollbarState, Table, TableState, Tabs,
     },
 };
 
 use crate::{
-    app::ui_models::TorrentItem,
+    app::ui_models::{SessionStatus, TorrentItem},
     torrent::{
         Peer,
         files::{FileEntry, FileKind},
+        geoip::GeoStats,
+        peer_session::{
+            capture::Direction as MessageDirection, peer_stats::PeerStatsSnapshot,
+            upload::UploadQueueSnapshot,
+        },
+        peer_store::{PeerSource, SourceStats},
+        privacy,
+        tracker::TrackerStats,
     },
+    tui::{format, torrents_table},
 };
 
 pub struct TorrentDetails {
@@ -27,6 +37,7 @@ impl TorrentDetails {
         f: &mut Frame,
         area: Rect,
         torrent_item: &TorrentItem,
+        status: &SessionStatus,
         active: bool,
     ) {
         // Split into tab bar and content
@@ -36,21 +47,28 @@ impl TorrentDetails {
             .split(area);
 
         // Tab bar
-        let titles: Vec<Span> = vec!["[P]eers", "[F]iles"]
-            .iter()
-            .enumerate()
-            .map(|(idx, t)| {
-                let title = if idx == self.selected_tab {
-                    format!("{}{}", &t[1..2], &t[3..])
-                } else {
-                    String::from(*t)
-                };
-                let style = Style::default()
-                    .fg(Color::LightBlue)
-                    .add_modifier(Modifier::BOLD);
-                Span::styled(format!(" {} ", title), style)
-            })
-            .collect();
+        let titles: Vec<Span> = [
+            "[P]eers",
+            "[F]iles",
+            "I[n]fo",
+            "De[b]ug",
+            "T[r]ackers",
+            "Disco[v]ery",
+        ]
+        .iter()
+        .enumerate()
+        .map(|(idx, t)| {
+            let title = if idx == self.selected_tab {
+                t.replace(['[', ']'], "")
+            } else {
+                String::from(*t)
+            };
+            let style = Style::default()
+                .fg(Color::LightBlue)
+                .add_modifier(Modifier::BOLD);
+            Span::styled(format!(" {} ", title), style)
+        })
+        .collect();
 
         let tabs = Tabs::new(titles).select(self.selected_tab);
 
@@ -59,12 +77,32 @@ impl TorrentDetails {
         match self.selected_tab {
             0 => self.render_peers(f, chunks[1], &torrent_item.peer_list, active),
             1 => self.render_files(f, chunks[1], &torrent_item.files, active),
+            2 => Self::render_info(f, chunks[1], torrent_item),
+            3 => Self::render_debug(f, chunks[1], torrent_item, status),
+            4 => Self::render_trackers(f, chunks[1], &torrent_item.trackers),
+            5 => Self::render_sources(
+                f,
+                chunks[1],
+                &torrent_item.peer_sources,
+                torrent_item.peer_locations.as_ref(),
+            ),
             _ => (),
         }
     }
 
     pub fn render_peers(&mut self, f: &mut Frame, area: Rect, peers: &[Peer], active: bool) {
-        let header = Row::new(vec![Cell::from("IP"), Cell::from("Port")]).style(
+        let header = Row::new(vec![
+            Cell::from("IP"),
+            Cell::from("Port"),
+            Cell::from("Client"),
+            Cell::from("Down"),
+            Cell::from("Up"),
+            Cell::from("Requests"),
+            Cell::from("Queued"),
+            Cell::from("Idle"),
+            Cell::from("Comments"),
+        ])
+        .style(
             Style::default()
                 .fg(Color::Yellow)
                 .add_modifier(Modifier::BOLD),
@@ -74,13 +112,32 @@ impl TorrentDetails {
             .iter()
             .map(|peer| {
                 Row::new(vec![
-                    Cell::from(peer.ip.clone()),
+                    Cell::from(privacy::address(&peer.ip)),
                     Cell::from(peer.port.to_string()),
+                    Cell::from(peer.client.clone().unwrap_or_default()),
+                    Cell::from(stat(peer, |s| format::rate(s.download_rate))),
+                    Cell::from(stat(peer, |s| format::rate(s.upload_rate))),
+                    Cell::from(stat(peer, |s| {
+                        format!("{}/{}", s.requests_sent, s.requests_received)
+                    })),
+                    Cell::from(peer.upload_queue.as_ref().map(queued).unwrap_or_default()),
+                    Cell::from(stat(peer, |s| format::duration(s.idle))),
+                    Cell::from(peer.comments.join(" | ")),
                 ])
             })
             .collect();
 
-        let widths = [Constraint::Percentage(70), Constraint::Percentage(30)];
+        let widths = [
+            Constraint::Percentage(16),
+            Constraint::Percentage(6),
+            Constraint::Percentage(13),
+            Constraint::Percentage(10),
+            Constraint::Percentage(10),
+            Constraint::Percentage(9),
+            Constraint::Percentage(11),
+            Constraint::Percentage(7),
+            Constraint::Percentage(18),
+        ];
 
         let table = Table::new(rows, widths).header(header);
 
@@ -111,12 +168,19 @@ impl TorrentDetails {
                     FileKind::Directory { .. } => "📁 ",
                     FileKind::File => "📄 ",
                 };
-                ListItem::new(Span::raw(format!("{}{}{}", indent, prefix, entry.name)))
+                ListItem::new(Span::raw(format!(
+                    "{}{}{}",
+                    indent,
+                    prefix,
+                    privacy::name(&entry.name)
+                )))
             })
             .collect();
 
         let mut state = ListState::default();
-        state.select(Some(self.selected));
+        if active {
+            state.select(Some(self.selected));
+        }
 
         let list = List::new(items).highlight_style(Style::default().fg(Color::LightBlue));
 
@@ -124,6 +188,312 @@ impl TorrentDetails {
     }
 }
 
+impl TorrentDetails {
+    fn render_info(f: &mut Frame, area: Rect, torrent_item: &TorrentItem) {
+        let completed = match torrent_item.completed {
+            Some(completed) => format::date(completed),
+            None => String::from("-"),
+        };
+
+        let lines = vec![
+            Line::from(format!("Name:      {}", privacy::name(&torrent_item.name))),
+            Line::from(format!(
+                "Info hash: {}",
+                privacy::info_hash(&torrent_item.info_hash)
+            )),
+            Line::from(vec![
+                Span::raw("State:     "),
+                Span::styled(
+                    torrent_item.state.to_string(),
+                    torrents_table::state_style(&torrent_item.state),
+                ),
+            ]),
+            Line::from(format!("Added:     {}", format::date(torrent_item.added))),
+            Line::from(format!("Completed: {completed}")),
+            Line::from(format!(
+                "Down:      {} ({} total)",
+                format::rate(torrent_item.download_rate),
+                format::size(torrent_item.downloaded)
+            )),
+            Line::from(format!(
+                "Up:        {} ({} total)",
+                format::rate(torrent_item.upload_rate),
+                format::size(torrent_item.uploaded)
+            )),
+            Line::from(format!(
+                "Left:      {}, ETA {}",
+                format::size(torrent_item.left),
+                format::eta(torrent_item.left, torrent_item.download_rate)
+            )),
+            Line::from(format!("Tracker:   {}", torrent_item.tracker_status)),
+            Line::from(format!(
+                "Allocate:  {}",
+                torrent_item
+                    .allocation
+                    .map_or(String::from("by filesystem"), |a| a.to_string())
+            )),
+        ];
+
+        f.render_widget(Paragraph::new(lines), area);
+    }
+}
+
+impl TorrentDetails {
+    /// Histogram of every message received since start up, above the
+    /// message counts and upload queue of each of this torrent's peers.
+    fn render_debug(f: &mut Frame, area: Rect, torrent_item: &TorrentItem, status: &SessionStatus) {
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Length(1),
+                Constraint::Length(8),
+                Constraint::Min(0),
+            ])
+            .split(area);
+
+        let tasks = status
+            .tasks
+            .iter()
+            .map(|(name, count)| format!("{name} {count}"))
+            .collect::<Vec<_>>()
+            .join(" | ");
+        f.render_widget(Paragraph::new(format!("Running tasks: {tasks}")), chunks[0]);
+
+        let bars: Vec<Bar> = status
+            .messages
+            .top(MessageDirection::Received, 8)
+            .into_iter()
+            .map(|(name, count)| Bar::default().label(name.into()).value(count))
+            .collect();
+        let chart = BarChart::default()
+            .block(Block::default().title("Received by all peers"))
+            .data(BarGroup::default().bars(&bars))
+            .bar_width(9)
+            .bar_gap(1);
+        f.render_widget(chart, chunks[1]);
+
+        let header = Row::new(vec![
+            Cell::from("Peer"),
+            Cell::from("Sent"),
+            Cell::from("Received"),
+            Cell::from("Upload queue"),
+            Cell::from("Refused"),
+            Cell::from("Most received"),
+        ])
+        .style(
+            Style::default()
+                .fg(Color::Yellow)
+                .add_modifier(Modifier::BOLD),
+        );
+
+        let rows: Vec<Row> = torrent_item
+            .peer_messages
+            .iter()
+            .map(|(address, counts, uploads)| {
+                let top = counts
+                    .top(MessageDirection::Received, 3)
+                    .iter()
+                    .map(|(name, count)| format!("{name} {count}"))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+
+                Row::new(vec![
+                    Cell::from(privacy::address(address)),
+                    Cell::from(counts.total(MessageDirection::Sent).to_string()),
+                    Cell::from(counts.total(MessageDirection::Received).to_string()),
+                    Cell::from(queued(uploads)),
+                    Cell::from(uploads.dropped.to_string()),
+                    Cell::from(top),
+                ])
+            })
+            .collect();
+
+        let widths = [
+            Constraint::Percentage(26),
+            Constraint::Percentage(9),
+            Constraint::Percentage(9),
+            Constraint::Percentage(15),
+            Constraint::Percentage(8),
+            Constraint::Percentage(33),
+        ];
+
+        f.render_widget(Table::new(rows, widths).header(header), chunks[2]);
+    }
+}
+
+impl TorrentDetails {
+    fn render_trackers(f: &mut Frame, area: Rect, trackers: &[TrackerStats]) {
+        let header = Row::new(vec![
+            Cell::from("URL"),
+            Cell::from("Status"),
+            Cell::from("Last announce"),
+            Cell::from("Next"),
+            Cell::from("Peers"),
+            Cell::from("Seeds"),
+            Cell::from("Leechers"),
+            Cell::from("Warning / last error"),
+        ])
+        .style(
+            Style::default()
+                .fg(Color::Yellow)
+                .add_modifier(Modifier::BOLD),
+        );
+
+        let count = |count: Option<u64>| count.map_or(String::from("-"), |c| c.to_string());
+        let rows: Vec<Row> = trackers
+            .iter()
+            .map(|tracker| {
+                Row::new(vec![
+                    Cell::from(privacy::tracker_url(&tracker.url)),
+                    Cell::from(tracker.status.to_string()),
+                    Cell::from(
+                        tracker
+                            .last_announce
+                            .map_or(String::from("-"), format::date),
+                    ),
+                    Cell::from(
+                        tracker
+                            .next_announce
+                            .map_or(String::from("-"), format::duration),
+                    ),
+                    Cell::from(tracker.peers.to_string()),
+                    Cell::from(count(tracker.seeders)),
+                    Cell::from(count(tracker.leechers)),
+                    match (&tracker.warning, &tracker.last_error) {
+                        (Some(warning), _) => {
+                            Cell::from(warning.clone()).style(Style::default().fg(Color::Yellow))
+                        }
+                        (None, error) => Cell::from(error.clone().unwrap_or_default()),
+                    },
+                ])
+            })
+            .collect();
+
+        let widths = [
+            Constraint::Percentage(20),
+            Constraint::Percentage(12),
+            Constraint::Percentage(14),
+            Constraint::Percentage(6),
+            Constraint::Percentage(6),
+            Constraint::Percentage(6),
+            Constraint::Percentage(8),
+            Constraint::Percentage(28),
+        ];
+
+        f.render_widget(Table::new(rows, widths).header(header), area);
+    }
+}
+
+impl TorrentDetails {
+    fn render_sources(
+        f: &mut Frame,
+        area: Rect,
+        sources: &[(PeerSource, SourceStats)],
+        locations: Option<&GeoStats>,
+    ) {
+        // Where connected peers are, below where they were found.
+        let area = match locations {
+            Some(locations) => {
+                let chunks = Layout::default()
+                    .direction(Direction::Vertical)
+                    .constraints([
+                        Constraint::Length(sources.len() as u16 + 2),
+                        Constraint::Min(0),
+                    ])
+                    .split(area);
+                let columns = Layout::default()
+                    .direction(Direction::Horizontal)
+                    .constraints([Constraint::Percentage(40), Constraint::Percentage(60)])
+                    .split(chunks[1]);
+                Self::render_distribution(f, columns[0], "Country", &locations.countries);
+                Self::render_distribution(f, columns[1], "Network", &locations.networks);
+                chunks[0]
+            }
+            None => area,
+        };
+
+        let header = Row::new(vec![
+            Cell::from("Source"),
+            Cell::from("Peers"),
+            Cell::from("Attempted"),
+            Cell::from("Connected"),
+            Cell::from("Success"),
+        ])
+        .style(
+            Style::default()
+                .fg(Color::Yellow)
+                .add_modifier(Modifier::BOLD),
+        );
+
+        let rows: Vec<Row> = sources
+            .iter()
+            .map(|(source, stats)| {
+                let success = match stats.attempted {
+                    0 => String::from("-"),
+                    attempted => {
+                        format!("{:.0}%", stats.connected as f64 * 100.0 / attempted as f64)
+                    }
+                };
+                Row::new(vec![
+                    Cell::from(source.to_string()),
+                    Cell::from(stats.peers.to_string()),
+                    Cell::from(stats.attempted.to_string()),
+                    Cell::from(stats.connected.to_string()),
+                    Cell::from(success),
+                ])
+            })
+            .collect();
+
+        let widths = [Constraint::Percentage(20); 5];
+
+        f.render_widget(Table::new(rows, widths).header(header), area);
+    }
+
+    /// Connected peers per `kind` of location, with their share.
+    fn render_distribution(f: &mut Frame, area: Rect, kind: &str, counts: &[(String, usize)]) {
+        let header = Row::new(vec![
+            Cell::from(kind),
+            Cell::from("Peers"),
+            Cell::from("Share"),
+        ])
+        .style(
+            Style::default()
+                .fg(Color::Yellow)
+                .add_modifier(Modifier::BOLD),
+        );
+
+        let total: usize = counts.iter().map(|(_, count)| count).sum();
+        let rows: Vec<Row> = counts
+            .iter()
+            .map(|(name, count)| {
+                Row::new(vec![
+                    Cell::from(name.as_str()),
+                    Cell::from(count.to_string()),
+                    Cell::from(format!("{:.0}%", *count as f64 * 100.0 / total as f64)),
+                ])
+            })
+            .collect();
+
+        let widths = [
+            Constraint::Percentage(60),
+            Constraint::Percentage(20),
+            Constraint::Percentage(20),
+        ];
+
+        f.render_widget(Table::new(rows, widths).header(header), area);
+    }
+}
+
+/// A stat of `peer` while it is connected, blank otherwise.
+fn stat(peer: &Peer, show: impl Fn(&PeerStatsSnapshot) -> String) -> String {
+    peer.stats.as_ref().map(show).unwrap_or_default()
+}
+
+/// Blocks a peer waits for us to send, and their size.
+fn queued(uploads: &UploadQueueSnapshot) -> String {
+    format!("{} ({})", uploads.blocks, format::size(uploads.bytes))
+}
+
 fn flatten_all<'a>(entry: &'a FileEntry, depth: usize, out: &mut Vec<(usize, &'a FileEntry)>) {
     out.push((depth, entry));
     if let FileKind::Directory { children } = &entry.kind {
@@ -3,17 +3,193 @@ use ratatui::{
     widgets::{Block, Borders, Cell, HighlightSpacing, Row, Table, TableState},
 };
 
-use crate::app::ui_models::TorrentItem;
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet},
+};
+
+use reqwest::Url;
+
+use crate::{
+    app::ui_models::TorrentItem,
+    torrent::{privacy, state::TorrentState},
+    tui::format,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TorrentSort {
+    /// Order torrents were loaded in the app, by info hash.
+    #[default]
+    InfoHash,
+    /// Newest first.
+    Added,
+    /// Most recently completed first, incomplete torrents last.
+    Completed,
+}
+
+impl TorrentSort {
+    fn next(self) -> Self {
+        match self {
+            Self::InfoHash => Self::Added,
+            Self::Added => Self::Completed,
+            Self::Completed => Self::InfoHash,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TorrentGrouping {
+    #[default]
+    None,
+    /// By the host of the torrent's first tracker.
+    Tracker,
+    State,
+}
+
+impl TorrentGrouping {
+    fn next(self) -> Self {
+        match self {
+            Self::None => Self::Tracker,
+            Self::Tracker => Self::State,
+            Self::State => Self::None,
+        }
+    }
+
+    /// Group `torrent` falls into.
+    fn group(self, torrent: &TorrentItem) -> String {
+        match self {
+            Self::None => String::new(),
+            Self::Tracker => torrent
+                .trackers
+                .first()
+                .and_then(|tracker| Url::parse(&tracker.url).ok())
+                .and_then(|url| url.host_str().map(String::from))
+                .unwrap_or_else(|| String::from("no tracker")),
+            // Errors share a group whatever the reason.
+            Self::State => match &torrent.state {
+                TorrentState::Error(_) => String::from("Error"),
+                state => state.to_string(),
+            },
+        }
+    }
+}
+
+/// A line of the table.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TableRow {
+    /// Header of a group, with the number of torrents in it and their
+    /// combined progress.
+    Group {
+        name: String,
+        count: usize,
+        progress: f64,
+        collapsed: bool,
+    },
+    /// Index of a torrent in the sorted torrents.
+    Torrent(usize),
+}
 
+#[derive(Default)]
 pub struct TorrentsTable {
+    /// Selected row, see [`TorrentsTable::rows`].
     pub selected: usize,
+    pub sort: TorrentSort,
+    pub grouping: TorrentGrouping,
+    /// Groups showing only their header.
+    collapsed: BTreeSet<String>,
 }
 
 impl TorrentsTable {
-    pub fn render(&self, f: &mut Frame, area: Rect, torrents: &[TorrentItem], active: bool) {
+    pub fn cycle_sort(&mut self) {
+        self.sort = self.sort.next();
+    }
+
+    pub fn cycle_grouping(&mut self) {
+        self.grouping = self.grouping.next();
+        self.collapsed.clear();
+        self.selected = 0;
+    }
+
+    /// Collapses the group `name`, or expands it again.
+    pub fn toggle_group(&mut self, name: &str) {
+        if !self.collapsed.remove(name) {
+            self.collapsed.insert(String::from(name));
+        }
+    }
+
+    /// Lines of the table for the sorted `torrents`: one per torrent, or
+    /// a header per group followed by its torrents unless collapsed.
+    pub fn rows(&self, torrents: &[TorrentItem]) -> Vec<TableRow> {
+        if self.grouping == TorrentGrouping::None {
+            return (0..torrents.len()).map(TableRow::Torrent).collect();
+        }
+
+        let mut groups: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+        for (index, torrent) in torrents.iter().enumerate() {
+            groups
+                .entry(self.grouping.group(torrent))
+                .or_default()
+                .push(index);
+        }
+
+        let mut rows = vec![];
+        for (name, members) in groups {
+            let collapsed = self.collapsed.contains(&name);
+            let progress = members
+                .iter()
+                .map(|&index| torrents[index].progress)
+                .sum::<f64>()
+                / members.len() as f64;
+
+            rows.push(TableRow::Group {
+                name,
+                count: members.len(),
+                progress,
+                collapsed,
+            });
+            if !collapsed {
+                rows.extend(members.into_iter().map(TableRow::Torrent));
+            }
+        }
+
+        rows
+    }
+
+    pub fn sorted(&self, torrents: &[TorrentItem]) -> Vec<TorrentItem> {
+        let mut torrents = torrents.to_vec();
+
+        match self.sort {
+            TorrentSort::InfoHash => {}
+            TorrentSort::Added => torrents.sort_by_key(|t| Reverse(t.added)),
+            TorrentSort::Completed => torrents.sort_by_key(|t| Reverse(t.completed)),
+        }
+
+        torrents
+    }
+
+    pub fn render(
+        &self,
+        f: &mut Frame,
+        area: Rect,
+        torrents: &[TorrentItem],
+        rows: &[TableRow],
+        active: bool,
+    ) {
+        let sort = match self.sort {
+            TorrentSort::InfoHash => "",
+            TorrentSort::Added => " by added",
+            TorrentSort::Completed => " by completed",
+        };
+        let sort = match self.grouping {
+            TorrentGrouping::None => String::from(sort),
+            TorrentGrouping::Tracker => format!("{sort} per tracker"),
+            TorrentGrouping::State => format!("{sort} per state"),
+        };
         let header = Row::new(vec![
             Cell::from("Name"),
             Cell::from("Status"),
+            Cell::from("Down"),
+            Cell::from("Up"),
             Cell::from("Info Hash"),
         ])
         .style(
@@ -22,28 +198,62 @@ impl TorrentsTable {
                 .add_modifier(Modifier::BOLD),
         );
 
-        let rows: Vec<Row> = torrents
+        let indent = if self.grouping == TorrentGrouping::None {
+            ""
+        } else {
+            "  "
+        };
+        let rows: Vec<Row> = rows
             .iter()
-            .map(|t| {
-                Row::new(vec![
-                    Cell::from(t.name.clone()),
-                    Cell::from(t.status.clone()),
-                    Cell::from(t.info_hash.clone()),
-                ])
+            .map(|row| match row {
+                TableRow::Group {
+                    name,
+                    count,
+                    progress,
+                    collapsed,
+                } => {
+                    let name = match self.grouping {
+                        TorrentGrouping::Tracker => privacy::tracker_url(name),
+                        _ => name.clone(),
+                    };
+                    Row::new(vec![
+                        Cell::from(format!(
+                            "{} {name} ({count})",
+                            if *collapsed { "▶" } else { "▼" }
+                        )),
+                        Cell::from(format!("{:.1}% done", progress * 100.0)),
+                        Cell::from(""),
+                        Cell::from(""),
+                        Cell::from(""),
+                    ])
+                    .style(Style::default().add_modifier(Modifier::BOLD))
+                }
+                TableRow::Torrent(index) => {
+                    let t = &torrents[*index];
+                    Row::new(vec![
+                        Cell::from(format!("{indent}{}", privacy::name(&t.name))),
+                        Cell::from(t.state.to_string()).style(state_style(&t.state)),
+                        Cell::from(format::rate(t.download_rate)),
+                        Cell::from(format::rate(t.upload_rate)),
+                        Cell::from(privacy::info_hash(&t.info_hash)),
+                    ])
+                }
             })
             .collect();
 
         let widths = [
-            Constraint::Percentage(40),
-            Constraint::Percentage(30),
             Constraint::Percentage(30),
+            Constraint::Percentage(20),
+            Constraint::Percentage(12),
+            Constraint::Percentage(12),
+            Constraint::Percentage(26),
         ];
 
         let mut table = Table::new(rows, widths)
             .header(header)
             .block(
                 Block::default()
-                    .title("[T]orrents")
+                    .title(format!("[T]orrents{sort}"))
                     .borders(Borders::ALL)
                     .border_set(symbols::border::ROUNDED),
             )
@@ -59,7 +269,7 @@ impl TorrentsTable {
         if active {
             table = table.block(
                 Block::default()
-                    .title("Torrents")
+                    .title(format!("Torrents{sort} (S) sort (Y) group"))
                     .borders(Borders::ALL)
                     .border_set(symbols::border::ROUNDED)
                     .add_modifier(Modifier::BOLD)
@@ -75,3 +285,108 @@ impl TorrentsTable {
         f.render_stateful_widget(table, area, &mut state);
     }
 }
+
+/// Colours a torrent's state the same wherever it is shown.
+pub fn state_style(state: &TorrentState) -> Style {
+    let color = match state {
+        TorrentState::Queued | TorrentState::Checking => Color::Yellow,
+        TorrentState::FetchingMetadata => Color::Magenta,
+        TorrentState::Downloading => Color::LightBlue,
+        TorrentState::Seeding => Color::Green,
+        TorrentState::Paused => Color::DarkGray,
+        TorrentState::Error(_) => Color::Red,
+    };
+
+    Style::default().fg(color)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::{files::FileEntry, state::TorrentState};
+
+    #[test]
+    fn test_groups_collapse() {
+        let mut table = TorrentsTable::default();
+        let mut seeding = item("b", 0, Some(1));
+        seeding.state = TorrentState::Seeding;
+        seeding.progress = 1.0;
+        let torrents = vec![item("a", 0, None), seeding, item("c", 0, None)];
+
+        assert_eq!(table.rows(&torrents).len(), 3);
+
+        table.cycle_grouping();
+        table.cycle_grouping();
+        assert_eq!(table.grouping, TorrentGrouping::State);
+        let group = |name: &str, count, progress, collapsed| TableRow::Group {
+            name: String::from(name),
+            count,
+            progress,
+            collapsed,
+        };
+        assert_eq!(
+            table.rows(&torrents),
+            vec![
+                group("Paused", 2, 0.0, false),
+                TableRow::Torrent(0),
+                TableRow::Torrent(2),
+                group("Seeding", 1, 1.0, false),
+                TableRow::Torrent(1),
+            ]
+        );
+
+        table.toggle_group("Paused");
+        assert_eq!(
+            table.rows(&torrents)[..2],
+            [
+                group("Paused", 2, 0.0, true),
+                group("Seeding", 1, 1.0, false)
+            ]
+        );
+    }
+
+    fn item(name: &str, added: u64, completed: Option<u64>) -> TorrentItem {
+        TorrentItem {
+            name: String::from(name),
+            progress: 0.0,
+            state: TorrentState::Paused,
+            tracker_status: String::new(),
+            download_rate: 0,
+            upload_rate: 0,
+            downloaded: 0,
+            uploaded: 0,
+            left: 0,
+            info_hash: String::new(),
+            peer_list: vec![],
+            files: FileEntry::new("."),
+            added,
+            completed,
+            peer_messages: vec![],
+            trackers: vec![],
+            peer_sources: vec![],
+            peer_locations: None,
+            allocation: None,
+        }
+    }
+
+    #[test]
+    fn test_sort_by_dates() {
+        let mut table = TorrentsTable::default();
+        let torrents = vec![
+            item("old", 10, Some(50)),
+            item("new", 30, None),
+            item("middle", 20, Some(40)),
+        ];
+        let names = |items: Vec<TorrentItem>| -> Vec<String> {
+            items.into_iter().map(|t| t.name).collect()
+        };
+
+        assert_eq!(names(table.sorted(&torrents)), vec!["old", "new", "middle"]);
+
+        table.cycle_sort();
+        assert_eq!(names(table.sorted(&torrents)), vec!["new", "middle", "old"]);
+
+        table.cycle_sort();
+        assert_eq!(names(table.sorted(&torrents)), vec!["old", "middle", "new"]);
+    }
+}
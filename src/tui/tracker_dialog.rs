@@ -0,0 +1,99 @@
+use ratatui::{
+    crossterm::event::KeyCode,
+    prelude::*,
+    widgets::{Block, Borders, Clear, Paragraph},
+};
+
+use crate::{
+    torrent::privacy,
+    tui::create_dialog::{DialogAction, centered},
+};
+
+/// Form for adding a tracker to a torrent, or picking one of its trackers
+/// to remove.
+pub struct TrackerDialog {
+    pub info_hash: String,
+    pub url: String,
+    /// Removing one of `trackers` instead of adding `url`.
+    pub remove: bool,
+    trackers: Vec<String>,
+    selected: usize,
+}
+
+impl TrackerDialog {
+    pub fn new(info_hash: String, trackers: Vec<String>) -> Self {
+        Self {
+            info_hash,
+            url: String::new(),
+            remove: false,
+            trackers,
+            selected: 0,
+        }
+    }
+
+    /// The tracker chosen for removal.
+    pub fn selected_tracker(&self) -> Option<&String> {
+        self.trackers.get(self.selected)
+    }
+
+    pub fn handle_key(&mut self, code: KeyCode) -> DialogAction {
+        match code {
+            KeyCode::Esc => return DialogAction::Cancel,
+            KeyCode::Enter if self.remove && self.selected_tracker().is_some() => {
+                return DialogAction::Submit;
+            }
+            KeyCode::Enter if !self.remove && !self.url.is_empty() => {
+                return DialogAction::Submit;
+            }
+            KeyCode::Tab | KeyCode::BackTab => self.remove = !self.remove,
+            KeyCode::Up if self.remove => self.selected = self.selected.saturating_sub(1),
+            KeyCode::Down if self.remove && self.selected + 1 < self.trackers.len() => {
+                self.selected += 1
+            }
+            KeyCode::Backspace if !self.remove => {
+                self.url.pop();
+            }
+            KeyCode::Char(c) if !self.remove => self.url.push(c),
+            _ => (),
+        }
+
+        DialogAction::None
+    }
+
+    pub fn render(&self, f: &mut Frame, area: Rect) {
+        let popup = centered(area, 60, self.trackers.len() as u16 + 6);
+
+        let style = |active: bool| {
+            if active {
+                Style::default().fg(Color::LightBlue)
+            } else {
+                Style::default()
+            }
+        };
+
+        let mut text = vec![Line::styled(
+            format!("Add:     {}", self.url),
+            style(!self.remove),
+        )];
+        for (idx, tracker) in self.trackers.iter().enumerate() {
+            text.push(Line::styled(
+                format!("Remove:  {}", privacy::tracker_url(tracker)),
+                style(self.remove && idx == self.selected),
+            ));
+        }
+        text.push(Line::from(""));
+        text.push(Line::from(
+            "(Tab) add/remove | (↑↓) pick tracker | (⏎) apply | (Esc) cancel",
+        ));
+
+        let dialog = Paragraph::new(text).block(
+            Block::default()
+                .title("Edit trackers")
+                .borders(Borders::ALL)
+                .border_set(symbols::border::ROUNDED),
+        );
+
+        f.render_widget(Clear, popup);
+        f.render_widget(dialog, popup);
+    }
+}
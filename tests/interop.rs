@@ -0,0 +1,128 @@
+//! Interoperability tests against a reference BitTorrent client.
+//!
+//! These are ignored by default since they need a running seed. Start a
+//! transmission-daemon container seeding the torrent under test, e.g.
+//!
+//! ```text
+//! docker run -d --name btrs-interop -p 51413:51413 \
+//!     -v "$PWD/interop/data:/downloads/complete" \
+//!     -v "$PWD/interop/watch:/watch" \
+//!     lscr.io/linuxserver/transmission
+//! ```
+//!
+//! then point the tests at it:
+//!
+//! ```text
+//! BTRS_INTEROP_TORRENT=interop/watch/test.torrent \
+//! BTRS_INTEROP_PEER=127.0.0.1:51413 \
+//!     cargo test --test interop -- --ignored
+//! ```
+
+use std::{env, fs, sync::Arc, time::Duration};
+
+use sha1::{Digest, Sha1};
+use tokio::sync::{Mutex, mpsc::channel};
+
+use btrs::torrent::{
+    Torrent,
+    metainfo::{MetaInfo, info::InfoEnum},
+    peer_session::PeerSession,
+    piece_manager::{PieceRequest, PieceResponse, WorkQueue},
+};
+
+const CLIENT_ID: [u8; 20] = *b"-RS0001-interoptest1";
+
+/// Overall deadline for downloading the whole torrent from the seed.
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(300);
+
+struct Fixture {
+    peer: String,
+    info_hash: [u8; 20],
+    piece_hashes: Vec<[u8; 20]>,
+    piece_length: u64,
+    total_length: u64,
+}
+
+fn load_fixture() -> Fixture {
+    let torrent_path =
+        env::var("BTRS_INTEROP_TORRENT").expect("BTRS_INTEROP_TORRENT must point to a .torrent");
+    let peer = env::var("BTRS_INTEROP_PEER").unwrap_or_else(|_| String::from("127.0.0.1:51413"));
+
+    let bytes = fs::read(&torrent_path).expect("failed to read BTRS_INTEROP_TORRENT");
+    let torrent = Torrent::load(&bytes, "-RS0001-interoptest1").unwrap();
+    let metainfo = MetaInfo::from_bytes(&bytes).unwrap();
+
+    let info_hash = *torrent.info_hash();
+
+    let (pieces, piece_length, total_length) = match metainfo.info() {
+        InfoEnum::SingleFile(info) => (&info.pieces, info.piece_length, info.length),
+        InfoEnum::MultiFile(info) => (
+            &info.pieces,
+            info.piece_length,
+            info.files.iter().map(|f| f.length).sum(),
+        ),
+    };
+
+    Fixture {
+        peer,
+        info_hash,
+        piece_hashes: pieces
+            .chunks_exact(20)
+            .map(|hash| hash.try_into().unwrap())
+            .collect(),
+        piece_length,
+        total_length,
+    }
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_download_from_reference_seed() {
+    let fixture = load_fixture();
+    let num_pieces = fixture.piece_hashes.len();
+
+    let work_queue = Arc::new(Mutex::new(WorkQueue::new()));
+    let (piece_tx, mut piece_rx) = channel::<PieceResponse>(100);
+
+    {
+        let mut queue = work_queue.lock().await;
+        for (idx, _) in fixture.piece_hashes.iter().enumerate() {
+            let offset = idx as u64 * fixture.piece_length;
+            let length = fixture.piece_length.min(fixture.total_length - offset);
+
+            queue.push(PieceRequest {
+                piece_index: idx as u32,
+                length_bytes: length as usize,
+            });
+        }
+    }
+
+    let mut session = PeerSession::new(&fixture.peer, CLIENT_ID, fixture.info_hash)
+        .await
+        .unwrap();
+    session.start(work_queue.clone(), piece_tx).await.unwrap();
+
+    let mut verified = vec![false; num_pieces];
+    let download = async {
+        while verified.iter().any(|v| !v) {
+            let response = piece_rx.recv().await.expect("peer session ended early");
+            let idx = response.piece_index as usize;
+            let data = response.result.expect("peer sent a malformed piece");
+
+            let hash: [u8; 20] = Sha1::digest(&data).into();
+            assert_eq!(
+                hash, fixture.piece_hashes[idx],
+                "piece {idx} failed hash check"
+            );
+
+            verified[idx] = true;
+            work_queue.lock().await.complete(response.piece_index);
+        }
+    };
+
+    tokio::time::timeout(DOWNLOAD_TIMEOUT, download)
+        .await
+        .expect("download from reference seed timed out");
+
+    // TODO: seed back to the reference client once uploading is supported.
+}